@@ -1,5 +1,5 @@
 use memmap::MmapOptions;
-use tdb_core::dtf::{self, file_format as ff};
+use tdb_core::dtf::{self, file_format as ff, scale::Scale};
 use tdb_core::postprocessing::candle::time_bars::TimeBars;
 use std::fs::File;
 use indicatif::{ProgressBar, ProgressStyle};
@@ -52,6 +52,7 @@ pub fn run(matches: &clap::ArgMatches) {
                 let rdr = unsafe { MmapOptions::new().map(&file).unwrap() };
                 let mut rdr = std::io::Cursor::new(rdr);
                 let meta = dtf::file_format::read_meta_from_buf(&mut rdr).unwrap();
+                let scale = meta.scale;
                 let mut it = dtf::file_format::iterators::DTFBufReader::new(rdr);
 
                 let bar = ProgressBar::new(meta.count);
@@ -69,16 +70,17 @@ pub fn run(matches: &clap::ArgMatches) {
                     if has_output {
                         ret.push(up);
                     } else if csv {
-                        println!("{}", up.to_csv()) // TODO: slooooow
+                        println!("{}", up.to_csv(&scale)) // TODO: slooooow
                     } else {
-                        println!("[{}]", up.as_json())
+                        println!("[{}]", up.as_json(&scale))
                     }
                 }
                 bar.finish();
 
                 if has_output {
                     let fname = matches.value_of("output").unwrap();
-                    ff::encode(fname, symbol, &ret).unwrap();
+                    let opts = ff::EncodeOptions { scale, ..Default::default() };
+                    ff::encode_with(fname, symbol, &ret, &opts).unwrap();
                 }
             }
         }
@@ -102,6 +104,7 @@ pub fn run(matches: &clap::ArgMatches) {
                     .to_csv();
                 println!("{}", rebinned)
             } else {
+                let scale = folder_scale(folder, symbol);
                 let mut ret = vec![];
                 tdb_core::dtf::file_format::scan_files_for_range_for_each(
                     &folder,
@@ -112,15 +115,16 @@ pub fn run(matches: &clap::ArgMatches) {
                         if has_output {
                             ret.push(*up);
                         } else if csv {
-                            println!("{}", up.to_csv())
+                            println!("{}", up.to_csv(&scale))
                         } else {
-                            println!("[{}]", up.as_json())
+                            println!("[{}]", up.as_json(&scale))
                         }
                     }).unwrap();
 
                 if has_output {
                     let fname = matches.value_of("output").unwrap();
-                    ff::encode(fname, symbol, &ret).unwrap();
+                    let opts = ff::EncodeOptions { scale, ..Default::default() };
+                    ff::encode_with(fname, symbol, &ret, &opts).unwrap();
                 }
 
             }
@@ -128,4 +132,18 @@ pub fn run(matches: &clap::ArgMatches) {
     };
 
 
-}
\ No newline at end of file
+}
+
+/// scale of the first file of `symbol` in `folder`
+fn folder_scale(folder: &str, symbol: &str) -> Scale {
+    std::fs::read_dir(folder).ok()
+        .and_then(|entries| entries
+            .filter_map(|entry| ff::read_meta(entry.ok()?.path().to_str()?).ok())
+            .find(|meta| meta.symbol == symbol))
+        .or_else(|| {
+            let partitions = tdb_core::storage::partition::partitions(folder, symbol).ok()?;
+            ff::read_meta(&partitions.first()?.fname).ok()
+        })
+        .map(|meta| meta.scale)
+        .unwrap_or_default()
+}
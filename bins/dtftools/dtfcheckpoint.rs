@@ -0,0 +1,11 @@
+use tdb_core::storage::checkpoint;
+
+pub fn run(matches: &clap::ArgMatches) {
+    let folder = matches.value_of("folder").expect("Must supply folder");
+    let book = matches.value_of("book").expect("Must supply book");
+    let interval = matches.value_of("interval").unwrap().parse().expect("Interval must be a number");
+
+    println!("Rewriting the files of {} in {}", book, folder);
+    let written = checkpoint::regenerate(folder, book, interval).unwrap();
+    println!("Wrote {} checkpoints", written);
+}
@@ -31,6 +31,10 @@ pub fn run(matches: &clap::ArgMatches) {
         );
         exit(1);
     }
+    if input1_metadata.scale != input2_metadata.scale {
+        println!("ERROR: The two input files provided have different tick or lot sizes");
+        exit(1);
+    }
     let (start_filename, start_metadata, end_filename, end_metadata) = if input1_metadata.min_ts > input2_metadata.min_ts {
         (input1_filename, input1_metadata, input2_filename, input2_metadata)
     } else {
@@ -137,7 +141,8 @@ pub fn combine_files(
     joined_updates.append(&mut overlapping_updates);
     joined_updates.append(&mut file2_updates);
 
-    dtf::file_format::encode(output_filename, &symbol, &joined_updates)
+    let opts = dtf::file_format::EncodeOptions { scale: start_metadata.scale, ..Default::default() };
+    dtf::file_format::encode_with(output_filename, &symbol, &joined_updates, &opts)
         .map_err(|_| String::from("Error while writing output file!"))?;
 
     Ok(())
@@ -165,8 +170,8 @@ fn dtf_merging() {
                     seq: i as u32 + seq_offset as u32,
                     is_trade: false,
                     is_bid: true,
-                    price: *ts as f32 + if last_timestamp == *ts { 1. } else { 0. },
-                    size: *ts as f32,
+                    price: *ts + if last_timestamp == *ts { 1 } else { 0 },
+                    size: *ts,
                 };
 
                 last_timestamp = *ts;
@@ -191,20 +196,20 @@ fn dtf_merging() {
     let metadata1 = dtf::file_format::read_meta(filename1).unwrap();
     let metadata2 = dtf::file_format::read_meta(filename2).unwrap();
 
-    let expected_ts_price: &[(u64, f32)] = &[
-        (1001, 1001.),
-        (1002, 1002.),
-        (1003, 1003.),
-        (1004, 1004.),
-        (1004, 1005.),
-        (1007, 1007.),
-        (1008, 1008.),
-        (1009, 1009.),
-        (1009, 1010.),
-        (1010, 1010.),
-        (1010, 1011.),
-        (1011, 1011.),
-        (1012, 1012.),
+    let expected_ts_price: &[(u64, u64)] = &[
+        (1001, 1001),
+        (1002, 1002),
+        (1003, 1003),
+        (1004, 1004),
+        (1004, 1005),
+        (1007, 1007),
+        (1008, 1008),
+        (1009, 1009),
+        (1009, 1010),
+        (1010, 1010),
+        (1010, 1011),
+        (1011, 1011),
+        (1012, 1012),
     ];
 
     // Concat the files and verify that they contain the correct data
@@ -215,7 +220,7 @@ fn dtf_merging() {
     remove_file(filename2).unwrap();
     remove_file(output_filename).unwrap();
 
-    let actual_ts_price: Vec<(u64, f32)> = merged_updates
+    let actual_ts_price: Vec<(u64, u64)> = merged_updates
         .into_iter()
         .skip(1000)
         .map(|Update { ts, price, .. }| (ts, price))
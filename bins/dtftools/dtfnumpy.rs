@@ -64,8 +64,9 @@ pub fn run(matches: &clap::ArgMatches) -> Option<()> {
 
         write_arr!(num "ts", "<i8",    ts);
         write_arr!(num "seq", "<i4",   seq);
-        write_arr!(num "price", "<f4", price);
-        write_arr!(num "size", "<f4",  size);
+        // in ticks and lots, see the tick_size and lot_size of the file
+        write_arr!(num "price", "<u8", price);
+        write_arr!(num "size", "<u8",  size);
         write_arr!(bool "is_bid", "?",  is_bid);
         write_arr!(bool "is_trade", "?",is_trade);
 
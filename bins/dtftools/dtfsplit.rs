@@ -11,11 +11,12 @@ pub fn run(matches: &clap::ArgMatches) {
     let meta = dtf::file_format::read_meta(fname).unwrap();
     let rdr = dtf::file_format::file_reader(fname).expect("cannot open file");
     let mut it = dtf::file_format::iterators::DTFBufReader::new(rdr);
+    let opts = dtf::file_format::EncodeOptions { scale: meta.scale, ..Default::default() };
     let mut i = 0;
     for batch in &(&mut it).chunks(batch_size) {
         let outname = format!("{}-{}.dtf", file_stem, i);
         println!("Writing to {}", outname);
-        dtf::file_format::encode(&outname, &meta.symbol, &batch.collect::<Vec<_>>()).unwrap();
+        dtf::file_format::encode_with(&outname, &meta.symbol, &batch.collect::<Vec<_>>(), &opts).unwrap();
         i += 1;
     }
 }
\ No newline at end of file
@@ -12,6 +12,7 @@ mod dtfcat;
 mod dtfsplit;
 mod dtfconcat;
 mod dtfrepair;
+mod dtfcheckpoint;
 use clap::{Arg, App};
 
 fn main() {
@@ -244,6 +245,35 @@ fn main() {
                 .required(false)
                 .takes_value(true),
             ))
+        .subcommand(clap::SubCommand::with_name("checkpoint")
+            .about(indoc!("
+                Regenerates the orderbook checkpoints in the dtf files of a book
+                Examples:
+                dtftools checkpoint db bnc_btc_eth -n 100000
+                "))
+            .arg(
+                Arg::with_name("folder")
+                    .value_name("FOLDER")
+                    .help("dtf folder")
+                    .required(true)
+                    .takes_value(true)
+                    .index(1))
+            .arg(
+                Arg::with_name("book")
+                    .value_name("BOOK")
+                    .help("book name")
+                    .required(true)
+                    .takes_value(true)
+                    .index(2))
+            .arg(
+                Arg::with_name("interval")
+                    .short("n")
+                    .long("interval")
+                    .value_name("UPDATES")
+                    .help("checkpoint every n updates, removes the checkpoints when 0")
+                    .default_value("100000")
+                    .takes_value(true),
+            ))
     .get_matches();
 
     if let Some(matches) = matches.subcommand_matches("cat") {
@@ -258,6 +288,8 @@ fn main() {
         dtfconcat::run(matches);
     } else if let Some(matches) = matches.subcommand_matches("repair") {
         dtfrepair::run(matches);
+    } else if let Some(matches) = matches.subcommand_matches("checkpoint") {
+        dtfcheckpoint::run(matches);
     } else {
         println!("{}", matches.usage());
     }
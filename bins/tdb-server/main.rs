@@ -35,6 +35,61 @@ fn main() {
             None => cli_setting,
         }
     };
+    let wal = {
+        let cli_setting: bool = matches.is_present("wal");
+        let env_setting = key_or_none("TDB_WAL");
+        match env_setting {
+            Some(s) => match s.as_ref() {
+                "true" | "1" => true,
+                "false" => false,
+                _ => cli_setting,
+            },
+            None => cli_setting,
+        }
+    };
+    let wal_sync = matches
+        .value_of("wal_sync")
+        .map(String::from)
+        .unwrap_or_else(|| key_or_default("TDB_WAL_SYNC", "always"));
+    let partitioning = matches
+        .value_of("partition")
+        .map(String::from)
+        .unwrap_or_else(|| key_or_default("TDB_PARTITION", "none"));
+    let retention = {
+        use tdb_server_core::plugins::retention::{parse_limit, parse_age, parse_bytes, Retention};
+        let max_age = matches
+            .value_of("retention_age")
+            .map(String::from)
+            .unwrap_or_else(|| key_or_default("TDB_RETENTION_AGE", "none"));
+        let max_bytes = matches
+            .value_of("retention_bytes")
+            .map(String::from)
+            .unwrap_or_else(|| key_or_default("TDB_RETENTION_BYTES", "none"));
+        Retention {
+            max_age: parse_limit(&max_age, parse_age).expect("Invalid retention age"),
+            max_bytes: parse_limit(&max_bytes, parse_bytes).expect("Invalid retention size"),
+        }
+    };
+    let rollups = matches
+        .value_of("rollups")
+        .map(String::from)
+        .unwrap_or_else(|| key_or_default("TDB_ROLLUPS", ""));
+    let checkpoint_interval = matches
+        .value_of("checkpoint_interval")
+        .map(String::from)
+        .unwrap_or_else(|| key_or_default("TDB_CHECKPOINT_INTERVAL", "100000"));
+    let integrity = matches
+        .value_of("integrity")
+        .map(String::from)
+        .unwrap_or_else(|| key_or_default("TDB_INTEGRITY", "off"));
+    let slow_consumer = matches
+        .value_of("slow_consumer")
+        .map(String::from)
+        .unwrap_or_else(|| key_or_default("TDB_SLOW_CONSUMER", "drop"));
+    let retention_interval = matches
+        .value_of("retention_interval")
+        .map(String::from)
+        .unwrap_or_else(|| key_or_default("TDB_RETENTION_INTERVAL", "3600"));
     let flush_interval = matches
         .value_of("flush_interval")
         .map(String::from)
@@ -80,6 +135,15 @@ fn main() {
             granularity: granularity.parse().unwrap(),
             q_capacity: q_capacity.parse().unwrap(),
             influx,
+            wal,
+            wal_sync: wal_sync.parse().unwrap(),
+            partitioning: partitioning.parse().unwrap(),
+            retention,
+            retention_interval: retention_interval.parse().unwrap(),
+            rollups: rollups.split(',').filter(|s| !s.is_empty()).map(|s| s.parse().unwrap()).collect(),
+            checkpoint_interval: checkpoint_interval.parse().unwrap(),
+            integrity: integrity.parse().unwrap(),
+            slow_consumer: slow_consumer.parse().unwrap(),
         }
     );
 
@@ -170,6 +234,72 @@ fn get_matches<'a>() -> ArgMatches<'a> {
                 .value_name("INTERVAL")
                 .help("Sets autoflush interval (default every 1000 inserts)"),
         )
+        .arg(Arg::with_name("wal").short("w").long("wal").help(
+            "Log inserts to a write-ahead log until they are flushed (default is false)",
+        ))
+        .arg(
+            Arg::with_name("wal_sync")
+                .long("wal_sync")
+                .value_name("POLICY")
+                .help("Sets when to fsync the write-ahead log: always, never or every n ms (default always)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("partition")
+                .long("partition")
+                .value_name("SCHEME")
+                .help("Splits the dtf file of each book into time partitions: none, hourly, daily or monthly (default none)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("retention_age")
+                .long("retention_age")
+                .value_name("AGE")
+                .help("Drops updates older than AGE, e.g. 90d or 12h (default none)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("retention_bytes")
+                .long("retention_bytes")
+                .value_name("SIZE")
+                .help("Drops the oldest updates of a book above SIZE on disk, e.g. 10G (default none)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("retention_interval")
+                .long("retention_interval")
+                .value_name("SECONDS")
+                .help("Sets how often retention policies are enforced, never when 0 (default 3600)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rollups")
+                .long("rollups")
+                .value_name("RESOLUTIONS")
+                .help("Maintains candles of every book at comma separated resolutions, e.g. 1m,1h,tick:500,volume:1000,dollar:1000000")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("checkpoint_interval")
+                .long("checkpoint_interval")
+                .value_name("UPDATES")
+                .help("Checkpoints the orderbook of every book every n updates, never when 0 (default 100000)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("integrity")
+                .long("integrity")
+                .value_name("MODE")
+                .help("Counts orderbook anomalies shown by INFO and PERF: off, count or heal to also clear crossed levels (default off)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("slow_consumer")
+                .long("slow_consumer")
+                .value_name("POLICY")
+                .help("What to do with subscribers that fall behind: drop their messages or disconnect them (default drop)")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("granularity")
                 .short("g")
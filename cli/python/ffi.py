@@ -37,16 +37,16 @@ class Update(Structure):
     seq: u32,
     is_trade: bool,
     is_bid: bool,
-    price: f32,
-    size: f32,
+    price: u64, in ticks of the book
+    size: u64, in lots of the book
     """
     _fields_ = [
         ("ts", c_uint64),
         ("seq", c_uint32),
         ("is_trade", c_bool),
         ("is_bid", c_bool),
-        ("price", c_float),
-        ("size", c_float),
+        ("price", c_uint64),
+        ("size", c_uint64),
     ]
     def __repr__(self):
         return 'Update<{},{},{},{},{},{}>'.format(
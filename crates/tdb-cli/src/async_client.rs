@@ -0,0 +1,437 @@
+//! Asynchronous client
+//!
+//! `AsyncClient` speaks version 2 of the protocol (see `tdb_core::protocol`):
+//! every request is tagged with an id and the server tags its responses with
+//! the same id, so requests are sent without waiting for the responses of the
+//! previous ones and any number of tasks can share a client.
+//!
+//! A client whose connection is closed connects again on its next request and
+//! replays the last `USE`. Requests in flight when the connection is lost fail
+//! with `TectonicError::ConnectionError`, they are not sent again since they
+//! may have been processed already.
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex as SyncMutex};
+use async_std::net::{Shutdown, TcpStream};
+use async_std::prelude::*;
+use async_std::sync::Mutex;
+use async_std::task;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use tdb_core::dtf::file_format::decode_buffer;
+use tdb_core::dtf::scale::Scale;
+use tdb_core::dtf::update::Update;
+use tdb_core::protocol::{self, ContentType, Header, PUSH_ID};
+use crate::client::server_error;
+use crate::error::TectonicError;
+
+/// status, content type and payload of a response frame
+type Frame = (u8, ContentType, Vec<u8>);
+
+/// response to a request
+#[derive(Debug)]
+pub struct Response {
+    /// content type of the payload
+    pub content: ContentType,
+    /// payload in the chunks it was streamed in
+    pub chunks: Vec<Vec<u8>>,
+    /// cursor of a page of `GET ... LIMIT n`, see `Chunks::cursor`
+    pub cursor: Option<String>,
+    /// tick size and lot size of the updates of a binary `GET`
+    pub scale: Option<Scale>,
+}
+
+impl Response {
+    /// payload as text
+    pub fn text(&self) -> String {
+        self.chunks.iter().map(|chunk| String::from_utf8_lossy(chunk)).collect()
+    }
+
+    /// updates of a binary `GET` in ticks and lots of `scale`, empty for other content types
+    pub fn updates(&self) -> Vec<Update> {
+        if self.content != ContentType::Dtf {
+            return vec![];
+        }
+        self.chunks
+            .iter()
+            .flat_map(|chunk| decode_buffer(&mut std::io::Cursor::new(chunk.as_slice())))
+            .collect()
+    }
+}
+
+/// a connection and the requests waiting for their response on it
+struct Conn {
+    writer: TcpStream,
+    /// frames of each request in flight by id, cleared once the connection is closed
+    pending: Arc<SyncMutex<HashMap<u32, UnboundedSender<Frame>>>>,
+    closed: Arc<AtomicBool>,
+}
+
+impl Conn {
+    /// connect to `addr` and negotiate protocol version 2
+    async fn open(addr: &str) -> Result<Conn, TectonicError> {
+        let mut stream = TcpStream::connect(addr).await.map_err(|_| TectonicError::ConnectionError)?;
+        let hello = format!("HELLO {}", protocol::VERSION);
+        let mut buf = (hello.len() as u32).to_be_bytes().to_vec();
+        buf.extend(hello.as_bytes());
+        stream.write_all(&buf).await.map_err(|_| TectonicError::ConnectionError)?;
+
+        // the response to `HELLO` is framed in version 1
+        let mut head = [0; 9];
+        stream.read_exact(&mut head).await.map_err(|_| TectonicError::ConnectionError)?;
+        let mut size = [0; 8];
+        size.copy_from_slice(&head[1..]);
+        let mut payload = vec![0; u64::from_be_bytes(size) as usize];
+        stream.read_exact(&mut payload).await.map_err(|_| TectonicError::ConnectionError)?;
+        if head[0] != 0x1 {
+            return Err(server_error(&payload));
+        }
+        let hello: serde_json::Value = serde_json::from_slice(&payload).map_err(|_| TectonicError::JsonError)?;
+        if hello["version"].as_u64() != Some(u64::from(protocol::VERSION)) {
+            return Err(TectonicError::ServerError(format!("Protocol version {} is not supported", protocol::VERSION)));
+        }
+
+        let conn = Conn {
+            writer: stream.clone(),
+            pending: Default::default(),
+            closed: Default::default(),
+        };
+        let pending = Arc::clone(&conn.pending);
+        let closed = Arc::clone(&conn.closed);
+        task::spawn(async move {
+            if let Err(e) = read_loop(stream, &pending).await {
+                info!("Connection closed: {}", e);
+            }
+            closed.store(true, Ordering::SeqCst);
+            // requests in flight see their channel closed
+            pending.lock().unwrap().clear();
+        });
+        Ok(conn)
+    }
+
+    /// wait for the frames of `id`, None once the connection is closed
+    fn register(&self, id: u32) -> Option<UnboundedReceiver<Frame>> {
+        let mut pending = self.pending.lock().unwrap();
+        if self.closed.load(Ordering::SeqCst) {
+            return None;
+        }
+        let (tx, rx) = unbounded();
+        pending.insert(id, tx);
+        Some(rx)
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+}
+
+/// routes the frames of the connection to the requests by id
+async fn read_loop(mut stream: TcpStream, pending: &SyncMutex<HashMap<u32, UnboundedSender<Frame>>>) -> std::io::Result<()> {
+    let mut head = [0; Header::SIZE];
+    loop {
+        stream.read_exact(&mut head).await?;
+        let header = Header::read(&mut &head[..])?;
+        let mut payload = vec![0; header.len as usize];
+        stream.read_exact(&mut payload).await?;
+        if header.id == PUSH_ID {
+            // subscriptions are read with `TectonicClient::subscribe`
+            continue;
+        }
+        let mut pending = pending.lock().unwrap();
+        let last = header.status == 0x0 || header.status == 0x1;
+        if let Some(tx) = pending.get(&header.id) {
+            let _ = tx.unbounded_send((header.status, header.content, payload));
+        }
+        if last {
+            pending.remove(&header.id);
+        }
+    }
+}
+
+/// Asynchronous client that pipelines its requests, see the module documentation.
+pub struct AsyncClient {
+    addr: String,
+    conn: Mutex<Option<Conn>>,
+    /// book of the last `USE`, replayed on reconnect
+    book: SyncMutex<Option<String>>,
+    last_id: AtomicU32,
+}
+
+impl AsyncClient {
+    pub async fn connect(host: &str, port: &str) -> Result<AsyncClient, TectonicError> {
+        let addr = format!("{}:{}", host, port);
+        info!("Connecting to {}", addr);
+        let conn = Conn::open(&addr).await?;
+        Ok(AsyncClient {
+            addr,
+            conn: Mutex::new(Some(conn)),
+            book: SyncMutex::new(None),
+            last_id: AtomicU32::new(PUSH_ID),
+        })
+    }
+
+    /// sends `command` and waits for its response
+    pub async fn cmd(&self, command: &str) -> Result<Response, TectonicError> {
+        let mut rxs = self.send(&[command.as_bytes().to_vec()]).await?;
+        let ret = response(rxs.remove(0)).await?;
+        let upper = command.trim().to_uppercase();
+        if upper.starts_with("USE ") {
+            *self.book.lock().unwrap() = Some(command.trim()[4..].trim().to_owned());
+        }
+        Ok(ret)
+    }
+
+    /// switches the book of the connection, it is selected again after a reconnect
+    pub async fn use_db(&self, book_name: &str) -> Result<Response, TectonicError> {
+        self.cmd(&format!("USE {}", book_name)).await
+    }
+
+    /// inserts an update into `book_name`, the current book if None
+    pub async fn insert(&self, book_name: Option<&str>, update: &Update) -> Result<(), TectonicError> {
+        self.insert_batch(book_name, std::slice::from_ref(update)).await
+    }
+
+    /// Inserts updates into `book_name`, the current book if None.
+    ///
+    /// Every insert is written at once and the responses are awaited
+    /// afterwards, returns the first error.
+    pub async fn insert_batch(&self, book_name: Option<&str>, updates: &[Update]) -> Result<(), TectonicError> {
+        let commands = updates
+            .iter()
+            .map(|up| tdb_core::utils::encode_insert_into(book_name, up))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut ret = Ok(());
+        for rx in self.send(&commands).await? {
+            if let Err(e) = response(rx).await {
+                if ret.is_ok() {
+                    ret = Err(e);
+                }
+            }
+        }
+        ret
+    }
+
+    /// write `commands` with new ids in one go, returns where their frames are received
+    async fn send(&self, commands: &[Vec<u8>]) -> Result<Vec<UnboundedReceiver<Frame>>, TectonicError> {
+        let mut conn = self.conn.lock().await;
+        if !matches!(conn.as_ref(), Some(conn) if !conn.is_closed()) {
+            *conn = Some(self.reconnect().await?);
+        }
+        let conn = conn.as_mut().unwrap();
+        let mut buf = vec![];
+        let mut rxs = vec![];
+        for command in commands {
+            let id = self.next_id();
+            rxs.push(conn.register(id).ok_or(TectonicError::ConnectionError)?);
+            protocol::write_request(&mut buf, id, command)?;
+        }
+        if conn.writer.write_all(&buf).await.is_err() {
+            let _ = conn.writer.shutdown(Shutdown::Both);
+            return Err(TectonicError::ConnectionError);
+        }
+        Ok(rxs)
+    }
+
+    /// a new connection with the book of the last `USE` selected
+    async fn reconnect(&self) -> Result<Conn, TectonicError> {
+        info!("Reconnecting to {}", self.addr);
+        let mut conn = Conn::open(&self.addr).await?;
+        let book = self.book.lock().unwrap().clone();
+        if let Some(book) = book {
+            let id = self.next_id();
+            let rx = conn.register(id).ok_or(TectonicError::ConnectionError)?;
+            let mut buf = vec![];
+            protocol::write_request(&mut buf, id, format!("USE {}", book).as_bytes())?;
+            conn.writer.write_all(&buf).await.map_err(|_| TectonicError::ConnectionError)?;
+            response(rx).await?;
+        }
+        Ok(conn)
+    }
+
+    /// a request id, never `PUSH_ID`
+    fn next_id(&self) -> u32 {
+        loop {
+            let id = self.last_id.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+            if id != PUSH_ID {
+                return id;
+            }
+        }
+    }
+}
+
+impl Drop for AsyncClient {
+    fn drop(&mut self) {
+        // stops the task reading the connection
+        if let Some(conn) = self.conn.get_mut().as_ref() {
+            let _ = conn.writer.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// collect the frames of a response
+async fn response(mut rx: UnboundedReceiver<Frame>) -> Result<Response, TectonicError> {
+    let mut ret = Response { content: ContentType::Text, chunks: vec![], cursor: None, scale: None };
+    while let Some((status, content, buf)) = rx.next().await {
+        match status {
+            0x0 => return Err(server_error(&buf)),
+            0x3 => ret.cursor = Some(String::from_utf8_lossy(&buf).into_owned()),
+            0x4 => ret.scale = serde_json::from_slice(&buf).ok(),
+            _ => {
+                ret.content = content;
+                if !buf.is_empty() {
+                    ret.chunks.push(buf);
+                }
+                if status == 0x1 {
+                    return Ok(ret);
+                }
+            }
+        }
+    }
+    Err(TectonicError::ConnectionError)
+}
+
+/// Connections shared round robin by the requests.
+///
+/// `USE` only applies to the connection it is sent on, so commands
+/// sent through a pool should name their book.
+pub struct Pool {
+    clients: Vec<AsyncClient>,
+    next: AtomicUsize,
+}
+
+impl Pool {
+    /// opens `size` connections
+    pub async fn connect(host: &str, port: &str, size: usize) -> Result<Pool, TectonicError> {
+        let mut clients = Vec::with_capacity(size);
+        for _ in 0..size.max(1) {
+            clients.push(AsyncClient::connect(host, port).await?);
+        }
+        Ok(Pool { clients, next: AtomicUsize::new(0) })
+    }
+
+    /// the next connection of the pool
+    pub fn get(&self) -> &AsyncClient {
+        let i = self.next.fetch_add(1, Ordering::Relaxed);
+        &self.clients[i % self.clients.len()]
+    }
+
+    /// sends `command` on the next connection
+    pub async fn cmd(&self, command: &str) -> Result<Response, TectonicError> {
+        self.get().cmd(command).await
+    }
+
+    /// inserts `updates` into `book_name` on the next connection, in order
+    pub async fn insert_batch(&self, book_name: &str, updates: &[Update]) -> Result<(), TectonicError> {
+        self.get().insert_batch(Some(book_name), updates).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::net::TcpListener;
+
+    /// commands received by the server with the index of their connection
+    type Log = Arc<SyncMutex<Vec<(usize, String)>>>;
+
+    /// a request or the text of a response, with its id
+    type Message = (u32, String);
+
+    /// An in-process server answering the requests of each connection `batch` at a time
+    /// with `respond`, a connection is closed when it returns None. Returns its port.
+    fn serve(batch: usize, respond: fn(&[Message]) -> Option<Vec<Message>>) -> (String, Log) {
+        let listener = task::block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        let log: Log = Default::default();
+        let ret = Arc::clone(&log);
+        task::spawn(async move {
+            let mut conns = 0;
+            while let Ok((stream, _)) = listener.accept().await {
+                task::spawn(handle(stream, conns, batch, respond, Arc::clone(&log)));
+                conns += 1;
+            }
+        });
+        (port, ret)
+    }
+
+    async fn handle(mut stream: TcpStream, conn: usize, batch: usize, respond: fn(&[Message]) -> Option<Vec<Message>>, log: Log) -> std::io::Result<()> {
+        let mut len = [0; 4];
+        stream.read_exact(&mut len).await?;
+        let mut hello = vec![0; u32::from_be_bytes(len) as usize];
+        stream.read_exact(&mut hello).await?;
+        let payload = br#"{"version":2,"capabilities":["pipelining"]}"#;
+        let mut buf = vec![0x1];
+        buf.extend(&(payload.len() as u64).to_be_bytes());
+        buf.extend(payload);
+        stream.write_all(&buf).await?;
+
+        loop {
+            let mut requests = vec![];
+            while requests.len() < batch {
+                let mut head = [0; 8];
+                stream.read_exact(&mut head).await?;
+                let mut command = vec![0; u32::from_be_bytes([head[0], head[1], head[2], head[3]]) as usize];
+                stream.read_exact(&mut command).await?;
+                let command = String::from_utf8(command).unwrap();
+                log.lock().unwrap().push((conn, command.clone()));
+                requests.push((u32::from_be_bytes([head[4], head[5], head[6], head[7]]), command));
+            }
+            let responses = match respond(&requests) {
+                Some(responses) => responses,
+                None => return Ok(()),
+            };
+            let mut buf = vec![];
+            for (id, text) in responses {
+                Header { status: 0x1, content: ContentType::Text, id, len: text.len() as u64 }.write(&mut buf)?;
+                buf.extend(text.as_bytes());
+            }
+            stream.write_all(&buf).await?;
+        }
+    }
+
+    fn echo(requests: &[Message]) -> Option<Vec<Message>> {
+        match requests {
+            [(_, command)] if command == "DROP" => None,
+            _ => Some(requests.to_vec()),
+        }
+    }
+
+    #[test]
+    fn should_match_out_of_order_responses() {
+        // the response to the second request comes first
+        let (port, log) = serve(2, |requests| Some(requests.iter().rev().cloned().collect()));
+        task::block_on(async {
+            let client = AsyncClient::connect("127.0.0.1", &port).await.unwrap();
+            let (a, b) = futures::join!(client.cmd("A"), client.cmd("B"));
+            assert_eq!((a.unwrap().text(), b.unwrap().text()), ("A".to_owned(), "B".to_owned()));
+        });
+        assert_eq!(*log.lock().unwrap(), vec![(0, "A".to_owned()), (0, "B".to_owned())]);
+    }
+
+    #[test]
+    fn should_reconnect_after_dropped_socket() {
+        let (port, log) = serve(1, echo);
+        task::block_on(async {
+            let client = AsyncClient::connect("127.0.0.1", &port).await.unwrap();
+            client.use_db("x").await.unwrap();
+            assert!(matches!(client.cmd("DROP").await, Err(TectonicError::ConnectionError)));
+            // a new connection with the book selected again
+            assert_eq!(client.cmd("PING").await.unwrap().text(), "PING");
+        });
+        let expected = [(0, "USE x"), (0, "DROP"), (1, "USE x"), (1, "PING")];
+        assert_eq!(*log.lock().unwrap(), expected.iter().map(|&(conn, cmd)| (conn, cmd.to_owned())).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn should_reuse_pool_connections() {
+        let (port, log) = serve(1, echo);
+        task::block_on(async {
+            let pool = Pool::connect("127.0.0.1", &port, 2).await.unwrap();
+            for i in 0..6 {
+                assert_eq!(pool.cmd(&i.to_string()).await.unwrap().text(), i.to_string());
+            }
+        });
+        // round robin over the two connections, none opened again
+        let conns: Vec<usize> = log.lock().unwrap().iter().map(|(conn, _)| *conn).collect();
+        assert_eq!(conns, vec![0, 1, 0, 1, 0, 1]);
+    }
+}
@@ -5,13 +5,21 @@ use byteorder::{BigEndian, ReadBytesExt};
 use bufstream::BufStream;
 use tdb_core::dtf::update::Update;
 use crate::error::TectonicError;
-use tdb_core::dtf::{update::UpdateVecConvert, file_format::decode_buffer};
+use tdb_core::dtf::{update::UpdateVecConvert, file_format::decode_buffer, scale::Scale};
 use tdb_core::postprocessing::orderbook::Orderbook;
+use tdb_core::protocol::{self, ContentType, Header};
 
 pub struct TectonicClient {
     pub stream: BufStream<TcpStream>,
     pub host: String,
     pub port: String,
+    /// tick size and lot size used to format prices and sizes of binary GET results
+    /// when the server does not send them, as in protocol version 1
+    pub scale: Scale,
+    /// protocol version negotiated by `HELLO`, 1 with servers that do not support it
+    pub version: u32,
+    /// id of the last request in protocol version 2
+    last_id: u32,
 }
 
 impl TectonicClient {
@@ -29,11 +37,16 @@ impl TectonicClient {
         let writer_cap = 1024;
         let stream = BufStream::with_capacities(reader_cap, writer_cap, stream);
 
-        Ok(TectonicClient {
+        let mut cli = TectonicClient {
             stream,
             host: host.to_owned(),
             port: port.to_owned(),
-        })
+            scale: Scale::default(),
+            version: 1,
+            last_id: 0,
+        };
+        cli.version = cli.hello().unwrap_or(1);
+        Ok(cli)
     }
 
     pub fn reconnect(&mut self) -> Result<(), TectonicError> {
@@ -43,58 +56,96 @@ impl TectonicClient {
             Ok(stm) => BufStream::new(stm),
             Err(_) => return Err(TectonicError::ConnectionError)
         };
+        self.version = 1;
+        self.version = self.hello().unwrap_or(1);
         Ok(())
     }
 
+    /// Negotiates the latest protocol version supported by the server, returns the version in use.
+    ///
+    /// Must be the first command of the connection.
+    pub fn hello(&mut self) -> Result<u32, TectonicError> {
+        let res = self.cmd(&format!("HELLO {}\n", protocol::VERSION))?;
+        let hello: serde_json::Value = serde_json::from_str(&res).map_err(|_| TectonicError::JsonError)?;
+        let version = hello["version"].as_u64().ok_or(TectonicError::JsonError)? as u32;
+        self.version = version;
+        Ok(version)
+    }
+
     pub fn cmd(&mut self, command: &str) -> Result<String, TectonicError> {
-        self.stream.write(&(command.len() as u32).to_be_bytes())?;
-        self.stream.write(command.as_bytes())?;
-        self.stream.flush()?;
+        let upper = command.to_uppercase();
+        // responses of protocol version 1 do not tell their content type
+        let sniffed = upper.starts_with("GET")
+            && !upper.starts_with("GET CANDLES")
+            && !upper.contains("AS CSV")
+            && !upper.contains("AS JSON");
+        let default_scale = self.scale;
 
-        let success = self.stream.read_u8()
-            .map(|i| i == 0x1)
-            .map_err(|_| TectonicError::ConnectionError)?;
-
-        if command.starts_with("GET")
-            && !command.contains("AS CSV")
-            && !command.contains("AS JSON")
-            && success
-        {
-            let size = self.stream.read_u64::<BigEndian>()?;
-            let mut buf = vec![0_u8; size as usize];
-            self.stream.read_exact(&mut buf)?;
-
-            let mut buf = Cursor::new(buf.as_slice());
-            let v = decode_buffer(&mut buf);
-            Ok(format!("[{}]\n", v.as_json()))
-        } else {
-            let size = self.stream.read_u64::<BigEndian>()?;
-            let mut buf = vec![0; size as usize];
-            self.stream.read_exact(&mut buf)?;
-            let res = std::str::from_utf8(&buf).unwrap().to_owned();
-            if success {
-                Ok(res)
-            } else if res.contains("ERR: DB") {
-                let book_name = res.split(" ").nth(2).unwrap();
-                Err(TectonicError::DBNotFoundError(book_name.to_owned()))
-            } else  {
-                Err(TectonicError::ServerError(res))
+        let mut ups = vec![];
+        let mut res = String::new();
+        let mut chunks = self.chunks(command)?;
+        let all = (&mut chunks).collect::<Result<Vec<_>, _>>()?;
+        let binary = match chunks.content_type() {
+            Some(content) => content == ContentType::Dtf,
+            None => sniffed,
+        };
+        for chunk in all {
+            if binary {
+                ups.extend(decode_buffer(&mut Cursor::new(chunk.as_slice())));
+            } else {
+                res.push_str(&String::from_utf8_lossy(&chunk));
             }
         }
+        if binary {
+            let scale = chunks.scale().unwrap_or(default_scale);
+            res = format!("[{}]\n", ups.as_json(&scale));
+        }
+        if let Some(cursor) = chunks.cursor() {
+            res.push_str(&format!("AFTER {}\n", cursor));
+        }
+        Ok(res)
     }
 
-    unsafe fn cmd_bytes_no_check(&mut self, command: &[u8], discard_result: bool) -> Result<bool, TectonicError> {
-        self.stream.write(&(command.len() as u32).to_be_bytes())?;
-        self.stream.write(command)?;
+    /// Sends a command and returns its response as an iterator of chunks.
+    ///
+    /// Large results such as `GET` are streamed by the server in many chunks,
+    /// other responses are a single chunk. Chunks that are not read are
+    /// discarded when the iterator is dropped.
+    pub fn chunks(&mut self, command: &str) -> Result<Chunks<'_>, TectonicError> {
+        self.send(command.as_bytes())?;
+        Ok(Chunks { client: self, done: false, cursor: None, content: None, scale: None })
+    }
+
+    /// write a request, tagged with a new id in protocol version 2
+    fn send(&mut self, command: &[u8]) -> Result<(), TectonicError> {
+        if self.version >= 2 {
+            // `PUSH_ID` is never the id of a request
+            self.last_id = self.last_id.wrapping_add(1).max(protocol::PUSH_ID + 1);
+            protocol::write_request(&mut self.stream, self.last_id, command)?;
+        } else {
+            self.stream.write_all(&(command.len() as u32).to_be_bytes())?;
+            self.stream.write_all(command)?;
+        }
         self.stream.flush()?;
+        Ok(())
+    }
+
+    /// Returns the updates of a `GET` query as an iterator, e.g. `GET ALL FROM 1513749500 TO 1513753100`.
+    ///
+    /// Updates are decoded one chunk at a time, so the query must not ask for
+    /// csv or json.
+    pub fn get(&mut self, query: &str) -> Result<Updates<'_>, TectonicError> {
+        Ok(Updates {
+            chunks: self.chunks(query)?,
+            ups: vec![].into_iter(),
+        })
+    }
+
+    unsafe fn cmd_bytes_no_check(&mut self, command: &[u8], discard_result: bool) -> Result<bool, TectonicError> {
+        self.send(command)?;
         if !discard_result {
-            let _ret = self.stream.read_u8().map(|i| i == 0x1)?;
-            let size = self.stream.read_u64::<BigEndian>()?;
             // ignore bytes
-            std::io::copy(
-                &mut self.stream.get_ref().take(size as u64),
-                &mut std::io::sink()
-            )?;
+            self.read_frame()?;
         }
         Ok(true)
     }
@@ -120,16 +171,7 @@ impl TectonicClient {
         let (tx, rx) = channel();
 
         std::thread::spawn(move || {
-            loop {
-                let success = self.stream.read_u8()
-                    .map(|i| i == 0x1)
-                    .map_err(|_| TectonicError::ConnectionError).unwrap();
-
-                if !success { break }
-
-                let size = self.stream.read_u64::<BigEndian>().unwrap();
-                let mut buf = vec![0; size as usize];
-                self.stream.read_exact(&mut buf).unwrap();
+            while let Ok((0x1, _, buf)) = self.read_frame() {
                 let decoded = tdb_core::utils::decode_insert_into(&buf);
                 match decoded {
                     Some((Some(up), Some(_book_name))) => tx.send(up).unwrap(),
@@ -144,12 +186,31 @@ impl TectonicClient {
         Ok(rx)
     }
 
+    /// best `depth` levels of each side of a book as json, whenever they change
+    pub fn subscribe_depth(mut self, book_name: &str, depth: u32) -> Result<Receiver<String>, TectonicError> {
+        self.cmd(&format!("SUBSCRIBE {} DEPTH {}\n", book_name, depth))?;
+
+        let (tx, rx) = channel();
+
+        std::thread::spawn(move || {
+            while let Ok((0x1, _, buf)) = self.read_frame() {
+                let json = String::from_utf8_lossy(&buf).into_owned();
+                if tx.send(json).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(rx)
+    }
+
     #[deprecated]
     pub fn insert_text(&mut self, book_name: String, update: &Update) -> Result<String, TectonicError> {
         let is_trade = if update.is_trade {"t"} else {"f"};
         let is_bid = if update.is_bid {"t"} else {"f"};
         let cmdstr = format!("ADD {}, {}, {}, {}, {}, {}; INTO {}\n",
-                        update.ts, update.seq, is_trade, is_bid, update.price, update.size, book_name);
+                        update.ts, update.seq, is_trade, is_bid,
+                        self.scale.format_price(update.price), self.scale.format_size(update.size), book_name);
         self.cmd(&cmdstr)
     }
 
@@ -163,4 +224,140 @@ impl TectonicClient {
     pub fn shutdown(self) {
         self.stream.into_inner().unwrap().shutdown(std::net::Shutdown::Both).unwrap()
     }
+
+    /// read one frame, returns its status, content type in protocol version 2 and payload
+    fn read_frame(&mut self) -> Result<(u8, Option<ContentType>, Vec<u8>), TectonicError> {
+        let (status, content, size) = if self.version >= 2 {
+            let header = Header::read(&mut self.stream).map_err(|_| TectonicError::ConnectionError)?;
+            (header.status, Some(header.content), header.len)
+        } else {
+            let status = self.stream.read_u8().map_err(|_| TectonicError::ConnectionError)?;
+            (status, None, self.stream.read_u64::<BigEndian>()?)
+        };
+        let mut buf = vec![0; size as usize];
+        self.stream.read_exact(&mut buf)?;
+        Ok((status, content, buf))
+    }
+}
+
+/// Chunks of a response
+///
+/// A streamed response is a sequence of `0x2` frames ended by an empty `0x1`
+/// frame, any other response is a single `0x1` frame. `0x0` frames are errors.
+/// A page of `GET ... LIMIT n` also has a `0x3` frame with its cursor before the end,
+/// and binary `GET` results start with a `0x4` frame with their scale in protocol version 2.
+pub struct Chunks<'a> {
+    client: &'a mut TectonicClient,
+    done: bool,
+    cursor: Option<String>,
+    content: Option<ContentType>,
+    scale: Option<Scale>,
+}
+
+impl<'a> Chunks<'a> {
+    /// cursor of the page to pass to `AFTER` for the next page, once every chunk is read
+    pub fn cursor(&self) -> Option<&str> {
+        self.cursor.as_deref()
+    }
+
+    /// content type of the response once a chunk is read, None in protocol version 1
+    pub fn content_type(&self) -> Option<ContentType> {
+        self.content
+    }
+
+    /// tick size and lot size of the updates of a binary `GET` once a chunk is read,
+    /// None in protocol version 1
+    pub fn scale(&self) -> Option<Scale> {
+        self.scale
+    }
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = Result<Vec<u8>, TectonicError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let (status, buf) = loop {
+            match self.client.read_frame() {
+                Ok((0x3, _, buf)) => self.cursor = Some(String::from_utf8_lossy(&buf).into_owned()),
+                Ok((0x4, _, buf)) => self.scale = serde_json::from_slice(&buf).ok(),
+                Ok((status, content, buf)) => {
+                    if status != 0x0 {
+                        self.content = content;
+                    }
+                    break (status, buf);
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        };
+        match status {
+            0x2 => Some(Ok(buf)),
+            0x1 => {
+                self.done = true;
+                // a response that is not streamed, or the end of a stream
+                if buf.is_empty() { None } else { Some(Ok(buf)) }
+            }
+            _ => {
+                self.done = true;
+                Some(Err(server_error(&buf)))
+            }
+        }
+    }
+}
+
+/// error of a `0x0` frame
+pub(crate) fn server_error(buf: &[u8]) -> TectonicError {
+    let res = String::from_utf8_lossy(buf).into_owned();
+    if res.contains("ERR: DB") {
+        let book_name = res.split(' ').nth(2).unwrap_or_default();
+        TectonicError::DBNotFoundError(book_name.to_owned())
+    } else {
+        TectonicError::ServerError(res)
+    }
+}
+
+impl<'a> Drop for Chunks<'a> {
+    fn drop(&mut self) {
+        // keep the connection in sync with the next response
+        for _ in self {}
+    }
+}
+
+/// Updates of a `GET` response, see `TectonicClient::get`
+pub struct Updates<'a> {
+    chunks: Chunks<'a>,
+    ups: std::vec::IntoIter<Update>,
+}
+
+impl<'a> Updates<'a> {
+    /// cursor of the page, see `Chunks::cursor`
+    pub fn cursor(&self) -> Option<&str> {
+        self.chunks.cursor()
+    }
+
+    /// scale of the updates, see `Chunks::scale`
+    pub fn scale(&self) -> Option<Scale> {
+        self.chunks.scale()
+    }
+}
+
+impl<'a> Iterator for Updates<'a> {
+    type Item = Result<Update, TectonicError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(up) = self.ups.next() {
+                return Some(Ok(up));
+            }
+            match self.chunks.next()? {
+                Ok(chunk) => self.ups = decode_buffer(&mut Cursor::new(chunk.as_slice())).into_iter(),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
 }
@@ -6,6 +6,7 @@ extern crate tdb_core;
 
 pub mod error;
 pub mod client;
+pub mod async_client;
 
 use std::env;
 use crate::client::TectonicClient;
@@ -59,7 +60,7 @@ pub fn benchmark(mut cli: TectonicClient, times: usize) {
 
         let res = cli.insert(
             Some("benchmark"),
-            &Update { ts, seq: 0, is_bid: true, is_trade: false, price: 0.001939,  size: 22.85 },
+            &Update { ts, seq: 0, is_bid: true, is_trade: false, price: 193900,  size: 2285000000 },
             true,
         );
         res.unwrap();
@@ -3,16 +3,26 @@
 //!
 //!
 //! File Spec:
-//! Offset 00: ([u8; 5]) magic value 0x4454469001
-//! Offset 05: ([u8; 20]) Symbol
+//! Offset 00: ([u8; 5]) magic value 0x4454469001 (v1) or 0x4454469002 (v2)
+//! Offset 05: ([u8; 20]) Symbol, right padded with spaces, truncated if longer (see offset 71)
 //! Offset 25: (u64) number of records
 //! Offset 33: (u64) max ts
-//! Offset 80: -- records - see below --
+//! Offset 41: (u64) offset of the block index footer, 0 if none (see `storage::dtf_index`)
+//! Offset 49: (u64, u8) tick size as mantissa and exponent, 0 for the default (see `dtf::scale`)
+//! Offset 58: (u64, u8) lot size as mantissa and exponent, 0 for the default
+//! Offset 67: (u32) length of the extended header, 0 if none
+//! Offset 71: (u8) length of the symbol if it is longer than 20 bytes, 0 otherwise
+//! Offset 80: extended header
+//!        full symbol if it is longer than 20 bytes
+//!        properties section, key/value pairs describing the book, empty if none
+//!            (u16) number of pairs
+//!            each key and value: (u16) length followed by the utf-8 bytes
+//! Offset 80 + length of the extended header: -- records - see below --
 //!
 //!
 //! Record Spec:
-//! Offset 81: bool for `is_snapshot`
-//! 1. if is true
+//! Offset 81: (u8) marker, 0x1 for a batch or 0x3 for a checkpoint (see Checkpoint Spec)
+//! 1. if it is a batch
 //!        4 bytes (u32): reference ts
 //!        2 bytes (u32): reference seq
 //!        2 bytes (u16): how many records between this snapshot and the next snapshot
@@ -20,10 +30,35 @@
 //!        dts (u16): $ts - reference ts$, 2^16 = 65536 - ~65 seconds
 //!        dseq (u8) $seq - reference seq$ , 2^8 = 256
 //!        `is_trade & is_bid`: (u8): bitwise and to store two bools in one byte
-//!        price: (f32)
-//!        size: (f32)
+//!        price: (f32) converted from and to ticks with the tick size in the header
+//!        size: (f32) converted from and to lots with the lot size in the header
+//!
+//! Batches sent over the wire (`write_batches`, `decode_buffer`) have no header,
+//! their records store price and size as (u64) ticks and lots instead.
+//!
+//!
+//! Record Spec (v2):
+//! Same batch metadata as v1, the records are compressed as a whole
+//!        4 bytes (u32): length of the compressed block
+//!        4 bytes (u32): crc32 of the compressed block
+//!        compressed block: lz4 with the uncompressed size prepended, containing
+//!            dts column (u16 * count)
+//!            dseq column (u8 * count)
+//!            flags column (u8 * count)
+//!            (u32) length of the price stream
+//!            prices in ticks, Gorilla XOR encoded (see `dtf::gorilla`)
+//!            sizes in lots, Gorilla XOR encoded
+//!
+//!
+//! Checkpoint Spec:
+//! Between batches, marker 0x3 instead of 0x1, the state of the orderbook right after
+//! the last update before it (see `storage::checkpoint`), skipped by readers of updates
+//!        (u64) ts and (u32) seq of the last update applied to the book
+//!        (u32) length of the levels
+//!        levels, see `Orderbook::write_levels`
 
 const BYTES_PER_ROW: usize = 12;
+const BYTES_PER_UNITS_ROW: usize = 20;
 
 #[cfg(feature = "count_alloc")]
 use alloc_counter::{count_alloc, no_alloc};
@@ -39,23 +74,96 @@ use std::io::{self, Write, Read, Seek, BufWriter, BufReader, SeekFrom};
 
 use std::iter::Peekable;
 use std::io::Cursor;
-use std::sync::Mutex;
-use std::cell::RefCell;
-use std::ops::DerefMut;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 
 use crate::dtf::update::*;
+use crate::dtf::gorilla;
+use crate::dtf::scale::{Decimal, Scale, MAX_EXPONENT};
+use crate::storage::dtf_index::{DTFIndex, IndexEntry};
+use crate::storage::partition::partitions;
 use crate::utils::epoch_to_human;
 
 static MAGIC_VALUE: &[u8] = &[0x44, 0x54, 0x46, 0x90, 0x01]; // DTF9001
+static MAGIC_VALUE_V2: &[u8] = &[0x44, 0x54, 0x46, 0x90, 0x02]; // DTF9002
 const SYMBOL_LEN: usize = 20;
+/// maximum length of a symbol in bytes
+pub const MAX_SYMBOL_LEN: usize = u8::MAX as usize;
 static SYMBOL_OFFSET: u64 = 5;
 static LEN_OFFSET: u64 = 25;
 static MAX_TS_OFFSET: u64 = 33;
-static MAIN_OFFSET: u64 = 80; // main section start at 80
+static INDEX_PTR_OFFSET: u64 = 41;
+static SCALE_OFFSET: u64 = 49;
+static EXT_LEN_OFFSET: u64 = 67;
+static LONG_SYMBOL_LEN_OFFSET: u64 = 71;
+static EXT_OFFSET: u64 = 80;
+/// marker byte of a checkpoint record between batches
+pub const CHECKPOINT_MARKER: u8 = 0x3;
 // static ITEM_OFFSET : u64 = 13; // each item has 13 bytes
 
+/// Version of the file format
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Version {
+    /// fixed size rows
+    V1,
+    /// compressed batches with Gorilla encoded prices and sizes and a checksum
+    V2,
+}
+
+/// Version used for new files
+pub const LATEST_VERSION: Version = Version::V2;
+
+impl Default for Version {
+    fn default() -> Self {
+        LATEST_VERSION
+    }
+}
+
+impl Version {
+    fn magic_value(self) -> &'static [u8] {
+        match self {
+            Version::V1 => MAGIC_VALUE,
+            Version::V2 => MAGIC_VALUE_V2,
+        }
+    }
+}
+
+/// Key/value pairs describing a book, e.g. exchange, base, quote, asset_type, timezone, source
+pub type Properties = BTreeMap<String, String>;
+
+/// Options for writing a new file
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EncodeOptions {
+    /// format version
+    pub version: Version,
+    /// tick size and lot size of the updates
+    pub scale: Scale,
+    /// properties stored in the header
+    pub properties: Properties,
+}
+
+/// checkpoint record stored between batches
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CheckpointRecord {
+    /// ts of the last update applied to the book
+    pub ts: u64,
+    /// seq of the last update applied to the book
+    pub seq: u32,
+    /// levels of the book, see `Orderbook::write_levels`
+    pub levels: Vec<u8>,
+}
+
+/// encoding of the records in uncompressed batches
+#[derive(Clone, Copy, Debug)]
+enum Rows {
+    /// u64 ticks and lots, used on the wire
+    Units,
+    /// f32 prices and sizes of v1 files, converted with the scale of the file
+    Float(Scale),
+}
+
 /// Metadata block, one per file
-#[derive(Debug, Eq, PartialEq, PartialOrd)]
+#[derive(Debug, Eq, PartialEq)]
 pub struct Metadata {
     /// Symbol name
     pub symbol: String,
@@ -65,6 +173,10 @@ pub struct Metadata {
     pub max_ts: u64,
     /// The smallest timestamp
     pub min_ts: u64,
+    /// Tick size and lot size of the updates
+    pub scale: Scale,
+    /// Properties of the book
+    pub properties: Properties,
 }
 
 
@@ -74,6 +186,12 @@ impl Ord for Metadata {
     }
 }
 
+impl PartialOrd for Metadata {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 /// Metadata block for each Batch
 #[derive(Clone, Debug)]
 pub struct BatchMetadata {
@@ -95,14 +213,20 @@ impl fmt::Display for Metadata {
   "max_ts": {},
   "max_ts_human": "{}",
   "min_ts": {},
-  "min_ts_human": "{}"
+  "min_ts_human": "{}",
+  "tick_size": "{}",
+  "lot_size": "{}",
+  "properties": {}
 }}"#,
             self.symbol,
             self.count,
             self.max_ts,
             epoch_to_human(self.max_ts / 1000),
             self.min_ts,
-            epoch_to_human(self.min_ts / 1000)
+            epoch_to_human(self.min_ts / 1000),
+            self.scale.tick_size,
+            self.scale.lot_size,
+            serde_json::to_string(&self.properties).map_err(|_| fmt::Error)?
         )
     }
 }
@@ -129,16 +253,26 @@ pub fn write_magic_value(wtr: &mut dyn Write) -> Result<usize, io::Error> {
 }
 
 /// write symbol
+/// symbols longer than 20 bytes are truncated here and stored in full by `write_extended_header`
 pub fn write_symbol(wtr: &mut dyn Write, symbol: &str) -> Result<usize, io::Error> {
-    if symbol.len() > SYMBOL_LEN {
-        return Err(io::Error::new(InvalidData,
-            format!("Symbol length is longer than {}", SYMBOL_LEN)));
+    check_symbol(symbol)?;
+    let mut end = cmp::min(symbol.len(), SYMBOL_LEN);
+    while !symbol.is_char_boundary(end) {
+        end -= 1;
     }
-    let padded_symbol = format!("{:width$}", symbol, width = SYMBOL_LEN); // right pad w/ space
+    let padded_symbol = format!("{:width$}", &symbol[..end], width = SYMBOL_LEN); // right pad w/ space
     assert_eq!(padded_symbol.len(), SYMBOL_LEN);
     wtr.write(padded_symbol.as_bytes())
 }
 
+fn check_symbol(symbol: &str) -> Result<(), io::Error> {
+    if symbol.len() > MAX_SYMBOL_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            format!("Symbol length is longer than {}", MAX_SYMBOL_LEN)));
+    }
+    Ok(())
+}
+
 /// write length in header
 pub fn write_len<T: Write + Seek>(wtr: &mut T, len: u64) -> Result<(), io::Error> {
     let _ = wtr.seek(SeekFrom::Start(LEN_OFFSET));
@@ -151,6 +285,47 @@ pub fn write_max_ts<T: Write + Seek>(wtr: &mut T, max_ts: u64) -> Result<(), io:
     wtr.write_u64::<BigEndian>(max_ts)
 }
 
+/// write tick size and lot size in header
+pub fn write_scale<T: Write + Seek>(wtr: &mut T, scale: &Scale) -> Result<(), io::Error> {
+    wtr.seek(SeekFrom::Start(SCALE_OFFSET))?;
+    for increment in &[scale.tick_size, scale.lot_size] {
+        wtr.write_u64::<BigEndian>(increment.mantissa)?;
+        wtr.write_u8(increment.exponent)?;
+    }
+    Ok(())
+}
+
+/// write the full symbol if it doesn't fit in the header and the properties section,
+/// must be done before the records are written
+pub fn write_extended_header<T: Write + Seek>(wtr: &mut T, symbol: &str, properties: &Properties) -> Result<(), io::Error> {
+    check_symbol(symbol)?;
+    let mut buf = Vec::new();
+    let long_symbol_len = if symbol.len() > SYMBOL_LEN { symbol.len() } else { 0 };
+    buf.write_all(&symbol.as_bytes()[..long_symbol_len])?;
+    if !properties.is_empty() {
+        write_property_len(&mut buf, properties.len())?;
+        for (key, value) in properties {
+            write_property_len(&mut buf, key.len())?;
+            buf.write_all(key.as_bytes())?;
+            write_property_len(&mut buf, value.len())?;
+            buf.write_all(value.as_bytes())?;
+        }
+    }
+    wtr.seek(SeekFrom::Start(EXT_LEN_OFFSET))?;
+    wtr.write_u32::<BigEndian>(buf.len() as u32)?;
+    wtr.write_u8(long_symbol_len as u8)?;
+    wtr.seek(SeekFrom::Start(EXT_OFFSET))?;
+    wtr.write_all(&buf)
+}
+
+fn write_property_len(wtr: &mut dyn Write, len: usize) -> Result<(), io::Error> {
+    if len > u16::MAX as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            format!("Property is longer than {} bytes", u16::MAX)));
+    }
+    wtr.write_u16::<BigEndian>(len as u16)
+}
+
 fn write_metadata<T: Write + Seek>(wtr: &mut T, ups: &[Update]) -> Result<(), io::Error> {
     write_len(wtr, ups.len() as u64)?;
     write_max_ts(wtr, get_max_ts_sorted(ups))
@@ -164,33 +339,110 @@ fn write_reference(wtr: &mut dyn Write, ref_ts: u64, ref_seq: u32, len: u16) ->
 }
 
 use std::ops::Deref;
-/// write a list of updates as batches
+/// write a list of updates as batches with price and size in ticks and lots
 #[cfg_attr(feature="count_alloc", count_alloc)]
-pub fn write_batches<U: Deref<Target=Update>, I: Iterator<Item=U>>(mut wtr: &mut dyn Write, mut ups: Peekable<I>) -> Result<(), io::Error> {
-    lazy_static! {
-        static ref BUF: Mutex<RefCell<Vec<u8>>> = Mutex::new(RefCell::new(vec![0; 100_000_000]));
-    }
-    let mut b = BUF.lock().unwrap();
-    let mut c = b.deref_mut().borrow_mut();
-    let mut buf = Cursor::new(&mut c[..]);
-    // let mut buf = Vec::new();
-    let head = ups.peek().unwrap();
+pub fn write_batches<U: Deref<Target=Update>, I: Iterator<Item=U>>(wtr: &mut dyn Write, ups: Peekable<I>) -> Result<(), io::Error> {
+    write_batches_with(wtr, ups, Rows::Units, &mut |_, _, _| ())
+}
+
+/// write one record of a batch
+fn write_row(buf: &mut dyn Write, up: &Update, ref_ts: u64, ref_seq: u32, rows: Rows) -> Result<(), io::Error> {
+    match rows {
+        Rows::Units => {
+            up.serialize_to_buffer(buf, ref_ts, ref_seq);
+            Ok(())
+        }
+        Rows::Float(scale) => {
+            buf.write_u16::<BigEndian>((up.ts - ref_ts) as u16)?;
+            buf.write_u8((up.seq - ref_seq) as u8)?;
+            buf.write_u8(up.flags().bits())?;
+            buf.write_f32::<BigEndian>(scale.tick_size.to_f64(up.price) as f32)?;
+            buf.write_f32::<BigEndian>(scale.lot_size.to_f64(up.size) as f32)
+        }
+    }
+}
+
+/// whether `elem` can't be stored relative to the current batch reference
+fn is_new_batch(elem: &Update, ref_ts: u64, ref_seq: u32, count: u16) -> bool {
+    count != 0 // if we got things to write
+    && (
+         elem.ts >= ref_ts + 0xFFFF // if still addressable (ref_ts is 4 bytes)
+      || elem.seq >= ref_seq + 0xF // ref_seq is 1 byte
+      || elem.seq < ref_seq // sometimes the data is scrambled, just write that line down
+      || elem.ts < ref_ts // ^
+      || count == 0xFFFF
+     )
+}
+
+/// write a list of updates as compressed v2 batches, see `write_batches_with`
+fn write_compressed_batches_with<U, I, F>(wtr: &mut dyn Write, ups: I, on_batch: &mut F) -> Result<(), io::Error>
+    where U: Deref<Target=Update>, I: Iterator<Item=U>, F: FnMut(u64, u64, u16)
+{
+    let mut batch: Vec<Update> = Vec::with_capacity(1024);
+    let mut offset = 0;
+    for elem in ups {
+        if let Some(head) = batch.first() {
+            if is_new_batch(&elem, head.ts, head.seq, batch.len() as u16) {
+                on_batch(offset, head.ts, batch.len() as u16);
+                offset += write_compressed_batch(wtr, &batch)?;
+                batch.clear();
+            }
+        }
+        batch.push(*elem);
+    }
+    if let Some(head) = batch.first() {
+        on_batch(offset, head.ts, batch.len() as u16);
+        write_compressed_batch(wtr, &batch)?;
+    }
+    Ok(())
+}
+
+/// write one v2 batch, returns the number of bytes written
+fn write_compressed_batch(wtr: &mut dyn Write, batch: &[Update]) -> Result<u64, io::Error> {
+    let ref_ts = batch[0].ts;
+    let ref_seq = batch[0].seq;
+
+    let mut raw = Vec::with_capacity(batch.len() * BYTES_PER_UNITS_ROW);
+    for up in batch {
+        raw.write_u16::<BigEndian>((up.ts - ref_ts) as u16)?;
+    }
+    raw.extend(batch.iter().map(|up| (up.seq - ref_seq) as u8));
+    raw.extend(batch.iter().map(|up| up.flags().bits()));
+    let prices = gorilla::compress(batch.iter().map(|up| up.price));
+    raw.write_u32::<BigEndian>(prices.len() as u32)?;
+    raw.extend(prices);
+    raw.extend(gorilla::compress(batch.iter().map(|up| up.size)));
+
+    let block = lz4_flex::compress_prepend_size(&raw);
+    write_reference(wtr, ref_ts, ref_seq, batch.len() as u16)?;
+    wtr.write_u32::<BigEndian>(block.len() as u32)?;
+    wtr.write_u32::<BigEndian>(checksum(&block))?;
+    wtr.write_all(&block)?;
+    Ok(1 /* indicator byte */ + 14 /* metadata */ + 8 /* length and checksum */ + block.len() as u64)
+}
+
+/// write a list of updates as batches,
+/// `on_batch` is called with the offset relative to the start, ref_ts and count of every batch
+fn write_batches_with<U, I, F>(mut wtr: &mut dyn Write, mut ups: Peekable<I>, rows: Rows, on_batch: &mut F) -> Result<(), io::Error>
+    where U: Deref<Target=Update>, I: Iterator<Item=U>, F: FnMut(u64, u64, u16)
+{
+    // rows of the current batch, a batch has at most 0xFFFF rows
+    let mut buf = Cursor::new(Vec::with_capacity(1024 * BYTES_PER_UNITS_ROW));
+    let head = match ups.peek() {
+        Some(head) => head,
+        None => return Ok(()),
+    };
     let mut ref_ts = head.ts;
     let mut ref_seq = head.seq;
     let mut count: u16 = 0;
+    let mut offset = 0;
 
     for elem in ups {
-        if count != 0 // if we got things to write
-        && (
-             elem.ts >= ref_ts + 0xFFFF // if still addressable (ref_ts is 4 bytes)
-          || elem.seq >= ref_seq + 0xF // ref_seq is 1 byte
-          || elem.seq < ref_seq // sometimes the data is scrambled, just write that line down
-          || elem.ts < ref_ts // ^
-          || count == 0xFFFF
-         )
-        {
+        if is_new_batch(&elem, ref_ts, ref_seq, count) {
             write_reference(&mut wtr, ref_ts, ref_seq, count)?;
             let _ = wtr.write(&buf.get_ref()[0..(buf.position() as usize)]);
+            on_batch(offset, ref_ts, count);
+            offset += 1 /* indicator byte */ + 14 /* metadata */ + buf.position();
             buf.set_position(0);
             // let _ = wtr.write(buf.as_slice());
             // buf.clear();
@@ -200,19 +452,67 @@ pub fn write_batches<U: Deref<Target=Update>, I: Iterator<Item=U>>(mut wtr: &mut
             count = 0;
         }
 
-        elem.serialize_to_buffer(&mut buf, ref_ts, ref_seq);
+        write_row(&mut buf, &elem, ref_ts, ref_seq, rows)?;
 
         count += 1;
     }
 
     write_reference(&mut wtr, ref_ts, ref_seq, count)?;
+    on_batch(offset, ref_ts, count);
     wtr.write_all(&buf.get_ref()[0..(buf.position() as usize)])
     // wtr.write_all(buf.as_slice())
 }
 
-/// write main section
+/// write batches at the current position and record them in `index`
+fn write_indexed_batches<U, I, T>(wtr: &mut T, ups: Peekable<I>, version: Version, scale: Scale, index: &mut DTFIndex) -> Result<(), io::Error>
+    where U: Deref<Target=Update>, I: Iterator<Item=U>, T: Write + Seek
+{
+    let start = wtr.stream_position()?;
+    let mut on_batch = |offset, ref_ts, count: u16| {
+        index.push_batch(start + offset, ref_ts, count as u64)
+    };
+    match version {
+        Version::V1 => write_batches_with(wtr, ups, Rows::Float(scale), &mut on_batch),
+        Version::V2 => write_compressed_batches_with(wtr, ups, &mut on_batch),
+    }
+}
+
+/// Write batches at the current position and record them in `index`, each checkpoint
+/// right after the batch holding its update. Batches are split at checkpoints.
+fn write_checkpointed_batches<T>(wtr: &mut T, ups: &[Update], checkpoints: &[CheckpointRecord], version: Version, scale: Scale, index: &mut DTFIndex) -> Result<(), io::Error>
+    where T: Write + Seek
+{
+    let mut rest = ups;
+    for ck in checkpoints {
+        let n = rest.partition_point(|up| (up.ts, up.seq) <= (ck.ts, ck.seq));
+        let (head, tail) = rest.split_at(n);
+        write_indexed_batches(wtr, head.iter().peekable(), version, scale, index)?;
+        write_checkpoint(wtr, ck)?;
+        rest = tail;
+    }
+    write_indexed_batches(wtr, rest.iter().peekable(), version, scale, index)
+}
+
+fn write_checkpoint(wtr: &mut dyn Write, ck: &CheckpointRecord) -> Result<(), io::Error> {
+    wtr.write_u8(CHECKPOINT_MARKER)?;
+    wtr.write_u64::<BigEndian>(ck.ts)?;
+    wtr.write_u32::<BigEndian>(ck.seq)?;
+    wtr.write_u32::<BigEndian>(ck.levels.len() as u32)?;
+    wtr.write_all(&ck.levels)
+}
+
+/// write the index footer at the current position (right after the last batch)
+/// and point the header to it
+fn write_index<T: Write + Seek>(wtr: &mut T, index: &DTFIndex) -> Result<(), io::Error> {
+    let offset = wtr.stream_position()?;
+    index.write_to(wtr)?;
+    wtr.seek(SeekFrom::Start(INDEX_PTR_OFFSET))?;
+    wtr.write_u64::<BigEndian>(offset)
+}
+
+/// write main section of a file without properties
 pub fn write_main<'a, D: Deref<Target=Update>, T: Write + Seek, I: Iterator<Item=D>>(wtr: &mut T, ups: Peekable<I>) -> Result<(), io::Error> {
-    wtr.seek(SeekFrom::Start(MAIN_OFFSET))?;
+    wtr.seek(SeekFrom::Start(EXT_OFFSET))?;
     write_batches(wtr, ups)?;
     Ok(())
 }
@@ -220,19 +520,55 @@ pub fn write_main<'a, D: Deref<Target=Update>, T: Write + Seek, I: Iterator<Item
 
 /// write a list of updates to file
 pub fn encode(fname: &str, symbol: &str, ups: &[Update]) -> Result<(), io::Error> {
+    encode_with(fname, symbol, ups, &EncodeOptions::default())
+}
+
+/// write a list of updates to file with `opts`
+pub fn encode_with(fname: &str, symbol: &str, ups: &[Update], opts: &EncodeOptions) -> Result<(), io::Error> {
     let mut wtr = file_writer(fname, true)?;
-    encode_buffer(&mut wtr, symbol, ups)?;
+    encode_buffer_with(&mut wtr, symbol, ups, opts)?;
     wtr.flush()
 }
 
 /// encode file format into a buffer
 /// complete w ith magic value, symbol, metadata
 pub fn encode_buffer<T: Write + Seek>(wtr: &mut T, symbol: &str, ups: &[Update]) -> Result<(), io::Error> {
+    encode_buffer_with(wtr, symbol, ups, &EncodeOptions::default())
+}
+
+/// write a list of updates and the checkpoints between them to file with `opts`,
+/// checkpoints must be sorted by (ts, seq)
+pub fn encode_with_checkpoints(fname: &str, symbol: &str, ups: &[Update], checkpoints: &[CheckpointRecord], opts: &EncodeOptions) -> Result<(), io::Error> {
+    let mut wtr = file_writer(fname, true)?;
+    encode_buffer_with_checkpoints(&mut wtr, symbol, ups, checkpoints, opts)?;
+    wtr.flush()
+}
+
+/// encode file format into a buffer with `opts`
+pub fn encode_buffer_with<T: Write + Seek>(wtr: &mut T, symbol: &str, ups: &[Update], opts: &EncodeOptions) -> Result<(), io::Error> {
+    encode_buffer_with_checkpoints(wtr, symbol, ups, &[], opts)
+}
+
+/// write the header of a file without updates, read back by `read_meta_from_buf`
+pub fn encode_header<T: Write + Seek>(wtr: &mut T, symbol: &str, opts: &EncodeOptions) -> Result<(), io::Error> {
+    wtr.write_all(opts.version.magic_value())?;
+    write_symbol(wtr, symbol)?;
+    write_len(wtr, 0)?;
+    write_scale(wtr, &opts.scale)?;
+    write_extended_header(wtr, symbol, &opts.properties)
+}
+
+/// encode file format into a buffer with `opts` and checkpoints sorted by (ts, seq)
+pub fn encode_buffer_with_checkpoints<T: Write + Seek>(wtr: &mut T, symbol: &str, ups: &[Update], checkpoints: &[CheckpointRecord], opts: &EncodeOptions) -> Result<(), io::Error> {
     if !ups.is_empty() {
-        write_magic_value(wtr)?;
+        wtr.write_all(opts.version.magic_value())?;
         write_symbol(wtr, symbol)?;
         write_metadata(wtr, ups)?;
-        write_main(wtr, ups.iter().peekable())?;
+        write_scale(wtr, &opts.scale)?;
+        write_extended_header(wtr, symbol, &opts.properties)?;
+        let mut index = DTFIndex::default();
+        write_checkpointed_batches(wtr, ups, checkpoints, opts.version, opts.scale, &mut index)?;
+        write_index(wtr, &index)?;
     }
     Ok(())
 }
@@ -250,7 +586,21 @@ pub fn read_magic_value<T: Read + Seek>(rdr: &mut T) -> Result<bool, io::Error>
     rdr.seek(SeekFrom::Start(0))?;
     let mut buf = vec![0u8; 5];
     rdr.read_exact(&mut buf)?;
-    Ok(buf == MAGIC_VALUE)
+    Ok(buf == MAGIC_VALUE || buf == MAGIC_VALUE_V2)
+}
+
+/// reads magic value from buffer and returns the version of the file
+pub fn read_version<T: Read + Seek>(rdr: &mut T) -> Result<Version, io::Error> {
+    rdr.seek(SeekFrom::Start(0))?;
+    let mut buf = vec![0u8; 5];
+    rdr.read_exact(&mut buf)?;
+    if buf == MAGIC_VALUE {
+        Ok(Version::V1)
+    } else if buf == MAGIC_VALUE_V2 {
+        Ok(Version::V2)
+    } else {
+        Err(io::Error::new(InvalidData, "Magic Value incorrect"))
+    }
 }
 
 /// BufReader for dtf file
@@ -267,18 +617,253 @@ pub fn file_reader(fname: &str) -> Result<BufReader<File>, io::Error> {
 }
 
 fn read_symbol<T: Read + Seek>(rdr: &mut T) -> Result<String, io::Error> {
-    rdr.seek(SeekFrom::Start(SYMBOL_OFFSET))?;
-    let mut buffer = [0; SYMBOL_LEN];
-    rdr.read_exact(&mut buffer)?;
-    let ret = str::from_utf8(&buffer).unwrap().trim().to_owned();
+    let long_symbol_len = read_long_symbol_len(rdr)?;
+    let buffer = if long_symbol_len > 0 {
+        rdr.seek(SeekFrom::Start(EXT_OFFSET))?;
+        let mut buffer = vec![0; long_symbol_len as usize];
+        rdr.read_exact(&mut buffer)?;
+        buffer
+    } else {
+        rdr.seek(SeekFrom::Start(SYMBOL_OFFSET))?;
+        let mut buffer = vec![0; SYMBOL_LEN];
+        rdr.read_exact(&mut buffer)?;
+        buffer
+    };
+    let ret = str::from_utf8(&buffer)
+        .map_err(|_| io::Error::new(InvalidData, "Symbol is not valid utf-8"))?
+        .trim()
+        .to_owned();
     Ok(ret)
 }
 
+fn read_long_symbol_len<T: Read + Seek>(rdr: &mut T) -> Result<u64, io::Error> {
+    rdr.seek(SeekFrom::Start(LONG_SYMBOL_LEN_OFFSET))?;
+    Ok(rdr.read_u8()? as u64)
+}
+
 fn read_len<T: Read + Seek>(rdr: &mut T) -> Result<u64, io::Error> {
     rdr.seek(SeekFrom::Start(LEN_OFFSET))?;
     rdr.read_u64::<BigEndian>()
 }
 
+/// reads tick size and lot size from the header
+pub fn read_scale<T: Read + Seek>(rdr: &mut T) -> Result<Scale, io::Error> {
+    rdr.seek(SeekFrom::Start(SCALE_OFFSET))?;
+    let mut increments = [Decimal::new(0, 0); 2];
+    for increment in increments.iter_mut() {
+        let mantissa = rdr.read_u64::<BigEndian>()?;
+        let exponent = rdr.read_u8()?;
+        if exponent > MAX_EXPONENT {
+            return Err(io::Error::new(InvalidData, "Scale exponent out of range"));
+        }
+        *increment = Decimal::new(mantissa, exponent);
+    }
+    // files written before the scale was stored have zeros here
+    Ok(Scale::new(increments[0], increments[1]).unwrap_or_default())
+}
+
+/// read the properties section
+pub fn read_properties<T: Read + Seek>(rdr: &mut T) -> Result<Properties, io::Error> {
+    let mut properties = Properties::new();
+    let start = EXT_OFFSET + read_long_symbol_len(rdr)?;
+    if main_offset(rdr)? == start {
+        return Ok(properties);
+    }
+    rdr.seek(SeekFrom::Start(start))?;
+    let n = rdr.read_u16::<BigEndian>()?;
+    for _ in 0..n {
+        let key = read_property_str(rdr)?;
+        let value = read_property_str(rdr)?;
+        properties.insert(key, value);
+    }
+    Ok(properties)
+}
+
+fn read_property_str<T: Read>(rdr: &mut T) -> Result<String, io::Error> {
+    let len = rdr.read_u16::<BigEndian>()?;
+    let mut buf = vec![0; len as usize];
+    rdr.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|_| io::Error::new(InvalidData, "Property is not valid utf-8"))
+}
+
+/// offset of the first batch, right after the extended header
+fn main_offset<T: Read + Seek>(rdr: &mut T) -> Result<u64, io::Error> {
+    rdr.seek(SeekFrom::Start(EXT_LEN_OFFSET))?;
+    Ok(EXT_OFFSET + rdr.read_u32::<BigEndian>()? as u64)
+}
+
+/// read the marker byte of the next batch, skipping checkpoints
+fn read_marker<T: Read + Seek>(rdr: &mut T) -> Result<u8, io::Error> {
+    loop {
+        let marker = rdr.read_u8()?;
+        if marker != CHECKPOINT_MARKER {
+            return Ok(marker);
+        }
+        rdr.seek(SeekFrom::Current(12))?;
+        let len = rdr.read_u32::<BigEndian>()?;
+        rdr.seek(SeekFrom::Current(len as i64))?;
+    }
+}
+
+/// The last checkpoint at or before `ts` in a file, None if there is none.
+///
+/// Batches are skipped without being decoded.
+pub fn last_checkpoint(fname: &str, ts: u64) -> Result<Option<CheckpointRecord>, io::Error> {
+    let mut rdr = file_reader(fname)?;
+    let version = read_version(&mut rdr)?;
+    let end = match read_index_offset(&mut rdr)? {
+        0 => rdr.seek(SeekFrom::End(0))?,
+        offset => offset,
+    };
+    let mut pos = main_offset(&mut rdr)?;
+    rdr.seek(SeekFrom::Start(pos))?;
+    let mut found = None;
+    while pos < end {
+        match rdr.read_u8() {
+            Ok(0x1) => {
+                let meta = read_one_batch_meta(&mut rdr);
+                skip_batch_main(&mut rdr, version, &meta)?;
+            }
+            Ok(CHECKPOINT_MARKER) => {
+                let ck_ts = rdr.read_u64::<BigEndian>()?;
+                rdr.seek(SeekFrom::Current(4))?;
+                let len = rdr.read_u32::<BigEndian>()?;
+                if ck_ts > ts {
+                    break;
+                }
+                found = Some(pos);
+                rdr.seek(SeekFrom::Current(len as i64))?;
+            }
+            _ => break,
+        }
+        pos = rdr.stream_position()?;
+    }
+    match found {
+        Some(offset) => {
+            rdr.seek(SeekFrom::Start(offset + 1))?;
+            Ok(Some(read_checkpoint(&mut rdr)?))
+        }
+        None => Ok(None),
+    }
+}
+
+/// every checkpoint of a file in order
+pub fn read_checkpoints(fname: &str) -> Result<Vec<CheckpointRecord>, io::Error> {
+    let mut rdr = file_reader(fname)?;
+    let version = read_version(&mut rdr)?;
+    let end = match read_index_offset(&mut rdr)? {
+        0 => rdr.seek(SeekFrom::End(0))?,
+        offset => offset,
+    };
+    let mut pos = main_offset(&mut rdr)?;
+    rdr.seek(SeekFrom::Start(pos))?;
+    let mut ret = vec![];
+    while pos < end {
+        match rdr.read_u8() {
+            Ok(0x1) => {
+                let meta = read_one_batch_meta(&mut rdr);
+                skip_batch_main(&mut rdr, version, &meta)?;
+            }
+            Ok(CHECKPOINT_MARKER) => ret.push(read_checkpoint(&mut rdr)?),
+            _ => break,
+        }
+        pos = rdr.stream_position()?;
+    }
+    Ok(ret)
+}
+
+/// read a checkpoint record after its marker
+fn read_checkpoint<T: Read>(rdr: &mut T) -> Result<CheckpointRecord, io::Error> {
+    let ts = rdr.read_u64::<BigEndian>()?;
+    let seq = rdr.read_u32::<BigEndian>()?;
+    let mut levels = vec![0; rdr.read_u32::<BigEndian>()? as usize];
+    rdr.read_exact(&mut levels)?;
+    Ok(CheckpointRecord { ts, seq, levels })
+}
+
+fn read_index_offset<T: Read + Seek>(rdr: &mut T) -> Result<u64, io::Error> {
+    rdr.seek(SeekFrom::Start(INDEX_PTR_OFFSET))?;
+    rdr.read_u64::<BigEndian>()
+}
+
+/// offset of the index footer if the file has one covering every update
+fn find_index<T: Read + Seek>(rdr: &mut T) -> Result<Option<u64>, io::Error> {
+    let offset = read_index_offset(rdr)?;
+    if offset == 0 {
+        return Ok(None);
+    }
+    let len = read_len(rdr)?;
+    match DTFIndex::read_count(rdr, offset) {
+        Ok(count) if count == len => Ok(Some(offset)),
+        _ => Ok(None),
+    }
+}
+
+/// the closest indexed batch starting before `ts`
+fn seek_index_ts<T: Read + Seek>(rdr: &mut T, ts: u64) -> Result<Option<IndexEntry>, io::Error> {
+    match find_index(rdr)? {
+        Some(offset) => DTFIndex::find(rdr, offset, |entry| entry.ref_ts < ts),
+        None => Ok(None),
+    }
+}
+
+/// the closest indexed batch starting at or before `offset`
+fn seek_index_offset<T: Read + Seek>(rdr: &mut T, offset: u64) -> Result<Option<IndexEntry>, io::Error> {
+    match find_index(rdr)? {
+        Some(index) => DTFIndex::find(rdr, index, |entry| entry.offset <= offset),
+        None => Ok(None),
+    }
+}
+
+/// if a batch or checkpoint starts at `offset`, walking the batch headers from the
+/// closest indexed batch before it, or from `first` in a file without index
+fn is_record_start<T: Read + Seek>(rdr: &mut T, version: Version, first: u64, offset: u64) -> Result<bool, io::Error> {
+    let mut pos = match seek_index_offset(rdr, offset)? {
+        Some(entry) => entry.offset,
+        None => first,
+    };
+    rdr.seek(SeekFrom::Start(pos))?;
+    while pos < offset {
+        match rdr.read_u8()? {
+            0x1 => {
+                let meta = read_one_batch_meta(rdr);
+                skip_batch_main(rdr, version, &meta)?;
+            }
+            CHECKPOINT_MARKER => {
+                rdr.seek(SeekFrom::Current(12))?;
+                let len = rdr.read_u32::<BigEndian>()?;
+                rdr.seek(SeekFrom::Current(len as i64))?;
+            }
+            _ => return Ok(false),
+        }
+        pos = rdr.stream_position()?;
+    }
+    Ok(pos == offset)
+}
+
+/// the closest indexed batch starting at or before the `i`-th update
+fn seek_index_idx<T: Read + Seek>(rdr: &mut T, i: u64) -> Result<Option<IndexEntry>, io::Error> {
+    match find_index(rdr)? {
+        Some(offset) => DTFIndex::find(rdr, offset, |entry| entry.first_idx <= i),
+        None => Ok(None),
+    }
+}
+
+/// load the index and the offset right after the last batch,
+/// the index is rebuilt from the batch headers if the file doesn't have one
+fn load_index<T: Read + Seek>(rdr: &mut T) -> Result<(DTFIndex, u64), io::Error> {
+    if let Some(offset) = find_index(rdr)? {
+        return Ok((DTFIndex::read_from(rdr, offset)?, offset));
+    }
+    let mut index = DTFIndex::default();
+    let start = main_offset(rdr)?;
+    let (batches, end) = read_batch_offsets(rdr, start)?;
+    for (offset, meta) in batches {
+        index.push_batch(offset, meta.ref_ts, meta.count as u64);
+    }
+    Ok((index, end))
+}
+
 fn read_min_ts<T: Read + Seek>(rdr: &mut T) -> Result<u64, io::Error> {
     Ok(read_first(rdr)?.ts)
 }
@@ -310,12 +895,18 @@ fn range_for_each<T: Read + Seek, F: for<'a> FnMut(&'a Update)>(rdr: &mut T, min
     if min_ts > max_ts {
         return Ok(());
     }
-    // go to beginning of main section
-    rdr.seek(SeekFrom::Start(MAIN_OFFSET)).expect("SEEKING");
+    let version = read_version(rdr)?;
+    let scale = read_scale(rdr)?;
+    // go to the closest indexed batch or the beginning of main section
+    let start = match seek_index_ts(rdr, min_ts)? {
+        Some(entry) => entry.offset,
+        None => main_offset(rdr)?,
+    };
+    rdr.seek(SeekFrom::Start(start)).expect("SEEKING");
 
     loop {
         // read marker byte
-        match rdr.read_u8() {
+        match read_marker(rdr) {
             Ok(byte) => {
                 if byte != 0x1 {
                     return Ok(());
@@ -329,18 +920,13 @@ fn range_for_each<T: Read + Seek, F: for<'a> FnMut(&'a Update)>(rdr: &mut T, min
         // read the metadata of the current batch
         let current_meta = read_one_batch_meta(rdr);
         let current_ref_ts = current_meta.ref_ts;
+        let current_main = rdr.stream_position()?;
 
         // skip a few bytes and read the next metadata
-        let bytes_to_skip = current_meta.count as usize * BYTES_PER_ROW;
-        rdr.seek(SeekFrom::Current(bytes_to_skip as i64)).expect(
-            &format!(
-                "Skipping {} rows",
-                current_meta.count
-            ),
-        );
+        skip_batch_main(rdr, version, &current_meta)?;
 
-        // must be a batch
-        match rdr.read_u8() {
+        // must be a batch, checkpoints in between are skipped
+        match read_marker(rdr) {
             Ok(byte) => {
                 if byte != 0x1 {
                     return Ok(());
@@ -350,6 +936,7 @@ fn range_for_each<T: Read + Seek, F: for<'a> FnMut(&'a Update)>(rdr: &mut T, min
                 return Ok(());
             }                        // EOF
         };
+        let next_offset = rdr.stream_position()? - 1 /* indicator byte */;
         let next_meta = read_one_batch_meta(rdr);
         let next_ref_ts = next_meta.ref_ts;
 
@@ -380,16 +967,15 @@ fn range_for_each<T: Read + Seek, F: for<'a> FnMut(&'a Update)>(rdr: &mut T, min
                    || (min_ts > current_ref_ts && max_ts < next_ref_ts)
         {
             // seek back
-            let bytes_to_scrollback = - (bytes_to_skip as i64) - 14 /* metadata */ - 1 /* indicator byte */ ;
-            rdr.seek(SeekFrom::Current(bytes_to_scrollback)).expect(
+            rdr.seek(SeekFrom::Start(current_main)).expect(
                 "scrolling back",
             );
             //   |1*------|1--          <- we are here
             // read and filter current batch
             if min_ts <= current_ref_ts && max_ts >= next_ref_ts {
-                read_one_batch_main_for_each(rdr, current_meta, f)?;
+                read_batch_main_for_each(rdr, version, scale, current_meta, f)?;
             } else {
-                read_one_batch_main_for_each(rdr, current_meta, &mut |up| {
+                read_batch_main_for_each(rdr, version, scale, current_meta, &mut |up| {
                     if up.ts <= max_ts && up.ts >= min_ts {
                         f(up);
                     }
@@ -402,8 +988,7 @@ fn range_for_each<T: Read + Seek, F: for<'a> FnMut(&'a Update)>(rdr: &mut T, min
         } else if min_ts >= next_ref_ts {
             // simply skip back to the beginning of the second batch
             // |1----*|1---|1---
-            let bytes_to_scrollback = - 14 /* metadata */ - 1 /* indicator byte */ ;
-            rdr.seek(SeekFrom::Current(bytes_to_scrollback)).expect(
+            rdr.seek(SeekFrom::Start(next_offset)).expect(
                 "SKIPPING n ROWS",
             );
         } else {
@@ -412,7 +997,57 @@ fn range_for_each<T: Read + Seek, F: for<'a> FnMut(&'a Update)>(rdr: &mut T, min
     }
 }
 
-/// Read metadata block and main batch block
+/// Calls `f` with the offset of its batch and every update of a file, starting at the batch
+/// at `offset`, or at the first batch that may hold `min_ts` when `offset` is None or doesn't
+/// point to a batch starting at or before `min_ts`.
+/// The offset may come from a client, it is only used if a batch of the file starts there.
+/// Stops at the end of the file or after `f` returns false.
+pub fn for_each_from<F: FnMut(u64, &Update) -> bool>(fname: &str, offset: Option<u64>, min_ts: u64, f: &mut F) -> Result<(), io::Error> {
+    let mut rdr = file_reader(fname)?;
+    let version = read_version(&mut rdr)?;
+    let scale = read_scale(&mut rdr)?;
+    // batches end where the index footer starts
+    let end = match read_index_offset(&mut rdr)? {
+        0 => rdr.seek(SeekFrom::End(0))?,
+        offset => offset,
+    };
+    let first = main_offset(&mut rdr)?;
+    let offset = match offset {
+        Some(offset) if offset >= first && offset + 15 <= end && is_record_start(&mut rdr, version, first, offset)? => {
+            rdr.seek(SeekFrom::Start(offset))?;
+            let is_ref = rdr.read_u8()? == 0x1;
+            if is_ref && rdr.read_u64::<BigEndian>()? <= min_ts { Some(offset) } else { None }
+        }
+        _ => None,
+    };
+    let start = match offset {
+        Some(offset) => offset,
+        None => match seek_index_ts(&mut rdr, min_ts)? {
+            Some(entry) => entry.offset,
+            None => first,
+        },
+    };
+    rdr.seek(SeekFrom::Start(start))?;
+    let mut pos = start;
+    let mut more = true;
+    while more && pos < end {
+        match read_marker(&mut rdr) {
+            Ok(0x1) => (),
+            _ => return Ok(()),
+        }
+        pos = rdr.stream_position()? - 1;
+        let meta = read_one_batch_meta(&mut rdr);
+        read_batch_main_for_each(&mut rdr, version, scale, meta, &mut |up| {
+            if more {
+                more = f(pos, up);
+            }
+        })?;
+        pos = rdr.stream_position()?;
+    }
+    Ok(())
+}
+
+/// Read metadata block and main batch block of a batch written by `write_batches`
 pub fn read_one_batch<R: Read + Seek>(rdr: &mut R) -> Result<Vec<Update>, io::Error> {
     let is_ref = rdr.read_u8()? == 0x1;
     if !is_ref {
@@ -423,14 +1058,14 @@ pub fn read_one_batch<R: Read + Seek>(rdr: &mut R) -> Result<Vec<Update>, io::Er
     }
 }
 
-/// Read metadata block and main batch block
+/// Read metadata block and main batch block of a batch written by `write_batches`
 pub fn read_one_batch_for_each<R: Read + Seek, F: for<'a> FnMut(&'a Update)>(rdr: &mut R, f: &mut F) -> Result<(), io::Error> {
     let is_ref = rdr.read_u8()? == 0x1;
     if !is_ref {
         Ok(())
     } else {
         let meta = read_one_batch_meta(rdr);
-        read_one_batch_main_for_each(rdr, meta, f)
+        read_one_batch_main_for_each(rdr, meta, Rows::Units, f)
     }
 }
 
@@ -447,31 +1082,115 @@ pub fn read_one_batch_meta(rdr: &mut impl Read) -> BatchMetadata {
     }
 }
 
-fn read_one_batch_main_for_each<R: Read + Seek, F: for<'a> FnMut(&'a Update)>(rdr: &mut R, meta: BatchMetadata, f: &mut F) -> Result<(), io::Error> {
+fn read_one_batch_main_for_each<R: Read + Seek, F: for<'a> FnMut(&'a Update)>(rdr: &mut R, meta: BatchMetadata, rows: Rows, f: &mut F) -> Result<(), io::Error> {
     for _i in 0..meta.count {
-        let up = read_one_update(rdr, &meta)?;
+        let up = read_one_update(rdr, &meta, rows)?;
         f(&up);
     }
     Ok(())
 }
 
+/// read the updates of a batch in the format of `version`, right after its metadata
+fn read_batch_main_for_each<R: Read + Seek, F: for<'a> FnMut(&'a Update)>(rdr: &mut R, version: Version, scale: Scale, meta: BatchMetadata, f: &mut F) -> Result<(), io::Error> {
+    match version {
+        Version::V1 => read_one_batch_main_for_each(rdr, meta, Rows::Float(scale), f),
+        Version::V2 => {
+            read_compressed_batch(rdr, &meta)?.iter().for_each(|up| f(up));
+            Ok(())
+        }
+    }
+}
+
+/// skip the updates of a batch right after its metadata, returns the number of bytes skipped
+fn skip_batch_main<R: Read + Seek>(rdr: &mut R, version: Version, meta: &BatchMetadata) -> Result<u64, io::Error> {
+    match version {
+        Version::V1 => {
+            let len = meta.count as u64 * BYTES_PER_ROW as u64;
+            rdr.seek(SeekFrom::Current(len as i64))?;
+            Ok(len)
+        }
+        Version::V2 => {
+            let len = rdr.read_u32::<BigEndian>()? as u64;
+            rdr.seek(SeekFrom::Current(4 /* checksum */ + len as i64))?;
+            Ok(8 /* length and checksum */ + len)
+        }
+    }
+}
+
+fn checksum(block: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(block);
+    hasher.finalize()
+}
+
+/// read and verify a v2 batch right after its metadata
+fn read_compressed_batch<R: Read>(rdr: &mut R, meta: &BatchMetadata) -> Result<Vec<Update>, io::Error> {
+    let len = rdr.read_u32::<BigEndian>()?;
+    let expected = rdr.read_u32::<BigEndian>()?;
+    let mut block = vec![0; len as usize];
+    rdr.read_exact(&mut block)?;
+    if checksum(&block) != expected {
+        return Err(io::Error::new(InvalidData, "Batch checksum mismatch"));
+    }
+    let raw = lz4_flex::decompress_size_prepended(&block)
+        .map_err(|e| io::Error::new(InvalidData, format!("Unable to decompress batch: {}", e)))?;
+
+    let n = meta.count as usize;
+    let corrupt = || io::Error::new(InvalidData, "Batch is corrupt");
+    let mut cols = Cursor::new(&raw);
+    let mut dts = Vec::with_capacity(n);
+    for _ in 0..n {
+        dts.push(cols.read_u16::<BigEndian>()?);
+    }
+    let mut dseqs = vec![0; n];
+    cols.read_exact(&mut dseqs)?;
+    let mut flags = vec![0; n];
+    cols.read_exact(&mut flags)?;
+    let prices_len = cols.read_u32::<BigEndian>()? as usize;
+    let pos = cols.position() as usize;
+    let prices = raw.get(pos..pos + prices_len)
+        .and_then(|buf| gorilla::decompress(buf, n))
+        .ok_or_else(corrupt)?;
+    let sizes = gorilla::decompress(&raw[pos + prices_len..], n).ok_or_else(corrupt)?;
+
+    let mut ret = Vec::with_capacity(n);
+    for i in 0..n {
+        let flags = Flags::from_bits(flags[i]).ok_or_else(corrupt)?;
+        ret.push(Update {
+            ts: meta.ref_ts + u64::from(dts[i]),
+            seq: meta.ref_seq + u32::from(dseqs[i]),
+            is_trade: (flags & Flags::FLAG_IS_TRADE).to_bool(),
+            is_bid: (flags & Flags::FLAG_IS_BID).to_bool(),
+            price: prices[i],
+            size: sizes[i],
+        });
+    }
+    Ok(ret)
+}
+
 fn read_one_batch_main(rdr: &mut (impl Read + Seek), meta: BatchMetadata) -> Result<Vec<Update>, io::Error> {
     let mut v: Vec<Update> = vec![];
     for _i in 0..meta.count {
-        let up = read_one_update(rdr, &meta)?;
+        let up = read_one_update(rdr, &meta, Rows::Units)?;
         v.push(up);
     }
     Ok(v)
 }
 
-fn read_one_update(rdr: &mut (impl Read + Seek), meta: &BatchMetadata) -> Result<Update, io::Error> {
+fn read_one_update(rdr: &mut (impl Read + Seek), meta: &BatchMetadata, rows: Rows) -> Result<Update, io::Error> {
     let ts = u64::from(rdr.read_u16::<BigEndian>()?) + meta.ref_ts;
     let seq = u32::from(rdr.read_u8()?) + meta.ref_seq;
     let flags = rdr.read_u8()?;
     let is_trade = ( Flags::from_bits(flags).ok_or_else(||{ InvalidData })? & Flags::FLAG_IS_TRADE).to_bool();
     let is_bid = ( Flags::from_bits(flags).ok_or_else(||{ InvalidData })? & Flags::FLAG_IS_BID).to_bool();
-    let price = rdr.read_f32::<BigEndian>()?;
-    let size = rdr.read_f32::<BigEndian>()?;
+    let (price, size) = match rows {
+        Rows::Units => (rdr.read_u64::<BigEndian>()?, rdr.read_u64::<BigEndian>()?),
+        Rows::Float(scale) => {
+            let price = rdr.read_f32::<BigEndian>()?;
+            let size = rdr.read_f32::<BigEndian>()?;
+            (scale.tick_size.from_f64(price as f64), scale.lot_size.from_f64(size as f64))
+        }
+    };
     Ok(Update {
         ts,
         seq,
@@ -482,9 +1201,17 @@ fn read_one_update(rdr: &mut (impl Read + Seek), meta: &BatchMetadata) -> Result
     })
 }
 
-fn read_first_batch<T: Read + Seek>(mut rdr: &mut T) -> Result<Vec<Update>, io::Error> {
-    rdr.seek(SeekFrom::Start(MAIN_OFFSET)).expect("SEEKING");
-    read_one_batch(&mut rdr)
+fn read_first_batch<T: Read + Seek>(rdr: &mut T) -> Result<Vec<Update>, io::Error> {
+    let version = read_version(rdr)?;
+    let scale = read_scale(rdr)?;
+    let start = main_offset(rdr)?;
+    rdr.seek(SeekFrom::Start(start)).expect("SEEKING");
+    let mut v = vec![];
+    if read_marker(rdr)? == 0x1 {
+        let meta = read_one_batch_meta(rdr);
+        read_batch_main_for_each(rdr, version, scale, meta, &mut |up| v.push(*up))?;
+    }
+    Ok(v)
 }
 
 fn read_first<T: Read + Seek>(mut rdr: &mut T) -> Result<Update, io::Error> {
@@ -503,6 +1230,8 @@ pub fn read_meta_from_buf<T: Read + Seek>(mut rdr: &mut T) -> Result<Metadata, i
     let symbol = read_symbol(&mut rdr)?;
     let count = read_len(&mut rdr)?;
     let max_ts = read_max_ts(&mut rdr)?;
+    let scale = read_scale(&mut rdr)?;
+    let properties = read_properties(&mut rdr)?;
     let min_ts = if count > 0 {
         read_min_ts(&mut rdr)?
     } else {
@@ -514,6 +1243,8 @@ pub fn read_meta_from_buf<T: Read + Seek>(mut rdr: &mut T) -> Result<Metadata, i
         count,
         max_ts,
         min_ts,
+        scale,
+        properties,
     })
 }
 
@@ -530,15 +1261,19 @@ pub mod iterators {
 
     /// read batch metadata from dtf files
     pub struct DTFMetadataReader<T: Read + Seek> {
-        rdr: T
+        rdr: T,
+        version: Version,
     }
 
     impl<T: Read + Seek> DTFMetadataReader<T> {
         /// create a new DTFBufReader
         pub fn new(mut rdr: T) -> Self {
-            rdr.seek(SeekFrom::Start(MAIN_OFFSET)).expect("SEEKING");
+            let version = read_version(&mut rdr).expect("MAGIC VALUE");
+            let start = main_offset(&mut rdr).expect("SEEKING");
+            rdr.seek(SeekFrom::Start(start)).expect("SEEKING");
             DTFMetadataReader {
                 rdr,
+                version,
             }
         }
     }
@@ -546,10 +1281,10 @@ pub mod iterators {
     impl<T: Read + Seek> Iterator for DTFMetadataReader<T> {
         type Item = BatchMetadata;
         fn next(&mut self) -> Option<Self::Item> {
-            if let Ok(is_ref) = self.rdr.read_u8() {
+            if let Ok(is_ref) = read_marker(&mut self.rdr) {
                 if is_ref == 0x1 {
                     let meta = read_one_batch_meta(&mut self.rdr);
-                    self.rdr.seek(SeekFrom::Current(meta.count as i64 * BYTES_PER_ROW as i64)).unwrap();
+                    skip_batch_main(&mut self.rdr, self.version, &meta).ok()?;
                     Some(meta)
                 } else { None }
             } else { None }
@@ -560,7 +1295,13 @@ pub mod iterators {
     #[derive(Clone, Debug)]
     pub struct DTFBufReader<T: Read + Seek> {
         rdr: T,
+        version: Version,
+        scale: Scale,
+        /// offset of the first batch
+        main_offset: u64,
         current_meta: Option<BatchMetadata>,
+        /// decoded updates of the current batch in v2 files
+        block: Option<Vec<Update>>,
         /// total number of updates
         n_up: u64,
         /// index of the last update to read
@@ -574,32 +1315,48 @@ pub mod iterators {
         /// start at i-th update in file
         pub fn with_offset(mut rdr: T, offset: usize) -> Self {
             let meta = read_meta_from_buf(&mut rdr).unwrap();
-            rdr.seek(SeekFrom::Start(MAIN_OFFSET)).expect("SEEKING");
+            let version = read_version(&mut rdr).unwrap();
+            let main_offset = main_offset(&mut rdr).unwrap();
+            // jump to the closest indexed batch
+            let (start, mut cur) = match seek_index_idx(&mut rdr, offset as u64).unwrap() {
+                Some(entry) => (entry.offset, entry.first_idx as usize),
+                None => (main_offset, 0),
+            };
+            rdr.seek(SeekFrom::Start(start)).expect("SEEKING");
 
             let mut dtf = DTFBufReader {
                 rdr,
+                version,
+                scale: meta.scale,
+                main_offset,
                 current_meta: None,
+                block: None,
                 n_up: meta.count,
                 last_idx: None,
-                i_up_in_file: 0,
+                i_up_in_file: cur as u32,
                 i_up: 0,
             };
 
             dtf.next_block().unwrap();
-            let mut cur = 0;
             while cur < offset {
                 let count = dtf.current_meta.as_ref().unwrap().count;
                 if (offset - cur) < count as usize {
-                    let skip_bytes = (offset - cur) as i64 * 12;
-                    dtf.rdr.seek(SeekFrom::Current(skip_bytes)).unwrap();
+                    match dtf.version {
+                        Version::V1 => {
+                            let skip_bytes = (offset - cur) as i64 * BYTES_PER_ROW as i64;
+                            dtf.rdr.seek(SeekFrom::Current(skip_bytes)).unwrap();
+                        }
+                        Version::V2 => dtf.load_block().unwrap(),
+                    }
                     dtf.i_up = (offset - cur) as u32;
                     cur = offset;
                     dtf.i_up_in_file = offset as u32;
                 } else {
-                    let skip_bytes = count as i64 * 12;
-                    dtf.rdr.seek(SeekFrom::Current(skip_bytes)).unwrap();
+                    let meta = dtf.current_meta.as_ref().unwrap();
+                    skip_batch_main(&mut dtf.rdr, dtf.version, meta).unwrap();
 
                     cur += count as usize;
+                    dtf.i_up_in_file = cur as u32;
                     dtf.next_block().unwrap();
                 }
             }
@@ -609,10 +1366,16 @@ pub mod iterators {
         /// create a new DTFBufReader
         pub fn new(mut rdr: T) -> Self {
             let meta = read_meta_from_buf(&mut rdr).unwrap();
-            rdr.seek(SeekFrom::Start(MAIN_OFFSET)).expect("SEEKING");
+            let version = read_version(&mut rdr).unwrap();
+            let main_offset = main_offset(&mut rdr).unwrap();
+            rdr.seek(SeekFrom::Start(main_offset)).expect("SEEKING");
             DTFBufReader {
                 rdr,
+                version,
+                scale: meta.scale,
+                main_offset,
                 current_meta: None,
+                block: None,
                 n_up: meta.count,
                 last_idx: None,
                 i_up_in_file: 0,
@@ -622,8 +1385,9 @@ pub mod iterators {
 
         /// reset iterator
         pub fn reset(&mut self) {
-            self.rdr.seek(SeekFrom::Start(MAIN_OFFSET)).expect("SEEKING");
+            self.rdr.seek(SeekFrom::Start(self.main_offset)).expect("SEEKING");
             self.current_meta = None;
+            self.block = None;
             self.last_idx = None;
             self.i_up_in_file = 0;
             self.i_up = 0;
@@ -647,10 +1411,11 @@ pub mod iterators {
         }
 
         fn next_block(&mut self) -> Option<()> {
-            if let Ok(is_ref) = self.rdr.read_u8() {
+            if let Ok(is_ref) = read_marker(&mut self.rdr) {
                 if is_ref == 0x1 {
                     let meta = read_one_batch_meta(&mut self.rdr);
                     self.current_meta = Some(meta);
+                    self.block = None;
                     self.i_up = 0;
                     Some(())
                 } else {
@@ -660,8 +1425,23 @@ pub mod iterators {
                 None
             }
         }
+        /// decode the current v2 batch
+        fn load_block(&mut self) -> Option<()> {
+            if self.block.is_none() {
+                let meta = self.current_meta.as_ref()?;
+                self.block = Some(read_compressed_batch(&mut self.rdr, meta).ok()?);
+            }
+            Some(())
+        }
+
         fn read_one(&mut self) -> Option<Update> {
-            let up = read_one_update(&mut self.rdr, self.current_meta.as_ref()?).ok()?;
+            let up = match self.version {
+                Version::V1 => read_one_update(&mut self.rdr, self.current_meta.as_ref()?, Rows::Float(self.scale)).ok()?,
+                Version::V2 => {
+                    self.load_block()?;
+                    *self.block.as_ref()?.get(self.i_up as usize)?
+                }
+            };
             self.i_up += 1;
             self.i_up_in_file += 1;
             Some(up)
@@ -692,15 +1472,19 @@ pub mod iterators {
 }
 
 
-fn read_n_batches_for_each<T: Read + Seek, F: for<'a> FnMut(&'a Update)>(mut rdr: &mut T, num_rows: u32, f: &mut F) -> Result<(), io::Error> {
-    rdr.seek(SeekFrom::Start(MAIN_OFFSET)).expect("SEEKING");
+fn read_n_batches_for_each<T: Read + Seek, F: for<'a> FnMut(&'a Update)>(rdr: &mut T, num_rows: u32, f: &mut F) -> Result<(), io::Error> {
+    let version = read_version(rdr)?;
+    let scale = read_scale(rdr)?;
+    let start = main_offset(rdr)?;
+    rdr.seek(SeekFrom::Start(start)).expect("SEEKING");
     let mut count = 0;
     if num_rows == 0 { return Ok(()); }
-    while let Ok(is_ref) = rdr.read_u8() {
-        if is_ref == 0x1 {
-            rdr.seek(SeekFrom::Current(-1)).expect("ROLLBACK ONE BYTE");
-            read_one_batch_for_each(&mut rdr, f)?;
+    while let Ok(is_ref) = read_marker(rdr) {
+        if is_ref != 0x1 {
+            break;
         }
+        let meta = read_one_batch_meta(rdr);
+        read_batch_main_for_each(rdr, version, scale, meta, f)?;
         count += 1;
         if count > num_rows {
             break;
@@ -709,31 +1493,23 @@ fn read_n_batches_for_each<T: Read + Seek, F: for<'a> FnMut(&'a Update)>(mut rdr
     Ok(())
 }
 
-fn read_n_batches<T: Read + Seek>(mut rdr: &mut T, num_rows: u32) -> Result<Vec<Update>, io::Error> {
-    rdr.seek(SeekFrom::Start(MAIN_OFFSET)).expect("SEEKING");
+fn read_n_batches<T: Read + Seek>(rdr: &mut T, num_rows: u32) -> Result<Vec<Update>, io::Error> {
     let mut v: Vec<Update> = Vec::with_capacity(num_rows as usize);
-    let mut count = 0;
-    if num_rows == 0 { return Ok(v); }
-    while let Ok(is_ref) = rdr.read_u8() {
-        if is_ref == 0x1 {
-            rdr.seek(SeekFrom::Current(-1)).expect("ROLLBACK ONE BYTE");
-            v.extend(read_one_batch(&mut rdr)?);
-        }
-        count += 1;
-        if count > num_rows {
-            break;
-        }
-    }
+    read_n_batches_for_each(rdr, num_rows, &mut |up| v.push(*up))?;
     Ok(v)
 }
 
-fn read_all_for_each<T: Read + Seek, F: for<'a> FnMut(&'a Update)>(mut rdr: &mut T, f: &mut F) -> Result<(), io::Error> {
-    rdr.seek(SeekFrom::Start(MAIN_OFFSET)).expect("SEEKING");
-    while let Ok(is_ref) = rdr.read_u8() {
-        if is_ref == 0x1 {
-            rdr.seek(SeekFrom::Current(-1)).expect("ROLLBACK ONE BYTE");
-            read_one_batch_for_each(&mut rdr, f)?;
+fn read_all_for_each<T: Read + Seek, F: for<'a> FnMut(&'a Update)>(rdr: &mut T, f: &mut F) -> Result<(), io::Error> {
+    let version = read_version(rdr)?;
+    let scale = read_scale(rdr)?;
+    let start = main_offset(rdr)?;
+    rdr.seek(SeekFrom::Start(start)).expect("SEEKING");
+    while let Ok(is_ref) = read_marker(rdr) {
+        if is_ref != 0x1 {
+            break;
         }
+        let meta = read_one_batch_meta(rdr);
+        read_batch_main_for_each(rdr, version, scale, meta, f)?;
     }
     Ok(())
 }
@@ -766,56 +1542,175 @@ pub fn decode_for_each<F: for<'a> FnMut(&'a Update)>(fname: &str, num_rows: Opti
     }
 }
 
-/// Decode an entire buffer to Updates
+/// Decode an entire buffer written by `write_batches` to Updates
 pub fn decode_buffer(mut buf: &mut (impl Read + Seek)) -> Vec<Update> {
     let mut v = vec![];
-    let mut res = read_one_batch(&mut buf);
-    while let Ok(ups) = res {
-        v.extend(ups);
-        res = read_one_batch(&mut buf);
+    while let Ok(is_ref) = buf.read_u8() {
+        if is_ref != 0x1 {
+            break;
+        }
+        let meta = read_one_batch_meta(&mut buf);
+        if read_one_batch_main_for_each(&mut buf, meta, Rows::Units, &mut |up| v.push(*up)).is_err() {
+            break;
+        }
     }
     v
 }
 
 /// append a list of Updates to file
-/// Panic when range is wrong (new_min_ts <= old_max_ts)
+///
+/// Updates newer than the last update in the file are simply appended.
+/// Late or overlapping updates are merged into the trailing batches they overlap with,
+/// which are then rewritten so the file stays sorted by (ts, seq).
+/// Exact duplicates of updates already in the file are dropped.
+/// Prices and sizes must be in the tick size and lot size of the file.
 #[cfg_attr(feature = "count_alloc", count_alloc)]
 pub fn append(fname: &str, ups: &[Update]) -> Result<(), io::Error> {
+    append_with_checkpoints(fname, ups, &[])
+}
+
+/// Append a list of Updates and the checkpoints between them to file, see `append`.
+///
+/// Checkpoints must be sorted by (ts, seq). The checkpoints of the batches rewritten to
+/// merge late updates are dropped since the state of the book changed after them.
+pub fn append_with_checkpoints(fname: &str, ups: &[Update], checkpoints: &[CheckpointRecord]) -> Result<(), io::Error> {
+    if ups.is_empty() {
+        return Ok(());
+    }
+
     let mut rdr = file_reader(fname)?;
+    let version = read_version(&mut rdr)?;
+    let scale = read_scale(&mut rdr)?;
     let _symbol = read_symbol(&mut rdr)?;
-
     let old_max_ts = read_max_ts(&mut rdr)?;
+    let cur_len = read_len(&mut rdr)?;
 
-    let mut ups = ups.into_iter().filter(|up| up.ts > old_max_ts).peekable();
+    let ups = if is_sorted(ups) {
+        Cow::Borrowed(ups)
+    } else {
+        let mut sorted = ups.to_vec();
+        sorted.sort();
+        Cow::Owned(sorted)
+    };
 
-    if ups.peek().is_none() {
-        return Ok(());
+    let new_min_ts = ups.first().unwrap().ts;
+    if cur_len != 0 && new_min_ts <= old_max_ts {
+        return merge_append(fname, &mut rdr, version, scale, cur_len, old_max_ts, &ups, checkpoints);
     }
 
-    let new_min_ts = ups.clone().next().unwrap().ts;
-    let new_max_ts = ups.clone().next_back().unwrap().ts;
+    let new_max_ts = get_max_ts_sorted(&ups);
+    let new_len = cur_len + ups.len() as u64;
 
-    if new_min_ts <= old_max_ts {
-        panic!("Cannot append data!(not implemented)");
-    }
+    let (mut index, data_end) = if cur_len == 0 {
+        (DTFIndex::default(), main_offset(&mut rdr)?)
+    } else {
+        load_index(&mut rdr)?
+    };
 
-    let cur_len = read_len(&mut rdr)?;
+    // the footer is rewritten after the new batches
+    let mut wtr = file_writer(fname, false)?;
+    wtr.get_ref().set_len(data_end)?;
+    write_len(&mut wtr, new_len)?;
+    write_max_ts(&mut wtr, new_max_ts)?;
+    wtr.seek(SeekFrom::Start(data_end))?;
+    write_checkpointed_batches(&mut wtr, &ups, checkpoints, version, scale, &mut index)?;
+    write_index(&mut wtr, &index)?;
+    wtr.flush()
+}
 
-    let new_len = cur_len + ups.clone().count() as u64;
+/// rewrite the batches overlapping with `ups` (sorted) merged with `ups`
+#[allow(clippy::too_many_arguments)]
+fn merge_append<T: Read + Seek>(fname: &str, rdr: &mut T, version: Version, scale: Scale, cur_len: u64, old_max_ts: u64, ups: &[Update], checkpoints: &[CheckpointRecord]) -> Result<(), io::Error> {
+    let new_min_ts = ups.first().unwrap().ts;
+
+    // every update before the last batch starting before `new_min_ts` is older than `new_min_ts`,
+    // the index narrows down where to start looking for it
+    let (mut index, _) = load_index(rdr)?;
+    let start = main_offset(rdr)?;
+    let (walk_offset, walk_idx) = index.seek_ts(new_min_ts)
+        .map(|entry| (entry.offset, entry.first_idx))
+        .unwrap_or((start, 0));
+    let (batches, _) = read_batch_offsets(rdr, walk_offset)?;
+    let start = batches.iter()
+        .rposition(|(_offset, meta)| meta.ref_ts < new_min_ts)
+        .unwrap_or(0);
+    let tail_offset = batches.get(start).map(|(offset, _)| *offset).unwrap_or(walk_offset);
+    let tail_idx = walk_idx + batches[..start].iter().map(|(_, meta)| meta.count as u64).sum::<u64>();
+
+    let mut tail = Vec::new();
+    rdr.seek(SeekFrom::Start(tail_offset))?;
+    while let Ok(is_ref) = read_marker(rdr) {
+        if is_ref != 0x1 { break; }
+        let meta = read_one_batch_meta(rdr);
+        read_batch_main_for_each(rdr, version, scale, meta, &mut |up| tail.push(*up))?;
+    }
+    let tail_len = tail.len() as u64;
+
+    let merged = merge_dedup(tail, ups);
+    let new_max_ts = u64::max(old_max_ts, get_max_ts_sorted(&merged));
+    let new_len = cur_len - tail_len + merged.len() as u64;
 
     let mut wtr = file_writer(fname, false)?;
+    wtr.get_ref().set_len(tail_offset)?;
     write_len(&mut wtr, new_len)?;
     write_max_ts(&mut wtr, new_max_ts)?;
+    wtr.seek(SeekFrom::Start(tail_offset))?;
+    index.truncate(tail_offset, tail_idx);
+    write_checkpointed_batches(&mut wtr, &merged, checkpoints, version, scale, &mut index)?;
+    write_index(&mut wtr, &index)?;
+    wtr.flush()
+}
 
-    if cur_len == 0 {
-        wtr.seek(SeekFrom::Start(MAIN_OFFSET)).unwrap();
-    } else {
-        wtr.seek(SeekFrom::End(0)).unwrap();
+/// merge two lists of updates sorted by (ts, seq)
+/// and drop exact duplicates among updates that share the same (ts, seq)
+pub fn merge_dedup(old: Vec<Update>, new: &[Update]) -> Vec<Update> {
+    let mut merged = old;
+    merged.extend_from_slice(new);
+    merged.sort(); // stable, so existing updates stay in front
+
+    let mut ret: Vec<Update> = Vec::with_capacity(merged.len());
+    let mut group_start = 0;
+    for up in merged {
+        let same_key = ret.last()
+            .map(|last| last.ts == up.ts && last.seq == up.seq)
+            .unwrap_or(false);
+        if !same_key {
+            group_start = ret.len();
+        }
+        if !ret[group_start..].contains(&up) {
+            ret.push(up);
+        }
     }
-    write_batches(&mut wtr, ups.peekable())?;
-    wtr.flush().unwrap();
+    ret
+}
 
-    Ok(())
+fn is_sorted(ups: &[Update]) -> bool {
+    ups.windows(2).all(|w| w[0] <= w[1])
+}
+
+/// Read the offset and metadata of every batch from the batch at `start` to the end of main section,
+/// also returns the offset right after the last batch or checkpoint
+pub fn read_batch_offsets<T: Read + Seek>(rdr: &mut T, start: u64) -> Result<(Vec<(u64, BatchMetadata)>, u64), io::Error> {
+    let version = read_version(rdr)?;
+    let mut ret = vec![];
+    let mut offset = rdr.seek(SeekFrom::Start(start))?;
+    loop {
+        match rdr.read_u8() {
+            Ok(0x1) => (),
+            Ok(CHECKPOINT_MARKER) => {
+                rdr.seek(SeekFrom::Current(12))?;
+                let len = rdr.read_u32::<BigEndian>()?;
+                offset = rdr.seek(SeekFrom::Current(len as i64))?;
+                continue;
+            }
+            _ => break,
+        }
+        let meta = read_one_batch_meta(rdr);
+        skip_batch_main(rdr, version, &meta)?;
+        ret.push((offset, meta));
+        offset = rdr.stream_position()?;
+    }
+    Ok((ret, offset))
 }
 
 /// search every matching dtf file under folder for timestamp range
@@ -830,6 +1725,47 @@ pub fn scan_files_for_range(
     Ok(ret)
 }
 
+/// every dtf file of the book under folder overlapping the timestamp range, sorted by min_ts
+pub fn files_for_range(
+    folder: &str,
+    symbol: &str,
+    min_ts: u64,
+    max_ts: u64,
+) -> Result<Vec<(String, Metadata)>, io::Error> {
+    let entries = fs::read_dir(folder).map_err(|e| io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Unable to read dir entries: {:?}", e),
+    ))?;
+    let mut v = entries
+        .filter_map(|entry| {
+            let entry = entry.unwrap();
+            let fname = entry.file_name();
+            let fname = fname.to_str().unwrap().to_owned();
+            let fname = &format!("{}/{}", folder, fname);
+            let meta = read_meta(fname).ok()?;
+            Some((fname.to_owned(), meta))
+        })
+        .filter(|(_fname, meta)| {
+            &meta.symbol == symbol && crate::utils::within_range(min_ts, max_ts, meta.min_ts, meta.max_ts)
+        })
+        .collect::<Vec<_>>();
+
+    // partitions of the book in `{folder}/{symbol}/`, only overlapping ones are opened
+    for partition in partitions(folder, symbol)? {
+        if !partition.overlaps(min_ts, max_ts) {
+            continue;
+        }
+        let meta = read_meta(&partition.fname)?;
+        if crate::utils::within_range(min_ts, max_ts, meta.min_ts, meta.max_ts) {
+            v.push((partition.fname, meta));
+        }
+    }
+
+    // sort by min_ts
+    v.sort_by(|(_f0, m0), (_f1, m1)| m0.cmp(m1));
+    Ok(v)
+}
+
 /// search every matching dtf file under folder for timestamp range
 pub fn scan_files_for_range_for_each<F: for<'a> FnMut(&'a Update)>(
     folder: &str,
@@ -838,38 +1774,11 @@ pub fn scan_files_for_range_for_each<F: for<'a> FnMut(&'a Update)>(
     max_ts: u64,
     f: &mut F,
 ) -> Result<(), io::Error> {
-    match fs::read_dir(folder) {
-        Err(e) => {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                format!("Unable to read dir entries: {:?}", e),
-            ))
-        }
-        Ok(entries) => {
-            let mut v = entries
-                .filter_map(|entry| {
-                    let entry = entry.unwrap();
-                    let fname = entry.file_name();
-                    let fname = fname.to_str().unwrap().to_owned();
-                    let fname = &format!("{}/{}", folder, fname);
-                    let meta = read_meta(fname).ok()?;
-                    Some((fname.to_owned(), meta))
-                })
-                .filter(|(_fname, meta)| {
-                    &meta.symbol == symbol && crate::utils::within_range(min_ts, max_ts, meta.min_ts, meta.max_ts)
-                })
-                .collect::<Vec<_>>();
-
-            // sort by min_ts
-            v.sort_by(|(_f0, m0), (_f1, m1)| m0.cmp(m1));
-
-            for (fname, _meta) in &v {
-                eprintln!("Reading: {}", fname);
-                let mut rdr = file_reader(fname)?;
-                range_for_each(&mut rdr, min_ts, max_ts, f)?;
-            }
-        }
-    };
+    for (fname, _meta) in &files_for_range(folder, symbol, min_ts, max_ts)? {
+        eprintln!("Reading: {}", fname);
+        let mut rdr = file_reader(fname)?;
+        range_for_each(&mut rdr, min_ts, max_ts, f)?;
+    }
     Ok(())
 }
 
@@ -892,24 +1801,24 @@ mod tests {
             seq: 113,
             is_trade: false,
             is_bid: false,
-            price: 5100.01,
-            size: 1.14564564645,
+            price: 510001000000,
+            size: 114564565,
         };
         let t1 = Update {
             ts: 101,
             seq: 113,
             is_trade: false,
             is_bid: false,
-            price: 5100.01,
-            size: 2.14564564645,
+            price: 510001000000,
+            size: 214564565,
         };
         let t2 = Update {
             ts: 1000000,
             seq: 113,
             is_trade: true,
             is_bid: false,
-            price: 5100.01,
-            size: 1.123465,
+            price: 510001000000,
+            size: 112346500,
         };
         ts.push(t);
         ts.push(t1);
@@ -927,8 +1836,8 @@ mod tests {
             seq: 113,
             is_trade: false,
             is_bid: false,
-            price: 5100.01,
-            size: 1.14564564645,
+            price: 510001000000,
+            size: 114564565,
         };
         ts.push(t);
 
@@ -944,24 +1853,24 @@ mod tests {
             seq: 113,
             is_trade: false,
             is_bid: false,
-            price: 5100.01,
-            size: 1.14564564645,
+            price: 510001000000,
+            size: 114564565,
         };
         let t1 = Update {
             ts: 20000001,
             seq: 113,
             is_trade: false,
             is_bid: false,
-            price: 5100.01,
-            size: 1.14564564645,
+            price: 510001000000,
+            size: 114564565,
         };
         let t = Update {
             ts: 20000000,
             seq: 113,
             is_trade: false,
             is_bid: false,
-            price: 5100.01,
-            size: 1.14564564645,
+            price: 510001000000,
+            size: 114564565,
         };
         ts.push(t);
         ts.push(t1);
@@ -989,6 +1898,8 @@ mod tests {
             count: 1,
             max_ts: 1,
             min_ts: 1,
+            scale: Scale::default(),
+            properties: vec![("exchange".to_owned(), "bnc".to_owned())].into_iter().collect(),
         };
 
         assert_eq!(
@@ -999,7 +1910,10 @@ mod tests {
   "max_ts": 1,
   "max_ts_human": "1970-01-01 00:00:00 UTC",
   "min_ts": 1,
-  "min_ts_human": "1970-01-01 00:00:00 UTC"
+  "min_ts_human": "1970-01-01 00:00:00 UTC",
+  "tick_size": "0.00000001",
+  "lot_size": "0.00000001",
+  "properties": {"exchange":"bnc"}
 }"#
         );
     }
@@ -1042,8 +1956,8 @@ mod tests {
                     Update {
                         ts: i * 1000 as u64,
                         seq: i as u32,
-                        price: 0.,
-                        size: 0.,
+                        price: 0,
+                        size: 0,
                         is_bid: false,
                         is_trade: false,
                     }
@@ -1059,8 +1973,8 @@ mod tests {
                 Update {
                     ts: i * 1000 as u64,
                     seq: i as u32,
-                    price: 0.,
-                    size: 0.,
+                    price: 0,
+                    size: 0,
                     is_bid: false,
                     is_trade: false,
                 }
@@ -1079,8 +1993,8 @@ mod tests {
                     Update {
                         ts: i * 1000 as u64,
                         seq: i as u32 % 500 * 500,
-                        price: 0.,
-                        size: 0.,
+                        price: 0,
+                        size: 0,
                         is_bid: false,
                         is_trade: false,
                     }
@@ -1097,8 +2011,8 @@ mod tests {
                     Update {
                         ts: i * 1000 as u64,
                         seq: i as u32 % 500 * 500,
-                        price: 0.,
-                        size: 0.,
+                        price: 0,
+                        size: 0,
                         is_bid: false,
                         is_trade: false,
                     }
@@ -1201,6 +2115,228 @@ mod tests {
 
     }
 
+    #[test]
+    fn should_merge_overlapping_append() {
+        let fname = "test-merge.dtf";
+        let up = |ts: u64, seq: u32, size: u64| Update { ts, seq, is_trade: false, is_bid: true, price: 1, size };
+        let old: Vec<Update> = (0..200_000).map(|i| up(i * 10, i as u32, 1)).collect();
+        encode(fname, "test", &old).unwrap();
+
+        // late updates, one exact duplicate and one update sharing (ts, seq) with an existing one
+        let late = vec![up(1_000_005, 0, 2), up(1_999_990, 199_999, 1), up(1_999_990, 199_999, 3), up(2_000_000, 0, 4)];
+        append(fname, &late).unwrap();
+
+        let mut expected = old.clone();
+        expected.push(late[0]);
+        expected.push(late[2]);
+        expected.push(late[3]);
+        expected.sort();
+
+        let decoded = decode(fname, None).unwrap();
+        assert_eq!(decoded, expected);
+        let meta = read_meta(fname).unwrap();
+        assert_eq!(meta.count, expected.len() as u64);
+        assert_eq!(meta.max_ts, 2_000_000);
+
+        // appending the same data again changes nothing
+        append(fname, &late).unwrap();
+        assert_eq!(decode(fname, None).unwrap(), expected);
+        assert_eq!(read_meta(fname).unwrap().count, expected.len() as u64);
+        fs::remove_file(fname).unwrap();
+    }
+
+    #[test]
+    fn should_seek_with_block_index() {
+        for &version in &[Version::V1, Version::V2] {
+            seek_with_block_index(version);
+        }
+    }
+
+    fn seek_with_block_index(version: Version) {
+        let fname = &format!("test-index-{:?}.dtf", version);
+        let ups: Vec<Update> = (0..200_000).map(|i| Update {
+            ts: i * 1000, seq: i as u32, is_trade: false, is_bid: true, price: 1, size: i,
+        }).collect();
+        let opts = EncodeOptions { version, ..Default::default() };
+        encode_with(fname, "test", &ups[..100_000], &opts).unwrap();
+        append(fname, &ups[100_000..]).unwrap();
+
+        let mut rdr = file_reader(fname).unwrap();
+        let (index, _) = load_index(&mut rdr).unwrap();
+        assert_eq!(index.count, 200_000);
+        assert!(index.entries.len() > 10);
+        assert_eq!(decode(fname, None).unwrap(), ups);
+
+        let (min_ts, max_ts) = (123_456_789, 156_789_000);
+        let expected: Vec<Update> = ups.iter().filter(|up| up.ts >= min_ts && up.ts <= max_ts).cloned().collect();
+        assert_eq!(range(&mut rdr, min_ts, max_ts).unwrap(), expected);
+
+        // resume from the batch of the 150_000th update and read to the end of the file
+        let mut offset = None;
+        for_each_from(fname, None, 150_000_000, &mut |pos, up| {
+            offset = Some(pos);
+            up.ts < 150_000_000
+        }).unwrap();
+        let mut rest = vec![];
+        for_each_from(fname, offset, 0, &mut |_, up| { rest.push(*up); true }).unwrap();
+        let first = rest.iter().position(|up| up.ts == 150_000_000).unwrap();
+        assert_eq!(&rest[first..], &ups[150_000..]);
+        // an offset that is not the start of a batch is ignored, even where it looks like one
+        let read = |offset| {
+            let mut ret = vec![];
+            for_each_from(fname, offset, u64::MAX, &mut |_, up| { ret.push(*up); true }).unwrap();
+            ret
+        };
+        let from_ts = read(None);
+        let start = main_offset(&mut rdr).unwrap();
+        let (batches, end) = read_batch_offsets(&mut rdr, start).unwrap();
+        let bytes = fs::read(fname).unwrap();
+        let lookalikes = (start..end - 15)
+            .filter(|&pos| bytes[pos as usize] == 0x1 && !batches.iter().any(|(offset, _)| *offset == pos))
+            .take(50);
+        for pos in lookalikes {
+            assert_eq!(read(Some(pos)), from_ts, "{}", pos);
+        }
+
+        for &i in &[0, 65, 131_073] {
+            let mut it = iterators::DTFBufReader::with_offset(file_reader(fname).unwrap(), i);
+            assert_eq!((&mut it).next().unwrap(), ups[i]);
+        }
+        let batches = iterators::DTFMetadataReader::new(file_reader(fname).unwrap());
+        assert_eq!(batches.map(|meta| meta.count as u64).sum::<u64>(), 200_000);
+        assert_eq!(read_version(&mut rdr).unwrap(), version);
+        fs::remove_file(fname).unwrap();
+    }
+
+    #[test]
+    fn should_skip_checkpoints() {
+        for &version in &[Version::V1, Version::V2] {
+            let fname = &format!("test-checkpoints-{:?}.dtf", version);
+            let ups: Vec<Update> = (0..1000).map(|i| Update {
+                ts: i * 10, seq: i as u32, is_trade: false, is_bid: true, price: 1, size: i,
+            }).collect();
+            let ck = |i: usize| CheckpointRecord { ts: ups[i].ts, seq: ups[i].seq, levels: vec![i as u8; 7] };
+            let opts = EncodeOptions { version, ..Default::default() };
+            encode_with_checkpoints(fname, "test", &ups[..500], &[ck(99), ck(499)], &opts).unwrap();
+            append_with_checkpoints(fname, &ups[500..], &[ck(899)]).unwrap();
+
+            assert_eq!(decode(fname, None).unwrap(), ups);
+            assert_eq!(get_range_in_file(fname, 950, 5200).unwrap(), &ups[95..=520]);
+            let mut it = iterators::DTFBufReader::with_offset(file_reader(fname).unwrap(), 600);
+            assert_eq!((&mut it).collect::<Vec<_>>(), &ups[600..]);
+            let batches = iterators::DTFMetadataReader::new(file_reader(fname).unwrap());
+            assert_eq!(batches.map(|meta| meta.count as u64).sum::<u64>(), 1000);
+            let mut rest = vec![];
+            for_each_from(fname, None, 5000, &mut |_, up| { rest.push(*up); true }).unwrap();
+            assert_eq!(rest.last(), ups.last());
+            assert_eq!(last_checkpoint(fname, 8990).unwrap(), Some(ck(899)));
+            assert_eq!(last_checkpoint(fname, 8980).unwrap(), Some(ck(499)));
+            assert_eq!(last_checkpoint(fname, 50).unwrap(), None);
+            assert_eq!(read_checkpoints(fname).unwrap(), vec![ck(99), ck(499), ck(899)]);
+
+            // merging a late update drops the checkpoints after it
+            let late = Update { ts: 8995, ..ups[0] };
+            append_with_checkpoints(fname, &[late], &[]).unwrap();
+            assert_eq!(decode(fname, None).unwrap().len(), 1001);
+            assert_eq!(last_checkpoint(fname, u64::MAX).unwrap(), Some(ck(499)));
+            fs::remove_file(fname).unwrap();
+        }
+    }
+
+    #[test]
+    fn should_compress_v2_batches() {
+        let ups: Vec<Update> = (0..10_000).map(|i| Update {
+            ts: i * 10, seq: i as u32, is_trade: i % 3 == 0, is_bid: i % 2 == 0,
+            price: 10200 + (i % 7), size: i % 5,
+        }).collect();
+        let scale = Scale::new("0.5".parse().unwrap(), "1".parse().unwrap()).unwrap();
+        let mut v1 = Cursor::new(vec![]);
+        encode_buffer_with(&mut v1, "test", &ups, &EncodeOptions { version: Version::V1, scale, ..Default::default() }).unwrap();
+        let mut v2 = Cursor::new(vec![]);
+        encode_buffer_with(&mut v2, "test", &ups, &EncodeOptions { version: Version::V2, scale, ..Default::default() }).unwrap();
+        assert!(v2.get_ref().len() < v1.get_ref().len());
+        assert_eq!(read_all(&mut v1).unwrap(), ups);
+        assert_eq!(read_all(&mut v2).unwrap(), ups);
+
+        // flip a byte in the compressed block of the first batch
+        let start = main_offset(&mut v2).unwrap() as usize;
+        let mut corrupt = v2.into_inner();
+        corrupt[start + 30] ^= 0xFF;
+        let err = read_all(&mut Cursor::new(corrupt)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn should_store_scale_in_header() {
+        let scale = Scale::new("0.5".parse().unwrap(), "0.00000001".parse().unwrap()).unwrap();
+        let ups: Vec<Update> = (0..1000).map(|i| Update {
+            ts: i, seq: i as u32, is_trade: false, is_bid: true,
+            price: 10_000_000 + i, size: 765064249 + i,
+        }).collect();
+        let mut buf = Cursor::new(vec![]);
+        encode_buffer_with(&mut buf, "test", &ups, &EncodeOptions { scale, ..Default::default() }).unwrap();
+        assert_eq!(read_meta_from_buf(&mut buf).unwrap().scale, scale);
+        assert_eq!(read_all(&mut buf).unwrap(), ups);
+        assert_eq!(ups[0].as_json(&scale), r#"{"ts":0,"seq":0,"is_trade":false,"is_bid":true,"price":5000000,"size":7.65064249}"#);
+
+        // files without a scale use the default one
+        let mut buf = Cursor::new(vec![]);
+        encode_buffer(&mut buf, "test", &ups).unwrap();
+        assert_eq!(read_meta_from_buf(&mut buf).unwrap().scale, Scale::default());
+    }
+
+    #[test]
+    fn should_store_properties_in_header() {
+        let ups: Vec<Update> = (0..1000).map(|i| Update {
+            ts: i, seq: i as u32, is_trade: false, is_bid: true, price: i, size: i,
+        }).collect();
+        let mut properties = Properties::new();
+        properties.insert("exchange".to_owned(), "binance".to_owned());
+        properties.insert("timezone".to_owned(), "UTC".to_owned());
+        let fname = "test_properties.dtf";
+        encode_with(fname, "test", &ups[..500], &EncodeOptions { properties: properties.clone(), ..Default::default() }).unwrap();
+        append(fname, &ups[500..]).unwrap();
+
+        let meta = read_meta(fname).unwrap();
+        assert_eq!(meta.properties, properties);
+        assert_eq!(meta.min_ts, 0);
+        assert_eq!(decode(fname, None).unwrap(), ups);
+        assert_eq!(get_range_in_file(fname, 100, 200).unwrap(), ups[100..=200].to_vec());
+        std::fs::remove_file(fname).unwrap();
+    }
+
+    #[test]
+    fn should_store_long_symbols() {
+        let ups = sample_data();
+        let symbol = "deribit_btc_perpetual_inverse";
+        let mut properties = Properties::new();
+        properties.insert("asset_type".to_owned(), "perpetual".to_owned());
+        let mut buf = Cursor::new(vec![]);
+        encode_buffer_with(&mut buf, symbol, &ups, &EncodeOptions { properties: properties.clone(), ..Default::default() }).unwrap();
+        let meta = read_meta_from_buf(&mut buf).unwrap();
+        assert_eq!(meta.symbol, symbol);
+        assert_eq!(meta.properties, properties);
+        assert_eq!(read_all(&mut buf).unwrap(), ups);
+
+        // readers of the fixed size field see a truncated symbol
+        assert_eq!(&buf.get_ref()[5..25], &symbol.as_bytes()[..20]);
+
+        let too_long = "x".repeat(MAX_SYMBOL_LEN + 1);
+        let err = encode_buffer(&mut Cursor::new(vec![]), &too_long, &ups).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn should_dedup_merged_updates() {
+        let up = |ts: u64, seq: u32, size: u64| Update { ts, seq, is_trade: false, is_bid: true, price: 1, size };
+        let old = vec![up(1, 1, 1), up(1, 1, 2), up(3, 1, 1)];
+        let new = vec![up(1, 1, 2), up(2, 1, 1), up(3, 1, 1), up(3, 1, 5)];
+        assert_eq!(
+            merge_dedup(old, &new),
+            vec![up(1, 1, 1), up(1, 1, 2), up(2, 1, 1), up(3, 1, 1), up(3, 1, 5)]
+        );
+    }
+
     #[test]
     fn should_speak_json() {
         let t1 = Update {
@@ -1208,10 +2344,10 @@ mod tests {
             seq: 113,
             is_trade: false,
             is_bid: false,
-            price: 5100.01,
-            size: 1.14564564645,
+            price: 510001000000,
+            size: 114564565,
         };
-        assert_eq!(r#"{"ts":20000.001,"seq":113,"is_trade":false,"is_bid":false,"price":5100.01,"size":1.1456456}"#, t1.as_json());
+        assert_eq!(r#"{"ts":20000.001,"seq":113,"is_trade":false,"is_bid":false,"price":5100.01,"size":1.14564565}"#, t1.as_json(&Scale::default()));
     }
 
     #[test]
@@ -1222,8 +2358,8 @@ mod tests {
             seq: 0,
             is_trade: false,
             is_bid: false,
-            price: 0.,
-            size: 0.,
+            price: 0,
+            size: 0,
         };
         let mut bytes = vec![];
         write_batches(&mut bytes, [up].into_iter().peekable()).unwrap();
@@ -1256,6 +2392,14 @@ mod tests {
                 0,
                 0,
                 0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
             ],
             bytes
         );
@@ -1270,8 +2414,8 @@ mod tests {
                 seq: 10,
                 is_trade: true,
                 is_bid: true,
-                price: 9999.999,
-                size: 9999.999,
+                price: 999999900000,
+                size: 999999900000,
             };
             ups.push(up);
         }
@@ -0,0 +1,153 @@
+//! Gorilla XOR compression for the integer price and size columns
+//!
+//! Each value is XORed with the previous one:
+//! 1. `0`: same value as the previous one
+//! 2. `10` + meaningful bits: the XOR fits in the previous leading/trailing zero window
+//! 3. `11` + 6 bits leading zeros + 6 bits (length - 1) + meaningful bits
+//!
+//! The first value is stored verbatim. Bits are written msb first.
+
+/// msb first bit writer
+#[derive(Default)]
+struct BitWriter {
+    buf: Vec<u8>,
+    n_bits: u64,
+}
+
+impl BitWriter {
+    fn write_bit(&mut self, bit: bool) {
+        if self.n_bits == self.buf.len() as u64 * 8 {
+            self.buf.push(0);
+        }
+        if bit {
+            *self.buf.last_mut().unwrap() |= 0x80 >> (self.n_bits % 8);
+        }
+        self.n_bits += 1;
+    }
+
+    fn write_bits(&mut self, value: u64, n: u32) {
+        for i in (0..n).rev() {
+            self.write_bit((value >> i) & 1 == 1);
+        }
+    }
+}
+
+/// msb first bit reader
+struct BitReader<'a> {
+    buf: &'a [u8],
+    pos: u64,
+}
+
+impl<'a> BitReader<'a> {
+    fn read_bit(&mut self) -> Option<bool> {
+        let byte = self.buf.get((self.pos / 8) as usize)?;
+        let bit = byte & (0x80 >> (self.pos % 8)) != 0;
+        self.pos += 1;
+        Some(bit)
+    }
+
+    fn read_bits(&mut self, n: u32) -> Option<u64> {
+        let mut value = 0u64;
+        for _ in 0..n {
+            value = (value << 1) | self.read_bit()? as u64;
+        }
+        Some(value)
+    }
+}
+
+/// compress a column of integers
+pub fn compress(values: impl Iterator<Item = u64>) -> Vec<u8> {
+    let mut wtr = BitWriter::default();
+    let mut prev: Option<u64> = None;
+    // (leading zeros, meaningful bits) of the current window
+    let mut window: Option<(u32, u32)> = None;
+
+    for bits in values {
+        let prev_bits = match prev {
+            None => {
+                wtr.write_bits(bits, 64);
+                prev = Some(bits);
+                continue;
+            }
+            Some(prev_bits) => prev_bits,
+        };
+        prev = Some(bits);
+
+        let xor = bits ^ prev_bits;
+        if xor == 0 {
+            wtr.write_bit(false);
+            continue;
+        }
+        wtr.write_bit(true);
+
+        let leading = xor.leading_zeros();
+        let trailing = xor.trailing_zeros();
+        match window {
+            Some((w_leading, w_len)) if leading >= w_leading && trailing >= 64 - w_leading - w_len => {
+                wtr.write_bit(false);
+                wtr.write_bits(xor >> (64 - w_leading - w_len), w_len);
+            }
+            _ => {
+                let len = 64 - leading - trailing;
+                wtr.write_bit(true);
+                wtr.write_bits(leading as u64, 6);
+                wtr.write_bits((len - 1) as u64, 6);
+                wtr.write_bits(xor >> trailing, len);
+                window = Some((leading, len));
+            }
+        }
+    }
+    wtr.buf
+}
+
+/// decompress `n` integers, returns None if the stream is truncated
+pub fn decompress(buf: &[u8], n: usize) -> Option<Vec<u64>> {
+    let mut rdr = BitReader { buf, pos: 0 };
+    let mut ret = Vec::with_capacity(n);
+    if n == 0 {
+        return Some(ret);
+    }
+
+    let mut bits = rdr.read_bits(64)?;
+    ret.push(bits);
+    let mut window: Option<(u32, u32)> = None;
+
+    while ret.len() < n {
+        if rdr.read_bit()? {
+            let (leading, len) = if rdr.read_bit()? {
+                let leading = rdr.read_bits(6)? as u32;
+                let len = rdr.read_bits(6)? as u32 + 1;
+                window = Some((leading, len));
+                (leading, len)
+            } else {
+                window?
+            };
+            if leading + len > 64 {
+                return None;
+            }
+            bits ^= rdr.read_bits(len)? << (64 - leading - len);
+        }
+        ret.push(bits);
+    }
+    Some(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_roundtrip() {
+        let values = vec![193900, 193900, 194000, 2285000000, 0, 1, u64::MAX, 194000, 1 << 63, 1 << 63];
+        let compressed = compress(values.iter().cloned());
+        assert_eq!(decompress(&compressed, values.len()).unwrap(), values);
+        assert!(decompress(&compressed[..3], values.len()).is_none());
+        assert!(decompress(&[], 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn should_compress_repeated_prices() {
+        let values = vec![999999900000u64; 1000];
+        assert_eq!(compress(values.iter().cloned()).len(), 8 + 1000 / 8);
+    }
+}
@@ -2,6 +2,10 @@
 pub mod file_format;
 /// Represents a single row of orderbook update
 pub mod update;
+/// Gorilla XOR compression for price and size columns
+pub mod gorilla;
+/// Fixed-point prices and sizes
+pub mod scale;
 /// Financial symbol
 pub mod symbol;
 /// C FFI structs and functions
@@ -0,0 +1,209 @@
+//! Fixed-point prices and sizes
+//!
+//! `Update::price` and `Update::size` are integer multiples of the tick size and lot size
+//! of the book, so values round-trip exactly between text, memory and dtf files.
+
+use std::cmp;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+/// maximum number of digits after the decimal point
+pub const MAX_EXPONENT: u8 = 18;
+
+/// Exact decimal number, `mantissa * 10^-exponent`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Decimal {
+    /// significant digits
+    pub mantissa: u64,
+    /// number of digits after the decimal point
+    pub exponent: u8,
+}
+
+impl Decimal {
+    /// create a decimal of `mantissa * 10^-exponent`
+    pub const fn new(mantissa: u64, exponent: u8) -> Self {
+        Decimal { mantissa, exponent }
+    }
+
+    /// number of `step`s in `self`, None if `self` is not a multiple of `step`
+    // `u128::is_multiple_of` needs rust 1.87
+    #[allow(clippy::manual_is_multiple_of)]
+    pub fn units(&self, step: Decimal) -> Option<u64> {
+        let exponent = cmp::max(self.exponent, step.exponent) as u32;
+        let value = self.mantissa as u128 * 10u128.pow(exponent - self.exponent as u32);
+        let step = step.mantissa as u128 * 10u128.pow(exponent - step.exponent as u32);
+        if step == 0 || value % step != 0 {
+            return None;
+        }
+        u64::try_from(value / step).ok()
+    }
+
+    /// number of whole `step`s in `self` saturating at `u64::MAX`, and whether `self` is a multiple of `step`
+    // `u128::is_multiple_of` needs rust 1.87
+    #[allow(clippy::manual_is_multiple_of)]
+    pub fn floor_units(&self, step: Decimal) -> (u64, bool) {
+        let exponent = cmp::max(self.exponent, step.exponent) as u32;
+        let value = self.mantissa as u128 * 10u128.pow(exponent - self.exponent as u32);
+        let step = step.mantissa as u128 * 10u128.pow(exponent - step.exponent as u32);
+        if step == 0 {
+            return (u64::MAX, false);
+        }
+        let units = u64::try_from(value / step).unwrap_or(u64::MAX);
+        (units, value % step == 0)
+    }
+
+    /// exact decimal representation of `units` multiples of `self`
+    pub fn format_units(&self, units: u64) -> String {
+        let value = units as u128 * self.mantissa as u128;
+        let pow = 10u128.pow(self.exponent as u32);
+        let int = value / pow;
+        let frac = format!("{:0width$}", value % pow, width = self.exponent as usize);
+        let frac = frac.trim_end_matches('0');
+        if frac.is_empty() {
+            format!("{}", int)
+        } else {
+            format!("{}.{}", int, frac)
+        }
+    }
+
+    /// approximate value of `units` multiples of `self`
+    pub fn to_f64(&self, units: u64) -> f64 {
+        units as f64 * self.mantissa as f64 / 10f64.powi(self.exponent as i32)
+    }
+
+    /// closest number of multiples of `self` to `value`, for data that was stored as floats
+    pub fn from_f64(&self, value: f64) -> u64 {
+        (value * 10f64.powi(self.exponent as i32) / self.mantissa as f64).round() as u64
+    }
+}
+
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.format_units(1))
+    }
+}
+
+impl FromStr for Decimal {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (int, frac) = match s.find('.') {
+            Some(i) => (&s[..i], &s[i + 1..]),
+            None => (s, ""),
+        };
+        let is_digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+        if (int.is_empty() && frac.is_empty()) || !is_digits(int) || !is_digits(frac) {
+            return Err(format!("Invalid decimal: {}", s));
+        }
+        let frac = frac.trim_end_matches('0');
+        if frac.len() > MAX_EXPONENT as usize {
+            return Err(format!("More than {} decimals: {}", MAX_EXPONENT, s));
+        }
+        let digits = format!("{}{}", int, frac);
+        let mantissa = digits.trim_start_matches('0');
+        let mantissa = if mantissa.is_empty() { 0 } else {
+            mantissa.parse::<u64>().map_err(|_| format!("Decimal out of range: {}", s))?
+        };
+        Ok(Decimal::new(mantissa, frac.len() as u8))
+    }
+}
+
+impl TryFrom<String> for Decimal {
+    type Error = String;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Decimal> for String {
+    fn from(d: Decimal) -> String {
+        d.to_string()
+    }
+}
+
+/// 1e-8, e.g. one satoshi
+pub const DEFAULT_INCREMENT: Decimal = Decimal::new(1, 8);
+
+/// Tick size and lot size of a book
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Scale {
+    /// price increment, `Update::price` is a number of ticks
+    pub tick_size: Decimal,
+    /// size increment, `Update::size` is a number of lots
+    pub lot_size: Decimal,
+}
+
+impl Default for Scale {
+    fn default() -> Self {
+        Scale {
+            tick_size: DEFAULT_INCREMENT,
+            lot_size: DEFAULT_INCREMENT,
+        }
+    }
+}
+
+impl Scale {
+    /// create a scale, None if an increment is zero
+    pub fn new(tick_size: Decimal, lot_size: Decimal) -> Option<Self> {
+        if tick_size.mantissa == 0 || lot_size.mantissa == 0 {
+            None
+        } else {
+            Some(Scale { tick_size, lot_size })
+        }
+    }
+
+    /// number of ticks in `price`, None if it is not a multiple of the tick size
+    pub fn ticks(&self, price: Decimal) -> Option<u64> {
+        price.units(self.tick_size)
+    }
+
+    /// number of lots in `size`, None if it is not a multiple of the lot size
+    pub fn lots(&self, size: Decimal) -> Option<u64> {
+        size.units(self.lot_size)
+    }
+
+    /// exact decimal representation of a price
+    pub fn format_price(&self, ticks: u64) -> String {
+        self.tick_size.format_units(ticks)
+    }
+
+    /// exact decimal representation of a size
+    pub fn format_size(&self, lots: u64) -> String {
+        self.lot_size.format_units(lots)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_and_format_decimals() {
+        let d: Decimal = "7.65064249".parse().unwrap();
+        assert_eq!(d, Decimal::new(765064249, 8));
+        assert_eq!(d.to_string(), "7.65064249");
+        assert_eq!("0.0703620".parse::<Decimal>().unwrap(), Decimal::new(70362, 6));
+        assert_eq!("100".parse::<Decimal>().unwrap(), Decimal::new(100, 0));
+        assert_eq!(".5".parse::<Decimal>().unwrap().to_string(), "0.5");
+        assert_eq!("0".parse::<Decimal>().unwrap().to_string(), "0");
+        assert!("-1".parse::<Decimal>().is_err());
+        assert!("1e-8".parse::<Decimal>().is_err());
+        assert!("".parse::<Decimal>().is_err());
+    }
+
+    #[test]
+    fn should_scale_exactly() {
+        let scale = Scale::default();
+        let size = scale.lots("7.65064249".parse().unwrap()).unwrap();
+        assert_eq!(size, 765064249);
+        assert_eq!(scale.format_size(size), "7.65064249");
+        assert_eq!(scale.lots("0.000000001".parse().unwrap()), None);
+
+        let half = Scale::new(Decimal::new(5, 1), Decimal::new(1, 0)).unwrap();
+        assert_eq!(half.ticks("10.5".parse().unwrap()), Some(21));
+        assert_eq!(half.ticks("10.25".parse().unwrap()), None);
+        assert_eq!(half.format_price(21), "10.5");
+        assert!(Scale::new(Decimal::new(0, 0), Decimal::new(1, 0)).is_none());
+    }
+}
@@ -34,6 +34,12 @@ impl FromStr for Symbol {
 pub enum AssetType {
     /// spot
     SPOT,
+    /// futures
+    FUTURES,
+    /// perpetual swaps
+    PERPETUAL,
+    /// options
+    OPTIONS,
 }
 
 impl Default for AssetType {
@@ -45,7 +51,23 @@ impl Default for AssetType {
 impl fmt::Display for AssetType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            &AssetType::SPOT => write!(f, "spot"),
+            AssetType::SPOT => write!(f, "spot"),
+            AssetType::FUTURES => write!(f, "futures"),
+            AssetType::PERPETUAL => write!(f, "perpetual"),
+            AssetType::OPTIONS => write!(f, "options"),
+        }
+    }
+}
+
+impl FromStr for AssetType {
+    type Err = ();
+    fn from_str(asset_type: &str) -> Result<Self, Self::Err> {
+        match asset_type {
+            "spot" => Ok(AssetType::SPOT),
+            "futures" => Ok(AssetType::FUTURES),
+            "perpetual" => Ok(AssetType::PERPETUAL),
+            "options" => Ok(AssetType::OPTIONS),
+            _ => Err(()),
         }
     }
 }
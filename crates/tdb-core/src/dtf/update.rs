@@ -3,41 +3,42 @@ use std::io::ErrorKind::InvalidData;
 use std::io::Write;
 use std::io::Cursor;
 use byteorder::{BigEndian, WriteBytesExt, ReadBytesExt};
+use crate::dtf::scale::Scale;
 
 /// convertion methods for slice of `Update`s
 pub trait UpdateVecConvert {
-    /// convert into json
-    fn as_json(&self) -> String;
-    /// convert into csv
-    fn to_csv(&self) -> String;
+    /// convert into json, prices and sizes are formatted with `scale`
+    fn as_json(&self, scale: &Scale) -> String;
+    /// convert into csv, prices and sizes are formatted with `scale`
+    fn to_csv(&self, scale: &Scale) -> String;
 }
 
 impl UpdateVecConvert for [Update] {
-    fn as_json(&self) -> String {
-        update_vec_to_json(self)
+    fn as_json(&self, scale: &Scale) -> String {
+        update_vec_to_json(self, scale)
     }
-    fn to_csv(&self) -> String {
-        update_vec_to_csv(&self)
+    fn to_csv(&self, scale: &Scale) -> String {
+        update_vec_to_csv(&self, scale)
     }
 }
 
 impl UpdateVecConvert for Vec<Update> {
-    fn as_json(&self) -> String {
-        update_vec_to_json(self)
+    fn as_json(&self, scale: &Scale) -> String {
+        update_vec_to_json(self, scale)
     }
-    fn to_csv(&self) -> String {
-        update_vec_to_csv(&self)
+    fn to_csv(&self, scale: &Scale) -> String {
+        update_vec_to_csv(&self, scale)
     }
 }
 
 
-fn update_vec_to_csv(vecs: &[Update]) -> String {
-    let objects: Vec<String> = vecs.into_iter().map(|up| up.to_csv()).collect();
+fn update_vec_to_csv(vecs: &[Update], scale: &Scale) -> String {
+    let objects: Vec<String> = vecs.into_iter().map(|up| up.to_csv(scale)).collect();
     objects.join("\n")
 }
 
-fn update_vec_to_json(vecs: &[Update]) -> String {
-    let objects: Vec<String> = vecs.into_iter().map(|up| up.as_json()).collect();
+fn update_vec_to_json(vecs: &[Update], scale: &Scale) -> String {
+    let objects: Vec<String> = vecs.into_iter().map(|up| up.as_json(scale)).collect();
     objects.join(", ")
 }
 
@@ -54,14 +55,26 @@ pub struct Update {
     pub is_trade: bool,
     /// is the update on the bid or ask side
     pub is_bid: bool,
-    /// price of the order
-    pub price: f32,
-    /// size of the order
-    pub size: f32,
+    /// price of the order, in ticks of the book (see `dtf::scale`)
+    pub price: u64,
+    /// size of the order, in lots of the book
+    pub size: u64,
 }
 
 impl Update {
 
+    /// Pack `is_bid` and `is_trade` into flags
+    pub fn flags(&self) -> Flags {
+        let mut flags = Flags::FLAG_EMPTY;
+        if self.is_bid {
+            flags |= Flags::FLAG_IS_BID;
+        }
+        if self.is_trade {
+            flags |= Flags::FLAG_IS_TRADE;
+        }
+        flags
+    }
+
     /// Serialize to raw
     pub fn serialize_raw_to_buffer(&self, buf: &mut dyn Write) -> Result<(), std::io::Error> {
         buf.write_u64::<BigEndian>(self.ts)?;
@@ -76,8 +89,8 @@ impl Update {
         }
         buf.write_u8(flags.bits())?;
 
-        buf.write_f32::<BigEndian>(self.price)?;
-        buf.write_f32::<BigEndian>(self.size)?;
+        buf.write_u64::<BigEndian>(self.price)?;
+        buf.write_u64::<BigEndian>(self.size)?;
         Ok(())
     }
 
@@ -98,8 +111,8 @@ impl Update {
         }
         let _ = buf.write_u8(flags.bits());
 
-        let _ = buf.write_f32::<BigEndian>(self.price);
-        let _ = buf.write_f32::<BigEndian>(self.size);
+        let _ = buf.write_u64::<BigEndian>(self.price);
+        let _ = buf.write_u64::<BigEndian>(self.size);
         buf
     }
 
@@ -112,8 +125,8 @@ impl Update {
         let flags = rdr.read_u8()?;
         let is_trade = (Flags::from_bits(flags).ok_or(InvalidData)? & Flags::FLAG_IS_TRADE).to_bool();
         let is_bid = (Flags::from_bits(flags).ok_or(InvalidData)? & Flags::FLAG_IS_BID).to_bool();
-        let price = rdr.read_f32::<BigEndian>()?;
-        let size = rdr.read_f32::<BigEndian>()?;
+        let price = rdr.read_u64::<BigEndian>()?;
+        let size = rdr.read_u64::<BigEndian>()?;
 
         Ok(Update {
             ts, seq, is_trade, is_bid, price, size,
@@ -137,33 +150,33 @@ impl Update {
         }
         let _ = buf.write_u8(flags.bits());
 
-        let _ = buf.write_f32::<BigEndian>(self.price);
-        let _ = buf.write_f32::<BigEndian>(self.size);
+        let _ = buf.write_u64::<BigEndian>(self.price);
+        let _ = buf.write_u64::<BigEndian>(self.size);
     }
 
-    /// Convert to json string
-    pub fn as_json(&self) -> String {
+    /// Convert to json string, prices and sizes are formatted with `scale`
+    pub fn as_json(&self, scale: &Scale) -> String {
         format!(
             r#"{{"ts":{},"seq":{},"is_trade":{},"is_bid":{},"price":{},"size":{}}}"#,
             (self.ts as f64) / 1000_f64,
             self.seq,
             self.is_trade,
             self.is_bid,
-            self.price,
-            self.size
+            scale.format_price(self.price),
+            scale.format_size(self.size)
         )
     }
 
-    /// Convert to csv string
-    pub fn to_csv(&self) -> String {
+    /// Convert to csv string, prices and sizes are formatted with `scale`
+    pub fn to_csv(&self, scale: &Scale) -> String {
         format!(
             r#"{},{},{},{},{},{}"#,
             (self.ts as f64) / 1000_f64,
             self.seq,
             if self.is_trade {"t"} else {"f"},
             if self.is_bid {"t"} else {"f"},
-            self.price,
-            self.size
+            scale.format_price(self.price),
+            scale.format_size(self.size)
         )
     }
 }
@@ -219,12 +232,14 @@ mod tests {
             seq: 1,
             is_trade: false,
             is_bid: false,
-            price: 10000000000000.,
-            size: 1000000000000.,
+            price: 10000000000000,
+            size: 1000000000000,
         };
         let result = up.serialize_raw();
         assert_eq!(
-            vec![0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1, 0, 85, 17, 132, 231, 83, 104, 212, 165],
+            vec![0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1, 0,
+                 0, 0, 9, 24, 78, 114, 160, 0,
+                 0, 0, 0, 232, 212, 165, 16, 0],
             result
         );
 
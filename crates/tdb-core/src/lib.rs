@@ -13,8 +13,6 @@ extern crate byteorder;
 #[macro_use]
 extern crate bitflags;
 extern crate log;
-#[macro_use]
-extern crate lazy_static;
 
 /// functions for histogram, event analytics
 pub mod postprocessing;
@@ -24,6 +22,8 @@ pub mod storage;
 pub mod utils;
 /// DTF(Dense Tick Format) implmentation
 pub mod dtf;
+/// framing of requests and responses
+pub mod protocol;
 
 /// Constant prefix during encoding/decoding raw insert command
 pub const RAW_INSERT_PREFIX: &'static [u8; 2] = b"ra";
\ No newline at end of file
@@ -0,0 +1,332 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use super::candle::Sampler;
+use super::candle::rollup::Resolution;
+use super::candle::tick_bars::TickSampler;
+use super::candle::volume_bars::VolumeSampler;
+use super::candle::dollar_bars::DollarSampler;
+use crate::dtf::update::Update;
+use crate::dtf::scale::Scale;
+
+/// field of an update
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Field {
+    /// price in ticks
+    Price,
+    /// size in lots
+    Size,
+}
+
+/// aggregate function computed for each group
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Aggregate {
+    /// number of updates
+    Count,
+    /// sum of sizes
+    SumSize,
+    /// sum of price * size
+    SumNotional,
+    /// sum of price * size over sum of sizes
+    Vwap,
+    /// smallest value
+    Min(Field),
+    /// largest value
+    Max(Field),
+    /// value of the first update
+    First(Field),
+    /// value of the last update
+    Last(Field),
+}
+
+impl fmt::Display for Aggregate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let field = |field: &Field| match field {
+            Field::Price => "price",
+            Field::Size => "size",
+        };
+        match self {
+            Aggregate::Count => write!(f, "count"),
+            Aggregate::SumSize => write!(f, "sum(size)"),
+            Aggregate::SumNotional => write!(f, "sum(price*size)"),
+            Aggregate::Vwap => write!(f, "vwap"),
+            Aggregate::Min(x) => write!(f, "min({})", field(x)),
+            Aggregate::Max(x) => write!(f, "max({})", field(x)),
+            Aggregate::First(x) => write!(f, "first({})", field(x)),
+            Aggregate::Last(x) => write!(f, "last({})", field(x)),
+        }
+    }
+}
+
+/// how updates are split into groups
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub struct GroupBy {
+    /// interval of the candle samplers, a single interval when None
+    pub interval: Option<Resolution>,
+    /// split bids and asks
+    pub side: bool,
+    /// split trades and other updates
+    pub trade: bool,
+}
+
+/// start of the interval, is_bid and is_trade of a group
+type Key = (u64, Option<bool>, Option<bool>);
+
+/// running statistics of one field
+#[derive(Clone, Copy, Debug)]
+struct Stats {
+    min: u64,
+    max: u64,
+    first: u64,
+    last: u64,
+}
+
+impl Stats {
+    fn new(v: u64) -> Self {
+        Stats { min: v, max: v, first: v, last: v }
+    }
+
+    fn add(&mut self, v: u64) {
+        self.min = self.min.min(v);
+        self.max = self.max.max(v);
+        self.last = v;
+    }
+}
+
+/// running statistics of a group
+#[derive(Clone, Copy, Debug)]
+struct Acc {
+    count: u64,
+    size: u128,
+    notional: u128,
+    price: Stats,
+    lots: Stats,
+}
+
+impl Acc {
+    fn new(up: &Update) -> Self {
+        Acc {
+            count: 0,
+            size: 0,
+            notional: 0,
+            price: Stats::new(up.price),
+            lots: Stats::new(up.size),
+        }
+    }
+
+    fn add(&mut self, up: &Update) {
+        self.count += 1;
+        self.size += up.size as u128;
+        self.notional += up.price as u128 * up.size as u128;
+        self.price.add(up.price);
+        self.lots.add(up.size);
+    }
+}
+
+/// Computes aggregates of updates grouped by interval, side and trade flag
+///
+/// Intervals follow the candles of the same resolution: time intervals start
+/// at a multiple of the interval, other intervals start at the first update
+/// after a sample. Tick intervals count every update, volume and dollar
+/// intervals only count trades.
+pub struct Aggregator {
+    aggregates: Vec<Aggregate>,
+    group_by: GroupBy,
+    sampler: Option<Box<dyn Sampler + Send>>,
+    /// start of the current interval of the sampler
+    start: Option<u64>,
+    groups: BTreeMap<Key, Acc>,
+}
+
+impl Aggregator {
+    /// create an aggregator without groups
+    pub fn new(aggregates: Vec<Aggregate>, group_by: GroupBy) -> Self {
+        let sampler: Option<Box<dyn Sampler + Send>> = match group_by.interval {
+            Some(Resolution::Tick(n)) => Some(Box::new(TickSampler::new(n))),
+            Some(Resolution::Volume(n)) => Some(Box::new(VolumeSampler::new(n))),
+            Some(Resolution::Dollar(n)) => Some(Box::new(DollarSampler::new(n))),
+            Some(Resolution::Time(_)) | None => None,
+        };
+        Self {
+            aggregates,
+            group_by,
+            sampler,
+            start: None,
+            groups: BTreeMap::new(),
+        }
+    }
+
+    /// add an update to its group, updates must be added in time order
+    pub fn add(&mut self, up: &Update) {
+        let start = match (self.group_by.interval, self.sampler.as_mut()) {
+            (Some(Resolution::Time(s)), _) => up.ts / (s * 1000) * (s * 1000),
+            (Some(res), Some(sampler)) => {
+                let counted = up.is_trade || matches!(res, Resolution::Tick(_));
+                if (counted && sampler.is_sample(up)) || self.start.is_none() {
+                    self.start = Some(up.ts);
+                }
+                self.start.unwrap_or(up.ts)
+            }
+            _ => 0,
+        };
+        let key = (
+            start,
+            if self.group_by.side { Some(up.is_bid) } else { None },
+            if self.group_by.trade { Some(up.is_trade) } else { None },
+        );
+        self.groups.entry(key).or_insert_with(|| Acc::new(up)).add(up);
+    }
+
+    /// names of the columns of the rows
+    pub fn columns(&self) -> Vec<String> {
+        let mut ret = vec![];
+        if self.group_by.interval.is_some() {
+            ret.push("start".to_owned());
+        }
+        if self.group_by.side {
+            ret.push("side".to_owned());
+        }
+        if self.group_by.trade {
+            ret.push("is_trade".to_owned());
+        }
+        ret.extend(self.aggregates.iter().map(|a| a.to_string()));
+        ret
+    }
+
+    /// a row of values for each group in order, prices and sizes are formatted with `scale`,
+    /// strings are quoted when `quote` is set
+    pub fn rows(&self, scale: &Scale, quote: bool) -> Vec<Vec<String>> {
+        let string = |s: &str| if quote { format!("\"{}\"", s) } else { s.to_owned() };
+        self.groups
+            .iter()
+            .map(|(&(start, is_bid, is_trade), acc)| {
+                let mut row = vec![];
+                if self.group_by.interval.is_some() {
+                    row.push(((start as f64) / 1000_f64).to_string());
+                }
+                if let Some(is_bid) = is_bid {
+                    row.push(string(if is_bid { "bid" } else { "ask" }));
+                }
+                if let Some(is_trade) = is_trade {
+                    row.push(is_trade.to_string());
+                }
+                row.extend(self.aggregates.iter().map(|a| format_aggregate(a, acc, scale)));
+                row
+            })
+            .collect()
+    }
+
+    /// rows as csv without a header
+    pub fn to_csv(&self, scale: &Scale) -> String {
+        self.rows(scale, false)
+            .iter()
+            .map(|row| row.join(","))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// rows as a json array of objects keyed by column
+    pub fn to_json(&self, scale: &Scale) -> String {
+        let columns = self.columns();
+        let objects: Vec<String> = self.rows(scale, true)
+            .iter()
+            .map(|row| {
+                let fields: Vec<String> = columns
+                    .iter()
+                    .zip(row)
+                    .map(|(k, v)| format!(r#""{}":{}"#, k, v))
+                    .collect();
+                format!("{{{}}}", fields.join(","))
+            })
+            .collect();
+        format!("[{}]", objects.join(", "))
+    }
+}
+
+fn format_aggregate(a: &Aggregate, acc: &Acc, scale: &Scale) -> String {
+    let stats = |field: &Field| match field {
+        Field::Price => (acc.price, scale.tick_size),
+        Field::Size => (acc.lots, scale.lot_size),
+    };
+    match a {
+        Aggregate::Count => acc.count.to_string(),
+        Aggregate::SumSize => format_sum(acc.size, scale.lot_size.mantissa as u128, scale.lot_size.exponent as u32),
+        Aggregate::SumNotional => format_sum(
+            acc.notional,
+            scale.tick_size.mantissa as u128 * scale.lot_size.mantissa as u128,
+            scale.tick_size.exponent as u32 + scale.lot_size.exponent as u32,
+        ),
+        Aggregate::Vwap if acc.size == 0 => "null".to_owned(),
+        Aggregate::Vwap => {
+            let ticks = acc.notional as f64 / acc.size as f64 * scale.tick_size.mantissa as f64;
+            (ticks / 10f64.powi(scale.tick_size.exponent as i32)).to_string()
+        },
+        Aggregate::Min(x) => stats(x).1.format_units(stats(x).0.min),
+        Aggregate::Max(x) => stats(x).1.format_units(stats(x).0.max),
+        Aggregate::First(x) => stats(x).1.format_units(stats(x).0.first),
+        Aggregate::Last(x) => stats(x).1.format_units(stats(x).0.last),
+    }
+}
+
+/// exact decimal representation of `units * mantissa * 10^-exponent`, approximate on overflow
+fn format_sum(units: u128, mantissa: u128, exponent: u32) -> String {
+    let value = match units.checked_mul(mantissa) {
+        Some(value) if exponent <= 38 => value,
+        _ => return (units as f64 * mantissa as f64 / 10f64.powi(exponent as i32)).to_string(),
+    };
+    let pow = 10u128.pow(exponent);
+    let frac = format!("{:0width$}", value % pow, width = exponent as usize);
+    let frac = frac.trim_end_matches('0');
+    if frac.is_empty() {
+        format!("{}", value / pow)
+    } else {
+        format!("{}.{}", value / pow, frac)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dtf::scale::Decimal;
+
+    fn ups() -> Vec<Update> {
+        (0..6).map(|i| Update {
+            ts: 60_000 + i * 20_000,
+            seq: i as u32,
+            is_trade: i % 2 == 0,
+            is_bid: i < 3,
+            price: 100 + i,
+            size: 10,
+        })
+        .collect()
+    }
+
+    #[test]
+    fn should_aggregate_by_time() {
+        let scale = Scale::new(Decimal::new(1, 2), Decimal::new(1, 1)).unwrap();
+        let aggs = vec![Aggregate::Count, Aggregate::SumSize, Aggregate::SumNotional, Aggregate::Vwap,
+                        Aggregate::First(Field::Price), Aggregate::Max(Field::Price)];
+        let group_by = GroupBy { interval: Some("1m".parse().unwrap()), ..Default::default() };
+        let mut agg = Aggregator::new(aggs, group_by);
+        for up in &ups() {
+            agg.add(up);
+        }
+        assert_eq!(agg.columns().join(","), "start,count,sum(size),sum(price*size),vwap,first(price),max(price)");
+        assert_eq!(agg.to_csv(&scale), "60,3,3,3.03,1.01,1,1.02\n120,3,3,3.12,1.04,1.03,1.05");
+    }
+
+    #[test]
+    fn should_split_by_side_and_trade() {
+        let scale = Scale::default();
+        let group_by = GroupBy { interval: Some(Resolution::Tick(3)), side: true, trade: true };
+        let mut agg = Aggregator::new(vec![Aggregate::Count], group_by);
+        for up in &ups() {
+            agg.add(up);
+        }
+        assert_eq!(
+            agg.to_json(&scale),
+            r#"[{"start":60,"side":"bid","is_trade":false,"count":1}, {"start":60,"side":"bid","is_trade":true,"count":2}, "#.to_owned()
+                + r#"{"start":120,"side":"ask","is_trade":false,"count":2}, {"start":120,"side":"ask","is_trade":true,"count":1}]"#
+        );
+    }
+}
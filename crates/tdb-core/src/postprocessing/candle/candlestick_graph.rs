@@ -22,11 +22,11 @@ impl CandleStickGraph {
     /// create a new graph
     pub fn new(height: u32, data: TimeBars) -> Self {
         let global_min = data.get_candles()
-            .map(|candle| candle.low)
+            .map(|candle| candle.low as f32)
             .min_by(|a, b| a.partial_cmp(b).unwrap())
             .unwrap();
         let global_max = data.get_candles()
-            .map(|candle| candle.high)
+            .map(|candle| candle.high as f32)
             .max_by(|a, b| a.partial_cmp(b).unwrap())
             .unwrap();
 
@@ -70,11 +70,11 @@ impl CandleStickGraph {
     fn render_candle_at(&self, candle: &Candle, height_unit: u32) -> String {
         let height_unit = height_unit as f32;
 
-        let ts = self.to_height_units(candle.high);
-        let tc = self.to_height_units(candle.open.max(candle.close));
+        let ts = self.to_height_units(candle.high as f32);
+        let tc = self.to_height_units(candle.open.max(candle.close) as f32);
 
-        let bs = self.to_height_units(candle.low);
-        let bc = self.to_height_units(candle.open.min(candle.close));
+        let bs = self.to_height_units(candle.low as f32);
+        let bc = self.to_height_units(candle.open.min(candle.close) as f32);
 
         if f32::ceil(ts) >= height_unit && height_unit >= f32::floor(tc) {
             if tc - height_unit > 0.75 {
@@ -3,13 +3,13 @@ use crate::dtf::update::Update;
 
 /// sample by dollar traded
 pub struct DollarSampler {
-    interval: f32,
-    elapsed: f32,
+    interval: f64,
+    elapsed: f64,
 }
 
 impl DollarSampler {
-    /// create a new Dollar sampler
-    pub fn new(interval: f32) -> Self {
+    /// create a new Dollar sampler, `interval` is in ticks * lots
+    pub fn new(interval: f64) -> Self {
         Self {
             elapsed: 0.,
             interval,
@@ -22,8 +22,16 @@ impl Sampler for DollarSampler {
         self.elapsed = 0.;
     }
 
+    fn progress(&self) -> u64 {
+        self.elapsed.to_bits()
+    }
+
+    fn resume(&mut self, progress: u64) {
+        self.elapsed = f64::from_bits(progress);
+    }
+
     fn is_sample(&mut self, trade: &Update) -> bool {
-        self.elapsed += trade.price * trade.size;
+        self.elapsed += trade.price as f64 * trade.size as f64;
 
         if self.elapsed > self.interval {
             self.elapsed = 0.;
@@ -43,7 +51,7 @@ pub struct DollarBarsIter<I:Iterator<Item=Update>> {
 
 impl<I:Iterator<Item=Update>> DollarBarsIter<I> {
     /// Create a new iterator for time bars
-    pub fn new(it: I, dollar_interval: f32) -> Self {
+    pub fn new(it: I, dollar_interval: f64) -> Self {
         Self {
             it,
             current_candle: None,
@@ -112,7 +120,7 @@ pub struct DollarBars {
 impl DollarBars {
 
     /// Generate a vector of candles sampled by dollar traded.
-    pub fn from_updates(ups: &[Update], dollar_interval: f32) -> DollarBars {
+    pub fn from_updates(ups: &[Update], dollar_interval: f64) -> DollarBars {
         let v = DollarBarsIter::new(ups.iter().copied(), dollar_interval).collect();
         DollarBars { v }
     }
@@ -121,14 +129,13 @@ impl DollarBars {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::f32;
     #[test]
     fn test_dollar_bar() {
         let trades = (0..10).map(|i| Update {
             is_trade: true,
             is_bid: true,
-            price: i as f32,
-            size: f32::abs(i as f32),
+            price: i,
+            size: i,
             ts: i,
             seq: 0,
         })
@@ -139,27 +146,27 @@ mod tests {
         assert_eq!(DollarBars {v: vec![Candle {
                 start: 0,
                 end: 6,
-                open: 0.0,
-                high: 6.0,
-                low: 0.0,
-                close: 6.0,
-                volume: 21.0,
+                open: 0,
+                high: 6,
+                low: 0,
+                close: 6,
+                volume: 21,
             }, Candle {
                 start: 7,
                 end: 8,
-                open: 7.0,
-                high: 8.0,
-                low: 7.0,
-                close: 8.0,
-                volume: 15.0,
+                open: 7,
+                high: 8,
+                low: 7,
+                close: 8,
+                volume: 15,
             }, Candle {
                 start: 9,
                 end: 9,
-                open: 9.0,
-                high: 9.0,
-                low: 9.0,
-                close: 9.0,
-                volume: 9.0,
+                open: 9,
+                high: 9,
+                low: 9,
+                close: 9,
+                volume: 9,
             }]}, ret);
     }
 }
@@ -10,11 +10,13 @@ pub mod tick_bars;
 pub mod dollar_bars;
 /// plot candlesticks in terminal
 pub mod candlestick_graph;
+/// candles maintained incrementally as updates arrive
+pub mod rollup;
 use self::dtf::update::Update;
 
 type Time = u64;
-type Price = f32;
-type Volume = f32;
+type Price = u64;
+type Volume = u64;
 type Scale = u16;
 
 #[derive(PartialOrd, PartialEq, Clone, Copy, Debug)]
@@ -24,15 +26,15 @@ pub struct Candle {
     pub start: Time,
     /// end ts
     pub end: Time,
-    /// open price
+    /// open price in ticks
     pub open: Price,
-    /// high price
+    /// high price in ticks
     pub high: Price,
-    /// low price
+    /// low price in ticks
     pub low: Price,
-    /// close price
+    /// close price in ticks
     pub close: Price,
-    /// volume
+    /// volume in lots
     pub volume: Volume,
 }
 
@@ -69,6 +71,10 @@ pub trait Sampler {
     fn is_sample(&mut self, update: &Update) -> bool;
     /// reset sampler state
     fn reset(&mut self);
+    /// progress towards the next sample, see `resume`
+    fn progress(&self) -> u64;
+    /// continue from the `progress` of a sampler, 0 is the state after `reset`
+    fn resume(&mut self, progress: u64);
 }
 
 use std::ops::DerefMut;
@@ -79,4 +85,10 @@ impl<T: DerefMut<Target=dyn Sampler>> Sampler for T {
     fn reset(&mut self) {
         self.deref_mut().reset()
     }
+    fn progress(&self) -> u64 {
+        self.deref().progress()
+    }
+    fn resume(&mut self, progress: u64) {
+        self.deref_mut().resume(progress)
+    }
 }
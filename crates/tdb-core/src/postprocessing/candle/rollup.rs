@@ -0,0 +1,476 @@
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use super::{Candle, Sampler};
+use super::tick_bars::TickSampler;
+use super::volume_bars::VolumeSampler;
+use super::dollar_bars::DollarSampler;
+use crate::dtf::update::Update;
+use crate::dtf::scale::Scale;
+
+/// size of a candle in a candles file, 7 u64s and a flag
+const CANDLE_SIZE: usize = 7 * 8 + 1;
+
+/// flag of a record holding the progress of the sampler in place of a candle
+const PROGRESS: u8 = 2;
+
+/// how a rollup samples its candles
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Resolution {
+    /// every n seconds, e.g. `1m` or `1h`
+    Time(u64),
+    /// every n updates, e.g. `tick:500`
+    Tick(u32),
+    /// every n lots traded, e.g. `volume:1000`
+    Volume(u64),
+    /// every n ticks * lots traded, e.g. `dollar:1000000`
+    Dollar(f64),
+}
+
+impl FromStr for Resolution {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("Invalid resolution: `{}`", s);
+        let res = match s.split_once(':') {
+            Some(("tick", n)) => Resolution::Tick(n.parse().map_err(|_| err())?),
+            Some(("volume", n)) => Resolution::Volume(n.parse().map_err(|_| err())?),
+            Some(("dollar", n)) => Resolution::Dollar(n.parse().map_err(|_| err())?),
+            Some(_) => return Err(err()),
+            None => {
+                let idx = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+                let (n, unit) = s.split_at(idx);
+                let secs = match unit {
+                    "" | "s" => 1,
+                    "m" => 60,
+                    "h" => 60 * 60,
+                    "d" => 24 * 60 * 60,
+                    _ => return Err(err()),
+                };
+                Resolution::Time(n.parse::<u64>().map_err(|_| err())? * secs)
+            }
+        };
+        match res {
+            Resolution::Time(0) | Resolution::Tick(0) | Resolution::Volume(0) => Err(err()),
+            Resolution::Dollar(n) if n.is_nan() || n <= 0. => Err(err()),
+            res => Ok(res),
+        }
+    }
+}
+
+impl fmt::Display for Resolution {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Resolution::Time(s) if s % (24 * 60 * 60) == 0 => write!(f, "{}d", s / (24 * 60 * 60)),
+            Resolution::Time(s) if s % (60 * 60) == 0 => write!(f, "{}h", s / (60 * 60)),
+            Resolution::Time(s) if s % 60 == 0 => write!(f, "{}m", s / 60),
+            Resolution::Time(s) => write!(f, "{}s", s),
+            Resolution::Tick(n) => write!(f, "tick:{}", n),
+            Resolution::Volume(n) => write!(f, "volume:{}", n),
+            Resolution::Dollar(n) => write!(f, "dollar:{}", n),
+        }
+    }
+}
+
+/// Candles of a book maintained incrementally as updates are added
+///
+/// Candles follow the iterators of the same resolution, except that time bars
+/// start at the beginning of their interval in ms and end at their last trade.
+///
+/// Completed candles are appended to the candles file of the book on flush,
+/// followed by a snapshot of the current candle that the next candle with
+/// the same start replaces, and by the progress of the sampler towards the
+/// next candle so that bars continue where they were after a restart.
+pub struct Rollup {
+    /// resolution of the candles
+    pub resolution: Resolution,
+    /// time bars are sampled by their start
+    sampler: Option<Box<dyn Sampler + Send>>,
+    /// candle of the trades since the last sample
+    current: Option<Candle>,
+    /// completed candles that are not persisted yet
+    pending: Vec<Candle>,
+    /// last snapshot of the current candle in the candles file
+    persisted: Option<Candle>,
+    /// progress of the sampler at the last snapshot
+    persisted_progress: u64,
+}
+
+impl Rollup {
+    /// create an empty rollup
+    pub fn new(resolution: Resolution) -> Self {
+        let sampler: Option<Box<dyn Sampler + Send>> = match resolution {
+            Resolution::Time(_) => None,
+            Resolution::Tick(n) => Some(Box::new(TickSampler::new(n))),
+            Resolution::Volume(n) => Some(Box::new(VolumeSampler::new(n))),
+            Resolution::Dollar(n) => Some(Box::new(DollarSampler::new(n))),
+        };
+        Self {
+            resolution,
+            sampler,
+            current: None,
+            pending: vec![],
+            persisted: None,
+            persisted_progress: 0,
+        }
+    }
+
+    /// create a rollup that continues the current candle in the candles file of `book`
+    pub fn open(resolution: Resolution, folder: &str, book: &str) -> Result<Self, io::Error> {
+        let mut ret = Self::new(resolution);
+        let fname = ret.file_path(folder, book);
+        if Path::new(&fname).exists() {
+            let (mut records, progress) = read_records(&fname)?;
+            if let Some((c, true)) = records.pop() {
+                ret.current = Some(c);
+                ret.persisted = Some(c);
+            }
+            ret.persisted_progress = progress;
+            if let Some(sampler) = ret.sampler.as_mut() {
+                sampler.resume(progress);
+            }
+        }
+        Ok(ret)
+    }
+
+    /// update the current candle, returns the candle completed by the update if any
+    pub fn add(&mut self, up: &Update) -> Option<Candle> {
+        // tick bars count every update, other samplers only trades
+        let is_sample = match (self.resolution, self.sampler.as_mut()) {
+            (Resolution::Tick(_), Some(sampler)) => Some(sampler.is_sample(up)),
+            _ => None,
+        };
+        if !up.is_trade {
+            return None;
+        }
+        let start = match self.resolution {
+            Resolution::Time(s) => up.ts / (s * 1000) * (s * 1000),
+            _ => up.ts,
+        };
+        let new_candle = Candle {
+            start,
+            end: up.ts,
+            open: up.price,
+            high: up.price,
+            low: up.price,
+            close: up.price,
+            volume: up.size,
+        };
+
+        let c = match self.current {
+            Some(c) => c,
+            None => {
+                self.current = Some(new_candle);
+                return None;
+            }
+        };
+        let is_sample = match (is_sample, self.sampler.as_mut()) {
+            (Some(is_sample), _) => is_sample,
+            (None, Some(sampler)) => sampler.is_sample(up),
+            (None, None) => start != c.start,
+        };
+        if is_sample {
+            self.current = Some(new_candle);
+            self.pending.push(c);
+            return Some(c);
+        }
+        self.current = Some(Candle {
+            start: c.start,
+            end: up.ts,
+            open: c.open,
+            high: up.price.max(c.high),
+            low: up.price.min(c.low),
+            close: up.price,
+            volume: c.volume + up.size,
+        });
+        None
+    }
+
+    /// drop the trades that are not persisted yet
+    pub fn reset(&mut self) {
+        if let Some(sampler) = self.sampler.as_mut() {
+            sampler.resume(self.persisted_progress);
+        }
+        self.current = self.persisted;
+        self.pending.clear();
+    }
+
+    /// path of the candles file of `book`
+    pub fn file_path(&self, folder: &str, book: &str) -> String {
+        format!("{}/{}.{}.candles", folder, book, self.resolution.to_string().replace(':', "-"))
+    }
+
+    /// append the completed candles and the current candle to the candles file of `book`
+    pub fn flush(&mut self, folder: &str, book: &str) -> Result<(), io::Error> {
+        if let Some(flush) = self.prepare_flush(folder, book) {
+            flush.write()?;
+            self.flushed(&flush);
+        }
+        Ok(())
+    }
+
+    /// the candles that `flush` would append, None when there is nothing new;
+    /// they can be written while more updates are added and are only
+    /// persisted once the written flush is passed to `flushed`
+    pub fn prepare_flush(&self, folder: &str, book: &str) -> Option<RollupFlush> {
+        let progress = self.sampler.as_ref().map(|sampler| sampler.progress());
+        if self.pending.is_empty() && self.current == self.persisted && progress.unwrap_or(0) == self.persisted_progress {
+            return None;
+        }
+        Some(RollupFlush {
+            fname: self.file_path(folder, book),
+            pending: self.pending.clone(),
+            current: self.current,
+            progress,
+        })
+    }
+
+    /// mark the candles of a written flush as persisted
+    pub fn flushed(&mut self, flush: &RollupFlush) {
+        let n = usize::min(flush.pending.len(), self.pending.len());
+        self.pending.drain(..n);
+        self.persisted = flush.current;
+        self.persisted_progress = flush.progress.unwrap_or(0);
+    }
+
+    /// persisted, pending and current candles of `book` in order
+    pub fn candles(&self, folder: &str, book: &str) -> Result<Vec<Candle>, io::Error> {
+        let fname = self.file_path(folder, book);
+        let mut records = if Path::new(&fname).exists() {
+            read_records(&fname)?.0
+        } else {
+            vec![]
+        };
+        for c in &self.pending {
+            push_record(&mut records, *c, false);
+        }
+        if let Some(c) = self.current {
+            push_record(&mut records, c, true);
+        }
+        Ok(records.into_iter().map(|(c, _)| c).collect())
+    }
+}
+
+/// candles of a rollup to append to its candles file, see `Rollup::prepare_flush`
+pub struct RollupFlush {
+    fname: String,
+    pending: Vec<Candle>,
+    current: Option<Candle>,
+    /// progress of the sampler, None for time bars
+    progress: Option<u64>,
+}
+
+impl RollupFlush {
+    /// append the candles to the candles file
+    pub fn write(&self) -> Result<(), io::Error> {
+        let file = OpenOptions::new().create(true).append(true).open(&self.fname)?;
+        let mut wtr = BufWriter::new(file);
+        for c in &self.pending {
+            write_record(&mut wtr, c, false)?;
+        }
+        if let Some(c) = &self.current {
+            write_record(&mut wtr, c, true)?;
+        }
+        if let Some(progress) = self.progress {
+            wtr.write_u64::<BigEndian>(progress)?;
+            wtr.write_all(&[0; 6 * 8])?;
+            wtr.write_u8(PROGRESS)?;
+        }
+        wtr.flush()
+    }
+}
+
+/// add a candle, replacing the snapshot of the same candle
+fn push_record(records: &mut Vec<(Candle, bool)>, c: Candle, partial: bool) {
+    match records.last_mut() {
+        Some(last) if last.1 && last.0.start == c.start => *last = (c, partial),
+        _ => records.push((c, partial)),
+    }
+}
+
+/// write a candle as big endian u64s followed by whether it is a snapshot of the current candle
+fn write_record(wtr: &mut dyn Write, c: &Candle, partial: bool) -> Result<(), io::Error> {
+    for v in &[c.start, c.end, c.open, c.high, c.low, c.close, c.volume] {
+        wtr.write_u64::<BigEndian>(*v)?;
+    }
+    wtr.write_u8(partial as u8)
+}
+
+/// read the candles of a candles file and whether the last one is a snapshot,
+/// along with the last progress of the sampler
+fn read_records(fname: &str) -> Result<(Vec<(Candle, bool)>, u64), io::Error> {
+    let mut rdr = BufReader::new(File::open(fname)?);
+    let mut buf = vec![];
+    rdr.read_to_end(&mut buf)?;
+    // ignore a candle that was partially written
+    let mut rdr = &buf[..buf.len() - buf.len() % CANDLE_SIZE];
+    let mut ret = Vec::with_capacity(rdr.len() / CANDLE_SIZE);
+    let mut progress = 0;
+    while !rdr.is_empty() {
+        let c = Candle {
+            start: rdr.read_u64::<BigEndian>()?,
+            end: rdr.read_u64::<BigEndian>()?,
+            open: rdr.read_u64::<BigEndian>()?,
+            high: rdr.read_u64::<BigEndian>()?,
+            low: rdr.read_u64::<BigEndian>()?,
+            close: rdr.read_u64::<BigEndian>()?,
+            volume: rdr.read_u64::<BigEndian>()?,
+        };
+        match rdr.read_u8()? {
+            PROGRESS => progress = c.start,
+            flag => push_record(&mut ret, c, flag != 0),
+        }
+    }
+    Ok((ret, progress))
+}
+
+/// read every candle of a candles file
+pub fn read_candles(fname: &str) -> Result<Vec<Candle>, io::Error> {
+    Ok(read_records(fname)?.0.into_iter().map(|(c, _)| c).collect())
+}
+
+/// convert candles to a json array, prices and volumes are formatted with `scale`
+pub fn candles_to_json(candles: &[Candle], scale: &Scale) -> String {
+    let objects: Vec<String> = candles
+        .iter()
+        .map(|c| format!(
+            r#"{{"start":{},"end":{},"open":{},"high":{},"low":{},"close":{},"volume":{}}}"#,
+            (c.start as f64) / 1000_f64,
+            (c.end as f64) / 1000_f64,
+            scale.format_price(c.open),
+            scale.format_price(c.high),
+            scale.format_price(c.low),
+            scale.format_price(c.close),
+            scale.format_size(c.volume),
+        ))
+        .collect();
+    format!("[{}]", objects.join(", "))
+}
+
+/// convert candles to csv, prices and volumes are formatted with `scale`
+/// Format:
+///     S,E,O,H,L,C,V
+pub fn candles_to_csv(candles: &[Candle], scale: &Scale) -> String {
+    let rows: Vec<String> = candles
+        .iter()
+        .map(|c| format!(
+            "{},{},{},{},{},{},{}",
+            (c.start as f64) / 1000_f64,
+            (c.end as f64) / 1000_f64,
+            scale.format_price(c.open),
+            scale.format_price(c.high),
+            scale.format_price(c.low),
+            scale.format_price(c.close),
+            scale.format_size(c.volume),
+        ))
+        .collect();
+    rows.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::postprocessing::candle::volume_bars::VolumeBarsIter;
+
+    fn trades() -> Vec<Update> {
+        (0..100).map(|i| Update {
+            ts: 1_513_749_530_000 + i * 7_000,
+            seq: i as u32,
+            is_trade: i % 3 != 0,
+            is_bid: true,
+            price: 100 + i % 11,
+            size: i,
+        })
+        .collect()
+    }
+
+    #[test]
+    fn should_match_bars_iterators() {
+        let ups = trades();
+        let mut rollup = Rollup::new("volume:300".parse().unwrap());
+        for up in &ups {
+            rollup.add(up);
+        }
+        let expected: Vec<Candle> = VolumeBarsIter::new(ups.iter().copied(), 300).collect();
+        assert_eq!(rollup.candles("/nonexistent", "book").unwrap(), expected);
+    }
+
+    #[test]
+    fn should_persist_time_bars() {
+        let folder = "rollup-test";
+        let _ = std::fs::remove_dir_all(folder);
+        std::fs::create_dir(folder).unwrap();
+        let ups = trades();
+        let resolution: Resolution = "1m".parse().unwrap();
+        assert_eq!(resolution, Resolution::Time(60));
+        assert_eq!(resolution.to_string(), "1m");
+
+        let mut expected = Rollup::new(resolution);
+        for up in &ups {
+            expected.add(up);
+        }
+        let expected = expected.candles(folder, "book").unwrap();
+        assert!(expected.windows(2).all(|w| w[0].start + 60_000 <= w[1].start));
+
+        let mut rollup = Rollup::new(resolution);
+        for up in &ups[..25] {
+            rollup.add(up);
+        }
+        rollup.flush(folder, "book").unwrap();
+        for up in &ups[25..50] {
+            rollup.add(up);
+        }
+        rollup.flush(folder, "book").unwrap();
+
+        // a restarted rollup continues the current candle
+        let mut rollup = Rollup::open(resolution, folder, "book").unwrap();
+        for up in &ups[50..] {
+            rollup.add(up);
+        }
+        assert_eq!(rollup.candles(folder, "book").unwrap(), expected);
+        rollup.flush(folder, "book").unwrap();
+        assert_eq!(read_candles(&rollup.file_path(folder, "book")).unwrap(), expected);
+
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn should_persist_sampler_progress() {
+        let folder = "rollup-sampler-test";
+        let ups = trades();
+        for resolution in &["volume:300", "tick:7"] {
+            let _ = std::fs::remove_dir_all(folder);
+            std::fs::create_dir(folder).unwrap();
+            let resolution: Resolution = resolution.parse().unwrap();
+            let mut expected = Rollup::new(resolution);
+            for up in &ups {
+                expected.add(up);
+            }
+            let expected = expected.candles(folder, "book").unwrap();
+
+            let mut rollup = Rollup::new(resolution);
+            for up in &ups[..40] {
+                rollup.add(up);
+            }
+            rollup.flush(folder, "book").unwrap();
+            // cleared before they are flushed
+            for up in &ups[40..45] {
+                rollup.add(up);
+            }
+            rollup.reset();
+
+            // a restarted rollup continues the bar in progress
+            let mut restarted = Rollup::open(resolution, folder, "book").unwrap();
+            for up in &ups[40..] {
+                rollup.add(up);
+                restarted.add(up);
+            }
+            assert_eq!(rollup.candles(folder, "book").unwrap(), expected);
+            assert_eq!(restarted.candles(folder, "book").unwrap(), expected);
+        }
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+}
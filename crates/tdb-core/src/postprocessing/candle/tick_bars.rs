@@ -21,6 +21,12 @@ impl Sampler for TickSampler {
     fn reset(&mut self) {
         self.elapsed = 0;
     }
+    fn progress(&self) -> u64 {
+        self.elapsed as u64
+    }
+    fn resume(&mut self, progress: u64) {
+        self.elapsed = progress as u32;
+    }
     fn is_sample(&mut self, _update: &Update) -> bool {
         self.elapsed += 1;
 
@@ -121,14 +127,13 @@ impl TickBars {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::f32;
     #[test]
     fn test_tick_bar() {
         let trades = (0..10).map(|i| Update {
             is_trade: true,
             is_bid: true,
-            price: i as f32,
-            size: f32::abs(i as f32),
+            price: i,
+            size: i,
             ts: i,
             seq: 0,
         })
@@ -139,35 +144,35 @@ mod tests {
         assert_eq!(TickBars {v: vec![Candle {
                 start: 0,
                 end: 2,
-                open: 0.0,
-                high: 2.0,
-                low: 0.0,
-                close: 2.0,
-                volume: 3.0,
+                open: 0,
+                high: 2,
+                low: 0,
+                close: 2,
+                volume: 3,
             }, Candle {
                 start: 3,
                 end: 5,
-                open: 3.0,
-                high: 5.0,
-                low: 3.0,
-                close: 5.0,
-                volume: 12.0,
+                open: 3,
+                high: 5,
+                low: 3,
+                close: 5,
+                volume: 12,
             }, Candle {
                 start: 6,
                 end: 8,
-                open: 6.0,
-                high: 8.0,
-                low: 6.0,
-                close: 8.0,
-                volume: 21.0,
+                open: 6,
+                high: 8,
+                low: 6,
+                close: 8,
+                volume: 21,
             }, Candle {
                 start: 9,
                 end: 9,
-                open: 9.0,
-                high: 9.0,
-                low: 9.0,
-                close: 9.0,
-                volume: 9.0,
+                open: 9,
+                high: 9,
+                low: 9,
+                close: 9,
+                volume: 9,
             }]}, ret);
     }
 }
@@ -24,6 +24,12 @@ impl Sampler for TimeSampler {
     fn reset(&mut self) {
         self.last = None;
     }
+    fn progress(&self) -> u64 {
+        self.last.map_or(0, |last| last + 1)
+    }
+    fn resume(&mut self, progress: u64) {
+        self.last = progress.checked_sub(1);
+    }
     fn is_sample(&mut self, trade: &Update) -> bool {
         let ts = (fill_digits(trade.ts) / 1000 / self.s * self.s) as Time; // floor(ts)
 
@@ -205,7 +211,7 @@ impl TimeBars {
                         Candle {
                             start: cur,
                             end: cur + 60,
-                            volume: 0.,
+                            volume: 0,
                             high: last_close,
                             low: last_close,
                             open: last_close,
@@ -259,10 +265,10 @@ impl TimeBars {
         let mut res = IndexMap::new();
 
         let mut startacc = 0;
-        let mut openacc = 0.;
-        let mut highacc = 0.;
-        let mut lowacc = 0.;
-        let mut volumeacc = 0.;
+        let mut openacc = 0;
+        let mut highacc = 0;
+        let mut lowacc = 0;
+        let mut volumeacc = 0;
 
         let mut aligned = false;
         let mut i = 0;
@@ -404,11 +410,11 @@ mod tests {
         let inp = Candle {
             start: 0,
             end: 0,
-            open: 0.,
-            close: 0.,
-            high: 0.,
-            low: 0.,
-            volume: 0.,
+            open: 0,
+            close: 0,
+            high: 0,
+            low: 0,
+            volume: 0,
         };
         let target = "0,0,0,0,0,0,0";
         assert_eq!(inp.to_csv(), target);
@@ -424,11 +430,11 @@ mod tests {
                 Candle {
                     start: j,
                     end: j + 60,
-                    open: 0.,
-                    close: 1.,
-                    high: 2.,
-                    low: 0.,
-                    volume: 1.,
+                    open: 0,
+                    close: 1,
+                    high: 2,
+                    low: 0,
+                    volume: 1,
                 },
             );
         }
@@ -440,11 +446,11 @@ mod tests {
             Candle {
                 start: 1800,
                 end: 5340,
-                open: 0.,
-                high: 2.,
-                low: 0.,
-                close: 1.,
-                volume: 60.,
+                open: 0,
+                high: 2,
+                low: 0,
+                close: 1,
+                volume: 60,
             },
         );
 
@@ -511,11 +517,11 @@ mod tests {
                 Candle {
                     start: j,
                     end: j + 60,
-                    open: 0.,
-                    close: 1.,
-                    high: 2.,
-                    low: 0.,
-                    volume: 1.,
+                    open: 0,
+                    close: 1,
+                    high: 2,
+                    low: 0,
+                    volume: 1,
                 },
             );
         }
@@ -567,11 +573,11 @@ mod tests {
                 Candle {
                     start: j,
                     end: j + 60,
-                    open: 0.,
-                    close: 0.,
-                    high: 0.,
-                    low: 0.,
-                    volume: 0.,
+                    open: 0,
+                    close: 0,
+                    high: 0,
+                    low: 0,
+                    volume: 0,
                 },
             );
         }
@@ -587,11 +593,11 @@ mod tests {
             Candle {
                 start: 10000,
                 end: 18000,
-                open: 0.,
-                close: 0.,
-                high: 0.,
-                low: 0.,
-                volume: 0.,
+                open: 0,
+                close: 0,
+                high: 0,
+                low: 0,
+                volume: 0,
             },
         );
         let g = TimeBars {
@@ -613,11 +619,11 @@ mod tests {
                 Candle {
                     start: j,
                     end: j + 60,
-                    open: 0.,
-                    close: 0.,
-                    high: 0.,
-                    low: 0.,
-                    volume: 0.,
+                    open: 0,
+                    close: 0,
+                    high: 0,
+                    low: 0,
+                    volume: 0,
                 },
             );
         }
@@ -647,8 +653,8 @@ mod tests {
                 Candle {
                     start: j,
                     end: j+60,
-                    open: 100. * i as Price,
-                    close: 100. * i as Price,
+                    open: 100 * i as Price,
+                    close: 100 * i as Price,
                     high: i as Price,
                     low: i as Price,
                     volume: i as Price,
@@ -672,7 +678,7 @@ mod tests {
             println!("{:?}", bin);
             assert_eq!(bin.high, (i * to_scale) as Price);
             assert_eq!(bin.open, (100 * (i - 1) * to_scale + 100) as Price);
-            assert_eq!(bin.close, 100. * (i * to_scale) as Price);
+            assert_eq!(bin.close, 100 * (i * to_scale) as Price);
             assert_eq!(
                 bin.volume,
                 (1 + (i - 1) * to_scale..(i * to_scale + 1)).fold(0, |a, b| a + b) as Price
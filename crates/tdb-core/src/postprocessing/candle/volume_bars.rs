@@ -3,15 +3,15 @@ use crate::dtf::update::Update;
 
 /// sample by volume traded
 pub struct VolumeSampler {
-    interval: f32,
-    elapsed: f32,
+    interval: u64,
+    elapsed: u64,
 }
 
 impl VolumeSampler {
-    /// create a new Volume sampler
-    pub fn new(interval: f32) -> Self {
+    /// create a new Volume sampler, `interval` is in lots
+    pub fn new(interval: u64) -> Self {
         Self {
-            elapsed: 0.,
+            elapsed: 0,
             interval,
         }
     }
@@ -19,13 +19,19 @@ impl VolumeSampler {
 
 impl Sampler for VolumeSampler {
     fn reset(&mut self) {
-        self.elapsed = 0.;
+        self.elapsed = 0;
+    }
+    fn progress(&self) -> u64 {
+        self.elapsed
+    }
+    fn resume(&mut self, progress: u64) {
+        self.elapsed = progress;
     }
     fn is_sample(&mut self, trade: &Update) -> bool {
         self.elapsed += trade.size;
 
         if self.elapsed > self.interval {
-            self.elapsed = 0.;
+            self.elapsed = 0;
             true
         } else {
             false
@@ -42,7 +48,7 @@ pub struct VolumeBarsIter<I:Iterator<Item=Update>> {
 
 impl<I:Iterator<Item=Update>> VolumeBarsIter<I> {
     /// Create a new iterator for time bars
-    pub fn new(it: I, vol_interval: f32) -> Self {
+    pub fn new(it: I, vol_interval: u64) -> Self {
         Self {
             it,
             current_candle: None,
@@ -115,7 +121,7 @@ impl VolumeBars {
     /// let volume interval be 1,000 shares traded, then each candle
     /// is built from the trade updates that occurred during the interval
     /// in which 1k shares are traded.
-    pub fn from_updates(ups: &[Update], vol_interval: f32) -> VolumeBars {
+    pub fn from_updates(ups: &[Update], vol_interval: u64) -> VolumeBars {
         let v = VolumeBarsIter::new(ups.iter().copied(), vol_interval).collect();
         VolumeBars { v }
     }
@@ -124,37 +130,36 @@ impl VolumeBars {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::f32;
     #[test]
     fn test_vol_bar() {
         let trades = (0..10).map(|i| Update {
             is_trade: true,
             is_bid: true,
-            price: i as f32,
-            size: f32::abs(i as f32),
+            price: i,
+            size: i,
             ts: i,
             seq: 0,
         })
         .collect::<Vec<_>>();
 
-        let ret = VolumeBars::from_updates(&trades, 36.);
+        let ret = VolumeBars::from_updates(&trades, 36);
 
         assert_eq!(VolumeBars {v: vec![Candle {
                 start: 0,
                 end: 8,
-                open: 0.0,
-                high: 8.0,
-                low: 0.0,
-                close: 8.0,
-                volume: 36.0,
+                open: 0,
+                high: 8,
+                low: 0,
+                close: 8,
+                volume: 36,
             }, Candle {
                 start: 9,
                 end: 9,
-                open: 9.0,
-                high: 9.0,
-                low: 9.0,
-                close: 9.0,
-                volume: 9.0,
+                open: 9,
+                high: 9,
+                low: 9,
+                close: 9,
+                volume: 9,
             }]}, ret);
     }
 }
@@ -37,7 +37,7 @@ impl<'a> From<&'a [Update]> for Events {
         for row in ups {
 
             let ts = row.ts;
-            let price = row.price.to_bits();
+            let price = row.price;
 
             if row.is_trade {
                 let v = trades.entry(ts).or_insert(Vec::new());
@@ -46,9 +46,9 @@ impl<'a> From<&'a [Update]> for Events {
                 let prev = if current_level.contains_key(&price) {
                     *current_level.get(&price).unwrap()
                 } else {
-                    0.
+                    0
                 };
-                if row.size == 0. || row.size <= prev {
+                if row.size == 0 || row.size <= prev {
                     let v = cancelled.entry(ts).or_insert(Vec::new());
                     (*v).push(row.clone());
                 } else if row.size > prev {
@@ -72,8 +72,8 @@ impl<'a> From<&'a [Update]> for Events {
 }
 
 impl Events {
-    /// Filter order events based on size
-    pub fn filter_size(&self, event_type: EventType, from_size: f32, to_size: f32) -> Vec<Update> {
+    /// Filter order events based on size in lots
+    pub fn filter_size(&self, event_type: EventType, from_size: u64, to_size: u64) -> Vec<Update> {
         let obj = match event_type {
             EventType::CancelEvent => &self.cancelled,
             EventType::CreateEvent => &self.created,
@@ -114,22 +114,22 @@ mod test {
 
         let evts = Events::from(ups);
 
-        let cancels = evts.filter_size(EventType::CancelEvent, 100., 200.);
+        let cancels = evts.filter_size(EventType::CancelEvent, 100_0000_0000, 200_0000_0000);
         assert!(cancels.len() > 0);
         for up in cancels.iter() {
-            assert!(up.size >= 100. && up.size <= 200.);
+            assert!(up.size >= 100_0000_0000 && up.size <= 200_0000_0000);
         }
 
-        let creates = evts.filter_size(EventType::CreateEvent, 100., 200.);
+        let creates = evts.filter_size(EventType::CreateEvent, 100_0000_0000, 200_0000_0000);
         assert!(creates.len() > 0);
         for up in creates.iter() {
-            assert!(up.size >= 100. && up.size <= 200.);
+            assert!(up.size >= 100_0000_0000 && up.size <= 200_0000_0000);
         }
 
-        let trades = evts.filter_size(EventType::TradeEvent, 100., 200.);
+        let trades = evts.filter_size(EventType::TradeEvent, 100_0000_0000, 200_0000_0000);
         assert!(trades.len() > 0);
         for up in trades.iter() {
-            assert!(up.size >= 100. && up.size <= 200.);
+            assert!(up.size >= 100_0000_0000 && up.size <= 200_0000_0000);
         }
     }
 
@@ -142,10 +142,10 @@ mod test {
         let ups = records.as_slice();
         let evts = Events::from(ups);
 
-        let trades = evts.filter_size(EventType::TradeEvent, 100., 200.);
+        let trades = evts.filter_size(EventType::TradeEvent, 100_0000_0000, 200_0000_0000);
         assert!(trades.len() > 0);
         for up in trades.iter() {
-            assert!(up.size >= 100. && up.size <= 200.);
+            assert!(up.size >= 100_0000_0000 && up.size <= 200_0000_0000);
         }
     }
 }
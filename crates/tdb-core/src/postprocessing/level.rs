@@ -9,7 +9,7 @@ use crate::dtf::update::Update;
 
 type Price = u64;
 type Time = u32;
-type Size = f32;
+type Size = u64;
 
 /// data structure for storing levels
 #[derive(Debug)]
@@ -4,6 +4,8 @@ pub mod candle;
 pub mod orderbook;
 /// orderbook data structure: [price -> time -> size]
 pub mod level;
+/// aggregates of updates grouped by interval, side and trade flag
+pub mod aggregate;
 /// events stream from slice of updates
 pub mod event;
 ///
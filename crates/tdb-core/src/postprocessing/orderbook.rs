@@ -3,19 +3,23 @@
 use indexmap::IndexMap;
 use crate::postprocessing::histogram::{Histogram, BinCount};
 use crate::dtf::update::Update;
+use crate::dtf::scale::Scale;
 use std::collections::BTreeMap;
 use std::fmt;
 use std::f64;
+use std::io::{self, Read, Write};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
 type Price = u64;
-type Size = f64;
+type Size = i64;
 type Time = u64;
+type Level = (Price, Size);
 
-/// data structure for orderbook
+/// data structure for orderbook, prices are in ticks and sizes in lots
 #[derive(Clone, Serialize, Deserialize, PartialEq)]
 pub struct Orderbook {
-    /// precision of price
-    pub price_decimals: u8,
+    /// tick size and lot size of the book
+    pub scale: Scale,
     /// bids side of the orderbook
     pub bids: BTreeMap<Price, Size>,
     /// asks side of the orderbook
@@ -23,20 +27,10 @@ pub struct Orderbook {
 }
 
 impl Orderbook {
-    /// convert price from f32 to u64
-    pub fn discretize(&self, p: f32) -> Price {
-        (f64::from(p) * 10f64.powf(self.price_decimals as f64)) as Price
-    }
-
-    /// convert price from u64 to f32
-    pub fn undiscretize(&self, p: u64) -> f32 {
-        p as f32 / 10f32.powf(self.price_decimals as f32)
-    }
-
     /// Create empty orderbook
-    pub fn with_precision(price_decimals: u8) -> Orderbook {
+    pub fn with_scale(scale: Scale) -> Orderbook {
         Orderbook {
-            price_decimals,
+            scale,
             bids: BTreeMap::new(),
             asks: BTreeMap::new(),
         }
@@ -44,21 +38,17 @@ impl Orderbook {
 
     /// process depth update and clear empty price levels
     pub fn process_update(&mut self, up: &Update) {
+        let book = if up.is_bid {&mut self.bids} else {&mut self.asks};
         if up.is_trade {
-            let p = self.discretize(up.price);
-            let book = if up.is_bid {&mut self.bids} else {&mut self.asks};
-            book.entry(p)
+            book.entry(up.price)
                 .and_modify(|size| {
                     // assert!(*size >= up.size);
                     *size -= up.size as Size
                 });
+        } else if up.size == 0 {
+            book.remove(&up.price);
         } else {
-            let price = self.discretize(up.price);
-            let book = if up.is_bid {&mut self.bids} else {&mut self.asks};
-            book.insert(price, up.size as Size);
-            if book[&price] == 0. {
-                book.remove(&price);
-            }
+            book.insert(up.price, up.size as Size);
         }
     }
 
@@ -70,87 +60,272 @@ impl Orderbook {
 
     /// Remove zero levels from books
     pub fn clean(&mut self) {
-        self.bids = self.bids.iter()
-                .map(|(&a,&b)| (a,b))
-                .filter(|&(_p,s)|s != 0.)
-                .collect::<BTreeMap<Price, Size>>();
-        self.asks = self.asks.iter()
-                .map(|(&a,&b)| (a,b))
-                .filter(|&(_p,s)|s != 0.)
-                .collect::<BTreeMap<Price, Size>>();
+        self.bids.retain(|_p, s| *s != 0);
+        self.asks.retain(|_p, s| *s != 0);
     }
 
     /// get top of the book, max bid, min ask
-    pub fn top(&self) -> Option<((f32, Size), (f32, Size))> {
-        let bid_max = self.bids.iter().next_back()?;
-        let ask_min = self.asks.iter().next()?;
-        let (bid_p, bid_s) = (self.undiscretize(*bid_max.0), *bid_max.1);
-        let (ask_p, ask_s) = (self.undiscretize(*ask_min.0), *ask_min.1);
+    pub fn top(&self) -> Option<((Price, Size), (Price, Size))> {
+        let (&bid_p, &bid_s) = self.bids.iter().next_back()?;
+        let (&ask_p, &ask_s) = self.asks.iter().next()?;
         Some((
             (bid_p, bid_s),
             (ask_p, ask_s)
         ))
     }
 
-    /// get discretized best bid price
+    /// get best bid price in ticks
     pub fn best_bid_raw(&self) -> Option<u64> {
         let (bid_p, _bid_s) = self.bids.iter().next_back()?;
         Some(*bid_p)
     }
 
-    /// get discretized best ask price
+    /// get best ask price in ticks
     pub fn best_ask_raw(&self) -> Option<u64> {
         let (ask_p, _ask_s) = self.asks.iter().next()?;
         Some(*ask_p)
     }
 
-    /// get discretized best ask price
+    /// get midprice in ticks, rounded down
     pub fn midprice_raw(&self) -> Option<u64> {
         let bb = self.best_bid_raw()?;
         let ba = self.best_ask_raw()?;
         Some((bb + ba) / 2)
     }
 
-    /// get undiscretized best bid price
-    pub fn best_bid(&self) -> Option<f32> {
-        let (bid_p, _bid_s) = self.bids.iter().next_back()?;
-        Some(self.undiscretize(*bid_p))
+    /// get approximate best bid price
+    pub fn best_bid(&self) -> Option<f64> {
+        Some(self.scale.tick_size.to_f64(self.best_bid_raw()?))
     }
 
-    /// get undiscretized best ask price
-    pub fn best_ask(&self) -> Option<f32> {
-        let (ask_p, _ask_s) = self.asks.iter().next()?;
-        Some(self.undiscretize(*ask_p))
+    /// get approximate best ask price
+    pub fn best_ask(&self) -> Option<f64> {
+        Some(self.scale.tick_size.to_f64(self.best_ask_raw()?))
     }
 
     /// get midprice which is (bb + ba)/2
-    pub fn midprice(&self) -> Option<f32> {
+    pub fn midprice(&self) -> Option<f64> {
         let bb = self.best_bid()?;
         let ba = self.best_ask()?;
         Some((bb + ba) / 2.)
     }
+
+    /// if the best bid is at or above the best ask
+    pub fn is_crossed(&self) -> bool {
+        matches!((self.best_bid_raw(), self.best_ask_raw()), (Some(bid), Some(ask)) if bid >= ask)
+    }
+
+    /// remove the levels of the other side at or through the best price of the bid side
+    /// if `is_bid`, of the ask side otherwise. Returns the number of levels removed
+    pub fn uncross(&mut self, is_bid: bool) -> usize {
+        if is_bid {
+            match self.best_bid_raw() {
+                Some(bid) => {
+                    let asks = self.asks.split_off(&(bid + 1));
+                    std::mem::replace(&mut self.asks, asks).len()
+                }
+                None => 0,
+            }
+        } else {
+            match self.best_ask_raw() {
+                Some(ask) => self.bids.split_off(&ask).len(),
+                None => 0,
+            }
+        }
+    }
+
+    /// keep only the best `n` levels of each side
+    pub fn retain_depth(&mut self, n: usize) {
+        while self.bids.len() > n {
+            let lowest = *self.bids.keys().next().unwrap();
+            self.bids.remove(&lowest);
+        }
+        while self.asks.len() > n {
+            let highest = *self.asks.keys().next_back().unwrap();
+            self.asks.remove(&highest);
+        }
+    }
+
+    /// best `n` bids and asks as (price, size), best first
+    pub fn top_n(&self, n: usize) -> (Vec<Level>, Vec<Level>) {
+        let bids = self.bids.iter().rev().take(n).map(|(&p, &s)| (p, s)).collect();
+        let asks = self.asks.iter().take(n).map(|(&p, &s)| (p, s)).collect();
+        (bids, asks)
+    }
+
+    /// best `n` levels of each side with their cumulative sizes
+    pub fn depth(&self, n: usize) -> Depth {
+        let (bids, asks) = self.top_n(n);
+        Depth {
+            scale: self.scale,
+            bids: DepthLevel::cumulate(bids),
+            asks: DepthLevel::cumulate(asks),
+        }
+    }
+
+    /// Write the levels of the book: for bids then asks, (u32) number of levels
+    /// followed by (u64) price and (i64) size of each level, big endian
+    pub fn write_levels(&self, wtr: &mut dyn Write) -> Result<(), io::Error> {
+        for side in &[&self.bids, &self.asks] {
+            wtr.write_u32::<BigEndian>(side.len() as u32)?;
+            for (&price, &size) in side.iter() {
+                wtr.write_u64::<BigEndian>(price)?;
+                wtr.write_i64::<BigEndian>(size)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// read levels written by `write_levels` into a book of `scale`
+    pub fn read_levels(rdr: &mut dyn Read, scale: Scale) -> Result<Orderbook, io::Error> {
+        let mut ret = Orderbook::with_scale(scale);
+        for side in [&mut ret.bids, &mut ret.asks] {
+            let len = rdr.read_u32::<BigEndian>()?;
+            for _ in 0..len {
+                let price = rdr.read_u64::<BigEndian>()?;
+                side.insert(price, rdr.read_i64::<BigEndian>()?);
+            }
+        }
+        Ok(ret)
+    }
+}
+
+/// exact decimal representation of a level size, which can be negative after trades
+fn format_size(scale: &Scale, size: Size) -> String {
+    let abs = scale.format_size(size.unsigned_abs());
+    if size < 0 { format!("-{}", abs) } else { abs }
+}
+
+/// price level of a `Depth` snapshot
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DepthLevel {
+    /// price in ticks
+    pub price: Price,
+    /// size in lots
+    pub size: Size,
+    /// size of this level and every better level of the side
+    pub cum_size: Size,
+}
+
+impl DepthLevel {
+    fn cumulate(levels: Vec<Level>) -> Vec<DepthLevel> {
+        let mut cum_size = 0;
+        levels.into_iter().map(|(price, size)| {
+            cum_size += size;
+            DepthLevel { price, size, cum_size }
+        }).collect()
+    }
+}
+
+/// best levels of each side of an orderbook, best first
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Depth {
+    /// tick size and lot size of the book
+    pub scale: Scale,
+    /// best bids, highest first
+    pub bids: Vec<DepthLevel>,
+    /// best asks, lowest first
+    pub asks: Vec<DepthLevel>,
+}
+
+impl Depth {
+    /// `{"bids":[[price,size,cum_size],...],"asks":[...]}` formatted with the scale of the book
+    pub fn to_json(&self) -> String {
+        let scale = &self.scale;
+        let side = |levels: &[DepthLevel]| levels.iter()
+            .map(|l| format!("[{},{},{}]", scale.format_price(l.price), format_size(scale, l.size), format_size(scale, l.cum_size)))
+            .collect::<Vec<_>>()
+            .join(",");
+        format!(r#"{{"bids":[{}],"asks":[{}]}}"#, side(&self.bids), side(&self.asks))
+    }
+}
+
+/// Counts the anomalies of the updates applied to an orderbook
+///
+/// A book is counted as crossed once when an update crosses it. With `heal`, the levels of
+/// the other side crossed by the update are removed, the update being the most recent state.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Integrity {
+    /// remove the levels crossed by an update
+    pub heal: bool,
+    /// trades that left their level with a negative size
+    pub negative_levels: u64,
+    /// updates that crossed the book
+    pub crossed: u64,
+    /// trades at a price without a level
+    pub missing_levels: u64,
+    /// updates whose seq skipped sequence numbers after the previous update
+    pub seq_gaps: u64,
+    /// levels removed to uncross the book
+    pub healed_levels: u64,
+    last_seq: Option<u32>,
+}
+
+impl Integrity {
+    /// counter of anomalies, clearing crossed levels if `heal`
+    pub fn new(heal: bool) -> Self {
+        Integrity { heal, ..Default::default() }
+    }
+
+    /// apply `up` to `orderbook` like `Orderbook::process_update` and count its anomalies
+    pub fn process_update(&mut self, orderbook: &mut Orderbook, up: &Update) {
+        if let Some(next) = self.last_seq.and_then(|seq| seq.checked_add(1)) {
+            if up.seq > next {
+                self.seq_gaps += 1;
+            }
+        }
+        self.last_seq = Some(up.seq);
+        if up.is_trade {
+            let side = if up.is_bid {&orderbook.bids} else {&orderbook.asks};
+            match side.get(&up.price) {
+                None => self.missing_levels += 1,
+                Some(&size) if size < up.size as Size => self.negative_levels += 1,
+                Some(_) => (),
+            }
+        }
+        let was_crossed = orderbook.is_crossed();
+        orderbook.process_update(up);
+        if !was_crossed && orderbook.is_crossed() {
+            self.crossed += 1;
+            if self.heal {
+                self.healed_levels += orderbook.uncross(up.is_bid) as u64;
+            }
+        }
+    }
+
+    /// anomalies counted so far as a json object
+    pub fn to_json(&self) -> String {
+        format!(
+            r#"{{"negative_levels":{},"crossed":{},"missing_levels":{},"seq_gaps":{},"healed_levels":{}}}"#,
+            self.negative_levels,
+            self.crossed,
+            self.missing_levels,
+            self.seq_gaps,
+            self.healed_levels,
+        )
+    }
 }
 
 impl fmt::Debug for Orderbook {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let _ = write!(f, "bids:\n");
-        for (&price, size) in self.bids.iter() {
+        for (&price, &size) in self.bids.iter() {
             let _ = write!(
                 f,
                 "- price: {} \t - size: {}\n",
-                self.undiscretize(price),
-                size
+                self.scale.format_price(price),
+                format_size(&self.scale, size)
             );
         }
         let _ = write!(f, "\n");
 
         let _ = write!(f, "asks:\n");
-        for (&price, size) in self.asks.iter() {
+        for (&price, &size) in self.asks.iter() {
             let _ = write!(
                 f,
                 "- price: {} \t - size: {}\n",
-                self.undiscretize(price),
-                size
+                self.scale.format_price(price),
+                format_size(&self.scale, size)
             );
         }
         write!(f, "\n")
@@ -173,17 +348,17 @@ pub struct RebinnedOrderbook {
 
 impl RebinnedOrderbook {
     /// convert a list of updates to rebinned orderbook with fixed number of time steps bins and ticks bins
-    pub fn from(price_decimals: u8, ups: &[Update], step_bins: BinCount, tick_bins: BinCount, m: f64) -> RebinnedOrderbook {
+    pub fn from(scale: Scale, ups: &[Update], step_bins: BinCount, tick_bins: BinCount, m: f64) -> RebinnedOrderbook {
 
         // build histogram so later can put price and time into bins
         let (price_hist, step_hist) = Histogram::from(&ups, step_bins, tick_bins, m);
 
         // raw_price -> size
         // using a fine_level to track individual price level instead of a batched one
-        let mut fine_level = Orderbook::with_precision(price_decimals);
+        let mut fine_level = Orderbook::with_scale(scale);
         // coarse grained books, temp_ob keeps track of current level
         // coarse means rebinned(like snap to grid)
-        let mut temp_ob = Orderbook::with_precision(price_decimals);
+        let mut temp_ob = Orderbook::with_scale(scale);
         // coarse price orderbook across coarse time
         let mut ob_across_time = IndexMap::<Time, Orderbook>::new();
 
@@ -195,7 +370,7 @@ impl RebinnedOrderbook {
             }
 
             // rebinned ts, price
-            let ts = step_hist.to_bin((up.ts / 1000) as Size);
+            let ts = step_hist.to_bin((up.ts / 1000) as f64);
             let price = price_hist.to_bin(up.price as f64);
 
             // if is an outlier, don't update orderbook
@@ -203,7 +378,7 @@ impl RebinnedOrderbook {
                 continue;
             }
             let coarse_time = ts.unwrap().to_bits();
-            let coarse_price = temp_ob.discretize(price.unwrap() as f32);
+            let coarse_price = price.unwrap().round() as Price;
 
             // get coarse_size and update local book
             let coarse_size = {
@@ -216,7 +391,7 @@ impl RebinnedOrderbook {
                 } else {
                     &mut fine_level.asks
                 };
-                let fine_size = fine_book.entry(temp_ob.discretize(up.price)).or_insert(
+                let fine_size = fine_book.entry(up.price).or_insert(
                     up.size as Size,
                 );
 
@@ -246,8 +421,8 @@ impl RebinnedOrderbook {
                 // XXX: important
                 // there might be orders before the first cancellation
                 // we simply ignore those by setting the size to 0
-                if *coarse_size < 0. {
-                    *coarse_size = 0.;
+                if *coarse_size < 0 {
+                    *coarse_size = 0;
                 }
 
                 *coarse_size
@@ -298,13 +473,61 @@ mod tests {
     static FNAME: &str = "../../test/test-data/bt_btcnav.dtf";
     static ZRX: &str = "../../test/test-data/bnc_zrx_btc.dtf";
 
+    #[test]
+    fn should_return_depth() {
+        let mut ob = Orderbook::with_scale(Scale::default());
+        for (i, &price) in [100, 101, 102, 103, 104, 105].iter().enumerate() {
+            ob.process_update(&Update { ts: 0, seq: i as u32, is_trade: false, is_bid: price < 103, price, size: price - 99 });
+        }
+        let (bids, asks) = ob.top_n(2);
+        assert_eq!((bids, asks), (vec![(102, 3), (101, 2)], vec![(103, 4), (104, 5)]));
+        let depth = ob.depth(2);
+        assert_eq!(depth.bids.iter().map(|l| l.cum_size).collect::<Vec<_>>(), vec![3, 5]);
+        assert_eq!(depth.asks.iter().map(|l| l.cum_size).collect::<Vec<_>>(), vec![4, 9]);
+        assert_eq!(ob.depth(10).bids.len(), 3);
+    }
+
+    #[test]
+    fn should_count_anomalies() {
+        let up = |seq, is_trade, is_bid, price, size| Update { ts: 0, seq, is_trade, is_bid, price, size };
+        let ups = [
+            up(0, false, true, 100, 5),
+            up(1, false, false, 101, 5),
+            up(2, false, false, 102, 5),
+            up(3, true, true, 100, 7),
+            up(5, true, false, 103, 1),
+            up(6, false, true, 102, 1),
+            up(7, false, true, 102, 2),
+        ];
+        let mut ob = Orderbook::with_scale(Scale::default());
+        let mut integrity = Integrity::new(false);
+        for up in &ups {
+            integrity.process_update(&mut ob, up);
+        }
+        assert_eq!(ob.bids.get(&100), Some(&-2));
+        assert!(ob.is_crossed());
+        assert_eq!(
+            (integrity.negative_levels, integrity.missing_levels, integrity.seq_gaps, integrity.crossed),
+            (1, 1, 1, 1),
+        );
+
+        let mut ob = Orderbook::with_scale(Scale::default());
+        let mut integrity = Integrity::new(true);
+        for up in &ups {
+            integrity.process_update(&mut ob, up);
+        }
+        assert!(!ob.is_crossed());
+        assert_eq!(ob.asks.len(), 0);
+        assert_eq!((integrity.crossed, integrity.healed_levels), (1, 2));
+    }
+
     #[test]
     fn test_level_orderbook() {
         let step_bins = 100;
         let tick_bins = 100;
 
         let ups = dtf::file_format::decode(FNAME, Some(1000)).unwrap();
-        let ob = RebinnedOrderbook::from(10, ups.as_slice(), step_bins, tick_bins, 2.);
+        let ob = RebinnedOrderbook::from(Scale::default(), ups.as_slice(), step_bins, tick_bins, 2.);
 
         assert_eq!(ob.book.len(), step_bins - 1);
         for v in ob.book.values() {
@@ -317,7 +540,7 @@ mod tests {
     #[test]
     fn test_orderbook_real() {
         let ups = dtf::file_format::decode(ZRX, Some(1000)).unwrap();
-        let mut ob = Orderbook::with_precision(10);
+        let mut ob = Orderbook::with_scale(Scale::default());
         for i in &ups {
             ob.process_update(i);
         }
@@ -0,0 +1,119 @@
+//! Framing of the wire protocol
+//!
+//! A request is a `u32` length followed by the command. In version 1, a response
+//! is a status byte and a `u64` length followed by the payload.
+//!
+//! Once a connection is greeted with `HELLO 2`, every request carries a `u32` id
+//! after its length, and every response frame starts with a `Header` holding the
+//! content type of the payload and the id of its request, so that clients can
+//! send requests without waiting for responses. Messages pushed to subscribers
+//! have the id `PUSH_ID`. A stream of dtf batches starts with a `0x4` frame
+//! holding the scale of its updates as json, e.g. `{"tick_size":"0.01","lot_size":"1"}`.
+//!
+//! Integers are big endian.
+use std::io::{self, Read, Write};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+/// latest version of the protocol
+pub const VERSION: u32 = 2;
+
+/// capabilities of the server, those asked for by `HELLO` are acknowledged
+pub const CAPABILITIES: &[&str] = &["pipelining", "stream", "cursor", "subscribe", "binary_insert", "scale"];
+
+/// request id of the messages pushed to subscribers, never used by requests
+pub const PUSH_ID: u32 = 0;
+
+/// what the payload of a response frame is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ContentType {
+    /// utf-8 text such as `PONG`, a count or an error message
+    Text = 0x1,
+    /// a json document or json lines
+    Json = 0x2,
+    /// csv lines
+    Csv = 0x3,
+    /// batches of updates, see `dtf::file_format::decode_buffer`
+    Dtf = 0x4,
+    /// levels of an orderbook, see `Orderbook::write_levels`
+    Levels = 0x5,
+    /// an update encoded like a binary insert, see `utils::decode_insert_into`
+    Insert = 0x6,
+}
+
+impl ContentType {
+    /// content type of a tag, None when it is unknown
+    pub fn from_u8(tag: u8) -> Option<Self> {
+        use self::ContentType::*;
+        match tag {
+            0x1 => Some(Text),
+            0x2 => Some(Json),
+            0x3 => Some(Csv),
+            0x4 => Some(Dtf),
+            0x5 => Some(Levels),
+            0x6 => Some(Insert),
+            _ => None,
+        }
+    }
+}
+
+/// header of a response frame in version 2
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    /// `0x1` ok, `0x0` error, `0x2` chunk of a stream, `0x3` cursor of a page
+    /// or `0x4` scale of a stream of dtf batches
+    pub status: u8,
+    /// content type of the payload
+    pub content: ContentType,
+    /// id of the request, `PUSH_ID` for subscriptions
+    pub id: u32,
+    /// length of the payload
+    pub len: u64,
+}
+
+impl Header {
+    /// size of an encoded header
+    pub const SIZE: usize = 1 + 1 + 4 + 8;
+
+    /// write the header of a frame
+    pub fn write<W: Write>(&self, wtr: &mut W) -> io::Result<()> {
+        wtr.write_u8(self.status)?;
+        wtr.write_u8(self.content as u8)?;
+        wtr.write_u32::<BigEndian>(self.id)?;
+        wtr.write_u64::<BigEndian>(self.len)
+    }
+
+    /// read the header of a frame, an unknown content type is an error
+    pub fn read<R: Read>(rdr: &mut R) -> io::Result<Self> {
+        let status = rdr.read_u8()?;
+        let tag = rdr.read_u8()?;
+        let content = ContentType::from_u8(tag)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Unknown content type {}", tag)))?;
+        let id = rdr.read_u32::<BigEndian>()?;
+        let len = rdr.read_u64::<BigEndian>()?;
+        Ok(Header { status, content, id, len })
+    }
+}
+
+/// write a request of version 2
+pub fn write_request<W: Write>(wtr: &mut W, id: u32, command: &[u8]) -> io::Result<()> {
+    wtr.write_u32::<BigEndian>(command.len() as u32)?;
+    wtr.write_u32::<BigEndian>(id)?;
+    wtr.write_all(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_read_written_header() {
+        let header = Header { status: 0x2, content: ContentType::Dtf, id: 7, len: 1 << 40 };
+        let mut buf = vec![];
+        header.write(&mut buf).unwrap();
+        assert_eq!(buf.len(), Header::SIZE);
+        assert_eq!(Header::read(&mut buf.as_slice()).unwrap(), header);
+        buf[1] = 0x9;
+        assert!(Header::read(&mut buf.as_slice()).is_err());
+    }
+}
@@ -0,0 +1,178 @@
+//! Orderbook checkpoints of a book
+//!
+//! Checkpoints are stored in the dtf files of the book between batches (see the
+//! Checkpoint Spec of `dtf::file_format`), each holding the state of the orderbook
+//! right after an update so that the book at any time can be rebuilt by replaying
+//! only the updates after the closest checkpoint.
+
+use std::fs;
+use std::io;
+
+use crate::dtf::file_format::{
+    decode, encode_with_checkpoints, file_reader, files_for_range, for_each_from, last_checkpoint,
+    read_meta, read_version, CheckpointRecord, EncodeOptions,
+};
+use crate::dtf::scale::Scale;
+use crate::dtf::update::Update;
+use crate::postprocessing::orderbook::Orderbook;
+use crate::storage::partition::book_files;
+
+/// (ts, seq) of an update
+pub type Position = (u64, u32);
+
+/// state of the orderbook right after the update at (ts, seq)
+#[derive(Clone, Debug, PartialEq)]
+pub struct Checkpoint {
+    /// timestamp of the last update applied
+    pub ts: u64,
+    /// sequence number of the last update applied
+    pub seq: u32,
+    /// state of the book
+    pub orderbook: Orderbook,
+}
+
+impl Checkpoint {
+    /// the record stored in a dtf file
+    pub fn to_record(&self) -> CheckpointRecord {
+        let mut levels = vec![];
+        self.orderbook.write_levels(&mut levels).expect("writing to memory");
+        CheckpointRecord { ts: self.ts, seq: self.seq, levels }
+    }
+
+    /// read a record of a book of `scale`
+    pub fn from_record(record: &CheckpointRecord, scale: Scale) -> Result<Checkpoint, io::Error> {
+        let orderbook = Orderbook::read_levels(&mut &record.levels[..], scale)?;
+        Ok(Checkpoint { ts: record.ts, seq: record.seq, orderbook })
+    }
+}
+
+/// The last checkpoint at or before `ts` in a dtf file of a book of `scale`,
+/// None if there is none.
+pub fn latest(fname: &str, ts: u64, scale: Scale) -> Result<Option<Checkpoint>, io::Error> {
+    match last_checkpoint(fname, ts)? {
+        Some(record) => Ok(Some(Checkpoint::from_record(&record, scale)?)),
+        None => Ok(None),
+    }
+}
+
+/// Rebuild the orderbook of `book` at `ts` from its dtf files under `folder`, starting from
+/// the last checkpoint at or before `ts`.
+///
+/// Returns the book, the (ts, seq) of the last update applied to it and the number of
+/// updates replayed after the checkpoint.
+pub fn orderbook_at(folder: &str, book: &str, ts: u64, scale: Scale) -> Result<(Orderbook, Option<Position>, u64), io::Error> {
+    let files = files_for_range(folder, book, 0, ts)?;
+    let mut start = (0, None);
+    for (i, (fname, _meta)) in files.iter().enumerate().rev() {
+        if let Some(ck) = latest(fname, ts, scale)? {
+            start = (i, Some(ck));
+            break;
+        }
+    }
+    let (mut orderbook, mut last) = match start.1 {
+        Some(ck) => (ck.orderbook, Some((ck.ts, ck.seq))),
+        None => (Orderbook::with_scale(scale), None),
+    };
+    let min_ts = last.map(|(ts, _)| ts).unwrap_or(0);
+    let mut replayed = 0;
+    for (fname, _meta) in &files[start.0..] {
+        for_each_from(fname, None, min_ts, &mut |_, up| {
+            if up.ts > ts {
+                return false;
+            }
+            if is_after(last, up) {
+                orderbook.process_update(up);
+                last = Some((up.ts, up.seq));
+                replayed += 1;
+            }
+            true
+        })?;
+    }
+    Ok((orderbook, last, replayed))
+}
+
+/// if `up` comes after the update at `last`, or there is no such update
+pub fn is_after(last: Option<Position>, up: &Update) -> bool {
+    last.map(|last| (up.ts, up.seq) > last).unwrap_or(true)
+}
+
+/// Rewrite every dtf file of `book` under `folder` with a checkpoint every `interval` updates,
+/// replacing the checkpoints they had. Returns the number of checkpoints written.
+///
+/// Each file is decoded in memory and replaced once the new file is written.
+pub fn regenerate(folder: &str, book: &str, interval: u64) -> Result<usize, io::Error> {
+    let mut orderbook = None;
+    let mut count = 0;
+    let mut written = 0;
+    for fname in book_files(folder, book)? {
+        let meta = read_meta(&fname)?;
+        let version = read_version(&mut file_reader(&fname)?)?;
+        let ups = decode(&fname, None)?;
+        let orderbook = orderbook.get_or_insert_with(|| Orderbook::with_scale(meta.scale));
+        let mut records = vec![];
+        for up in &ups {
+            orderbook.process_update(up);
+            count += 1;
+            if interval != 0 && count % interval == 0 {
+                let ck = Checkpoint { ts: up.ts, seq: up.seq, orderbook: orderbook.clone() };
+                records.push(ck.to_record());
+            }
+        }
+        let opts = EncodeOptions { version, scale: meta.scale, properties: meta.properties };
+        let tmp = format!("{}.tmp", fname);
+        encode_with_checkpoints(&tmp, &meta.symbol, &ups, &records, &opts)?;
+        fs::rename(&tmp, &fname)?;
+        written += records.len();
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dtf::file_format::{append_with_checkpoints, encode, get_range_in_file};
+
+    #[test]
+    fn should_replay_from_checkpoints() {
+        let folder = "test-checkpoints";
+        fs::create_dir_all(folder).unwrap();
+        let fname = &format!("{}/book.dtf", folder);
+        let scale = Scale::default();
+        let ups: Vec<Update> = (0..100).map(|i| Update {
+            ts: i * 1000, seq: i as u32, is_trade: false, is_bid: i % 2 == 0, price: 100 + i % 10, size: i,
+        }).collect();
+
+        // a checkpoint every 30 updates, replaying everything up to each of them
+        let mut full = Orderbook::with_scale(scale);
+        let mut checkpoints = vec![];
+        for up in &ups {
+            full.process_update(up);
+            if up.seq % 30 == 29 {
+                checkpoints.push(Checkpoint { ts: up.ts, seq: up.seq, orderbook: full.clone() });
+            }
+        }
+        let records: Vec<CheckpointRecord> = checkpoints.iter().map(Checkpoint::to_record).collect();
+        encode_with_checkpoints(fname, "book", &ups[..50], &records[..1], &Default::default()).unwrap();
+        append_with_checkpoints(fname, &ups[50..], &records[1..]).unwrap();
+        assert_eq!(latest(fname, 65_000, scale).unwrap(), Some(checkpoints[1].clone()));
+        // readers of updates skip the checkpoints
+        assert_eq!(decode(fname, None).unwrap(), ups);
+        assert_eq!(get_range_in_file(fname, 25_000, 65_000).unwrap(), &ups[25..=65]);
+
+        let mut expected = Orderbook::with_scale(scale);
+        for up in &ups[..=65] {
+            expected.process_update(up);
+        }
+        let (orderbook, last, replayed) = orderbook_at(folder, "book", 65_000, scale).unwrap();
+        assert_eq!((last, replayed), (Some((65_000, 65)), 6));
+        assert_eq!(orderbook, expected);
+        assert_eq!(orderbook_at(folder, "book", 10_000, scale).unwrap().2, 11);
+
+        // checkpoints of files written without them
+        encode(fname, "book", &ups).unwrap();
+        assert_eq!(regenerate(folder, "book", 30).unwrap(), 3);
+        assert_eq!(latest(fname, u64::MAX, scale).unwrap(), Some(checkpoints[2].clone()));
+        assert_eq!(decode(fname, None).unwrap(), ups);
+        fs::remove_dir_all(folder).unwrap();
+    }
+}
@@ -73,8 +73,15 @@ impl DTFFileMetadata {
     pub fn new(fname: &str) -> Result<DTFFileMetadata, io::Error> {
         let metadata: Metadata = read_meta(fname)?;
         let file_size = fs::metadata(fname)?.len();
+        // properties in the header take precedence over the parts of the symbol
+        let props = &metadata.properties;
         let symbol = match Symbol::from_str(&metadata.symbol) {
             Ok(sym) => sym,
+            Err(()) if props.contains_key("exchange") => Symbol {
+                exchange: String::new(),
+                currency: String::new(),
+                asset: String::new(),
+            },
             Err(()) => {
                 return Err(io::Error::new(
                     io::ErrorKind::InvalidData,
@@ -82,6 +89,14 @@ impl DTFFileMetadata {
                 ));
             }
         };
+        let prop_or = |key: &str, default: String| props.get(key).cloned().unwrap_or(default);
+        let asset_type = match props.get("asset_type") {
+            Some(asset_type) => AssetType::from_str(asset_type).map_err(|()| io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown asset type {}", asset_type),
+            ))?,
+            None => AssetType::SPOT,
+        };
         let first_epoch = metadata.min_ts;
         let last_epoch = metadata.max_ts;
         let total_updates = metadata.count;
@@ -89,10 +104,10 @@ impl DTFFileMetadata {
         Ok(DTFFileMetadata {
             file_type: FileType::RawDtf,
             file_size,
-            exchange: symbol.exchange,
-            currency: symbol.currency,
-            asset: symbol.asset,
-            asset_type: AssetType::SPOT,
+            exchange: prop_or("exchange", symbol.exchange),
+            currency: prop_or("quote", symbol.currency),
+            asset: prop_or("base", symbol.asset),
+            asset_type,
             first_epoch,
             last_epoch,
             total_updates,
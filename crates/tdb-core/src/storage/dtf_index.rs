@@ -0,0 +1,196 @@
+//! Sparse block index stored in the footer of a dtf file
+//!
+//! Footer Spec:
+//! Offset 00: (u8) marker 0x2, readers walking batches stop here
+//! Offset 01: (u64) number of updates covered by the index
+//! Offset 09: (u64) number of entries
+//! Offset 17: -- entries --
+//!        ref_ts (u64): reference ts of the batch
+//!        offset (u64): absolute offset of the batch in the file
+//!        first_idx (u64): index of the first update of the batch in the file
+//!
+//! The header stores the offset of the footer (0 if the file has no index).
+//! An entry is only recorded every `INDEX_STRIDE` bytes so the footer stays small,
+//! readers seek to the closest entry and walk the remaining batch headers.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+/// marker byte of the footer
+pub const INDEX_MARKER: u8 = 0x2;
+/// minimum distance in bytes between two indexed batches
+pub const INDEX_STRIDE: u64 = 64 * 1024;
+/// size in bytes of an entry in the footer
+const ENTRY_SIZE: u64 = 24;
+
+/// Indexed batch
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IndexEntry {
+    /// reference ts of the batch
+    pub ref_ts: u64,
+    /// absolute offset of the batch in the file
+    pub offset: u64,
+    /// index of the first update of the batch in the file
+    pub first_idx: u64,
+}
+
+/// Sparse index of batch offsets -> `ref_ts`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DTFIndex {
+    /// number of updates covered by the index
+    pub count: u64,
+    /// indexed batches sorted by offset
+    pub entries: Vec<IndexEntry>,
+}
+
+impl DTFIndex {
+    /// record a batch of `len` updates written at `offset`
+    pub fn push_batch(&mut self, offset: u64, ref_ts: u64, len: u64) {
+        let due = self.entries.last()
+            .map(|last| offset >= last.offset + INDEX_STRIDE)
+            .unwrap_or(true);
+        if due {
+            self.entries.push(IndexEntry { ref_ts, offset, first_idx: self.count });
+        }
+        self.count += len;
+    }
+
+    /// forget every batch at or after `offset`, `count` is the number of updates before it
+    pub fn truncate(&mut self, offset: u64, count: u64) {
+        self.entries.retain(|entry| entry.offset < offset);
+        self.count = count;
+    }
+
+    /// the last indexed batch starting before `ts`,
+    /// in a sorted file every update before it is older than `ts`
+    pub fn seek_ts(&self, ts: u64) -> Option<&IndexEntry> {
+        let i = self.entries.partition_point(|entry| entry.ref_ts < ts);
+        self.entries.get(i.checked_sub(1)?)
+    }
+
+    /// the last indexed batch that starts at or before the `i`-th update
+    pub fn seek_idx(&self, i: u64) -> Option<&IndexEntry> {
+        let pos = self.entries.partition_point(|entry| entry.first_idx <= i);
+        self.entries.get(pos.checked_sub(1)?)
+    }
+
+    /// read the footer at `offset`
+    pub fn read_from<T: Read + Seek>(rdr: &mut T, offset: u64) -> Result<DTFIndex, io::Error> {
+        rdr.seek(SeekFrom::Start(offset))?;
+        if rdr.read_u8()? != INDEX_MARKER {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Index marker incorrect"));
+        }
+        let count = rdr.read_u64::<BigEndian>()?;
+        let len = rdr.read_u64::<BigEndian>()?;
+        let mut entries = Vec::with_capacity(len as usize);
+        for _ in 0..len {
+            let ref_ts = rdr.read_u64::<BigEndian>()?;
+            let offset = rdr.read_u64::<BigEndian>()?;
+            let first_idx = rdr.read_u64::<BigEndian>()?;
+            entries.push(IndexEntry { ref_ts, offset, first_idx });
+        }
+        Ok(DTFIndex { count, entries })
+    }
+
+    /// number of updates covered by the footer at `offset`
+    pub fn read_count<T: Read + Seek>(rdr: &mut T, offset: u64) -> Result<u64, io::Error> {
+        rdr.seek(SeekFrom::Start(offset))?;
+        if rdr.read_u8()? != INDEX_MARKER {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Index marker incorrect"));
+        }
+        rdr.read_u64::<BigEndian>()
+    }
+
+    /// binary search the footer at `offset` on disk without loading every entry,
+    /// returns the last entry for which `pred` holds
+    pub fn find<T: Read + Seek, F: Fn(&IndexEntry) -> bool>(rdr: &mut T, offset: u64, pred: F)
+        -> Result<Option<IndexEntry>, io::Error>
+    {
+        rdr.seek(SeekFrom::Start(offset + 9))?;
+        let len = rdr.read_u64::<BigEndian>()?;
+        let read_entry = |rdr: &mut T, i: u64| -> Result<IndexEntry, io::Error> {
+            rdr.seek(SeekFrom::Start(offset + 17 + i * ENTRY_SIZE))?;
+            let ref_ts = rdr.read_u64::<BigEndian>()?;
+            let offset = rdr.read_u64::<BigEndian>()?;
+            let first_idx = rdr.read_u64::<BigEndian>()?;
+            Ok(IndexEntry { ref_ts, offset, first_idx })
+        };
+
+        let (mut lo, mut hi) = (0, len);
+        let mut found = None;
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let entry = read_entry(rdr, mid)?;
+            if pred(&entry) {
+                found = Some(entry);
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        Ok(found)
+    }
+
+    /// write the footer at the current position
+    pub fn write_to(&self, wtr: &mut dyn Write) -> Result<(), io::Error> {
+        wtr.write_u8(INDEX_MARKER)?;
+        wtr.write_u64::<BigEndian>(self.count)?;
+        wtr.write_u64::<BigEndian>(self.entries.len() as u64)?;
+        for entry in &self.entries {
+            wtr.write_u64::<BigEndian>(entry.ref_ts)?;
+            wtr.write_u64::<BigEndian>(entry.offset)?;
+            wtr.write_u64::<BigEndian>(entry.first_idx)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn sample_index() -> DTFIndex {
+        let mut idx = DTFIndex::default();
+        for i in 0..100 {
+            idx.push_batch(80 + i * INDEX_STRIDE / 2, i * 1000, 10);
+        }
+        idx
+    }
+
+    #[test]
+    fn should_index_sparsely() {
+        let idx = sample_index();
+        assert_eq!(idx.count, 1000);
+        assert_eq!(idx.entries.len(), 50);
+        assert_eq!(idx.entries[1], IndexEntry { ref_ts: 2000, offset: 80 + INDEX_STRIDE, first_idx: 20 });
+    }
+
+    #[test]
+    fn should_seek() {
+        let idx = sample_index();
+        assert_eq!(idx.seek_ts(0), None);
+        assert_eq!(idx.seek_ts(2000).unwrap().ref_ts, 0);
+        assert_eq!(idx.seek_ts(2001).unwrap().ref_ts, 2000);
+        assert_eq!(idx.seek_ts(50_500).unwrap().ref_ts, 50_000);
+        assert_eq!(idx.seek_idx(0).unwrap().first_idx, 0);
+        assert_eq!(idx.seek_idx(39).unwrap().first_idx, 20);
+        assert_eq!(idx.seek_idx(40).unwrap().first_idx, 40);
+    }
+
+    #[test]
+    fn should_roundtrip_footer() {
+        let idx = sample_index();
+        let mut buf = Cursor::new(vec![0; 10]);
+        buf.seek(SeekFrom::Start(10)).unwrap();
+        idx.write_to(&mut buf).unwrap();
+        assert_eq!(DTFIndex::read_from(&mut buf, 10).unwrap(), idx);
+        assert!(DTFIndex::read_from(&mut buf, 0).is_err());
+        assert_eq!(DTFIndex::read_count(&mut buf, 10).unwrap(), 1000);
+
+        for ts in &[0, 1, 2000, 2001, 50_500, 1_000_000] {
+            let on_disk = DTFIndex::find(&mut buf, 10, |e| e.ref_ts < *ts).unwrap();
+            assert_eq!(on_disk.as_ref(), idx.seek_ts(*ts));
+        }
+    }
+}
@@ -5,7 +5,8 @@ use csv::{DeserializeRecordsIntoIter, ReaderBuilder};
 use std::path::Path;
 use crate::dtf::{
     update::Update,
-    file_format::{append, encode},
+    scale::{Decimal, Scale},
+    file_format::{append, encode, read_meta},
 };
 
 /// File types for storing financial data, currently there's only RawDtf
@@ -46,21 +47,23 @@ struct KaikoCsvEntry {
     pub exchange: String,
     pub symbol: String,
     pub date: u64,
-    pub price: f32,
-    pub amount: f32,
+    pub price: Decimal,
+    pub amount: Decimal,
     pub sell: Option<bool>,
 }
 
-impl Into<Update> for KaikoCsvEntry {
-    fn into(self) -> Update {
-        Update {
+impl KaikoCsvEntry {
+    fn into_update(self, scale: &Scale) -> Result<Update, String> {
+        Ok(Update {
             ts: self.date,
             seq: self.id.parse().unwrap_or(0),
             is_trade: true,
             is_bid: !self.sell.unwrap_or(false),
-            price: self.price,
-            size: self.amount,
-        }
+            price: scale.ticks(self.price)
+                .ok_or_else(|| format!("Price {} is not a multiple of the tick size {}", self.price, scale.tick_size))?,
+            size: scale.lots(self.amount)
+                .ok_or_else(|| format!("Amount {} is not a multiple of the lot size {}", self.amount, scale.lot_size))?,
+        })
     }
 }
 
@@ -74,15 +77,25 @@ pub(crate) fn parse_kaiko_csv_to_dtf_inner(symbol: &str, filename: &str, csv_str
     let size_hint = iter.size_hint().0;
     let mut updates: Vec<Update> = Vec::with_capacity(size_hint);
 
+    // prices and sizes are scaled like the updates already in the target file
+    let fpath = Path::new(&filename);
+    let scale = if fpath.exists() {
+        match read_meta(filename) {
+            Ok(meta) => meta.scale,
+            Err(err) => { return Some(format!("Error reading DTF output file: {:?}", err)); }
+        }
+    } else {
+        Scale::default()
+    };
+
     for kaiko_entry_res in iter {
-        match kaiko_entry_res {
-            Ok(kaiko_entry) => updates.push(kaiko_entry.into()),
-            Err(err) => { return Some(format!("{:?}", err)); }
+        match kaiko_entry_res.map_err(|err| format!("{:?}", err)).and_then(|entry| entry.into_update(&scale)) {
+            Ok(update) => updates.push(update),
+            Err(err) => { return Some(err); }
         }
     }
 
     // Write or append the updates into the target DTF file
-    let fpath = Path::new(&filename);
     let res = if fpath.exists() {
         append(filename, &updates)
     } else {
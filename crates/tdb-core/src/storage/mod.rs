@@ -1,3 +1,5 @@
+/// Sparse block index in the footer of dtf files
+pub mod dtf_index;
 /// Metadata data structure for dtf file
 pub mod dtf_file_metadata;
 /// Financial data file types
@@ -6,3 +8,7 @@ pub mod filetype;
 pub mod file_metadata;
 /// Utility functions
 pub mod utils;
+/// Time-partitioned dtf files of a book
+pub mod partition;
+/// Orderbook checkpoints stored next to the dtf files of a book
+pub mod checkpoint;
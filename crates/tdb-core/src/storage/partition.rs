@@ -0,0 +1,171 @@
+//! Time-partitioned layout of the dtf files of a book
+//!
+//! Without partitioning a book is stored in a single `{dtf_folder}/{book}.dtf` file.
+//! With partitioning every period gets its own file `{dtf_folder}/{book}/{period}.dtf`,
+//! where period is `YYYY-MM-DDTHH`, `YYYY-MM-DD` or `YYYY-MM` (UTC).
+
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use chrono::{DateTime, Datelike, Duration, NaiveDate};
+
+/// How the updates of a book are split into files
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Partitioning {
+    /// one file per book
+    #[default]
+    None,
+    /// one file per book and hour
+    Hourly,
+    /// one file per book and day
+    Daily,
+    /// one file per book and month
+    Monthly,
+}
+
+impl FromStr for Partitioning {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Partitioning::None),
+            "hourly" => Ok(Partitioning::Hourly),
+            "daily" => Ok(Partitioning::Daily),
+            "monthly" => Ok(Partitioning::Monthly),
+            _ => Err(format!("Invalid partitioning: `{}`", s)),
+        }
+    }
+}
+
+impl Partitioning {
+    /// name of the partition containing `ts` (in ms), None without partitioning,
+    /// fails if `ts` is out of the range of dates
+    pub fn partition_name(self, ts: u64) -> Result<Option<String>, io::Error> {
+        let fmt = match self {
+            Partitioning::None => return Ok(None),
+            Partitioning::Hourly => "%Y-%m-%dT%H",
+            Partitioning::Daily => "%Y-%m-%d",
+            Partitioning::Monthly => "%Y-%m",
+        };
+        let datetime = i64::try_from(ts)
+            .ok()
+            .and_then(DateTime::from_timestamp_millis)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Timestamp {} is out of range", ts)))?;
+        Ok(Some(datetime.format(fmt).to_string()))
+    }
+
+    /// path of the file holding the updates of `book` at `ts`
+    pub fn file_path(self, folder: &str, book: &str, ts: u64) -> Result<String, io::Error> {
+        Ok(match self.partition_name(ts)? {
+            Some(name) => format!("{}/{}/{}.dtf", folder, book, name),
+            None => format!("{}/{}.dtf", folder, book),
+        })
+    }
+}
+
+/// A partition file of a book
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Partition {
+    /// path of the file
+    pub fname: String,
+    /// first timestamp covered by the partition
+    pub start: u64,
+    /// first timestamp after the partition
+    pub end: u64,
+}
+
+impl Partition {
+    /// if the partition may have updates in `[min_ts, max_ts]`
+    pub fn overlaps(&self, min_ts: u64, max_ts: u64) -> bool {
+        self.start <= max_ts && min_ts < self.end
+    }
+}
+
+/// time range `[start, end)` in ms covered by a partition name
+pub fn partition_range(name: &str) -> Option<(u64, u64)> {
+    let (start, end) = if let Some((date, hour)) = name.split_once('T') {
+        if hour.len() != 2 {
+            return None;
+        }
+        let start = NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()?
+            .and_hms_opt(hour.parse().ok()?, 0, 0)?;
+        (start, start + Duration::hours(1))
+    } else if name.len() == "YYYY-MM-DD".len() {
+        let start = NaiveDate::parse_from_str(name, "%Y-%m-%d").ok()?.and_hms_opt(0, 0, 0)?;
+        (start, start + Duration::days(1))
+    } else {
+        let start = NaiveDate::parse_from_str(&format!("{}-01", name), "%Y-%m-%d").ok()?;
+        let end = if start.month() == 12 {
+            NaiveDate::from_ymd_opt(start.year() + 1, 1, 1)?
+        } else {
+            NaiveDate::from_ymd_opt(start.year(), start.month() + 1, 1)?
+        };
+        (start.and_hms_opt(0, 0, 0)?, end.and_hms_opt(0, 0, 0)?)
+    };
+    Some((
+        u64::try_from(start.and_utc().timestamp_millis()).ok()?,
+        u64::try_from(end.and_utc().timestamp_millis()).ok()?,
+    ))
+}
+
+/// partitions of `book` in `folder` sorted by time
+pub fn partitions(folder: &str, book: &str) -> Result<Vec<Partition>, io::Error> {
+    let dir = format!("{}/{}", folder, book);
+    if !Path::new(&dir).is_dir() {
+        return Ok(vec![]);
+    }
+    let mut ret = vec![];
+    for entry in fs::read_dir(&dir)? {
+        let fname = entry?.file_name();
+        let fname = match fname.to_str() {
+            Some(fname) => fname,
+            None => continue,
+        };
+        let range = fname.strip_suffix(".dtf").and_then(partition_range);
+        if let Some((start, end)) = range {
+            ret.push(Partition { fname: format!("{}/{}", dir, fname), start, end });
+        }
+    }
+    ret.sort_by_key(|p| p.start);
+    Ok(ret)
+}
+
+/// every dtf file of `book` in `folder`, the unpartitioned file first followed by the partitions
+pub fn book_files(folder: &str, book: &str) -> Result<Vec<String>, io::Error> {
+    let mut ret = vec![];
+    let fname = format!("{}/{}.dtf", folder, book);
+    if Path::new(&fname).is_file() {
+        ret.push(fname);
+    }
+    ret.extend(partitions(folder, book)?.into_iter().map(|p| p.fname));
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_name_partitions() {
+        let ts = 1_513_749_530_585; // 2017-12-20 05:58:50 UTC
+        assert_eq!(Partitioning::None.file_path("db", "bnc_btc_eth", ts).unwrap(), "db/bnc_btc_eth.dtf");
+        assert_eq!(Partitioning::Daily.file_path("db", "bnc_btc_eth", ts).unwrap(), "db/bnc_btc_eth/2017-12-20.dtf");
+        assert_eq!(Partitioning::Hourly.partition_name(ts).unwrap().unwrap(), "2017-12-20T05");
+        assert_eq!(Partitioning::Monthly.partition_name(ts).unwrap().unwrap(), "2017-12");
+
+        for partitioning in &[Partitioning::Hourly, Partitioning::Daily, Partitioning::Monthly] {
+            let name = partitioning.partition_name(ts).unwrap().unwrap();
+            let (start, end) = partition_range(&name).unwrap();
+            assert!(start <= ts && ts < end, "{}", name);
+            assert_eq!(partitioning.partition_name(end - 1).unwrap().unwrap(), name);
+            assert_ne!(partitioning.partition_name(end).unwrap().unwrap(), name);
+        }
+        assert_eq!(partition_range("bnc_btc_eth"), None);
+
+        // timestamps past the range of dates
+        assert!(Partitioning::Daily.partition_name(i64::MAX as u64).is_err());
+        assert!(Partitioning::Daily.partition_name(u64::MAX).is_err());
+        assert_eq!(Partitioning::None.file_path("db", "bnc_btc_eth", u64::MAX).unwrap(), "db/bnc_btc_eth.dtf");
+    }
+}
@@ -0,0 +1,138 @@
+//! Per-book actors
+//!
+//! Every book is owned by its own thread that processes the messages of its queue
+//! one at a time. Books are read and written concurrently with each other: a
+//! book flushing or serving a large read only holds back the commands on it,
+//! and its blocking disk I/O never holds up the executor of the connections.
+//! Flushes, loads and retention run on a thread of their own (see `spawn_io`),
+//! one at a time, so that the book keeps serving commands meanwhile.
+use crate::prelude::*;
+use crate::wal::Wal;
+use async_std::future::{self, TimeoutError};
+use futures::select;
+use std::sync::RwLock;
+use std::thread;
+use std::time::Duration;
+
+/// the actors of every book by name, shared by the broker and the connections
+pub type Books = Arc<RwLock<HashMap<BookName, BookHandle>>>;
+
+/// messages processed by the actor of a book
+pub enum BookMsg {
+    /// a command of a connection on the book and where to send its response
+    Command {
+        command: Command,
+        /// the connection and its outbound queue, for subscriptions
+        from: (SocketAddr, Sender<ReturnType>),
+        reply: oneshot::Sender<ReturnType>,
+    },
+    /// work of the broker on the book
+    With(Box<dyn FnOnce(&mut Book) + Send>),
+}
+
+/// queue of the actor of a book
+#[derive(Clone)]
+pub struct BookHandle {
+    tx: Sender<BookMsg>,
+}
+
+impl BookHandle {
+    /// spawn the actor owning `book`, it stops once every handle is dropped
+    pub fn spawn(book: Book) -> Self {
+        let (tx, rx) = mpsc::channel(CHANNEL_SZ);
+        thread::Builder::new()
+            .name(format!("book-{}", book.name))
+            .spawn(move || task::block_on(book_loop(book, rx)))
+            .expect("unable to spawn book thread");
+        BookHandle { tx }
+    }
+
+    /// queue a command of the connection `from`, its response is sent to `reply`
+    pub async fn command(&mut self, command: Command, from: (SocketAddr, Sender<ReturnType>), reply: oneshot::Sender<ReturnType>) {
+        if self.tx.send(BookMsg::Command { command, from, reply }).await.is_err() {
+            error!("book actor stopped");
+        }
+    }
+
+    /// run `f` on the book and wait for its result, None if the actor stopped
+    pub async fn ask<T, F>(&mut self, f: F) -> Option<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Book) -> T + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(BookMsg::With(Box::new(move |book| {
+            let _ = tx.send(f(book));
+        }))).await.ok()?;
+        rx.await.ok()
+    }
+
+    /// run `f` on the book without waiting for it
+    pub async fn tell<F>(&mut self, f: F)
+    where
+        F: FnOnce(&mut Book) + Send + 'static,
+    {
+        if self.tx.send(BookMsg::With(Box::new(f))).await.is_err() {
+            error!("book actor stopped");
+        }
+    }
+}
+
+/// run blocking disk I/O of the book `name` on its own thread
+pub(crate) fn spawn_io<F: FnOnce() + Send + 'static>(name: &str, f: F) {
+    thread::Builder::new()
+        .name(format!("io-{}", name))
+        .spawn(f)
+        .expect("unable to spawn io thread");
+}
+
+/// the next message, or an error once `sync` is up
+async fn next_message(messages: &mut Receiver<BookMsg>, sync: Option<Duration>) -> std::result::Result<Option<BookMsg>, TimeoutError> {
+    match sync {
+        Some(due) => future::timeout(due, messages.next()).await,
+        None => Ok(messages.next().await),
+    }
+}
+
+/// processes the messages of the book, applies the disk I/O in progress
+/// as soon as it is done and syncs its write-ahead log when it is due
+async fn book_loop(mut book: Book, mut messages: Receiver<BookMsg>) {
+    loop {
+        let sync = book.wal.as_ref().and_then(Wal::sync_due);
+        let msg = match book.io.take() {
+            Some(mut io) => select! {
+                msg = next_message(&mut messages, sync).fuse() => {
+                    book.io = Some(io);
+                    msg
+                },
+                done = io => {
+                    book.done(done.unwrap_or_else(|_| Box::new(Book::io_dropped)));
+                    continue;
+                },
+            },
+            None => next_message(&mut messages, sync).await,
+        };
+        let msg = match msg {
+            Ok(Some(msg)) => msg,
+            Ok(None) => break,
+            Err(_) => {
+                book.sync_wal();
+                continue;
+            }
+        };
+        match msg {
+            BookMsg::Command { command, from, reply } => {
+                let ret = book.process_command(command, from);
+                let _ = reply.send(ret);
+            }
+            BookMsg::With(f) => f(&mut book),
+        }
+    }
+    // the updates being flushed are kept in the log until they are written
+    while let Some(io) = book.io.take() {
+        if let Ok(done) = io.await {
+            book.done(done);
+        }
+    }
+    book.sync_wal();
+}
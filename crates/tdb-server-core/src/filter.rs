@@ -0,0 +1,172 @@
+//! predicates of `GET ... WHERE`
+//!
+//! A `Filter` is parsed with decimal prices and sizes and compiled against the
+//! scale of a book into a `Predicate` on ticks and lots, so that updates can be
+//! tested while they are read without converting them back to decimals.
+use crate::prelude::*;
+use tdb_core::dtf::scale::{Decimal, Scale};
+
+/// field compared in a filter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Price,
+    Size,
+}
+
+/// comparison operator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+/// parsed `WHERE` expression
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Filter {
+    IsTrade,
+    IsBid,
+    Compare(Field, Op, Decimal),
+    /// inclusive on both ends
+    Between(Field, Decimal, Decimal),
+    Not(Box<Filter>),
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+}
+
+/// `Filter` on the integer price and size of updates
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Predicate {
+    IsTrade,
+    IsBid,
+    /// field is within the inclusive range, never matches when empty
+    Range(Field, u64, u64),
+    Not(Box<Predicate>),
+    And(Box<Predicate>, Box<Predicate>),
+    Or(Box<Predicate>, Box<Predicate>),
+}
+
+/// an empty inclusive range
+const EMPTY: (u64, u64) = (1, 0);
+
+/// smallest number of units that is `>= value`
+fn ceil_units(value: Decimal, step: Decimal) -> Option<u64> {
+    match value.floor_units(step) {
+        (units, true) => Some(units),
+        (units, false) => units.checked_add(1),
+    }
+}
+
+/// inclusive range of units matching `op value`
+fn op_range(op: Op, value: Decimal, step: Decimal) -> (u64, u64) {
+    let (floor, exact) = value.floor_units(step);
+    match op {
+        Op::Lt => match ceil_units(value, step) {
+            Some(0) => EMPTY,
+            Some(ceil) => (0, ceil - 1),
+            None => (0, u64::MAX),
+        },
+        Op::Le => (0, floor),
+        Op::Gt => match floor.checked_add(1) {
+            Some(min) => (min, u64::MAX),
+            None => EMPTY,
+        },
+        Op::Ge => match ceil_units(value, step) {
+            Some(min) => (min, u64::MAX),
+            None => EMPTY,
+        },
+        Op::Eq | Op::Ne if exact => (floor, floor),
+        Op::Eq | Op::Ne => EMPTY,
+    }
+}
+
+impl Filter {
+    /// predicate on updates whose price and size are in ticks and lots of `scale`
+    pub fn compile(&self, scale: &Scale) -> Predicate {
+        let step = |field| match field {
+            Field::Price => scale.tick_size,
+            Field::Size => scale.lot_size,
+        };
+        match self {
+            Filter::IsTrade => Predicate::IsTrade,
+            Filter::IsBid => Predicate::IsBid,
+            Filter::Compare(field, op, value) => {
+                let (min, max) = op_range(*op, *value, step(*field));
+                let range = Predicate::Range(*field, min, max);
+                if *op == Op::Ne { Predicate::Not(Box::new(range)) } else { range }
+            }
+            Filter::Between(field, low, high) => {
+                let min = ceil_units(*low, step(*field));
+                let (max, _) = high.floor_units(step(*field));
+                match min {
+                    Some(min) => Predicate::Range(*field, min, max),
+                    None => Predicate::Range(*field, EMPTY.0, EMPTY.1),
+                }
+            }
+            Filter::Not(f) => Predicate::Not(Box::new(f.compile(scale))),
+            Filter::And(a, b) => Predicate::And(Box::new(a.compile(scale)), Box::new(b.compile(scale))),
+            Filter::Or(a, b) => Predicate::Or(Box::new(a.compile(scale)), Box::new(b.compile(scale))),
+        }
+    }
+}
+
+impl Predicate {
+    /// if `up` satisfies the predicate
+    pub fn matches(&self, up: &Update) -> bool {
+        match self {
+            Predicate::IsTrade => up.is_trade,
+            Predicate::IsBid => up.is_bid,
+            Predicate::Range(Field::Price, min, max) => *min <= up.price && up.price <= *max,
+            Predicate::Range(Field::Size, min, max) => *min <= up.size && up.size <= *max,
+            Predicate::Not(p) => !p.matches(up),
+            Predicate::And(a, b) => a.matches(up) && b.matches(up),
+            Predicate::Or(a, b) => a.matches(up) || b.matches(up),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn up(is_trade: bool, price: u64, size: u64) -> Update {
+        Update { ts: 0, seq: 0, is_trade, is_bid: true, price, size }
+    }
+
+    #[test]
+    fn should_round_bounds_to_ticks() {
+        let scale = Scale::new(Decimal::new(5, 1), Decimal::new(1, 0)).unwrap();
+        let price = |op, v: &str| Filter::Compare(Field::Price, op, v.parse().unwrap()).compile(&scale);
+        // ticks of 0.5, 10.25 is between 20 and 21 ticks
+        assert_eq!(price(Op::Gt, "10.25"), Predicate::Range(Field::Price, 21, u64::MAX));
+        assert_eq!(price(Op::Ge, "10.5"), Predicate::Range(Field::Price, 21, u64::MAX));
+        assert_eq!(price(Op::Lt, "10.25"), Predicate::Range(Field::Price, 0, 20));
+        assert_eq!(price(Op::Lt, "10.5"), Predicate::Range(Field::Price, 0, 20));
+        assert_eq!(price(Op::Le, "10.25"), Predicate::Range(Field::Price, 0, 20));
+        assert!(!price(Op::Lt, "0").matches(&up(false, 0, 0)));
+        assert!(!price(Op::Eq, "10.25").matches(&up(false, 20, 0)));
+        assert!(price(Op::Ne, "10.25").matches(&up(false, 20, 0)));
+    }
+
+    #[test]
+    fn should_match_updates() {
+        let scale = Scale::new(Decimal::new(1, 2), Decimal::new(1, 1)).unwrap();
+        // is_trade AND price BETWEEN 1 AND 1.5 AND size > 0.1
+        let filter = Filter::And(
+            Box::new(Filter::And(
+                Box::new(Filter::IsTrade),
+                Box::new(Filter::Between(Field::Price, "1".parse().unwrap(), "1.5".parse().unwrap())),
+            )),
+            Box::new(Filter::Compare(Field::Size, Op::Gt, "0.1".parse().unwrap())),
+        );
+        let pred = filter.compile(&scale);
+        assert!(pred.matches(&up(true, 100, 2)));
+        assert!(pred.matches(&up(true, 150, 2)));
+        assert!(!pred.matches(&up(false, 120, 2)));
+        assert!(!pred.matches(&up(true, 151, 2)));
+        assert!(!pred.matches(&up(true, 120, 1)));
+    }
+}
@@ -1,18 +1,58 @@
 use crate::prelude::*;
+use crate::parser::DecimalUpdate;
+use tdb_core::dtf::scale::Scale;
+use tdb_core::dtf::file_format::Properties;
+use crate::plugins::retention::Retention;
+use crate::query;
+use crate::filter::Filter;
+use crate::stream::Cursor;
+use tdb_core::postprocessing::candle::rollup::Resolution;
+use tdb_core::postprocessing::aggregate::{Aggregate, GroupBy};
+use tdb_core::protocol::ContentType;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug)]
 pub enum ReturnType {
     String(Cow<'static, str>),
     Bytes(Vec<u8>),
     Error(Cow<'static, str>),
+    /// chunks of a large result, see `stream`
+    Stream(Receiver<ReturnType>),
+    /// cursor of the last update of a page, at the end of its stream
+    Cursor(String),
+    /// tick size and lot size of the updates of a stream of dtf batches, at its start
+    Scale(Scale),
+    /// response of a command that is still being processed, see `session`
+    Pending(oneshot::Receiver<ReturnType>),
+    /// response framed by protocol version 2 with the id of its request and its content type
+    Tagged(u32, ContentType, Box<ReturnType>),
+}
+
+/// streams are never equal
+impl PartialEq for ReturnType {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (ReturnType::String(a), ReturnType::String(b)) => a == b,
+            (ReturnType::Bytes(a), ReturnType::Bytes(b)) => a == b,
+            (ReturnType::Error(a), ReturnType::Error(b)) => a == b,
+            (ReturnType::Cursor(a), ReturnType::Cursor(b)) => a == b,
+            (ReturnType::Scale(a), ReturnType::Scale(b)) => a == b,
+            (ReturnType::Tagged(a, x, r), ReturnType::Tagged(b, y, s)) => a == b && x == y && r == s,
+            _ => false,
+        }
+    }
 }
 
 impl ReturnType {
 
     pub const HELP_STR: &'static str = "
-    PING, INFO, USE [db], CREATE [db],
+    HELLO [version] [capability ...], PING, INFO, USE [db], CREATE [db] [TICK size] [LOT size] [key=value ...],
     ADD [ts],[seq],[is_trade],[is_bid],[price],[size];
-    FLUSH, FLUSH ALL, GET ALL, GET [count], CLEAR";
+    FLUSH, FLUSH ALL, GET ALL, GET [count], GET ... WHERE [expr], GET ... LIMIT [n] AFTER [cursor],
+    GET ... IN BOOKS ([db], [db] ...), CLEAR,
+    RETENTION [db|ALL] [AGE age|none] [BYTES size|none], RETENTION [db] DEFAULT,
+    GET CANDLES [resolution] [FROM epoch TO epoch] [AS CSV],
+    SELECT [aggregates] [FROM epoch TO epoch] [WHERE expr] [GROUP BY interval, side, is_trade] [AS CSV] [IN MEM],
+    OB [db] [DEPTH n], OB [db] AT [epoch] [DEPTH n] [AS JSON|BINARY], SUBSCRIBE [db] [DEPTH n]";
 
     pub fn ok() -> ReturnType {
         ReturnType::String("1".into())
@@ -38,7 +78,7 @@ pub enum ReqCount {
     Count(u32),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum GetFormat {
     Json,
     Csv,
@@ -54,26 +94,67 @@ pub enum ReadLocation {
 #[derive(Debug)]
 pub enum Void {}
 
+/// update to insert
+#[derive(Debug, PartialEq)]
+pub enum InsertData {
+    /// binary insert, price and size are already in ticks and lots
+    Units(Update),
+    /// text insert, scaled with the tick size and lot size of the book
+    Decimal(DecimalUpdate),
+}
+
+/// aggregates of the updates in range matching the filter
+#[derive(Debug)]
+pub struct Select {
+    pub aggregates: Vec<Aggregate>,
+    pub group_by: GroupBy,
+    pub range: Option<(u64, u64)>,
+    pub filter: Option<Filter>,
+}
+
+/// page of `GET ... LIMIT n AFTER cursor`, from the first update when `after` is None
+#[derive(Debug, PartialEq)]
+pub struct Page {
+    pub limit: Option<u32>,
+    pub after: Option<Cursor>,
+}
+
 #[derive(Debug)]
 pub enum Command {
     Noop,
+    /// protocol version and capabilities asked for by the client
+    Hello(u32, Vec<String>),
     Ping,
     Help,
     Info,
     Perf,
-    Orderbook(Option<BookName>),
-    Get(ReqCount, GetFormat, Option<(u64, u64)>, ReadLocation),
+    /// live orderbook, only the best n levels of each side with cumulative sizes when given
+    Orderbook(Option<BookName>, Option<u32>),
+    /// orderbook at a timestamp with at most n levels per side, as json or as levels with `GetFormat::Dtf`
+    OrderbookAt(Option<BookName>, u64, Option<u32>, GetFormat),
+    Get(ReqCount, GetFormat, Option<(u64, u64)>, ReadLocation, Option<Filter>, Option<Page>),
+    /// updates of several books merged by timestamp, tagged with their book
+    GetMerged(Vec<BookName>, ReqCount, GetFormat, Option<(u64, u64)>, ReadLocation, Option<Filter>),
+    GetCandles(Resolution, Option<(u64, u64)>, GetFormat),
+    Select(Select, GetFormat, ReadLocation),
     Count(ReqCount, ReadLocation),
     Clear(ReqCount),
     Flush(ReqCount),
-    Insert(Option<Update>, Option<BookName>),
-    Create(BookName),
-    Subscribe(BookName),
+    Insert(Option<InsertData>, Option<BookName>),
+    Create(BookName, Option<Scale>, Properties),
+    /// push every update of a book, or its best n levels whenever they change
+    Subscribe(BookName, Option<u32>),
     Load(BookName),
     Use(BookName),
     Exists(BookName),
-    Unknown,
+    /// show the retention policy of a book, of every book when None
+    Retention(Option<BookName>),
+    /// set the policy of a book or the default policy when None,
+    /// a book goes back to the default policy when the policy is None
+    SetRetention(Option<BookName>, Option<Retention>),
     BadFormat,
+    ParseError(query::ParseError),
+    InvalidBookName(String),
 }
 
 #[derive(Debug)]
@@ -82,12 +163,17 @@ pub enum Event {
         addr: SocketAddr,
         stream: Arc<TcpStream>,
         shutdown: Receiver<Void>,
+        /// responses and subscriptions to write to the connection
+        outbound: Sender<ReturnType>,
+        messages: Receiver<ReturnType>,
     },
+    /// a command spanning books
     Command {
-        from: Option<SocketAddr>,
         command: Command,
+        reply: oneshot::Sender<ReturnType>,
     },
     RecordHistory,
+    EnforceRetention,
     FetchSizes {
         // obname, on disk, in mem
         tx: Sender<Vec<(BookName, u64, u64)>>,
@@ -102,7 +188,7 @@ pub fn parse_to_command(mut line: &[u8]) -> Command {
     let l = tdb_core::RAW_INSERT_PREFIX.len();
     if line.len() > l && &line[0..l] == tdb_core::RAW_INSERT_PREFIX {
         return tdb_core::utils::decode_insert_into(line)
-            .map(|(up, book_name)| Command::Insert(up, book_name))
+            .map(|(up, book_name)| Command::Insert(up.map(InsertData::Units), book_name))
             .unwrap_or(Command::BadFormat);
     }
 
@@ -113,117 +199,140 @@ pub fn parse_to_command(mut line: &[u8]) -> Command {
         return Command::BadFormat;
     };
 
-    match line.borrow() {
-        "" => Noop,
-        "PING" => Ping,
-        "HELP" => Help,
-        "INFO" => Info,
-        "PERF" => Perf,
-        "OB" => Orderbook(None),
-        "COUNT" => Count(ReqCount::Count(1), ReadLocation::Fs),
-        "COUNT IN MEM" => Count(ReqCount::Count(1), ReadLocation::Mem),
-        "COUNT ALL" => Count(ReqCount::All, ReadLocation::Fs),
-        "COUNT ALL IN MEM" => Count(ReqCount::All, ReadLocation::Mem),
-        "CLEAR" => Clear(ReqCount::Count(1)),
-        "CLEAR ALL" => Clear(ReqCount::All),
-        "GET ALL AS JSON" => Get(ReqCount::All, GetFormat::Json, None, ReadLocation::Mem),
-        "GET ALL AS CSV" => Get(ReqCount::All, GetFormat::Csv, None, ReadLocation::Mem),
-        "GET ALL" => Get(ReqCount::All, GetFormat::Dtf, None, ReadLocation::Mem),
-        "FLUSH" => Flush(ReqCount::Count(1)),
-        "FLUSH ALL" => Flush(ReqCount::All),
-        _ => {
-            if line.starts_with("SUBSCRIBE ") {
-                let dbname: &str = &line[10..];
-                Subscribe(BookName::from(dbname).unwrap())
-            } else if line.starts_with("CREATE ") {
-                let dbname: &str = &line[7..];
-                Create(BookName::from(dbname).unwrap())
-            } else if line.starts_with("OB ") {
-                let dbname: &str = &line[3..];
-                Orderbook(Some(BookName::from(dbname).unwrap()))
-            } else if line.starts_with("LOAD ") {
-                let dbname: &str = &line[5..];
-                Load(BookName::from(dbname).unwrap())
-            } else if line.starts_with("USE ") {
-                let dbname: &str = &line[4..];
-                Use(BookName::from(dbname).unwrap())
-            } else if line.starts_with("EXISTS ") {
-                let dbname: &str = &line[7..];
-                Exists(BookName::from(dbname).unwrap())
-            } else if line.starts_with("ADD ") || line.starts_with("INSERT ") {
-                let (up, dbname) = if line.contains(" INTO ") {
-                    let (up, dbname) = crate::parser::parse_add_into(&line);
-                    (up, dbname)
-                } else {
-                    let data_string: &str = &line[3..];
-                    match crate::parser::parse_line(&data_string) {
-                        Some(up) => (Some(up), None),
-                        None => (None, None),
-                    }
-                };
-                Insert(up, dbname)
-            } else if line.starts_with("GET ") {
-                // how many records we want...
-                let count = if line.starts_with("GET ALL ") {
-                    ReqCount::All
-                } else {
-                    let count: &str = &line[4..];
-                    let count: Vec<&str> = count.split(' ').collect();
-                    let count = count[0].parse::<u32>().unwrap_or(1);
-                    ReqCount::Count(count)
-                };
-
-                let range = crate::parser::parse_get_range(&line);
-
-                // test if is Json
-                let format = if line.contains(" AS JSON") {
-                    GetFormat::Json
-                } else if line.contains(" AS CSV") {
-                    GetFormat::Csv
-                } else {
-                    GetFormat::Dtf
-                };
-                let loc = if line.contains(" IN MEM") { ReadLocation::Mem } else { ReadLocation::Fs };
-
-                Get(count, format, range, loc)
-            } else {
-                Unknown
-            }
-        }
+    match crate::query::parse(line) {
+        Ok(command) => command,
+        Err(query::ParseError { kind: query::ErrorKind::BookNameTooLong(dbname), .. }) => InvalidBookName(dbname),
+        Err(err) => Command::ParseError(err),
     }
 }
 
-
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::settings::Settings;
+    use crate::session::Session;
     use std::net;
 
-    fn gen_state() -> (TectonicServer, Option<SocketAddr>) {
-        let settings: Settings = Default::default();
-        let mut global = TectonicServer::new(Arc::new(settings));
+    /// session of a connection to a new server
+    fn connect(settings: Arc<Settings>) -> Session {
+        session(TectonicServer::new(settings))
+    }
+
+    /// session of a connection to `state`
+    fn session(state: TectonicServer) -> Session {
+        let books = Arc::clone(&state.books);
+        let backlog = Arc::clone(&state.backlog);
+        let (broker, events) = mpsc::channel(CHANNEL_SZ);
+        task::spawn(crate::server::broker_loop(events, state));
         let addr = SocketAddr::new(
             net::IpAddr::V4(net::Ipv4Addr::new(127, 0, 0, 1)),
             1);
-        let (client_sender, _client_receiver) = mpsc::channel(CHANNEL_SZ);
-        global.new_connection(client_sender, addr);
-        (global, Some(addr))
+        let (outbound, _receiver) = mpsc::channel(CHANNEL_SZ);
+        Session::new(addr, books, broker, outbound, backlog)
+    }
+
+    fn gen_state() -> Session {
+        connect(Default::default())
+    }
+
+    /// book `a` driven without its actor, and a runner of its commands
+    fn book(settings: Settings) -> (Book, impl Fn(&mut Book, &str) -> ReturnType) {
+        let book = Book::new("a", Arc::new(settings), &Default::default());
+        let addr = SocketAddr::new(net::IpAddr::V4(net::Ipv4Addr::new(127, 0, 0, 1)), 1);
+        let (outbound, _receiver) = mpsc::channel(CHANNEL_SZ);
+        let run = move |book: &mut Book, cmd: &str| {
+            book.process_command(parse_to_command(cmd.as_bytes()), (addr, outbound.clone()))
+        };
+        (book, run)
+    }
+
+    /// wait for the io of `book` in progress and apply its result, as the actor does
+    fn apply(book: &mut Book) {
+        let done = task::block_on(book.io.take().unwrap()).unwrap();
+        book.done(done);
+    }
+
+    /// concatenated chunks of a streamed result
+    fn collect(resp: ReturnType) -> ReturnType {
+        let chunks: Vec<ReturnType> = match resp {
+            ReturnType::Stream(chunks) => task::block_on(chunks.collect()),
+            resp => return resp,
+        };
+        let mut ret = String::new();
+        for chunk in chunks {
+            match chunk {
+                ReturnType::String(s) => ret.push_str(&s),
+                chunk => return chunk,
+            }
+        }
+        ReturnType::string(ret)
     }
 
     #[test]
     fn should_return_pong() {
-        let (mut state, addr) = gen_state();
-        let resp = task::block_on(state.process_command(Command::Ping, addr));
+        let mut state = gen_state();
+        let resp = task::block_on(state.process_command(Command::Ping));
         assert_eq!(ReturnType::String("PONG".into()), resp);
     }
 
+    #[test]
+    fn should_negotiate_hello() {
+        let mut state = gen_state();
+        let mut run = |cmd: &str| task::block_on(state.process_command(parse_to_command(cmd.as_bytes())));
+        assert_eq!(run("HELLO 0"), ReturnType::error("Unsupported protocol version 0"));
+
+        let mut state = gen_state();
+        let (client_sender, mut client_receiver) = mpsc::channel(CHANNEL_SZ);
+        state.outbound = client_sender;
+        assert_eq!(
+            task::block_on(state.process_command(parse_to_command(b"HELLO 3 cursor zstd subscribe"))),
+            ReturnType::string(r#"{"version":2,"capabilities":["cursor","subscribe"]}"#)
+        );
+        assert_eq!(state.version, 2);
+        assert_eq!(
+            task::block_on(state.process_command(parse_to_command(b"HELLO 1"))),
+            ReturnType::error("HELLO must be the first command")
+        );
+        assert!(task::block_on(state.dispatch(Command::Ping, 7)));
+        match client_receiver.try_next() {
+            Ok(Some(ReturnType::Tagged(7, ContentType::Text, _))) => (),
+            _ => panic!("response not tagged"),
+        }
+    }
+
+    #[test]
+    fn should_apply_pipelined_commands_in_order() {
+        let mut state = gen_state();
+        let (client_sender, mut client_receiver) = mpsc::channel(CHANNEL_SZ);
+        state.outbound = client_sender;
+        let commands = ["CREATE x", "USE x", "ADD 1.000,0,f,t,1.00,1;", "COUNT ALL IN MEM", "CLEAR ALL", "COUNT IN MEM"];
+        task::block_on(async {
+            for cmd in &commands {
+                assert!(state.dispatch(parse_to_command(cmd.as_bytes()), 0).await);
+            }
+        });
+        let responses: Vec<ReturnType> = commands.iter().map(|_| {
+            let mut ret = client_receiver.try_next().unwrap().unwrap();
+            while let ReturnType::Pending(response) = ret {
+                ret = task::block_on(response).unwrap();
+            }
+            ret
+        }).collect();
+        assert_eq!(responses, vec![
+            ReturnType::string("Created orderbook `x`."),
+            ReturnType::string("SWITCHED TO orderbook `x`."),
+            ReturnType::string(""),
+            ReturnType::string("1"),
+            ReturnType::ok(),
+            ReturnType::string("0"),
+        ]);
+    }
+
     #[test]
     fn should_not_insert_into_empty() {
-        let (mut state, addr) = gen_state();
+        let mut state = gen_state();
         let resp = task::block_on(state.process_command(
             parse_to_command(b"ADD 1513749530.585,0,t,t,0.04683200,0.18900000; INTO bnc_btc_eth"),
-            addr
         ));
         assert_eq!(
             ReturnType::Error("DB bnc_btc_eth not found.".into()),
@@ -233,28 +342,689 @@ mod tests {
 
     #[test]
     fn should_insert_ok() {
-        let (mut state, addr) = gen_state();
-        let resp = task::block_on(state.process_command(parse_to_command(b"CREATE bnc_btc_eth"), addr));
+        let mut state = gen_state();
+        let resp = task::block_on(state.process_command(parse_to_command(b"CREATE bnc_btc_eth")));
         assert_eq!(ReturnType::String("Created orderbook `bnc_btc_eth`.".into()), resp);
         let resp = task::block_on(state.process_command(
             parse_to_command(b"ADD 1513749530.585,0,t,t,0.04683200,0.18900000; INTO bnc_btc_eth"),
-            addr
         ));
         assert_eq!(ReturnType::String("".into()), resp);
     }
 
     #[test]
     fn should_raw_insert_ok() {
-        let (mut state, addr) = gen_state();
-        let resp = task::block_on(state.process_command(parse_to_command(b"CREATE bnc_btc_eth"), addr));
+        let mut state = gen_state();
+        let resp = task::block_on(state.process_command(parse_to_command(b"CREATE bnc_btc_eth")));
         assert_eq!(ReturnType::String("Created orderbook `bnc_btc_eth`.".into()), resp);
 
         // "ADD [update] INTO bnc_btc_eth"
         let book_name = Some("bnc_btc_eth");
-        let update = Update { ts: 1513922718770, seq: 0, is_bid: true, is_trade: false, price: 0.001939,  size: 22.85 };
+        let update = Update { ts: 1513922718770, seq: 0, is_bid: true, is_trade: false, price: 193900,  size: 2285000000 };
         let cmd = tdb_core::utils::encode_insert_into(book_name, &update).unwrap();
 
-        let resp = task::block_on(state.process_command(parse_to_command(&cmd), addr));
+        let resp = task::block_on(state.process_command(parse_to_command(&cmd)));
         assert_eq!(ReturnType::String("".into()), resp);
     }
+
+    #[test]
+    fn should_reject_insert_off_tick() {
+        let mut state = gen_state();
+        let resp = task::block_on(state.process_command(parse_to_command(b"CREATE bnc_btc_eth TICK 0.0001 LOT 0.01")));
+        assert_eq!(ReturnType::String("Created orderbook `bnc_btc_eth`.".into()), resp);
+        let resp = task::block_on(state.process_command(
+            parse_to_command(b"ADD 1513749530.585,0,t,t,0.0468,0.19; INTO bnc_btc_eth"),
+        ));
+        assert_eq!(ReturnType::String("".into()), resp);
+        let resp = task::block_on(state.process_command(
+            parse_to_command(b"ADD 1513749530.585,0,t,t,0.04683200,0.18900000; INTO bnc_btc_eth"),
+        ));
+        match resp {
+            ReturnType::Error(_) => (),
+            resp => panic!("expected error, got {:?}", resp),
+        }
+    }
+
+    #[test]
+    fn should_reject_long_book_names() {
+        let mut state = gen_state();
+        let name = "x".repeat(BOOK_NAME_LEN + 1);
+        let resp = task::block_on(state.process_command(parse_to_command(format!("CREATE {}", name).as_bytes())));
+        assert_eq!(
+            ReturnType::error(format!("Book name `{}` is longer than {} bytes.", name, BOOK_NAME_LEN)),
+            resp
+        );
+        let resp = task::block_on(state.process_command(parse_to_command(format!("USE {}", name).as_bytes())));
+        assert!(matches!(resp, ReturnType::Error(_)));
+    }
+
+    #[test]
+    fn should_get_candles() {
+        let settings = Settings { rollups: vec!["1m".parse().unwrap()], ..Default::default() };
+        let mut state = connect(Arc::new(settings));
+        let mut run = |cmd: &str| task::block_on(state.process_command(parse_to_command(cmd.as_bytes())));
+        run("CREATE bnc_btc_eth TICK 0.01 LOT 0.1");
+        run("USE bnc_btc_eth");
+        run("ADD 1513749500.000,0,t,t,1.00,0.5;");
+        run("ADD 1513749510.000,1,t,f,1.20,0.1;");
+        run("ADD 1513749520.000,2,f,f,9.00,0.1;");
+        run("ADD 1513749560.000,3,t,t,0.90,0.2;");
+        assert_eq!(
+            run("GET CANDLES 1m AS CSV"),
+            ReturnType::string("1513749480,1513749510,1,1.2,1,1.2,0.6\n1513749540,1513749560,0.9,0.9,0.9,0.9,0.2\n")
+        );
+        assert_eq!(
+            run("GET CANDLES 1m FROM 1513749500 TO 1513749600 AS CSV"),
+            ReturnType::string("1513749540,1513749560,0.9,0.9,0.9,0.9,0.2\n")
+        );
+        assert!(matches!(run("GET CANDLES 1h"), ReturnType::Error(_)));
+    }
+
+    #[test]
+    fn should_filter_get() {
+        let mut state = gen_state();
+        let mut run = |cmd: &str| task::block_on(state.process_command(parse_to_command(cmd.as_bytes())));
+        run("CREATE bnc_btc_eth TICK 0.01 LOT 0.1");
+        run("USE bnc_btc_eth");
+        run("ADD 1513749500.000,0,t,t,1.00,0.5;");
+        run("ADD 1513749510.000,1,t,f,1.20,0.1;");
+        run("ADD 1513749520.000,2,f,f,1.10,0.2;");
+        assert_eq!(
+            collect(run("GET ALL AS CSV IN MEM WHERE is_trade AND price BETWEEN 1 AND 1.15 OR size > 0.15 AND is_ask")),
+            ReturnType::string("1513749500,0,t,t,1,0.5\n1513749520,2,f,f,1.1,0.2\n")
+        );
+    }
+
+    #[test]
+    fn should_select_aggregates() {
+        let mut state = gen_state();
+        let mut run = |cmd: &str| task::block_on(state.process_command(parse_to_command(cmd.as_bytes())));
+        run("CREATE bnc_btc_eth TICK 0.01 LOT 0.1");
+        run("USE bnc_btc_eth");
+        run("ADD 1513749500.000,0,t,t,1.00,0.5;");
+        run("ADD 1513749510.000,1,t,f,1.20,0.1;");
+        run("ADD 1513749520.000,2,f,f,9.00,0.1;");
+        run("ADD 1513749560.000,3,t,t,0.90,0.2;");
+        assert_eq!(
+            run("SELECT count, sum(size), sum(price*size), ohlc WHERE is_trade GROUP BY 1m AS CSV"),
+            ReturnType::string("1513749480,2,0.6,0.62,1,1.2,1,1.2\n1513749540,1,0.2,0.18,0.9,0.9,0.9,0.9\n")
+        );
+        assert_eq!(
+            run("SELECT count GROUP BY side, is_trade"),
+            ReturnType::string(r#"[{"side":"ask","is_trade":false,"count":1}, {"side":"ask","is_trade":true,"count":1}, {"side":"bid","is_trade":true,"count":2}]"#.to_owned() + "\n")
+        );
+    }
+
+    #[test]
+    fn should_stream_get() {
+        let mut state = gen_state();
+        let mut run = |cmd: &str| task::block_on(state.process_command(parse_to_command(cmd.as_bytes())));
+        run("CREATE bnc_btc_eth TICK 0.01 LOT 0.1");
+        run("USE bnc_btc_eth");
+        let n = crate::stream::CHUNK_LEN + 10;
+        for i in 0..n {
+            run(&format!("ADD {}.000,{},f,t,1.00,0.5;", 1513749500 + i, i));
+        }
+        let chunks: Vec<ReturnType> = match run("GET ALL AS JSON") {
+            ReturnType::Stream(chunks) => task::block_on(chunks.collect()),
+            resp => panic!("unexpected {:?}", resp),
+        };
+        assert_eq!(chunks.len(), 2);
+        match &chunks[1] {
+            ReturnType::String(s) => assert_eq!(s.lines().count(), 10),
+            chunk => panic!("unexpected {:?}", chunk),
+        }
+
+        match collect(run("GET 3 AS CSV")) {
+            ReturnType::String(s) => assert_eq!(s.lines().count(), 3),
+            resp => panic!("unexpected {:?}", resp),
+        }
+        assert_eq!(
+            collect(run(&format!("GET {} AS CSV", n + 1))),
+            ReturnType::error(format!("Requested {} but only have {}.", n + 1, n))
+        );
+    }
+
+    #[test]
+    fn should_walk_pages() {
+        let folder = "test-pages";
+        let settings = Settings { dtf_folder: folder.to_owned(), ..Default::default() };
+        let mut state = connect(Arc::new(settings));
+        let mut run = |cmd: &str| task::block_on(state.process_command(parse_to_command(cmd.as_bytes())));
+        run("CREATE bnc_btc_eth TICK 0.01 LOT 0.1");
+        run("USE bnc_btc_eth");
+        // 20 updates in a file followed by 5 in memory
+        for i in 0..25 {
+            run(&format!("ADD {}.000,{},{},t,1.00,0.5;", 1513749500 + i, i, if i % 2 == 0 { "t" } else { "f" }));
+            if i == 19 {
+                run("FLUSH");
+            }
+        }
+
+        let mut page = |query: String| -> (Vec<String>, Option<String>) {
+            let chunks: Vec<ReturnType> = match run(&query) {
+                ReturnType::Stream(chunks) => task::block_on(chunks.collect()),
+                resp => panic!("unexpected {:?}", resp),
+            };
+            let mut lines = vec![];
+            let mut cursor = None;
+            for chunk in chunks {
+                match chunk {
+                    ReturnType::String(s) => lines.extend(s.lines().map(str::to_owned)),
+                    ReturnType::Cursor(c) => cursor = Some(c),
+                    chunk => panic!("unexpected {:?}", chunk),
+                }
+            }
+            (lines, cursor)
+        };
+        let mut walk = |query: &str| {
+            let mut lines = vec![];
+            let (mut page_lines, mut cursor) = page(format!("{} LIMIT 4 AS CSV", query));
+            while !page_lines.is_empty() {
+                lines.append(&mut page_lines);
+                let (next_lines, next_cursor) = page(format!("{} LIMIT 4 AFTER {} AS CSV", query, cursor.clone().unwrap()));
+                if next_lines.is_empty() {
+                    assert_eq!(next_cursor, cursor);
+                }
+                page_lines = next_lines;
+                cursor = next_cursor;
+            }
+            lines.iter().map(|line| line.split(',').nth(1).unwrap().parse().unwrap()).collect::<Vec<u32>>()
+        };
+        assert_eq!(walk("GET"), (0..25).collect::<Vec<_>>());
+        assert_eq!(walk("GET FROM 1513749505 TO 1513749522 WHERE is_trade"), (6..=22).step_by(2).collect::<Vec<_>>());
+
+        assert_eq!(run("GET LIMIT 0"), ReturnType::error("LIMIT must be at least 1"));
+
+        // binary results start with the scale of the book
+        let scale = Scale::new("0.01".parse().unwrap(), "0.1".parse().unwrap()).unwrap();
+        for query in ["GET 2", "GET LIMIT 2"] {
+            match run(query) {
+                ReturnType::Stream(chunks) => {
+                    let chunks: Vec<ReturnType> = task::block_on(chunks.collect());
+                    assert_eq!(chunks[0], ReturnType::Scale(scale));
+                    assert!(matches!(chunks[1], ReturnType::Bytes(_)));
+                }
+                resp => panic!("unexpected {:?}", resp),
+            }
+        }
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn should_walk_pages_of_equal_updates() {
+        let folder = "test-pages-equal";
+        let settings = Settings { dtf_folder: folder.to_owned(), ..Default::default() };
+        let mut state = connect(Arc::new(settings));
+        let mut run = |cmd: &str| task::block_on(state.process_command(parse_to_command(cmd.as_bytes())));
+        run("CREATE bnc_btc_eth TICK 0.01 LOT 0.1");
+        run("USE bnc_btc_eth");
+        // runs of updates with the same ts and seq across the pages, in a file and in memory
+        for i in 0..14 {
+            let ts = if i < 10 { 1513749500 } else { 1513749501 };
+            run(&format!("ADD {}.000,7,t,f,{}.00,0.5;", ts, i));
+            if i == 5 {
+                run("FLUSH");
+            }
+        }
+
+        let mut page = |query: String| -> (Vec<u32>, Option<String>) {
+            let chunks: Vec<ReturnType> = match run(&query) {
+                ReturnType::Stream(chunks) => task::block_on(chunks.collect()),
+                resp => panic!("unexpected {:?}", resp),
+            };
+            let mut prices = vec![];
+            let mut cursor = None;
+            for chunk in chunks {
+                match chunk {
+                    ReturnType::String(s) => prices.extend(s.lines().map(|line| line.split(',').nth(4).unwrap().parse::<f64>().unwrap() as u32)),
+                    ReturnType::Cursor(c) => cursor = Some(c),
+                    chunk => panic!("unexpected {:?}", chunk),
+                }
+            }
+            (prices, cursor)
+        };
+        let mut prices = vec![];
+        let (mut page_prices, mut cursor) = page("GET LIMIT 4 AS CSV".to_owned());
+        while !page_prices.is_empty() {
+            prices.append(&mut page_prices);
+            let next = page(format!("GET LIMIT 4 AFTER {} AS CSV", cursor.unwrap()));
+            page_prices = next.0;
+            cursor = next.1;
+        }
+        assert_eq!(prices, (0..14).collect::<Vec<_>>());
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn should_merge_books() {
+        let folder = "test-merge";
+        let settings = Settings { dtf_folder: folder.to_owned(), ..Default::default() };
+        let mut state = connect(Arc::new(settings));
+        let mut run = |cmd: &str| task::block_on(state.process_command(parse_to_command(cmd.as_bytes())));
+        run("CREATE a TICK 0.01 LOT 0.1");
+        run("CREATE b TICK 0.5 LOT 1");
+        // a is flushed, b stays in memory in the order it was inserted
+        for i in 0..4 {
+            run(&format!("ADD {}.000,{},t,t,1.00,0.5; INTO a", 1513749500 + 2 * i, i));
+            run(&format!("ADD {}.000,{},f,f,1.50,2; INTO b", 1513749507 - 2 * i, 3 - i));
+        }
+        run("USE a");
+        run("FLUSH");
+
+        assert_eq!(
+            collect(run("GET FROM 1513749501 TO 1513749504 IN BOOKS (b, a) AS CSV")),
+            ReturnType::string(
+                "b,1513749501,0,f,f,1.5,2\na,1513749502,1,t,t,1,0.5\nb,1513749503,1,f,f,1.5,2\na,1513749504,2,t,t,1,0.5\n"
+            )
+        );
+        match collect(run("GET ALL IN BOOKS (a, b) IN MEM AS CSV")) {
+            ReturnType::String(s) => assert!(s.lines().count() == 4 && s.lines().all(|l| l.starts_with("b,"))),
+            resp => panic!("unexpected {:?}", resp),
+        }
+        assert_eq!(
+            collect(run("GET 1 IN BOOKS (a, b) WHERE NOT is_trade")),
+            ReturnType::string("{\"book\":\"b\",\"ts\":1513749501,\"seq\":0,\"is_trade\":false,\"is_bid\":false,\"price\":1.5,\"size\":2}\n")
+        );
+        assert_eq!(
+            collect(run("GET 9 IN BOOKS (a, b)")),
+            ReturnType::error("Requested 9 but only have 8.")
+        );
+        assert_eq!(run("GET ALL IN BOOKS (a, c)"), ReturnType::error("DB c not found."));
+
+        // files read a batch at a time
+        run("CREATE c");
+        for i in 0..2500 {
+            run(&format!("ADD {}.{:03},{},t,t,1.00,0.5; INTO c", 1513749500 + i / 1000, i % 1000, i));
+        }
+        run("USE c");
+        run("FLUSH");
+        match collect(run("GET ALL IN BOOKS (c, b) AS CSV")) {
+            ReturnType::String(s) => {
+                let seqs: Vec<u32> = s.lines().filter(|l| l.starts_with("c,")).map(|l| l.split(',').nth(2).unwrap().parse().unwrap()).collect();
+                assert_eq!(seqs, (0..2500).collect::<Vec<_>>());
+                assert_eq!(s.lines().count(), 2504);
+            }
+            resp => panic!("unexpected {:?}", resp),
+        }
+
+        // an update inserted late comes between the ones in files
+        run("ADD 1513749503.000,4,t,t,1.00,0.5; INTO a");
+        assert_eq!(
+            collect(run("GET ALL FROM 1513749501 TO 1513749507 IN BOOKS (a) AS CSV")),
+            ReturnType::string(
+                "a,1513749502,1,t,t,1,0.5\na,1513749503,4,t,t,1,0.5\na,1513749504,2,t,t,1,0.5\na,1513749506,3,t,t,1,0.5\n"
+            )
+        );
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn should_end_stream_on_scan_error() {
+        let folder = "test-scan-error";
+        let settings = Settings { dtf_folder: folder.to_owned(), ..Default::default() };
+        let mut state = connect(Arc::new(settings));
+        let mut run = |cmd: &str| task::block_on(state.process_command(parse_to_command(cmd.as_bytes())));
+        run("CREATE bnc_btc_eth");
+        run("USE bnc_btc_eth");
+        for i in 0..10 {
+            run(&format!("ADD {}.000,{},t,t,1.00,0.5;", 1513749500 + i, i));
+        }
+        run("FLUSH");
+        // cut the file in the middle of its batch
+        let fname = format!("{}/bnc_btc_eth.dtf", folder);
+        let len = std::fs::metadata(&fname).unwrap().len();
+        std::fs::OpenOptions::new().write(true).open(&fname).unwrap().set_len(len - 20).unwrap();
+
+        for query in [
+            "GET ALL FROM 1513749500 TO 1513749600 AS CSV",
+            "GET FROM 1513749500 TO 1513749600 LIMIT 4 AS CSV",
+            "GET ALL FROM 1513749500 TO 1513749600 IN BOOKS (bnc_btc_eth) AS CSV",
+        ] {
+            match collect(run(query)) {
+                ReturnType::Error(e) => assert!(e.starts_with("Unable to scan files"), "{}", e),
+                resp => panic!("unexpected {:?} for {}", resp, query),
+            }
+        }
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn should_read_updates_being_flushed() {
+        let folder = "test-flushing";
+        let settings = Settings { dtf_folder: folder.to_owned(), ..Default::default() };
+        let (mut book, run) = book(settings);
+        run(&mut book, "ADD 1513749501.000,0,t,t,1.00,1;");
+        run(&mut book, "ADD 1513749502.000,1,t,t,1.00,1;");
+        let flushed = match run(&mut book, "FLUSH") {
+            ReturnType::Pending(flushed) => flushed,
+            resp => panic!("unexpected {:?}", resp),
+        };
+        // the flush is applied to the book by its actor once it is written
+        run(&mut book, "ADD 1513749503.000,2,t,t,1.00,1;");
+        assert_eq!(run(&mut book, "COUNT IN MEM"), ReturnType::string("3"));
+        let all = "1513749501,0,t,t,1,1\n1513749502,1,t,t,1,1\n1513749503,2,t,t,1,1\n";
+        assert_eq!(collect(run(&mut book, "GET ALL AS CSV")), ReturnType::string(all));
+
+        apply(&mut book);
+        assert_eq!(task::block_on(flushed).unwrap(), ReturnType::ok());
+        assert_eq!(run(&mut book, "COUNT IN MEM"), ReturnType::string("1"));
+        let ups = dtf::file_format::decode(&format!("{}/a.dtf", folder), None).unwrap();
+        assert_eq!(ups.iter().map(|up| up.seq).collect::<Vec<_>>(), vec![0, 1]);
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn should_read_range_during_flush() {
+        let folder = "test-range-flushing";
+        let _ = std::fs::remove_dir_all(folder);
+        let settings = Settings { dtf_folder: folder.to_owned(), ..Default::default() };
+        let (mut book, run) = book(settings);
+        run(&mut book, "ADD 1513749501.000,0,t,t,1.00,1;");
+        run(&mut book, "ADD 1513749502.000,1,t,t,1.00,1;");
+        run(&mut book, "FLUSH");
+        apply(&mut book);
+        run(&mut book, "ADD 1513749503.000,2,t,t,1.00,1;");
+        run(&mut book, "ADD 1513749504.000,3,t,t,1.00,1;");
+
+        // the flush waits for the scan in progress
+        let files = Arc::clone(&book.files_lock);
+        let scanning = files.read().unwrap();
+        run(&mut book, "FLUSH");
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert!(matches!(book.io.as_mut().unwrap().try_recv(), Ok(None)));
+        drop(scanning);
+
+        // the updates written by the flush are read from files only until it is applied
+        let query = "GET ALL FROM 1513749500 TO 1513749600 AS CSV";
+        let all = "1513749501,0,t,t,1,1\n1513749502,1,t,t,1,1\n1513749503,2,t,t,1,1\n1513749504,3,t,t,1,1\n";
+        let done = task::block_on(book.io.take().unwrap()).unwrap();
+        assert_eq!(collect(run(&mut book, query)), ReturnType::string(all));
+        book.done(done);
+        assert_eq!(collect(run(&mut book, query)), ReturnType::string(all));
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn should_keep_updates_of_dropped_flush() {
+        let folder = "test-dropped-flush";
+        let settings = Settings { dtf_folder: folder.to_owned(), wal: true, ..Default::default() };
+        let (mut book, run) = book(settings);
+        run(&mut book, "ADD 1513749501.000,0,t,t,1.00,1;");
+        run(&mut book, "ADD 1513749502.000,1,t,t,1.00,1;");
+        run(&mut book, "FLUSH");
+        run(&mut book, "ADD 1513749503.000,2,t,t,1.00,1;");
+        // the result of the flush never reaches the book
+        drop(task::block_on(book.io.take().unwrap()));
+        book.io_dropped();
+        assert!(book.flushing.is_none());
+        let seqs = |ups: &[Update]| ups.iter().map(|up| up.seq).collect::<Vec<_>>();
+        assert_eq!(seqs(&book.vec), vec![0, 1, 2]);
+        assert_eq!(seqs(&book.wal.as_mut().unwrap().replay().unwrap()), vec![0, 1, 2]);
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn should_enforce_retention_after_flush() {
+        let folder = "test-retention-flush";
+        let _ = std::fs::remove_dir_all(folder);
+        let settings = Settings { dtf_folder: folder.to_owned(), ..Default::default() };
+        let (mut book, run) = book(settings);
+        run(&mut book, "ADD 1513749501.000,0,t,t,1.00,1;");
+        run(&mut book, "ADD 1513749502.000,1,t,t,1.00,1;");
+        run(&mut book, "FLUSH");
+        apply(&mut book);
+
+        run(&mut book, "ADD 1513749503.000,2,t,t,1.00,1;");
+        run(&mut book, "FLUSH");
+        let policy = Retention { max_age: Some(1000), max_bytes: None };
+        let mut retained = book.enforce_retention(policy, 1513749502500);
+        // the file is not rewritten while the flush appends to it
+        assert_eq!(retained.try_recv(), Ok(None));
+        apply(&mut book);
+        assert_eq!(retained.try_recv(), Ok(None));
+        apply(&mut book);
+        assert_eq!(task::block_on(retained).unwrap(), ReturnType::ok());
+        let ups = dtf::file_format::decode(&format!("{}/a.dtf", folder), None).unwrap();
+        assert_eq!(ups.iter().map(|up| up.seq).collect::<Vec<_>>(), vec![1, 2]);
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn should_rebuild_orderbook_at() {
+        let folder = "test-ob-at";
+        let settings = Arc::new(Settings { dtf_folder: folder.to_owned(), checkpoint_interval: 3, ..Default::default() });
+        let mut state = connect(Arc::clone(&settings));
+        let mut run = |cmd: &str| task::block_on(state.process_command(parse_to_command(cmd.as_bytes())));
+        run("CREATE a TICK 0.01 LOT 1");
+        run("USE a");
+        // 10 updates flushed with 3 checkpoints, 5 in memory with 2 more
+        let mut expected = vec![];
+        for i in 0..15 {
+            let is_trade = if i % 5 == 4 { "t" } else { "f" };
+            let is_bid = if i % 2 == 0 { "t" } else { "f" };
+            run(&format!("ADD {}.000,{},{},{},1.0{},{};", 1513749500 + i, i, is_trade, is_bid, i % 4, i % 3));
+            expected.push(run("OB"));
+            if i == 9 {
+                run("FLUSH");
+            }
+        }
+        for (i, ob) in expected.iter().enumerate() {
+            assert_eq!(&run(&format!("OB AT {}", 1513749500 + i)), ob);
+        }
+        assert_eq!(
+            run("OB a AT 1513749400"),
+            ReturnType::string(r#"{"scale":{"tick_size":"0.01","lot_size":"1"},"bids":{},"asks":{}}"#)
+        );
+        assert_eq!(run("OB b AT 1513749400"), ReturnType::error("DB b not found."));
+        assert_eq!(
+            run("OB AT 1513749514 DEPTH 1"),
+            ReturnType::string(r#"{"bids":[[1.02,-1,-1]],"asks":[[1.01,1,1]]}"#)
+        );
+        match run("OB AT 1513749514 DEPTH 1 AS BINARY") {
+            ReturnType::Bytes(bytes) => assert_eq!(bytes.len(), 2 * (4 + 16)),
+            resp => panic!("unexpected {:?}", resp),
+        }
+
+        run("FLUSH");
+        let book = crate::state::Book::new("a", settings, &Default::default());
+        let mut handle = state.books.read().unwrap().get("a").cloned().unwrap();
+        assert_eq!(Some(book.orderbook), task::block_on(handle.ask(|book| book.orderbook.clone())));
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn should_report_integrity() {
+        let settings = Arc::new(Settings { integrity: crate::settings::IntegrityMode::Heal, ..Default::default() });
+        let mut state = connect(settings);
+        let mut run = |cmd: &str| task::block_on(state.process_command(parse_to_command(cmd.as_bytes())));
+        run("ADD 1.000,0,f,t,1.00,2; INTO default");
+        run("ADD 1.001,1,f,f,1.01,1; INTO default");
+        run("ADD 1.002,3,t,f,1.01,3; INTO default");
+        // crosses the ask, which is cleared
+        run("ADD 1.003,4,f,t,1.02,1; INTO default");
+        assert_eq!(run("OB default DEPTH 1"), ReturnType::string(r#"{"bids":[[1.02,1,1]],"asks":[]}"#));
+        let anomalies = r#"{"negative_levels":1,"crossed":1,"missing_levels":0,"seq_gaps":1,"healed_levels":1}"#;
+        match (run("INFO"), run("PERF")) {
+            (ReturnType::String(info), ReturnType::String(perf)) => {
+                assert!(info.contains(&format!(r#""integrity": {}"#, anomalies)));
+                assert!(perf.contains(&format!(r#"{{"integrity": {{"default": {}}}}}"#, anomalies)));
+            }
+            resp => panic!("unexpected {:?}", resp),
+        }
+    }
+
+    #[test]
+    fn should_insert_while_another_book_is_busy() {
+        let mut state = gen_state();
+        let mut busy = state.books.read().unwrap().get("default").cloned().unwrap();
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        task::block_on(busy.tell(move |_| {
+            let _ = released.recv_timeout(std::time::Duration::from_secs(10));
+        }));
+        let mut run = |cmd: &str| task::block_on(state.process_command(parse_to_command(cmd.as_bytes())));
+        run("CREATE a");
+        assert_eq!(run("ADD 1.000,0,f,t,1.00,1; INTO a"), ReturnType::string(""));
+        assert_eq!(run("USE a"), ReturnType::string("SWITCHED TO orderbook `a`."));
+        assert_eq!(run("COUNT IN MEM"), ReturnType::string("1"));
+        release.send(()).unwrap();
+        assert_eq!(run("ADD 1.000,0,f,t,1.00,1; INTO default"), ReturnType::string(""));
+        assert_eq!(run("COUNT ALL IN MEM"), ReturnType::string("2"));
+    }
+
+    #[test]
+    fn should_push_depth_changes() {
+        let mut state = gen_state();
+        let (client_sender, mut client_receiver) = mpsc::channel(CHANNEL_SZ);
+        state.outbound = client_sender;
+        let mut run = |cmd: &str| task::block_on(state.process_command(parse_to_command(cmd.as_bytes())));
+        assert_eq!(run("OB DEPTH 2"), ReturnType::string(r#"{"bids":[],"asks":[]}"#));
+        run("SUBSCRIBE default DEPTH 1");
+        run("ADD 1.000,0,f,t,1.00,2;");
+        // below the best bid
+        run("ADD 1.001,1,f,t,0.50,3;");
+        run("ADD 1.002,2,f,f,2.00,1;");
+        assert_eq!(run("OB DEPTH 2"), ReturnType::string(r#"{"bids":[[1,2,2],[0.5,3,5]],"asks":[[2,1,1]]}"#));
+
+        let pushed: Vec<ReturnType> = std::iter::from_fn(|| client_receiver.try_next().ok().flatten()).collect();
+        assert_eq!(pushed, vec![
+            ReturnType::string(r#"{"bids":[[1,2,2]],"asks":[]}"#),
+            ReturnType::string(r#"{"bids":[[1,2,2]],"asks":[[2,1,1]]}"#),
+        ]);
+    }
+
+    #[test]
+    fn should_push_dropped_depth_again() {
+        let mut state = gen_state();
+        let (client_sender, mut client_receiver) = mpsc::channel(1);
+        state.outbound = client_sender;
+        let mut run = |cmd: &str| task::block_on(state.process_command(parse_to_command(cmd.as_bytes())));
+        run("SUBSCRIBE default DEPTH 1");
+        run("ADD 1.000,0,f,t,1.00,2;");
+        run("ADD 1.001,1,f,f,2.00,1;");
+        // the queue is full, this snapshot is dropped
+        run("ADD 1.002,2,f,t,1.00,5;");
+        let pushed = std::iter::from_fn(|| client_receiver.try_next().ok().flatten()).count();
+        assert_eq!(pushed, 2);
+        // below the best bid, the book the subscriber has is still out of date
+        run("ADD 1.003,3,f,t,0.50,3;");
+        assert_eq!(client_receiver.try_next().unwrap(), Some(ReturnType::string(r#"{"bids":[[1,5,5]],"asks":[[2,1,1]]}"#)));
+    }
+
+    #[test]
+    fn should_count_slow_subscribers() {
+        use crate::settings::SlowConsumer;
+        for &policy in &[SlowConsumer::Drop, SlowConsumer::Disconnect] {
+            let mut state = connect(Arc::new(Settings { slow_consumer: policy, ..Default::default() }));
+            let (client_sender, mut client_receiver) = mpsc::channel(1);
+            state.outbound = client_sender;
+            let mut run = |cmd: &str| task::block_on(state.process_command(parse_to_command(cmd.as_bytes())));
+            run("SUBSCRIBE default");
+            for i in 0..5 {
+                run(&format!("ADD 1.00{},{},t,t,1.00,1;", i, i));
+            }
+            let info = match run("INFO") {
+                ReturnType::String(info) => info.into_owned(),
+                resp => panic!("unexpected {:?}", resp),
+            };
+            let pushed = std::iter::from_fn(|| client_receiver.try_next().ok().flatten()).count();
+            // one message for the queue and one for the sender of the subscription
+            assert_eq!(pushed, 2);
+            match policy {
+                SlowConsumer::Drop => {
+                    assert!(info.contains(r#""dropped_messages": 3"#));
+                    assert!(client_receiver.try_next().is_err());
+                }
+                SlowConsumer::Disconnect => {
+                    assert!(info.contains(r#""slow_disconnects": 1"#));
+                    assert_eq!(client_receiver.try_next().unwrap(), None);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn should_set_retention() {
+        let folder = "test-retention";
+        let _ = std::fs::remove_dir_all(folder);
+        let settings = Arc::new(Settings { dtf_folder: folder.to_owned(), ..Default::default() });
+        let mut state = connect(Arc::clone(&settings));
+        let mut run = |cmd: &str| task::block_on(state.process_command(parse_to_command(cmd.as_bytes())));
+        assert_eq!(run("RETENTION ALL BYTES 1G"), ReturnType::ok());
+        assert_eq!(run("RETENTION default AGE 90d"), ReturnType::ok());
+        assert_eq!(
+            run("RETENTION default"),
+            ReturnType::string(r#"{"max_age_ms": 7776000000, "max_bytes": null}"#)
+        );
+        assert_eq!(run("RETENTION default DEFAULT"), ReturnType::ok());
+        assert_eq!(
+            run("RETENTION"),
+            ReturnType::string(r#"{"default": {"max_age_ms": null, "max_bytes": 1073741824}, "books": {"default": {"max_age_ms": null, "max_bytes": 1073741824}}}"#)
+        );
+        assert!(matches!(run("RETENTION default AGE 1y"), ReturnType::Error(_)));
+        assert!(matches!(run("RETENTION missing AGE 1d"), ReturnType::Error(_)));
+        run(r#"CREATE 'a"\b'"#);
+        for cmd in &["RETENTION", "INFO"] {
+            match run(cmd) {
+                ReturnType::String(json) => assert!(serde_json::from_str::<serde_json::Value>(&json).is_ok(), "{}", json),
+                resp => panic!("unexpected {:?}", resp),
+            }
+        }
+
+        // the policies of books are kept across restarts
+        assert_eq!(run("RETENTION default AGE 90d"), ReturnType::ok());
+        drop(state);
+        let mut server = TectonicServer::new(settings);
+        task::block_on(utils::init_dbs(&mut server));
+        let mut state = session(server);
+        assert_eq!(
+            task::block_on(state.process_command(parse_to_command(b"RETENTION default"))),
+            ReturnType::string(r#"{"max_age_ms": 7776000000, "max_bytes": null}"#)
+        );
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn should_replay_wal_with_scale() {
+        let folder = "test-wal-scale";
+        let _ = std::fs::remove_dir_all(folder);
+        let settings = Arc::new(Settings { dtf_folder: folder.to_owned(), wal: true, ..Default::default() });
+        let get = |state: &mut Session| task::block_on(state.process_command(parse_to_command(b"GET ALL AS CSV")));
+
+        let mut state = connect(Arc::clone(&settings));
+        let mut run = |cmd: &str| task::block_on(state.process_command(parse_to_command(cmd.as_bytes())));
+        assert_eq!(run("CREATE x TICK 0.0001 LOT 0.01 exchange=binance"), ReturnType::string("Created orderbook `x`."));
+        run("USE x");
+        assert_eq!(run("ADD 1513749530.585,0,t,t,0.0468,1.89;"), ReturnType::string(""));
+        let before = collect(get(&mut state));
+        drop(state);
+
+        // restart before the first flush
+        let mut server = TectonicServer::new(Arc::clone(&settings));
+        task::block_on(utils::init_dbs(&mut server));
+        let mut state = session(server);
+        task::block_on(state.process_command(parse_to_command(b"USE x")));
+        assert_eq!(collect(get(&mut state)), before);
+        assert!(matches!(&before, ReturnType::String(csv) if csv.contains("0.0468,1.89")));
+        let info = task::block_on(state.process_command(Command::Info));
+        assert!(matches!(info, ReturnType::String(info) if info.contains("binance")));
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn should_parse_create_scale() {
+        match parse_to_command(b"CREATE bnc_btc_eth TICK 0.5 LOT 1 exchange=binance asset_type=spot") {
+            Command::Create(name, Some(scale), properties) => {
+                assert_eq!(name.as_str(), "bnc_btc_eth");
+                assert_eq!(scale.tick_size.to_string(), "0.5");
+                assert_eq!(scale.lot_size.to_string(), "1");
+                assert_eq!(properties.get("exchange").map(String::as_str), Some("binance"));
+                assert_eq!(properties.get("asset_type").map(String::as_str), Some("spot"));
+            }
+            cmd => panic!("unexpected {:?}", cmd),
+        }
+        match parse_to_command(b"CREATE bnc_btc_eth TICK 0") {
+            Command::ParseError(query::ParseError { pos: 19, .. }) => (),
+            cmd => panic!("unexpected {:?}", cmd),
+        }
+    }
 }
@@ -33,7 +33,14 @@ pub mod plugins;
 pub mod utils;
 pub mod server;
 pub mod state;
+pub mod actor;
+pub mod session;
 pub mod parser;
+pub mod query;
+pub mod filter;
+pub mod stream;
+pub mod merge;
 pub mod handler;
 pub mod settings;
+pub mod wal;
 pub mod prelude;
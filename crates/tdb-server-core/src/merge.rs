@@ -0,0 +1,247 @@
+//! `GET ... IN BOOKS (a, b, c)`
+//!
+//! The updates of each book are read in small batches on the thread of the
+//! merge as it needs them and merged with its updates in memory by (ts, seq),
+//! ties going to the files. The streams of the books are merged the same way,
+//! ties going to the book listed first, and every update is tagged with the
+//! name of its book.
+//!
+//! The files of the books are locked for reading for the whole merge, in the
+//! order of the names of the books so that two merges waiting behind flushes
+//! cannot deadlock.
+use crate::prelude::*;
+use crate::stream::{Mem, Scan, CHUNK_LEN, STREAM_CAPACITY};
+use tdb_core::dtf::file_format::{files_for_range, for_each_from};
+use tdb_core::dtf::scale::Scale;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, VecDeque};
+use std::iter::Peekable;
+use std::sync::{PoisonError, RwLock};
+use std::thread;
+
+/// number of updates in a batch read from the files of a book
+const BATCH_LEN: usize = 1024;
+
+/// updates of a book to merge
+pub struct Source {
+    pub book: String,
+    pub scale: Scale,
+    /// updates in memory in range and matching the filter in insertion order,
+    /// after the ones in files
+    pub mem: Mem<Update>,
+    pub scan: Option<Scan>,
+}
+
+/// Streams the updates of `sources` merged by (ts, seq), at most `limit` of them.
+///
+/// A limit that is not reached or a failed scan ends the stream with an error.
+pub fn stream_merged(sources: Vec<Source>, limit: Option<usize>, format: GetFormat) -> ReturnType {
+    let (mut tx, rx) = mpsc::channel(STREAM_CAPACITY);
+    thread::spawn(move || {
+        let books: Vec<(String, Scale)> = sources.iter().map(|s| (tag(&s.book, format), s.scale)).collect();
+        let locks: BTreeMap<String, Arc<RwLock<u64>>> = sources.iter()
+            .filter_map(|s| Some((s.book.clone(), Arc::clone(&s.scan.as_ref()?.files))))
+            .collect();
+        let files: BTreeMap<&str, _> = locks.iter()
+            .map(|(book, files)| (book.as_str(), files.read().unwrap_or_else(PoisonError::into_inner)))
+            .collect();
+        let mut readers: Vec<Reader> = sources.into_iter()
+            .map(|s| {
+                let written = files.get(s.book.as_str()).map(|written| **written).unwrap_or(0);
+                Reader::new(s, written)
+            })
+            .collect();
+
+        // smallest (ts, seq) first, then the book listed first
+        let mut heads: Vec<Option<Update>> = match readers.iter_mut().map(Reader::next).collect() {
+            Ok(heads) => heads,
+            Err(e) => {
+                let _ = task::block_on(tx.send(scan_error(e)));
+                return;
+            }
+        };
+        let mut heap: BinaryHeap<_> = heads.iter()
+            .enumerate()
+            .filter_map(|(i, up)| up.map(|up| Reverse((up.ts, up.seq, i))))
+            .collect();
+        let mut chunk = String::new();
+        let mut len = 0;
+        let mut sent = 0;
+        while let Some(Reverse((_, _, i))) = heap.pop() {
+            if Some(sent) == limit {
+                break;
+            }
+            let up = match heads[i].take() {
+                Some(up) => up,
+                None => continue,
+            };
+            let (tag, scale) = &books[i];
+            match format {
+                GetFormat::Csv => chunk.push_str(&format!("{},{}\n", tag, up.to_csv(scale))),
+                _ => chunk.push_str(&format!("{{\"book\":{},{}\n", tag, &up.as_json(scale)[1..])),
+            }
+            len += 1;
+            sent += 1;
+            if len == CHUNK_LEN {
+                len = 0;
+                let chunk = std::mem::take(&mut chunk);
+                if task::block_on(tx.send(ReturnType::string(chunk))).is_err() {
+                    return;
+                }
+            }
+            heads[i] = match readers[i].next() {
+                Ok(up) => up,
+                Err(e) => {
+                    if !chunk.is_empty() {
+                        let _ = task::block_on(tx.send(ReturnType::string(chunk)));
+                    }
+                    let _ = task::block_on(tx.send(scan_error(e)));
+                    return;
+                }
+            };
+            if let Some(up) = heads[i] {
+                heap.push(Reverse((up.ts, up.seq, i)));
+            }
+        }
+        if !chunk.is_empty() && task::block_on(tx.send(ReturnType::string(chunk))).is_err() {
+            return;
+        }
+        if let Some(limit) = limit {
+            if sent < limit {
+                let err = format!("Requested {} but only have {}.", limit, sent);
+                let _ = task::block_on(tx.send(ReturnType::error(err)));
+            }
+        }
+    });
+    ReturnType::Stream(rx)
+}
+
+/// error ending the stream when the files of a book cannot be read
+fn scan_error(e: std::io::Error) -> ReturnType {
+    ReturnType::error(format!("Unable to scan files: {}", e))
+}
+
+/// name of a book as a json string or a csv field
+fn tag(book: &str, format: GetFormat) -> String {
+    match format {
+        GetFormat::Csv if book.contains([',', '"', '\n']) => {
+            format!("\"{}\"", book.replace('"', "\"\""))
+        }
+        GetFormat::Csv => book.to_owned(),
+        _ => serde_json::to_string(book).unwrap_or_default(),
+    }
+}
+
+/// pulls the updates of a book, reading its files a batch at a time
+struct Reader {
+    book: String,
+    /// None once the files are read
+    scan: Option<Scan>,
+    /// files left to read, listed on the first read
+    files: Option<VecDeque<String>>,
+    /// where the first file was left: offset of a batch, number of its updates
+    /// that were read and ts of the last one, no flush moves it while the files are locked
+    resume: Option<(u64, usize, u64)>,
+    /// next update of the files
+    head: Option<Update>,
+    mem: Peekable<std::vec::IntoIter<Update>>,
+    batch: std::vec::IntoIter<Update>,
+}
+
+impl Reader {
+    fn new(source: Source, written: u64) -> Self {
+        let mut mem = source.mem.visible(written);
+        mem.sort_by_key(|up| (up.ts, up.seq));
+        Reader {
+            book: source.book,
+            scan: source.scan,
+            files: None,
+            resume: None,
+            head: None,
+            mem: mem.into_iter().peekable(),
+            batch: vec![].into_iter(),
+        }
+    }
+
+    /// the next update of the files or memory by (ts, seq)
+    fn next(&mut self) -> std::io::Result<Option<Update>> {
+        if self.head.is_none() {
+            self.head = self.next_in_files()?;
+        }
+        let in_mem = match (&self.head, self.mem.peek()) {
+            (Some(head), Some(up)) => (up.ts, up.seq) < (head.ts, head.seq),
+            (None, _) => true,
+            (Some(_), None) => false,
+        };
+        Ok(if in_mem { self.mem.next() } else { self.head.take() })
+    }
+
+    fn next_in_files(&mut self) -> std::io::Result<Option<Update>> {
+        loop {
+            if let Some(up) = self.batch.next() {
+                return Ok(Some(up));
+            }
+            if self.scan.is_none() {
+                return Ok(None);
+            }
+            match self.read_files() {
+                Ok(batch) if !batch.is_empty() => self.batch = batch.into_iter(),
+                Ok(_) => self.scan = None,
+                Err(e) => {
+                    error!("Unable to scan files of {}: {}", self.book, e);
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// the next batch of updates of the files, empty once they are all read
+    fn read_files(&mut self) -> std::io::Result<Vec<Update>> {
+        let scan = match &self.scan {
+            Some(scan) => scan,
+            None => return Ok(vec![]),
+        };
+        let (min_ts, max_ts) = scan.range;
+        if self.files.is_none() {
+            let files = files_for_range(&scan.folder, &scan.book, min_ts, max_ts)?;
+            self.files = Some(files.into_iter().map(|(fname, _meta)| fname).collect());
+        }
+        let files = self.files.as_mut().unwrap();
+        let mut batch = Vec::with_capacity(BATCH_LEN);
+        while let Some(fname) = files.front() {
+            let (offset, skip, from_ts) = match self.resume.take() {
+                Some((offset, skip, ts)) => (Some(offset), skip, ts),
+                None => (None, 0, min_ts),
+            };
+            // updates of the batch at `pos` seen so far
+            let mut seen = (None, 0);
+            let mut resume = None;
+            for_each_from(fname, offset, from_ts, &mut |pos, up| {
+                if seen.0 != Some(pos) {
+                    seen = (Some(pos), 0);
+                }
+                seen.1 += 1;
+                if offset == Some(pos) && seen.1 <= skip {
+                    return true;
+                }
+                if up.ts > max_ts {
+                    return false;
+                }
+                if up.ts >= min_ts && scan.predicate.as_ref().map(|p| p.matches(up)).unwrap_or(true) {
+                    batch.push(*up);
+                    if batch.len() == BATCH_LEN {
+                        resume = Some((pos, seen.1, up.ts));
+                        return false;
+                    }
+                }
+                true
+            })?;
+            if resume.is_some() {
+                self.resume = resume;
+                break;
+            }
+            files.pop_front();
+        }
+        Ok(batch)
+    }
+}
@@ -1,22 +1,47 @@
-use crate::prelude::*;
 use tdb_core::utils;
 use tdb_core::dtf::update::Update;
+use tdb_core::dtf::scale::{Decimal, Scale};
+
+/// An update as written in an ADD command,
+/// price and size are scaled once the tick size and lot size of the book are known
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecimalUpdate {
+    pub ts: u64,
+    pub seq: u32,
+    pub is_trade: bool,
+    pub is_bid: bool,
+    pub price: Decimal,
+    pub size: Decimal,
+}
 
+impl DecimalUpdate {
+    /// None if price or size is not a multiple of the tick size or lot size
+    pub fn to_update(&self, scale: &Scale) -> Option<Update> {
+        Some(Update {
+            ts: self.ts,
+            seq: self.seq,
+            is_trade: self.is_trade,
+            is_bid: self.is_bid,
+            price: scale.ticks(self.price)?,
+            size: scale.lots(self.size)?,
+        })
+    }
+}
 
 /// Parses a line that looks like
 ///
 /// 1505177459.658, 139010, t, t, 0.0703629, 7.65064249;
 ///
-/// into an `Update` struct.
+/// into a `DecimalUpdate` struct.
 ///
-pub fn parse_line(string: &str) -> Option<Update> {
-    let mut u = Update {
+pub fn parse_line(string: &str) -> Option<DecimalUpdate> {
+    let mut u = DecimalUpdate {
         ts: 0,
         seq: 0,
         is_bid: false,
         is_trade: false,
-        price: -0.1,
-        size: -0.1,
+        price: Decimal::new(0, 0),
+        size: Decimal::new(0, 0),
     };
     let mut buf: String = String::new();
     let mut count = 0;
@@ -50,13 +75,13 @@ pub fn parse_line(string: &str) -> Option<Update> {
                     u.is_bid = most_current_bool;
                 }
                 4 => {
-                    u.price = match buf.parse::<f32>() {
+                    u.price = match buf.parse::<Decimal>() {
                         Ok(price) => price,
                         Err(_) => return None,
                     }
                 }
                 5 => {
-                    u.size = match buf.parse::<f32>() {
+                    u.size = match buf.parse::<Decimal>() {
                         Ok(size) => size,
                         Err(_) => return None,
                     }
@@ -68,60 +93,13 @@ pub fn parse_line(string: &str) -> Option<Update> {
         }
     }
 
-    if u.price < 0. || u.size < 0. {
+    if count < 6 {
         None
     } else {
         Some(u)
     }
 }
 
-pub fn parse_dbname(string: &str) -> (usize, &str) {
-    let into_indices: Vec<_> = string.match_indices(" INTO ").collect();
-    let (index, _) = into_indices[0];
-    let dbname = &string[(index + 6)..];
-    (index, dbname)
-}
-
-/// returns Option<Update, dbname>
-pub fn parse_add_into(string: &str) -> (Option<Update>, Option<BookName>) {
-    let (index, dbname) = parse_dbname(string);
-    let data_string: &str = {
-        if string.contains("ADD ") {
-            &string[4..(index)]
-        } else if string.contains("INSERT ") {
-            &string[7..(index)]
-        } else {
-            return (None, None);
-        }
-    };
-
-    match parse_line(data_string) {
-        Some(up) => (Some(up), Some(BookName::from(dbname).unwrap())),
-        None => (None, None),
-    }
-}
-
-pub fn parse_get_range(string: &str) -> Option<(u64, u64)> {
-    if string.contains(" FROM ") {
-        // range to query
-        let from_epoch = &string[(string.find(" FROM ").unwrap() + 6)..]
-            .split(' ')
-            .collect::<Vec<&str>>()
-            [0]
-            .parse::<u64>()
-            .unwrap() * 1000;
-        let to_epoch = &string[(string.find(" TO ").unwrap() + 4)..]
-            .split(' ')
-            .collect::<Vec<&str>>()
-            [0]
-            .parse::<u64>()
-            .unwrap() * 1000;
-        Some((from_epoch, to_epoch))
-    } else {
-        None
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -138,69 +116,36 @@ mod tests {
     #[test]
     fn should_parse_string_okay() {
         let string = "1505177459.658, 139010, f, t, 0.0703629, 7.65064249;";
-        let target = Update {
+        let target = DecimalUpdate {
             ts: 1505177459658,
             seq: 139010,
             is_trade: false,
             is_bid: true,
-            price: 0.0703629,
-            size: 7.65064249,
+            price: "0.0703629".parse().unwrap(),
+            size: "7.65064249".parse().unwrap(),
         };
         assert_eq!(target, parse_line(&string).unwrap());
 
 
         let string1 = "1505177459.65, 139010, t, f, 0.0703620, 7.65064240;";
-        let target1 = Update {
+        let target1 = DecimalUpdate {
             ts: 1505177459650,
             seq: 139010,
             is_trade: true,
             is_bid: false,
-            price: 0.0703620,
-            size: 7.65064240,
+            price: "0.0703620".parse().unwrap(),
+            size: "7.65064240".parse().unwrap(),
         };
         assert_eq!(target1, parse_line(&string1).unwrap());
     }
 
     #[test]
-    fn should_parse_dbname_ok() {
-        assert_eq!(parse_dbname("INSERT 1 INTO dbname"), (8, "dbname"));
-        assert_eq!(parse_dbname("INSERT 1000, INTO dbname1;"), (12, "dbname1;"));
-    }
+    fn should_scale_exactly() {
+        let up = parse_line("1505177459.658, 139010, f, t, 0.0703629, 7.65064249;").unwrap();
+        let scaled = up.to_update(&Scale::default()).unwrap();
+        assert_eq!((scaled.price, scaled.size), (7036290, 765064249));
 
-    #[test]
-    fn should_parse_add_into_ok() {
-        let cmd = "INSERT 1505177459.65, 139010, t, f, 0.0703620, 7.65064240; INTO dbname";
-        info!("{:?}", parse_add_into(cmd));
-        let target = Update {
-            ts: 1505177459650,
-            seq: 139010,
-            is_trade: true,
-            is_bid: false,
-            price: 0.0703620,
-            size: 7.65064240,
-        };
-        assert_eq!(
-            (Some(target), Some(BookName::from("dbname").unwrap())),
-            parse_add_into(cmd)
-        );
+        let coarse = Scale::new("0.0001".parse().unwrap(), "0.01".parse().unwrap()).unwrap();
+        assert!(up.to_update(&coarse).is_none());
     }
-
-    #[test]
-    fn should_parse_default_ok() {
-        let cmd = "ADD 0,0,f,f,0,0; INTO default";
-        info!("{:?}", parse_add_into(cmd));
-        let target = Update {
-            ts: 0,
-            seq: 0,
-            is_trade: false,
-            is_bid: false,
-            price: 0.,
-            size: 0.,
-        };
-        assert_eq!(
-            (Some(target), Some(BookName::from("default").unwrap())),
-            parse_add_into(cmd)
-        );
-    }
-
 }
@@ -5,6 +5,7 @@ pub mod gstorage;
 #[cfg(feature = "influx")]
 pub mod influx;
 pub mod history;
+pub mod retention;
 
 /// Run each plugin in a separate thread
 pub async fn run_plugins(broker: Sender<Event>, settings: Arc<Settings>) {
@@ -12,6 +13,9 @@ pub async fn run_plugins(broker: Sender<Event>, settings: Arc<Settings>) {
     if settings.granularity > 0 {
         history::run(broker.clone(), settings.clone()).await;
     }
+    if settings.retention_interval > 0 {
+        retention::run(broker.clone(), settings.clone()).await;
+    }
     #[cfg(feature = "gcs")] gstorage::run(broker, settings).await;
     #[cfg(feature = "influx")] influx::run(broker, settings).await;
 }
@@ -0,0 +1,310 @@
+//! retention policies
+//!
+//! Periodically drops the updates that are older than the max age of a book
+//! or that don't fit in its max on-disk size. Expired partitions and files are
+//! deleted, partially expired files are rewritten without the expired updates.
+//! The checkpoints that are kept stay in place, and the first file kept starts
+//! with a checkpoint of the book right before the oldest update kept so that
+//! the orderbook can still be rebuilt from the updates left.
+//!
+//! The policy of a book that doesn't use the default one is kept next to its
+//! dtf files in `{dtf_folder}/{book}.retention`, as shown by `RETENTION book`.
+use crate::prelude::*;
+
+use std::{cmp, fmt, fs, io, time};
+use tdb_core::dtf::file_format::{self as ff, CheckpointRecord, EncodeOptions};
+use tdb_core::storage::checkpoint::{self, Checkpoint};
+use tdb_core::storage::partition::book_files;
+
+/// max age and max on-disk size of a book, no limit when None
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct Retention {
+    /// maximum age of updates in ms
+    pub max_age: Option<u64>,
+    /// maximum size of the dtf files of a book in bytes
+    pub max_bytes: Option<u64>,
+}
+
+impl Retention {
+    /// if nothing is ever dropped
+    pub fn is_unlimited(&self) -> bool {
+        self.max_age.is_none() && self.max_bytes.is_none()
+    }
+}
+
+impl fmt::Display for Retention {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let or_null = |v: Option<u64>| v.map(|v| v.to_string()).unwrap_or_else(|| "null".to_owned());
+        write!(f, r#"{{"max_age_ms": {}, "max_bytes": {}}}"#, or_null(self.max_age), or_null(self.max_bytes))
+    }
+}
+
+/// path of the policy of `book`
+pub fn policy_path(folder: &str, book: &str) -> String {
+    format!("{}/{}.retention", folder, book)
+}
+
+/// persist the policy of `book`, None removes it so that the default one applies
+pub fn save_policy(folder: &str, book: &str, policy: Option<Retention>) -> io::Result<()> {
+    let fname = policy_path(folder, book);
+    match policy {
+        Some(policy) => {
+            let tmp = format!("{}.tmp", fname);
+            fs::write(&tmp, policy.to_string())?;
+            fs::rename(&tmp, &fname)
+        }
+        None => match fs::remove_file(&fname) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        },
+    }
+}
+
+/// the policy persisted for `book`, None if it uses the default one
+pub fn load_policy(folder: &str, book: &str) -> io::Result<Option<Retention>> {
+    let buf = match fs::read(policy_path(folder, book)) {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let json: serde_json::Value = serde_json::from_slice(&buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(Some(Retention { max_age: json["max_age_ms"].as_u64(), max_bytes: json["max_bytes"].as_u64() }))
+}
+
+/// parses an age like `90d`, `12h`, `30m` or `45s` into ms, plain numbers are seconds
+pub fn parse_age(s: &str) -> Option<u64> {
+    let (n, unit) = split_unit(s);
+    let secs = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    n.parse::<u64>().ok()?.checked_mul(secs * 1000)
+}
+
+/// parses a size like `512M` or `10G` into bytes, plain numbers are bytes
+pub fn parse_bytes(s: &str) -> Option<u64> {
+    let (n, unit) = split_unit(s);
+    let shift = match unit {
+        "" | "B" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return None,
+    };
+    n.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+fn split_unit(s: &str) -> (&str, &str) {
+    let idx = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    s.split_at(idx)
+}
+
+/// parses `none` or a limit with `parse`
+pub fn parse_limit(s: &str, parse: fn(&str) -> Option<u64>) -> Option<Option<u64>> {
+    if s.eq_ignore_ascii_case("none") {
+        Some(None)
+    } else {
+        parse(s).map(Some)
+    }
+}
+
+pub async fn run(broker: Sender<Event>, settings: Arc<Settings>) {
+    task::spawn(timer_loop(broker, settings));
+}
+
+pub async fn timer_loop(mut broker: Sender<Event>, settings: Arc<Settings>) {
+    let dur = time::Duration::from_secs(settings.retention_interval);
+    loop {
+        broker.send(Event::EnforceRetention).await.unwrap();
+        task::sleep(dur).await;
+    }
+}
+
+/// Drops the updates of `book` in `folder` that fall outside of `policy` at `now` (in ms).
+///
+/// Returns the number of updates removed from disk and the timestamp
+/// of the oldest update that was kept.
+pub fn enforce(folder: &str, book: &str, policy: &Retention, now: u64) -> io::Result<(u64, u64)> {
+    let files = book_files(folder, book)?;
+    let mut cutoff = policy.max_age.map(|age| now.saturating_sub(age)).unwrap_or(0);
+    if let Some(max_bytes) = policy.max_bytes {
+        cutoff = cmp::max(cutoff, size_cutoff(&files, max_bytes)?);
+    }
+    let mut removed = 0;
+    if cutoff > 0 {
+        let mut base = base_checkpoint(folder, book, &files, cutoff)?;
+        for fname in &files {
+            removed += truncate_file(fname, cutoff, &mut base)?;
+        }
+    }
+    Ok((removed, cutoff))
+}
+
+/// checkpoint of the book right after its last update before `cutoff`, None if there is none
+fn base_checkpoint(folder: &str, book: &str, files: &[String], cutoff: u64) -> io::Result<Option<CheckpointRecord>> {
+    let meta = match files.first() {
+        Some(fname) => ff::read_meta(fname)?,
+        None => return Ok(None),
+    };
+    if meta.min_ts >= cutoff {
+        return Ok(None);
+    }
+    let (orderbook, last, _) = checkpoint::orderbook_at(folder, book, cutoff - 1, meta.scale)?;
+    Ok(last.map(|(ts, seq)| Checkpoint { ts, seq, orderbook }.to_record()))
+}
+
+/// oldest timestamp to keep so that the newest updates fit in `max_bytes`
+fn size_cutoff(files: &[String], max_bytes: u64) -> io::Result<u64> {
+    let mut total = 0;
+    for fname in files.iter().rev() {
+        let len = fs::metadata(fname)?.len();
+        if total + len <= max_bytes {
+            total += len;
+            continue;
+        }
+        // keep the newest part of the file in proportion to the remaining budget
+        let ups = ff::decode(fname, None)?;
+        let keep = (ups.len() as u64 * (max_bytes - total) / len) as usize;
+        return Ok(match keep {
+            0 => ups.iter().map(|up| up.ts).max().map(|ts| ts + 1).unwrap_or(0),
+            keep => ups[ups.len() - keep].ts,
+        });
+    }
+    Ok(0)
+}
+
+/// Removes the updates older than `cutoff` from a file, returns how many were removed.
+///
+/// The first file that is kept starts with the `base` checkpoint.
+fn truncate_file(fname: &str, cutoff: u64, base: &mut Option<CheckpointRecord>) -> io::Result<u64> {
+    let meta = ff::read_meta(fname)?;
+    if meta.count == 0 || (meta.min_ts >= cutoff && base.is_none()) {
+        return Ok(0);
+    }
+    if meta.max_ts < cutoff {
+        info!("Retention: removing {}", fname);
+        fs::remove_file(fname)?;
+        return Ok(meta.count);
+    }
+
+    info!("Retention: truncating {} before {}", fname, cutoff);
+    let version = ff::read_version(&mut ff::file_reader(fname)?)?;
+    let ups: Vec<Update> = ff::decode(fname, None)?
+        .into_iter()
+        .filter(|up| up.ts >= cutoff)
+        .collect();
+    let checkpoints: Vec<CheckpointRecord> = base
+        .take()
+        .into_iter()
+        .chain(ff::read_checkpoints(fname)?.into_iter().filter(|ck| ck.ts >= cutoff))
+        .collect();
+    let opts = EncodeOptions {
+        version,
+        scale: meta.scale,
+        properties: meta.properties,
+    };
+    let tmp = format!("{}.tmp", fname);
+    ff::encode_with_checkpoints(&tmp, &meta.symbol, &ups, &checkpoints, &opts)?;
+    fs::rename(&tmp, fname)?;
+    Ok(meta.count.saturating_sub(ups.len() as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tdb_core::postprocessing::orderbook::Orderbook;
+    use tdb_core::storage::partition::Partitioning;
+
+    #[test]
+    fn should_parse_limits() {
+        assert_eq!(parse_age("90d"), Some(90 * 24 * 3600 * 1000));
+        assert_eq!(parse_age("45"), Some(45_000));
+        assert_eq!(parse_age("1y"), None);
+        assert_eq!(parse_bytes("10G"), Some(10 << 30));
+        assert_eq!(parse_bytes("512"), Some(512));
+        assert_eq!(parse_limit("none", parse_bytes), Some(None));
+        assert_eq!(parse_limit("x", parse_bytes), None);
+    }
+
+    #[test]
+    fn should_persist_policies() {
+        let folder = "retention-policy-test";
+        fs::create_dir_all(folder).unwrap();
+        let policy = Retention { max_age: Some(90 * 24 * 3600 * 1000), max_bytes: None };
+        assert_eq!(load_policy(folder, "book").unwrap(), None);
+        save_policy(folder, "book", Some(policy)).unwrap();
+        assert_eq!(load_policy(folder, "book").unwrap(), Some(policy));
+        save_policy(folder, "book", None).unwrap();
+        assert_eq!(load_policy(folder, "book").unwrap(), None);
+        save_policy(folder, "book", None).unwrap();
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn should_drop_expired_partitions() {
+        let folder = "retention-test";
+        let _ = fs::remove_dir_all(folder);
+        fs::create_dir_all(format!("{}/book", folder)).unwrap();
+        let day = 24 * 3600 * 1000;
+        let ups: Vec<Update> = (0..4 * 24)
+            .map(|i| Update { ts: i * 3600 * 1000, seq: i as u32, is_trade: false, is_bid: true, price: 1, size: 1 })
+            .collect();
+        for chunk in ups.chunks(24) {
+            let fname = Partitioning::Daily.file_path(folder, "book", chunk[0].ts).unwrap();
+            ff::encode(&fname, "book", chunk).unwrap();
+        }
+
+        let policy = Retention { max_age: Some(2 * day + 12 * 3600 * 1000), max_bytes: None };
+        let (removed, cutoff) = enforce(folder, "book", &policy, 4 * day).unwrap();
+        assert_eq!(cutoff, day + 12 * 3600 * 1000);
+        assert_eq!(removed, 36);
+        let files = book_files(folder, "book").unwrap();
+        assert_eq!(files.len(), 3);
+        assert_eq!(ff::read_meta(&files[0]).unwrap().min_ts, cutoff);
+
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn should_keep_checkpoints() {
+        let folder = "retention-checkpoints-test";
+        let _ = fs::remove_dir_all(folder);
+        fs::create_dir_all(folder).unwrap();
+        let fname = format!("{}/book.dtf", folder);
+        let ups: Vec<Update> = (0..100)
+            .map(|i| Update { ts: i * 1000, seq: i as u32, is_trade: false, is_bid: i % 2 == 0, price: 100 + i % 10, size: i })
+            .collect();
+        let mut orderbook = Orderbook::with_scale(Default::default());
+        let mut checkpoints = vec![];
+        for up in &ups {
+            orderbook.process_update(up);
+            if up.seq % 30 == 29 {
+                checkpoints.push(Checkpoint { ts: up.ts, seq: up.seq, orderbook: orderbook.clone() }.to_record());
+            }
+        }
+        ff::encode_with_checkpoints(&fname, "book", &ups, &checkpoints, &Default::default()).unwrap();
+
+        let policy = Retention { max_age: Some(60_000), max_bytes: None };
+        let (removed, cutoff) = enforce(folder, "book", &policy, 100_000).unwrap();
+        assert_eq!((removed, cutoff), (40, 40_000));
+        assert_eq!(ff::decode(&fname, None).unwrap(), &ups[40..]);
+        // a base checkpoint at the cutoff followed by the checkpoints after it
+        let kept = ff::read_checkpoints(&fname).unwrap();
+        assert_eq!(kept.iter().map(|ck| ck.seq).collect::<Vec<_>>(), vec![39, 59, 89]);
+        assert_eq!(&kept[1..], &checkpoints[1..]);
+        // the book is rebuilt as if the expired updates were still there
+        let mut expected = Orderbook::with_scale(Default::default());
+        ups[..=50].iter().for_each(|up| expected.process_update(up));
+        let (rebuilt, last, replayed) = checkpoint::orderbook_at(folder, "book", 50_000, Default::default()).unwrap();
+        assert_eq!((last, replayed), (Some((50_000, 50)), 11));
+        assert!(rebuilt == expected);
+        assert!(checkpoint::orderbook_at(folder, "book", 99_000, Default::default()).unwrap().0 == orderbook);
+
+        fs::remove_dir_all(folder).unwrap();
+    }
+}
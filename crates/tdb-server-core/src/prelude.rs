@@ -27,7 +27,9 @@ pub use async_std::{
 pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
 pub use arrayvec::ArrayString;
-pub type BookName = ArrayString<64>;
+/// maximum length of a book name in bytes
+pub const BOOK_NAME_LEN: usize = 64;
+pub type BookName = ArrayString<BOOK_NAME_LEN>;
 
 pub use std::net::SocketAddr;
 
@@ -0,0 +1,1072 @@
+//! query language
+//!
+//! A query is a command keyword followed by its arguments and optional clauses.
+//! Keywords are case-insensitive, clauses can be given in any order and
+//! identifiers can be quoted with `"` or `'` (a backslash escapes the next character).
+//!
+//! ```text
+//! GET 10 FROM 1513749500 TO 1513749600 AS JSON IN MEM
+//! get 10 in mem as json from 1513749500 to 1513749600
+//! GET ALL WHERE is_trade AND price BETWEEN 0.05 AND 0.06 AND size > 1.5
+//! GET FROM 1513749500 TO 1513749600 IN BOOKS (bnc_btc_eth, "gdax btc eth") AS CSV
+//! GET LIMIT 1000 AFTER 000001607280c7d9000000070000000000000050 AS CSV
+//! SELECT count, sum(size), vwap, ohlc WHERE is_trade GROUP BY 1m, side AS CSV
+//! USE "bnc btc eth"
+//! ```
+//!
+//! Malformed queries are rejected with a `ParseError` giving the byte offset
+//! of the offending token.
+use crate::prelude::*;
+use crate::handler::{InsertData, Command, GetFormat, Page, ReadLocation, ReqCount, Select};
+use crate::filter::{Field, Filter, Op};
+use crate::stream::Cursor;
+use tdb_core::postprocessing::aggregate::{self, Aggregate, GroupBy};
+use crate::parser;
+use crate::plugins::retention::{self, Retention};
+use tdb_core::dtf::file_format::Properties;
+use tdb_core::dtf::scale::{Decimal, Scale};
+use std::fmt;
+
+/// what went wrong while parsing a query
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// the first word is not a command
+    UnknownCommand(String),
+    /// the query ended while expecting something
+    UnexpectedEnd(&'static str),
+    /// found a token while expecting something else
+    UnexpectedToken(String, &'static str),
+    /// a quoted identifier is not closed
+    UnterminatedQuote,
+    /// a value that can't be parsed as what it should be
+    InvalidValue(String, &'static str),
+    /// a clause given twice
+    DuplicateClause(&'static str),
+    /// a book name longer than `BOOK_NAME_LEN`
+    BookNameTooLong(String),
+    /// more than `MAX_DEPTH` nested `NOT` or parentheses
+    TooDeep,
+    /// a clause that can't be used with another one
+    Conflict(&'static str, &'static str),
+}
+
+/// error with the byte offset in the query where it occurred
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub pos: usize,
+    pub kind: ErrorKind,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ErrorKind::*;
+        match &self.kind {
+            UnknownCommand(cmd) => write!(f, "Unknown command `{}`", cmd)?,
+            UnexpectedEnd(expected) => write!(f, "Unexpected end of query, expected {}", expected)?,
+            UnexpectedToken(found, expected) => write!(f, "Unexpected `{}`, expected {}", found, expected)?,
+            UnterminatedQuote => write!(f, "Unterminated quote")?,
+            InvalidValue(value, what) => write!(f, "Invalid {} `{}`", what, value)?,
+            DuplicateClause(clause) => write!(f, "Duplicate {} clause", clause)?,
+            BookNameTooLong(name) => write!(f, "Book name `{}` is longer than {} bytes", name, BOOK_NAME_LEN)?,
+            TooDeep => write!(f, "Expression nested more than {} levels", MAX_DEPTH)?,
+            Conflict(a, b) => write!(f, "{} can't be used with {}", a, b)?,
+        }
+        write!(f, " at position {}.", self.pos)
+    }
+}
+
+type ParseResult<T> = std::result::Result<T, ParseError>;
+
+/// maximum nesting of `WHERE` expressions
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenKind<'a> {
+    /// keyword, number or unquoted identifier
+    Word(&'a str),
+    /// quoted identifier without its quotes
+    Quoted(String),
+    /// `,`, `;`, `=`, `<`, `>`, `!`, `(` or `)`
+    Punct(char),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token<'a> {
+    pub pos: usize,
+    pub kind: TokenKind<'a>,
+}
+
+impl<'a> Token<'a> {
+    fn text(&self) -> String {
+        match &self.kind {
+            TokenKind::Word(w) => (*w).to_owned(),
+            TokenKind::Quoted(q) => format!("\"{}\"", q),
+            TokenKind::Punct(c) => c.to_string(),
+        }
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.kind, TokenKind::Word(w) if w.eq_ignore_ascii_case(keyword))
+    }
+}
+
+/// split a query into tokens
+pub fn tokenize(line: &str) -> ParseResult<Vec<Token<'_>>> {
+    let mut ret = vec![];
+    let mut chars = line.char_indices().peekable();
+    while let Some(&(pos, ch)) = chars.peek() {
+        match ch {
+            _ if ch.is_whitespace() => {
+                chars.next();
+            }
+            ',' | ';' | '=' | '<' | '>' | '!' | '(' | ')' => {
+                chars.next();
+                ret.push(Token { pos, kind: TokenKind::Punct(ch) });
+            }
+            '"' | '\'' => {
+                chars.next();
+                let mut quoted = String::new();
+                loop {
+                    match chars.next() {
+                        None => return Err(ParseError { pos, kind: ErrorKind::UnterminatedQuote }),
+                        Some((_, c)) if c == ch => break,
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, c)) => quoted.push(c),
+                            None => return Err(ParseError { pos, kind: ErrorKind::UnterminatedQuote }),
+                        },
+                        Some((_, c)) => quoted.push(c),
+                    }
+                }
+                ret.push(Token { pos, kind: TokenKind::Quoted(quoted) });
+            }
+            _ => {
+                let mut end = line.len();
+                while let Some(&(i, c)) = chars.peek() {
+                    if c.is_whitespace() || matches!(c, ',' | ';' | '=' | '<' | '>' | '!' | '(' | ')' | '"' | '\'') {
+                        end = i;
+                        break;
+                    }
+                    chars.next();
+                }
+                ret.push(Token { pos, kind: TokenKind::Word(&line[pos..end]) });
+            }
+        }
+    }
+    Ok(ret)
+}
+
+/// parse a query into a command
+pub fn parse(line: &str) -> ParseResult<Command> {
+    let tokens = tokenize(line)?;
+    Parser { line, tokens, idx: 0, depth: 0 }.command()
+}
+
+struct Parser<'a> {
+    line: &'a str,
+    tokens: Vec<Token<'a>>,
+    idx: usize,
+    /// nesting of the `WHERE` expression being parsed
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token<'a>> {
+        self.tokens.get(self.idx)
+    }
+
+    fn next(&mut self, expected: &'static str) -> ParseResult<Token<'a>> {
+        let tok = self.tokens.get(self.idx).cloned().ok_or(ParseError {
+            pos: self.line.len(),
+            kind: ErrorKind::UnexpectedEnd(expected),
+        })?;
+        self.idx += 1;
+        Ok(tok)
+    }
+
+    /// consume the next token if it is `keyword`
+    fn keyword(&mut self, keyword: &str) -> bool {
+        let found = self.peek().map(|tok| tok.is_keyword(keyword)).unwrap_or(false);
+        if found {
+            self.idx += 1;
+        }
+        found
+    }
+
+    /// consume the next token if it is the punctuation `ch`
+    fn punct(&mut self, ch: char) -> bool {
+        let found = self.peek().map(|tok| tok.kind == TokenKind::Punct(ch)).unwrap_or(false);
+        if found {
+            self.idx += 1;
+        }
+        found
+    }
+
+    /// consume the next token if it is the punctuation `ch` right after `prev`
+    fn adjacent(&mut self, prev: &Token, ch: char) -> bool {
+        let found = self.peek().map(|tok| tok.pos == prev.pos + 1).unwrap_or(false);
+        found && self.punct(ch)
+    }
+
+    fn expect_keyword(&mut self, keyword: &'static str) -> ParseResult<()> {
+        let tok = self.next(keyword)?;
+        if tok.is_keyword(keyword) {
+            Ok(())
+        } else {
+            Err(unexpected(&tok, keyword))
+        }
+    }
+
+    /// an unquoted word
+    fn word(&mut self, expected: &'static str) -> ParseResult<(usize, &'a str)> {
+        let tok = self.next(expected)?;
+        match tok.kind {
+            TokenKind::Word(w) => Ok((tok.pos, w)),
+            _ => Err(unexpected(&tok, expected)),
+        }
+    }
+
+    /// a word or a quoted identifier
+    fn ident(&mut self, expected: &'static str) -> ParseResult<(usize, String)> {
+        let tok = self.next(expected)?;
+        match tok.kind {
+            TokenKind::Word(w) => Ok((tok.pos, w.to_owned())),
+            TokenKind::Quoted(q) => Ok((tok.pos, q)),
+            _ => Err(unexpected(&tok, expected)),
+        }
+    }
+
+    fn value<T: std::str::FromStr>(&mut self, what: &'static str) -> ParseResult<T> {
+        let (pos, w) = self.word(what)?;
+        w.parse().map_err(|_| ParseError { pos, kind: ErrorKind::InvalidValue(w.to_owned(), what) })
+    }
+
+    fn book_name(&mut self) -> ParseResult<BookName> {
+        let (pos, name) = self.ident("book name")?;
+        match BookName::from(&name) {
+            Ok(book_name) => Ok(book_name),
+            Err(_) => Err(ParseError { pos, kind: ErrorKind::BookNameTooLong(name) }),
+        }
+    }
+
+    /// timestamp in seconds with an optional fraction, in ms
+    fn timestamp(&mut self) -> ParseResult<u64> {
+        let (pos, w) = self.word("timestamp")?;
+        parse_timestamp(w).ok_or(ParseError { pos, kind: ErrorKind::InvalidValue(w.to_owned(), "timestamp") })
+    }
+
+    /// `FROM ts TO ts`, after `FROM`
+    fn range(&mut self) -> ParseResult<(u64, u64)> {
+        let min_ts = self.timestamp()?;
+        self.expect_keyword("TO")?;
+        let max_ts = self.timestamp()?;
+        Ok((min_ts, max_ts))
+    }
+
+    fn end(&mut self) -> ParseResult<()> {
+        match self.peek() {
+            None => Ok(()),
+            Some(tok) => Err(unexpected(tok, "end of query")),
+        }
+    }
+
+    fn command(&mut self) -> ParseResult<Command> {
+        let (pos, cmd) = match self.peek() {
+            None => return Ok(Command::Noop),
+            Some(_) => self.word("command")?,
+        };
+        let ret = match cmd.to_ascii_uppercase().as_str() {
+            "HELLO" => {
+                let version = self.value("protocol version")?;
+                let mut capabilities = vec![];
+                while self.peek().is_some() {
+                    capabilities.push(self.ident("capability")?.1.to_ascii_lowercase());
+                }
+                Command::Hello(version, capabilities)
+            }
+            "PING" => Command::Ping,
+            "HELP" => Command::Help,
+            "INFO" => Command::Info,
+            "PERF" => Command::Perf,
+            "OB" => {
+                let book = match self.peek() {
+                    Some(tok) if !tok.is_keyword("AT") && !tok.is_keyword("DEPTH") => Some(self.book_name()?),
+                    _ => None,
+                };
+                if self.keyword("AT") {
+                    self.orderbook_at(book)?
+                } else {
+                    Command::Orderbook(book, self.depth()?)
+                }
+            }
+            "COUNT" => {
+                let (mut all, mut mem) = (false, false);
+                while let Some(tok) = self.peek() {
+                    if tok.is_keyword("ALL") && !all {
+                        self.idx += 1;
+                        all = true;
+                    } else if tok.is_keyword("IN") && !mem {
+                        self.idx += 1;
+                        self.expect_keyword("MEM")?;
+                        mem = true;
+                    } else {
+                        return Err(unexpected(tok, "ALL or IN MEM"));
+                    }
+                }
+                Command::Count(req_count(all), read_location(mem))
+            }
+            "CLEAR" => Command::Clear(req_count(self.keyword("ALL"))),
+            "FLUSH" => Command::Flush(req_count(self.keyword("ALL"))),
+            "SUBSCRIBE" => Command::Subscribe(self.book_name()?, self.depth()?),
+            "LOAD" => Command::Load(self.book_name()?),
+            "USE" => Command::Use(self.book_name()?),
+            "EXISTS" => Command::Exists(self.book_name()?),
+            "CREATE" => self.create()?,
+            "RETENTION" => self.retention()?,
+            "ADD" | "INSERT" => self.insert()?,
+            "GET" => self.get()?,
+            "SELECT" => self.select()?,
+            _ => return Err(ParseError { pos, kind: ErrorKind::UnknownCommand(cmd.to_owned()) }),
+        };
+        self.end()?;
+        Ok(ret)
+    }
+
+    /// `CREATE db [TICK size] [LOT size] [key=value ...]`, increments not given default to 1e-8
+    fn create(&mut self) -> ParseResult<Command> {
+        let dbname = self.book_name()?;
+        let mut tick_size: Option<Decimal> = None;
+        let mut lot_size: Option<Decimal> = None;
+        let mut properties = Properties::new();
+        let pos = self.peek().map(|tok| tok.pos).unwrap_or(self.line.len());
+        while let Some(tok) = self.peek().cloned() {
+            if tok.is_keyword("TICK") || tok.is_keyword("LOT") {
+                self.idx += 1;
+                let (size, clause) = if tok.is_keyword("TICK") {
+                    (&mut tick_size, "TICK")
+                } else {
+                    (&mut lot_size, "LOT")
+                };
+                if size.is_some() {
+                    return Err(ParseError { pos: tok.pos, kind: ErrorKind::DuplicateClause(clause) });
+                }
+                *size = Some(self.value("decimal")?);
+                continue;
+            }
+            let (_, key) = self.ident("TICK, LOT or a property")?;
+            match self.next("=")? {
+                Token { kind: TokenKind::Punct('='), .. } => (),
+                tok => return Err(unexpected(&tok, "=")),
+            }
+            let (_, value) = self.ident("property value")?;
+            properties.insert(key, value);
+        }
+        let scale = match (tick_size, lot_size) {
+            (None, None) => None,
+            (tick_size, lot_size) => {
+                let default = Scale::default();
+                let tick_size = tick_size.unwrap_or(default.tick_size);
+                let lot_size = lot_size.unwrap_or(default.lot_size);
+                Some(Scale::new(tick_size, lot_size).ok_or(ParseError {
+                    pos,
+                    kind: ErrorKind::InvalidValue(format!("{} {}", tick_size, lot_size), "tick size and lot size"),
+                })?)
+            }
+        };
+        Ok(Command::Create(dbname, scale, properties))
+    }
+
+    /// `RETENTION [db|ALL] [AGE age|none] [BYTES size|none]` or `RETENTION db DEFAULT`,
+    /// limits that are not given are none
+    fn retention(&mut self) -> ParseResult<Command> {
+        if self.peek().is_none() {
+            return Ok(Command::Retention(None));
+        }
+        let dbname = if self.keyword("ALL") { None } else { Some(self.book_name()?) };
+        if dbname.is_some() && self.keyword("DEFAULT") {
+            return Ok(Command::SetRetention(dbname, None));
+        }
+        if self.peek().is_none() {
+            return Ok(Command::Retention(dbname));
+        }
+
+        let mut policy = Retention::default();
+        let (mut age, mut bytes) = (false, false);
+        while let Some(tok) = self.peek().cloned() {
+            self.idx += 1;
+            let (seen, clause, what, parse): (_, _, _, fn(&str) -> Option<u64>) = if tok.is_keyword("AGE") {
+                (&mut age, "AGE", "age", retention::parse_age)
+            } else if tok.is_keyword("BYTES") {
+                (&mut bytes, "BYTES", "size", retention::parse_bytes)
+            } else {
+                return Err(unexpected(&tok, "AGE or BYTES"));
+            };
+            if *seen {
+                return Err(ParseError { pos: tok.pos, kind: ErrorKind::DuplicateClause(clause) });
+            }
+            *seen = true;
+            let (pos, w) = self.word(what)?;
+            let limit = retention::parse_limit(w, parse)
+                .ok_or(ParseError { pos, kind: ErrorKind::InvalidValue(w.to_owned(), what) })?;
+            match clause {
+                "AGE" => policy.max_age = limit,
+                _ => policy.max_bytes = limit,
+            }
+        }
+        Ok(Command::SetRetention(dbname, Some(policy)))
+    }
+
+    /// `ADD ts, seq, is_trade, is_bid, price, size; [INTO db]`
+    fn insert(&mut self) -> ParseResult<Command> {
+        let start = self.peek().map(|tok| tok.pos).unwrap_or(self.line.len());
+        let mut end = self.line.len();
+        while let Some(tok) = self.peek() {
+            if tok.kind == TokenKind::Punct(';') {
+                end = tok.pos;
+                self.idx += 1;
+                break;
+            }
+            if tok.is_keyword("INTO") {
+                end = tok.pos;
+                break;
+            }
+            self.idx += 1;
+        }
+        let data = format!("{};", &self.line[start..end]);
+        let up = parser::parse_line(&data).ok_or(ParseError {
+            pos: start,
+            kind: ErrorKind::InvalidValue(self.line[start..end].trim().to_owned(), "update"),
+        })?;
+        let dbname = if self.keyword("INTO") { Some(self.book_name()?) } else { None };
+        Ok(Command::Insert(Some(InsertData::Decimal(up)), dbname))
+    }
+
+    /// `GET (ALL|count) [FROM ts TO ts] [AS JSON|CSV|DTF] [IN MEM] [WHERE expr] [LIMIT n] [AFTER cursor]`,
+    /// `GET (ALL|count) ... IN BOOKS (book[, book ...])` without pages and dtf,
+    /// or `GET CANDLES resolution [FROM ts TO ts] [AS JSON|CSV]`, the count can be left
+    /// out before a clause
+    fn get(&mut self) -> ParseResult<Command> {
+        let candles = self.keyword("CANDLES");
+        let resolution = if candles {
+            let (pos, w) = self.word("resolution")?;
+            Some(w.parse().map_err(|_| ParseError { pos, kind: ErrorKind::InvalidValue(w.to_owned(), "resolution") })?)
+        } else {
+            None
+        };
+        let clause = self.peek()
+            .map(|tok| ["FROM", "AS", "IN", "WHERE", "LIMIT", "AFTER"].iter().any(|&k| tok.is_keyword(k)))
+            .unwrap_or(false);
+        let count = if candles || self.keyword("ALL") || clause {
+            None
+        } else {
+            Some(self.value("count or ALL")?)
+        };
+
+        let clauses = if candles {
+            self.clauses(&["FROM", "AS"], "FROM or AS")?
+        } else {
+            let allowed = ["FROM", "AS", "DTF", "IN MEM", "IN BOOKS", "WHERE", "LIMIT", "AFTER"];
+            self.clauses(&allowed, "FROM, AS, IN, WHERE, LIMIT or AFTER")?
+        };
+        if let Some((pos, books)) = clauses.books {
+            let conflict = |clause| Err(ParseError { pos, kind: ErrorKind::Conflict(clause, "IN BOOKS") });
+            match (clauses.format, clauses.limit, clauses.after) {
+                (Some(GetFormat::Dtf), _, _) => return conflict("AS DTF"),
+                (_, Some(_), _) => return conflict("LIMIT"),
+                (_, _, Some(_)) => return conflict("AFTER"),
+                _ => (),
+            }
+            return Ok(Command::GetMerged(
+                books,
+                count.map(ReqCount::Count).unwrap_or(ReqCount::All),
+                clauses.format.unwrap_or(GetFormat::Json),
+                clauses.range,
+                read_location(clauses.mem),
+                clauses.filter,
+            ));
+        }
+        // a count is the limit of a page
+        if let (Some(_), Some((pos, _))) = (count, clauses.limit) {
+            return Err(ParseError { pos, kind: ErrorKind::DuplicateClause("LIMIT") });
+        }
+        let page = if clauses.limit.is_some() || clauses.after.is_some() {
+            Some(Page { limit: clauses.limit.map(|(_, n)| n).or(count), after: clauses.after })
+        } else {
+            None
+        };
+        let count = count.map(ReqCount::Count).unwrap_or(ReqCount::All);
+        Ok(match resolution {
+            Some(res) => Command::GetCandles(res, clauses.range, clauses.format.unwrap_or(GetFormat::Json)),
+            None => Command::Get(
+                count,
+                clauses.format.unwrap_or(GetFormat::Dtf),
+                clauses.range,
+                read_location(clauses.mem),
+                clauses.filter,
+                page,
+            ),
+        })
+    }
+
+    /// `SELECT aggregate[, aggregate ...] [FROM ts TO ts] [WHERE expr] [GROUP BY key[, key ...]] [AS JSON|CSV] [IN MEM]`
+    /// where keys are an interval, `side` or `is_trade`
+    fn select(&mut self) -> ParseResult<Command> {
+        let mut aggregates = vec![];
+        loop {
+            self.aggregate(&mut aggregates)?;
+            if !self.punct(',') {
+                break;
+            }
+        }
+        let clauses = self.clauses(&["FROM", "AS", "IN MEM", "WHERE", "GROUP BY"], "FROM, AS, IN MEM, WHERE or GROUP BY")?;
+        let query = Select {
+            aggregates,
+            group_by: clauses.group_by.unwrap_or_default(),
+            range: clauses.range,
+            filter: clauses.filter,
+        };
+        Ok(Command::Select(query, clauses.format.unwrap_or(GetFormat::Json), read_location(clauses.mem)))
+    }
+
+    /// `count`, `sum(size)`, `sum(price*size)`, `vwap`, `min`, `max`, `first` or `last` of `price` or `size`,
+    /// `ohlc` is the first, max, min and last price
+    fn aggregate(&mut self, aggregates: &mut Vec<Aggregate>) -> ParseResult<()> {
+        const EXPECTED: &str = "count, sum, vwap, min, max, first, last or ohlc";
+        let (pos, w) = self.word(EXPECTED)?;
+        let name = w.to_ascii_lowercase();
+        let field = |arg: &str, pos| match arg {
+            "price" => Ok(aggregate::Field::Price),
+            "size" => Ok(aggregate::Field::Size),
+            _ => Err(ParseError { pos, kind: ErrorKind::InvalidValue(arg.to_owned(), "price or size") }),
+        };
+        let ret = match name.as_str() {
+            "count" => {
+                if self.peek().map(|tok| tok.kind == TokenKind::Punct('(')).unwrap_or(false) {
+                    let (pos, arg) = self.argument()?;
+                    if arg != "*" {
+                        return Err(ParseError { pos, kind: ErrorKind::InvalidValue(arg, "count argument") });
+                    }
+                }
+                Aggregate::Count
+            }
+            "vwap" => Aggregate::Vwap,
+            "ohlc" => {
+                use self::aggregate::Field::Price;
+                aggregates.extend(&[Aggregate::First(Price), Aggregate::Max(Price), Aggregate::Min(Price), Aggregate::Last(Price)]);
+                return Ok(());
+            }
+            "sum" => match self.argument()? {
+                (_, arg) if arg == "size" => Aggregate::SumSize,
+                (_, arg) if arg == "price*size" || arg == "size*price" => Aggregate::SumNotional,
+                (pos, arg) => return Err(ParseError { pos, kind: ErrorKind::InvalidValue(arg, "size or price*size") }),
+            },
+            "min" | "max" | "first" | "last" => {
+                let (pos, arg) = self.argument()?;
+                let f = field(&arg, pos)?;
+                match name.as_str() {
+                    "min" => Aggregate::Min(f),
+                    "max" => Aggregate::Max(f),
+                    "first" => Aggregate::First(f),
+                    _ => Aggregate::Last(f),
+                }
+            }
+            _ => return Err(ParseError { pos, kind: ErrorKind::UnexpectedToken(w.to_owned(), EXPECTED) }),
+        };
+        aggregates.push(ret);
+        Ok(())
+    }
+
+    /// `(arg)` of an aggregate, lowercased without whitespace
+    fn argument(&mut self) -> ParseResult<(usize, String)> {
+        let open = self.next("(")?;
+        if open.kind != TokenKind::Punct('(') {
+            return Err(unexpected(&open, "("));
+        }
+        let mut ret = String::new();
+        loop {
+            let tok = self.next(")")?;
+            match tok.kind {
+                TokenKind::Punct(')') => break,
+                TokenKind::Word(w) => ret.push_str(&w.to_ascii_lowercase()),
+                _ => return Err(unexpected(&tok, ")")),
+            }
+        }
+        Ok((open.pos + 1, ret))
+    }
+
+    /// `BY key[, key ...]` where keys are an interval, `side` or `is_trade`, after `GROUP`
+    fn group_by(&mut self) -> ParseResult<GroupBy> {
+        const EXPECTED: &str = "interval, side or is_trade";
+        self.expect_keyword("BY")?;
+        let mut ret = GroupBy::default();
+        loop {
+            let (pos, w) = self.word(EXPECTED)?;
+            match w.to_ascii_lowercase().as_str() {
+                "side" if !ret.side => ret.side = true,
+                "is_trade" if !ret.trade => ret.trade = true,
+                "side" | "is_trade" => return Err(ParseError { pos, kind: ErrorKind::DuplicateClause("GROUP BY") }),
+                _ if ret.interval.is_none() => {
+                    let interval = w.parse().map_err(|_| ParseError { pos, kind: ErrorKind::InvalidValue(w.to_owned(), "interval") })?;
+                    ret.interval = Some(interval);
+                }
+                _ => return Err(ParseError { pos, kind: ErrorKind::DuplicateClause("GROUP BY") }),
+            }
+            if !self.punct(',') {
+                return Ok(ret);
+            }
+        }
+    }
+
+    /// `(book[, book ...])`, a book listed twice is only read once
+    fn books(&mut self) -> ParseResult<Vec<BookName>> {
+        match self.next("(")? {
+            Token { kind: TokenKind::Punct('('), .. } => (),
+            tok => return Err(unexpected(&tok, "(")),
+        }
+        let mut ret: Vec<BookName> = vec![];
+        loop {
+            let book = self.book_name()?;
+            if !ret.contains(&book) {
+                ret.push(book);
+            }
+            match self.next(", or )")? {
+                Token { kind: TokenKind::Punct(','), .. } => (),
+                Token { kind: TokenKind::Punct(')'), .. } => return Ok(ret),
+                tok => return Err(unexpected(&tok, ", or )")),
+            }
+        }
+    }
+
+    /// optional `DEPTH n`
+    fn depth(&mut self) -> ParseResult<Option<u32>> {
+        if self.keyword("DEPTH") {
+            Ok(Some(self.value("depth")?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// `OB [book] AT ts [DEPTH n] [AS JSON|BINARY]`, after `AT`
+    fn orderbook_at(&mut self, book: Option<BookName>) -> ParseResult<Command> {
+        let ts = self.timestamp()?;
+        let (mut depth, mut format) = (None, None);
+        while let Some(tok) = self.peek().cloned() {
+            self.idx += 1;
+            if tok.is_keyword("DEPTH") && depth.is_none() {
+                depth = Some(self.value("depth")?);
+            } else if tok.is_keyword("AS") && format.is_none() {
+                let (pos, w) = self.word("JSON or BINARY")?;
+                format = Some(match w.to_ascii_uppercase().as_str() {
+                    "JSON" => GetFormat::Json,
+                    "BINARY" => GetFormat::Dtf,
+                    _ => return Err(ParseError { pos, kind: ErrorKind::InvalidValue(w.to_owned(), "format") }),
+                });
+            } else if tok.is_keyword("DEPTH") || tok.is_keyword("AS") {
+                let clause = if tok.is_keyword("AS") { "AS" } else { "DEPTH" };
+                return Err(ParseError { pos: tok.pos, kind: ErrorKind::DuplicateClause(clause) });
+            } else {
+                return Err(unexpected(&tok, "DEPTH or AS"));
+            }
+        }
+        Ok(Command::OrderbookAt(book, ts, depth, format.unwrap_or(GetFormat::Json)))
+    }
+
+    /// optional clauses in any order, `allowed` among `FROM`, `AS`, `DTF` (as a format),
+    /// `IN MEM`, `IN BOOKS`, `WHERE`, `GROUP BY`, `LIMIT` and `AFTER`
+    fn clauses(&mut self, allowed: &[&str], expected: &'static str) -> ParseResult<Clauses> {
+        let mut ret = Clauses::default();
+        while let Some(tok) = self.peek().cloned() {
+            self.idx += 1;
+            let clause = ["FROM", "AS", "IN", "WHERE", "GROUP", "LIMIT", "AFTER"].iter().find(|&&k| tok.is_keyword(k)).copied();
+            let clause = match clause {
+                Some("IN") if self.peek().map(|tok| tok.is_keyword("BOOKS")).unwrap_or(false) => "IN BOOKS",
+                Some("IN") => "IN MEM",
+                Some("GROUP") => "GROUP BY",
+                Some(clause) => clause,
+                None => "",
+            };
+            if !allowed.contains(&clause) {
+                return Err(unexpected(&tok, expected));
+            }
+            let duplicate = ParseError { pos: tok.pos, kind: ErrorKind::DuplicateClause(clause) };
+            match clause {
+                "FROM" if ret.range.is_none() => ret.range = Some(self.range()?),
+                "AS" if ret.format.is_none() => {
+                    let (pos, w) = self.word(if allowed.contains(&"DTF") { "JSON, CSV or DTF" } else { "JSON or CSV" })?;
+                    ret.format = Some(match w.to_ascii_uppercase().as_str() {
+                        "JSON" => GetFormat::Json,
+                        "CSV" => GetFormat::Csv,
+                        "DTF" if allowed.contains(&"DTF") => GetFormat::Dtf,
+                        _ => return Err(ParseError { pos, kind: ErrorKind::InvalidValue(w.to_owned(), "format") }),
+                    });
+                }
+                "IN MEM" if !ret.mem => {
+                    self.expect_keyword("MEM")?;
+                    ret.mem = true;
+                }
+                "IN BOOKS" if ret.books.is_none() => {
+                    self.expect_keyword("BOOKS")?;
+                    ret.books = Some((tok.pos, self.books()?));
+                }
+                "WHERE" if ret.filter.is_none() => ret.filter = Some(self.filter()?),
+                "GROUP BY" if ret.group_by.is_none() => ret.group_by = Some(self.group_by()?),
+                "LIMIT" if ret.limit.is_none() => ret.limit = Some((tok.pos, self.value("limit")?)),
+                "AFTER" if ret.after.is_none() => ret.after = Some(self.value("cursor")?),
+                _ => return Err(duplicate),
+            }
+        }
+        Ok(ret)
+    }
+
+    /// `expr OR expr`, `expr AND expr`, `NOT expr`, `(expr)`, `is_trade`, `is_bid`, `is_ask`,
+    /// `side = bid|ask`, `price|size op value` and `price|size [NOT] BETWEEN value AND value`,
+    /// AND binds tighter than OR
+    fn filter(&mut self) -> ParseResult<Filter> {
+        let mut ret = self.filter_and()?;
+        while self.keyword("OR") {
+            ret = Filter::Or(Box::new(ret), Box::new(self.filter_and()?));
+        }
+        Ok(ret)
+    }
+
+    fn filter_and(&mut self) -> ParseResult<Filter> {
+        let mut ret = self.filter_not()?;
+        while self.keyword("AND") {
+            ret = Filter::And(Box::new(ret), Box::new(self.filter_not()?));
+        }
+        Ok(ret)
+    }
+
+    fn filter_not(&mut self) -> ParseResult<Filter> {
+        let pos = self.peek().map(|tok| tok.pos).unwrap_or(self.line.len());
+        let not = self.keyword("NOT") || self.punct('!');
+        let paren = !not && self.punct('(');
+        if !not && !paren {
+            return self.predicate();
+        }
+        if self.depth == MAX_DEPTH {
+            return Err(ParseError { pos, kind: ErrorKind::TooDeep });
+        }
+        self.depth += 1;
+        let ret = if not {
+            Filter::Not(Box::new(self.filter_not()?))
+        } else {
+            let ret = self.filter()?;
+            match self.next(")")? {
+                Token { kind: TokenKind::Punct(')'), .. } => ret,
+                tok => return Err(unexpected(&tok, ")")),
+            }
+        };
+        self.depth -= 1;
+        Ok(ret)
+    }
+
+    fn predicate(&mut self) -> ParseResult<Filter> {
+        const EXPECTED: &str = "is_trade, is_bid, is_ask, side, price or size";
+        let (pos, w) = self.word(EXPECTED)?;
+        let field = match w.to_ascii_lowercase().as_str() {
+            "is_trade" => return Ok(Filter::IsTrade),
+            "is_bid" => return Ok(Filter::IsBid),
+            "is_ask" => return Ok(Filter::Not(Box::new(Filter::IsBid))),
+            "side" => {
+                match self.next("=")? {
+                    Token { kind: TokenKind::Punct('='), .. } => (),
+                    tok => return Err(unexpected(&tok, "=")),
+                }
+                let (pos, side) = self.word("bid or ask")?;
+                return match side.to_ascii_lowercase().as_str() {
+                    "bid" => Ok(Filter::IsBid),
+                    "ask" => Ok(Filter::Not(Box::new(Filter::IsBid))),
+                    _ => Err(ParseError { pos, kind: ErrorKind::InvalidValue(side.to_owned(), "side") }),
+                };
+            }
+            "price" => Field::Price,
+            "size" => Field::Size,
+            _ => return Err(ParseError { pos, kind: ErrorKind::UnexpectedToken(w.to_owned(), EXPECTED) }),
+        };
+        let not = self.keyword("NOT");
+        if not || self.keyword("BETWEEN") {
+            if not {
+                self.expect_keyword("BETWEEN")?;
+            }
+            let low = self.value("decimal")?;
+            self.expect_keyword("AND")?;
+            let high = self.value("decimal")?;
+            let between = Filter::Between(field, low, high);
+            return Ok(if not { Filter::Not(Box::new(between)) } else { between });
+        }
+        let tok = self.next("comparison operator")?;
+        let op = match tok.kind {
+            TokenKind::Punct('<') if self.adjacent(&tok, '=') => Op::Le,
+            TokenKind::Punct('<') if self.adjacent(&tok, '>') => Op::Ne,
+            TokenKind::Punct('<') => Op::Lt,
+            TokenKind::Punct('>') if self.adjacent(&tok, '=') => Op::Ge,
+            TokenKind::Punct('>') => Op::Gt,
+            TokenKind::Punct('=') => Op::Eq,
+            TokenKind::Punct('!') if self.adjacent(&tok, '=') => Op::Ne,
+            _ => return Err(unexpected(&tok, "comparison operator")),
+        };
+        Ok(Filter::Compare(field, op, self.value("decimal")?))
+    }
+}
+
+/// optional clauses of `GET` and `SELECT`
+#[derive(Default)]
+struct Clauses {
+    range: Option<(u64, u64)>,
+    format: Option<GetFormat>,
+    mem: bool,
+    filter: Option<Filter>,
+    group_by: Option<GroupBy>,
+    /// position of the clause and number of updates
+    limit: Option<(usize, u32)>,
+    after: Option<Cursor>,
+    /// position of the clause and books to merge
+    books: Option<(usize, Vec<BookName>)>,
+}
+
+fn unexpected(tok: &Token, expected: &'static str) -> ParseError {
+    ParseError { pos: tok.pos, kind: ErrorKind::UnexpectedToken(tok.text(), expected) }
+}
+
+fn req_count(all: bool) -> ReqCount {
+    if all { ReqCount::All } else { ReqCount::Count(1) }
+}
+
+fn read_location(mem: bool) -> ReadLocation {
+    if mem { ReadLocation::Mem } else { ReadLocation::Fs }
+}
+
+/// seconds with up to 3 decimals into ms
+fn parse_timestamp(s: &str) -> Option<u64> {
+    let (secs, frac) = s.split_once('.').unwrap_or((s, ""));
+    if frac.len() > 3 || !frac.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let ms = format!("{:0<3}", frac).parse::<u64>().ok()?;
+    secs.parse::<u64>().ok()?.checked_mul(1000)?.checked_add(ms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::DecimalUpdate;
+
+    #[test]
+    fn should_parse_clauses_in_any_order() {
+        for query in &[
+            "GET 10 FROM 1 TO 2 AS JSON IN MEM",
+            "get 10 in mem as json from 1 to 2",
+            "  GET\t10   IN MEM FROM 1 TO 2 AS json  ",
+        ] {
+            match parse(query).unwrap() {
+                Command::Get(ReqCount::Count(10), GetFormat::Json, Some((1000, 2000)), ReadLocation::Mem, None, None) => (),
+                cmd => panic!("unexpected {:?} for {}", cmd, query),
+            }
+        }
+        match parse("count in mem all").unwrap() {
+            Command::Count(ReqCount::All, ReadLocation::Mem) => (),
+            cmd => panic!("unexpected {:?}", cmd),
+        }
+    }
+
+    #[test]
+    fn should_parse_quoted_identifiers() {
+        match parse(r#"use "bnc btc \"eth\"""#).unwrap() {
+            Command::Use(name) => assert_eq!(name.as_str(), r#"bnc btc "eth""#),
+            cmd => panic!("unexpected {:?}", cmd),
+        }
+        match parse("CREATE 'a b' exchange='binance us'").unwrap() {
+            Command::Create(name, None, properties) => {
+                assert_eq!(name.as_str(), "a b");
+                assert_eq!(properties.get("exchange").map(String::as_str), Some("binance us"));
+            }
+            cmd => panic!("unexpected {:?}", cmd),
+        }
+    }
+
+    #[test]
+    fn should_parse_insert_into() {
+        let target = DecimalUpdate {
+            ts: 1505177459650,
+            seq: 139010,
+            is_trade: true,
+            is_bid: false,
+            price: "0.070362".parse().unwrap(),
+            size: "7.6506424".parse().unwrap(),
+        };
+        for query in &[
+            "INSERT 1505177459.65, 139010, t, f, 0.0703620, 7.65064240; INTO dbname",
+            "add 1505177459.65,139010,t,f,0.0703620,7.65064240 into dbname",
+        ] {
+            match parse(query).unwrap() {
+                Command::Insert(Some(InsertData::Decimal(up)), Some(name)) => {
+                    assert_eq!(up, target);
+                    assert_eq!(name.as_str(), "dbname");
+                }
+                cmd => panic!("unexpected {:?} for {}", cmd, query),
+            }
+        }
+        match parse("ADD 0,0,f,f,0,0;").unwrap() {
+            Command::Insert(Some(InsertData::Decimal(up)), None) => assert_eq!(up.ts, 0),
+            cmd => panic!("unexpected {:?}", cmd),
+        }
+    }
+
+    #[test]
+    fn should_parse_where() {
+        let dec = |s: &str| s.parse::<Decimal>().unwrap();
+        let target = Filter::And(
+            Box::new(Filter::And(
+                Box::new(Filter::IsTrade),
+                Box::new(Filter::Between(Field::Price, dec("0.05"), dec("0.06"))),
+            )),
+            Box::new(Filter::Compare(Field::Size, Op::Gt, dec("1.5"))),
+        );
+        for query in &[
+            "GET ALL WHERE is_trade AND price BETWEEN 0.05 AND 0.06 AND size > 1.5 AS JSON",
+            "get all as json where is_trade and price between 0.05 and 0.06 and size>1.5",
+        ] {
+            match parse(query).unwrap() {
+                Command::Get(ReqCount::All, GetFormat::Json, None, ReadLocation::Fs, Some(filter), None) => assert_eq!(filter, target),
+                cmd => panic!("unexpected {:?} for {}", cmd, query),
+            }
+        }
+        let filter = |query: &str| match parse(query).unwrap() {
+            Command::Get(_, _, _, _, Some(filter), _) => filter,
+            cmd => panic!("unexpected {:?}", cmd),
+        };
+        assert_eq!(
+            filter("GET ALL WHERE NOT is_bid OR side = bid AND price <= 1"),
+            Filter::Or(
+                Box::new(Filter::Not(Box::new(Filter::IsBid))),
+                Box::new(Filter::And(Box::new(Filter::IsBid), Box::new(Filter::Compare(Field::Price, Op::Le, dec("1"))))),
+            )
+        );
+        assert_eq!(
+            filter("GET ALL WHERE !(is_trade OR size != 2)"),
+            Filter::Not(Box::new(Filter::Or(
+                Box::new(Filter::IsTrade),
+                Box::new(Filter::Compare(Field::Size, Op::Ne, dec("2"))),
+            )))
+        );
+    }
+
+    #[test]
+    fn should_parse_select() {
+        use tdb_core::postprocessing::aggregate::Field::{Price, Size};
+        match parse("select count(*), sum(price * size), min(size), ohlc from 1 to 2 where is_trade group by 1m, side as csv").unwrap() {
+            Command::Select(query, GetFormat::Csv, ReadLocation::Fs) => {
+                assert_eq!(query.aggregates, vec![
+                    Aggregate::Count, Aggregate::SumNotional, Aggregate::Min(Size),
+                    Aggregate::First(Price), Aggregate::Max(Price), Aggregate::Min(Price), Aggregate::Last(Price),
+                ]);
+                assert_eq!(query.group_by, GroupBy { interval: Some("1m".parse().unwrap()), side: true, trade: false });
+                assert_eq!(query.range, Some((1000, 2000)));
+                assert_eq!(query.filter, Some(Filter::IsTrade));
+            }
+            cmd => panic!("unexpected {:?}", cmd),
+        }
+        let err = |query| parse(query).unwrap_err().kind;
+        assert_eq!(err("SELECT sum(price)"), ErrorKind::InvalidValue("price".to_owned(), "size or price*size"));
+        assert_eq!(err("SELECT count GROUP BY side, side"), ErrorKind::DuplicateClause("GROUP BY"));
+        assert_eq!(err("SELECT count GROUP BY 1x"), ErrorKind::InvalidValue("1x".to_owned(), "interval"));
+        assert_eq!(err("SELECT count AS DTF"), ErrorKind::InvalidValue("DTF".to_owned(), "format"));
+        assert!(matches!(err("GET ALL GROUP BY side"), ErrorKind::UnexpectedToken(..)));
+    }
+
+    #[test]
+    fn should_parse_pages() {
+        let cursor = Cursor { ts: 1513749530585, seq: 7, offset: 80, nth: 2 };
+        assert_eq!(cursor.to_string(), "000001607280c7d900000007000000000000005000000002");
+        let page = |query: &str| match parse(query).unwrap() {
+            Command::Get(_, _, _, _, _, page) => page,
+            cmd => panic!("unexpected {:?}", cmd),
+        };
+        assert_eq!(page("GET LIMIT 100"), Some(Page { limit: Some(100), after: None }));
+        assert_eq!(
+            page(&format!("get 100 where is_trade after {} as csv", cursor)),
+            Some(Page { limit: Some(100), after: Some(cursor) })
+        );
+        assert_eq!(page(&format!("GET AFTER {}", cursor)), Some(Page { limit: None, after: Some(cursor) }));
+        assert_eq!(page("GET 100"), None);
+        let err = |query| parse(query).unwrap_err().kind;
+        assert_eq!(err("GET 10 LIMIT 10"), ErrorKind::DuplicateClause("LIMIT"));
+        assert_eq!(err("GET LIMIT 10 AFTER 12"), ErrorKind::InvalidValue("12".to_owned(), "cursor"));
+    }
+
+    #[test]
+    fn should_parse_books() {
+        match parse("get 10 from 1 to 2 in books (a, \"b c\", a) as csv in mem").unwrap() {
+            Command::GetMerged(books, ReqCount::Count(10), GetFormat::Csv, Some((1000, 2000)), ReadLocation::Mem, None) => {
+                assert_eq!(books.iter().map(|b| b.as_str()).collect::<Vec<_>>(), vec!["a", "b c"]);
+            }
+            cmd => panic!("unexpected {:?}", cmd),
+        }
+        let err = |query| parse(query).unwrap_err();
+        assert_eq!(err("GET IN BOOKS (a) AS DTF").kind, ErrorKind::Conflict("AS DTF", "IN BOOKS"));
+        assert_eq!(err("GET LIMIT 1 IN BOOKS (a)").pos, 12);
+        assert_eq!(err("GET IN BOOKS (a b)").kind, ErrorKind::UnexpectedToken("b".to_owned(), ", or )"));
+    }
+
+    #[test]
+    fn should_parse_orderbook_at() {
+        match parse("OB AT 1 DEPTH 10 AS BINARY").unwrap() {
+            Command::OrderbookAt(None, 1000, Some(10), GetFormat::Dtf) => (),
+            cmd => panic!("unexpected {:?}", cmd),
+        }
+        match parse("ob \"a b\" at 1.5").unwrap() {
+            Command::OrderbookAt(Some(book), 1500, None, GetFormat::Json) => assert_eq!(book.as_str(), "a b"),
+            cmd => panic!("unexpected {:?}", cmd),
+        }
+        let err = |query| parse(query).unwrap_err();
+        assert_eq!(err("OB AT 1 DEPTH 1 DEPTH 2").kind, ErrorKind::DuplicateClause("DEPTH"));
+        assert_eq!(err("OB AT 1 AS CSV").kind, ErrorKind::InvalidValue("CSV".to_owned(), "format"));
+        assert_eq!(err("OB a AS JSON").pos, 5);
+    }
+
+    #[test]
+    fn should_parse_depth() {
+        match parse("OB DEPTH 5").unwrap() {
+            Command::Orderbook(None, Some(5)) => (),
+            cmd => panic!("unexpected {:?}", cmd),
+        }
+        match parse("subscribe a depth 1").unwrap() {
+            Command::Subscribe(book, Some(1)) => assert_eq!(book.as_str(), "a"),
+            cmd => panic!("unexpected {:?}", cmd),
+        }
+        assert!(matches!(parse("SUBSCRIBE a").unwrap(), Command::Subscribe(_, None)));
+        let err = |query| parse(query).unwrap_err();
+        assert_eq!(err("OB a DEPTH -1").kind, ErrorKind::InvalidValue("-1".to_owned(), "depth"));
+        assert_eq!(err("SUBSCRIBE a DEPTH 1 DEPTH 2").pos, 20);
+    }
+
+    #[test]
+    fn should_return_errors_with_positions() {
+        let err = |query| parse(query).unwrap_err();
+        assert_eq!(err("FOO 1").kind, ErrorKind::UnknownCommand("FOO".to_owned()));
+        assert_eq!(err("GET 10 FROM 1").pos, 13);
+        assert_eq!(err("GET 10 FROM x TO 2").kind, ErrorKind::InvalidValue("x".to_owned(), "timestamp"));
+        assert_eq!(err("GET 10 AS XML").pos, 10);
+        assert_eq!(err("GET 10 AS JSON AS CSV").kind, ErrorKind::DuplicateClause("AS"));
+        assert_eq!(err("USE \"abc").kind, ErrorKind::UnterminatedQuote);
+        assert_eq!(err("PING PONG").kind, ErrorKind::UnexpectedToken("PONG".to_owned(), "end of query"));
+        assert_eq!(err("CREATE x TICK 0").pos, 9);
+        assert_eq!(err("GET ALL WHERE price = 1 WHERE is_bid").kind, ErrorKind::DuplicateClause("WHERE"));
+        let nested = format!("GET ALL WHERE {}is_bid", "(".repeat(100));
+        assert_eq!(parse(&nested).unwrap_err().kind, ErrorKind::TooDeep);
+        assert_eq!(
+            err("GET 10 FROM 1 TO 2 AS").to_string(),
+            "Unexpected end of query, expected JSON, CSV or DTF at position 21."
+        );
+
+        // malformed queries never panic
+        for query in &["GET", "GET ALL FROM", "ADD", "ADD ;;; INTO", "CREATE", "CREATE x y", "CREATE x =",
+                       "RETENTION x AGE", "OB \"", "=", "GET CANDLES", "GET CANDLES 1m IN MEM", "\u{e9}\u{e9}",
+                       "GET ALL WHERE", "GET ALL WHERE (is_bid", "GET ALL WHERE price", "GET ALL WHERE price < = 1",
+                       "GET ALL WHERE size BETWEEN 1", "GET ALL WHERE side = up", "GET ALL WHERE price > -1",
+                       "GET IN BOOKS", "GET IN BOOKS ()", "GET IN BOOKS (a,",
+                       "SELECT", "SELECT count,", "SELECT min", "SELECT min(price", "SELECT count GROUP", "SELECT count GROUP BY"] {
+            assert!(parse(query).is_err(), "{}", query);
+        }
+    }
+}
@@ -1,15 +1,21 @@
 use crate::prelude::*;
 use byteorder::{BigEndian, ReadBytesExt};
-use async_std::future;
+use crate::actor::Books;
+use crate::session::Session;
+use crate::state::Backlog;
+use tdb_core::protocol::{ContentType, Header, PUSH_ID};
 
-const IGNORE_TCP_WRITE: bool = true;
+/// capacity of the queue of the responses and subscription messages of a connection
+const OUTBOUND_SZ: usize = 2048;
 
 // TODO: add onexit once async-std support is stablized
 #[cfg(unix)]
 #[allow(unused)]
 async fn onexit(mut broker: Sender<Event>, settings: Arc<Settings>) {
     info!("`TERM` signal recieved; flushing all stores...");
-    broker.send(Event::Command {from: None, command: Command::Flush(ReqCount::All)}).await.unwrap();
+    let (reply, flushed) = oneshot::channel();
+    broker.send(Event::Command {command: Command::Flush(ReqCount::All), reply}).await.unwrap();
+    let _ = flushed.await;
     info!("All stores flushed; calling plugin exit hooks...");
     crate::plugins::run_plugin_exit_hooks(broker, settings);
     info!("Plugin exit hooks called; exiting...");
@@ -55,7 +61,11 @@ pub async fn run_server(host: &str, port: &str, settings: Arc<Settings>) -> Resu
     //     task::block_on(onexit(broker, settings));
     // });
 
-    let broker = task::spawn(broker_loop(broker_receiver, Arc::clone(&settings)));
+    let mut state = TectonicServer::new(Arc::clone(&settings));
+    crate::utils::init_dbs(&mut state).await;
+    let books = Arc::clone(&state.books);
+    let backlog = Arc::clone(&state.backlog);
+    let broker = task::spawn(broker_loop(broker_receiver, state));
     let plugins = task::spawn(crate::plugins::run_plugins(broker_sender.clone(), settings.clone()));
     plugins.await;
 
@@ -63,7 +73,7 @@ pub async fn run_server(host: &str, port: &str, settings: Arc<Settings>) -> Resu
     while let Some(stream) = incoming.next().await {
         let stream = stream?;
         info!("Accepting from: {}", stream.peer_addr()?);
-        spawn_and_log_error(connection_loop(broker_sender.clone(), stream));
+        spawn_and_log_error(connection_loop(broker_sender.clone(), Arc::clone(&books), Arc::clone(&backlog), stream));
     }
     drop(broker_sender);
     broker.await;
@@ -72,20 +82,26 @@ pub async fn run_server(host: &str, port: &str, settings: Arc<Settings>) -> Resu
 
 
 
-async fn connection_loop(mut broker: Sender<Event>, stream: TcpStream) -> Result<()> {
+/// Reads the commands of a connection and routes them with a `Session`,
+/// commands on a book are sent to its actor without going through the broker.
+async fn connection_loop(mut broker: Sender<Event>, books: Books, backlog: Arc<Backlog>, stream: TcpStream) -> Result<()> {
     let stream = Arc::new(stream);
     let mut reader = BufReader::new(&*stream);
     let addr = stream.peer_addr()?;
 
     let (_shutdown_sender, shutdown_receiver) = mpsc::channel::<Void>(CHANNEL_SZ);
+    let (outbound, messages) = mpsc::channel(OUTBOUND_SZ);
     broker
         .send(Event::NewConnection {
             addr: addr,
             stream: Arc::clone(&stream),
             shutdown: shutdown_receiver,
+            outbound: outbound.clone(),
+            messages,
         })
         .await
         .unwrap();
+    let mut session = Session::new(addr, books, broker, outbound, backlog);
 
     let mut bytes = [0; 4];
     let mut buf = Box::new([0; 65536*16]);
@@ -94,15 +110,18 @@ async fn connection_loop(mut broker: Sender<Event>, stream: TcpStream) -> Result
         let sz = rdr.read_u32::<BigEndian>().unwrap() as usize;
         bytes = rdr.into_inner();
 
+        // requests of protocol version 2 have an id after their length
+        let id = if session.version >= 2 {
+            reader.read_exact(&mut bytes).await?;
+            u32::from_be_bytes(bytes)
+        } else {
+            0
+        };
         reader.read_exact(&mut buf[..sz]).await?;
 
         let command = crate::handler::parse_to_command(&buf[..sz]);
-        let from = Some(addr);
-        if let Err(_) = broker
-            .send(Event::Command{from, command})
-            .await
-        {
-            error!("unable to send event to broker");
+        if !session.dispatch(command, id).await {
+            error!("unable to queue response");
             break;
         }
     }
@@ -113,11 +132,9 @@ async fn connection_loop(mut broker: Sender<Event>, stream: TcpStream) -> Result
 }
 
 
-async fn broker_loop(mut events: Receiver<Event>, settings: Arc<Settings>) {
+pub(crate) async fn broker_loop(mut events: Receiver<Event>, mut state: TectonicServer) {
     let (disconnect_sender, mut disconnect_receiver) = mpsc::channel::<(SocketAddr, Receiver<ReturnType>)>(1);
 
-    let mut state = TectonicServer::new(settings);
-
     loop {
         let event = select! {
             event = events.next().fuse() => match event {
@@ -127,32 +144,35 @@ async fn broker_loop(mut events: Receiver<Event>, settings: Arc<Settings>) {
             disconnect = disconnect_receiver.next().fuse() => {
                 let (addr, _pending_messages) = disconnect.unwrap();
                 assert!(state.connections.remove(&addr).is_some());
-                let _ = state.unsub(&addr);
+                state.unsub(&addr).await;
 
                 continue;
             },
         };
         match event {
-            Event::Command { from, command } => {
-                state.command(command, from).await;
+            Event::Command { command, reply } => {
+                let _ = reply.send(state.process_command(command).await);
             },
             Event::FetchSizes { mut tx } => {
-                let sizes = state.books.iter().map(|(name, book)|
-                    (name.clone(), book.nominal_count, book.vec.len() as u64)
-                ).collect();
+                let sizes = state.ask_all(|book| (book.nominal_count, book.mem_len() as u64)).await
+                    .into_iter()
+                    .map(|(name, (on_disk, in_mem))| (name, on_disk, in_mem))
+                    .collect();
                 tx.send(sizes).await.unwrap();
             }
             Event::RecordHistory => {
-                state.record_history();
+                state.record_history().await;
+            }
+            Event::EnforceRetention => {
+                state.enforce_retention().await;
             }
-            Event::NewConnection { addr, stream, shutdown } => {
-                let (client_sender, mut client_receiver) = mpsc::channel(2048);
-                if state.new_connection(client_sender, addr) {
+            Event::NewConnection { addr, stream, shutdown, outbound, mut messages } => {
+                if state.new_connection(outbound, addr) {
                     let mut disconnect_sender = disconnect_sender.clone();
                     spawn_and_log_error(async move {
-                        let res = connection_writer_loop(&mut client_receiver, stream, shutdown).await;
+                        let res = connection_writer_loop(&mut messages, stream, shutdown).await;
                         disconnect_sender
-                            .send((addr, client_receiver))
+                            .send((addr, messages))
                             .await
                             .unwrap();
                         res
@@ -169,6 +189,68 @@ async fn broker_loop(mut events: Receiver<Event>, settings: Arc<Settings>) {
     while let Some((_name, _pending_messages)) = disconnect_receiver.next().await { }
 }
 
+/// content type and request id of a response framed by protocol version 2
+type Tag = Option<(ContentType, u32)>;
+
+/// write a frame with the header of protocol version 2 when `tag` is given,
+/// an error is text
+fn write_frame(buf: &mut Vec<u8>, status: u8, tag: Tag, payload: &[u8]) {
+    match tag {
+        Some((content, id)) => {
+            let content = if status == 0x0 { ContentType::Text } else { content };
+            let header = Header { status, content, id, len: payload.len() as u64 };
+            // writing to a vec never fails
+            let _ = header.write(buf);
+        }
+        None => {
+            buf.push(status);
+            buf.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+        }
+    }
+    buf.extend_from_slice(payload);
+}
+
+/// Writes every chunk of a stream as a `0x2` frame and the cursor of a page as a `0x3` frame,
+/// followed by an empty `0x1` frame or by an `0x0` frame when the stream fails.
+/// The scale of dtf batches is written as a `0x4` frame in protocol version 2 only.
+///
+/// Chunks are written without a timeout so that the reader of the stream waits for the client.
+async fn write_stream(mut stream: &TcpStream, mut chunks: Receiver<ReturnType>, tag: Tag) -> Result<()> {
+    let mut buf = vec![];
+    while let Some(chunk) = chunks.next().await {
+        let (status, payload) = match chunk {
+            ReturnType::Bytes(bytes) => (0x2, bytes),
+            ReturnType::String(s) => (0x2, s.into_owned().into_bytes()),
+            ReturnType::Error(errmsg) => (0x0, format!("ERR: {}\n", errmsg).into_bytes()),
+            ReturnType::Cursor(cursor) => (0x3, cursor.into_bytes()),
+            ReturnType::Scale(_) if tag.is_none() => continue,
+            ReturnType::Scale(scale) => (0x4, serde_json::to_vec(&scale).unwrap_or_default()),
+            ReturnType::Stream(_) => unreachable!("nested stream"),
+            ReturnType::Pending(_) | ReturnType::Tagged(..) => unreachable!("response in a stream"),
+        };
+        // the cursor is text and the scale json
+        let tag = match status {
+            0x3 => tag.map(|(_, id)| (ContentType::Text, id)),
+            0x4 => tag.map(|(_, id)| (ContentType::Json, id)),
+            _ => tag,
+        };
+        write_frame(&mut buf, status, tag, &payload);
+        stream.write_all(&buf).await?;
+        buf.clear();
+        if status == 0x0 {
+            return Ok(());
+        }
+    }
+    write_frame(&mut buf, 0x1, tag, &[]);
+    stream.write_all(&buf).await?;
+    Ok(())
+}
+
+/// Writes the responses and subscription messages of a connection in full,
+/// the connection is closed once its queue is closed, e.g. for a slow subscriber.
+///
+/// Responses tagged by the session are framed by protocol version 2, and so are the
+/// messages pushed to subscribers once the connection uses it.
 async fn connection_writer_loop(
     messages: &mut Receiver<ReturnType>,
     stream: Arc<TcpStream>,
@@ -176,44 +258,45 @@ async fn connection_writer_loop(
 ) -> Result<()> {
     let mut buf = Vec::with_capacity(CHANNEL_SZ);
     let mut stream = &*stream;
+    // protocol version 2 is used from the first tagged response on
+    let mut tagged = false;
     loop {
         select! {
             msg = messages.next().fuse() => {
+                let (tag, msg) = match msg {
+                    Some(ReturnType::Tagged(id, content, response)) => {
+                        tagged = true;
+                        (Some((content, id)), Some(*response))
+                    }
+                    // a message pushed to a subscriber
+                    Some(msg @ ReturnType::Bytes(_)) if tagged => (Some((ContentType::Insert, PUSH_ID)), Some(msg)),
+                    Some(msg @ ReturnType::String(_)) if tagged => (Some((ContentType::Json, PUSH_ID)), Some(msg)),
+                    msg => (None, msg),
+                };
+                // responses are written in the order of the commands,
+                // a book answers a flush or a load once it is done
+                let mut msg = msg;
+                while let Some(ReturnType::Pending(response)) = msg {
+                    msg = Some(response.await.unwrap_or_else(|_| ReturnType::error("Command dropped")));
+                }
                 match msg {
-                    Some(ReturnType::Bytes(bytes)) => {
-                        buf.write(&[0x1]).await?;
-                        buf.write(&bytes.len().to_be_bytes()).await?;
-                        buf.write(&bytes).await?;
-                        // buf.flush().await?;
-                    },
-                    Some(ReturnType::String(str_resp)) => {
-                        buf.write(&[0x1]).await?;
-                        buf.write(&str_resp.len().to_be_bytes()).await?;
-                        buf.write(&str_resp.as_bytes()).await?;
-                        // buf.flush().await?;
-                    },
+                    Some(ReturnType::Bytes(bytes)) => write_frame(&mut buf, 0x1, tag, &bytes),
+                    Some(ReturnType::String(str_resp)) => write_frame(&mut buf, 0x1, tag, str_resp.as_bytes()),
                     Some(ReturnType::Error(errmsg)) => {
-                        // error!("Req: `{}`", line);
-                        // error!("Err: `{}`", errmsg.clone());
-                        buf.write(&[0x0]).await?;
                         let ret = format!("ERR: {}\n", errmsg);
-                        buf.write(&ret.len().to_be_bytes()).await?;
-                        buf.write(ret.as_bytes()).await?;
-                        // buf.flush().await?;
+                        write_frame(&mut buf, 0x0, tag, ret.as_bytes());
                     },
+                    Some(ReturnType::Stream(chunks)) => {
+                        write_stream(stream, chunks, tag).await?;
+                        continue;
+                    },
+                    Some(ReturnType::Cursor(_)) => unreachable!("cursor outside of a stream"),
+                    Some(ReturnType::Scale(_)) => unreachable!("scale outside of a stream"),
+                    Some(ReturnType::Pending(_)) | Some(ReturnType::Tagged(..)) => unreachable!("pending response"),
                     None => break,
                 };
-                if let Err(future::TimeoutError {..}) = future::timeout(
-                    std::time::Duration::from_millis(0),
-                    stream.write_all(&buf)
-                ).await
-                {
-                    if IGNORE_TCP_WRITE {
-
-                    } else {
-                        error!("tcpstream write_all timeout.");
-                    }
-                }
+                // a slow client holds back its own queue, see `Session::dispatch`
+                stream.write_all(&buf).await?;
                 buf.clear()
             },
             void = shutdown.next().fuse() => match void {
@@ -222,5 +305,6 @@ async fn connection_writer_loop(
             }
         }
     }
+    let _ = stream.shutdown(std::net::Shutdown::Both);
     Ok(())
 }
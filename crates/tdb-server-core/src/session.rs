@@ -0,0 +1,227 @@
+//! Routing of the commands of a connection
+//!
+//! Commands on a book are sent straight to the actor of the book (see `actor`),
+//! commands spanning books go through the broker and the rest are answered
+//! right away. The response of every command is queued on the connection as
+//! `ReturnType::Pending` before the command is routed, so that responses are
+//! written in the order of the commands. The next command is only routed once
+//! the broker is done with a command, so commands take effect in order too.
+//!
+//! A connection greeted with `HELLO 2` has its responses tagged with the id of
+//! their request and their content type (see `tdb_core::protocol`).
+use crate::prelude::*;
+use crate::actor::{BookHandle, Books};
+use crate::state::Backlog;
+use std::sync::atomic::Ordering;
+use tdb_core::protocol::{self, ContentType};
+
+/// routes the commands of a connection
+pub struct Session {
+    pub addr: SocketAddr,
+    /// the current book of the connection
+    pub book_entry: BookName,
+    pub books: Books,
+    pub broker: Sender<Event>,
+    /// responses and subscriptions written to the connection
+    pub outbound: Sender<ReturnType>,
+    /// counts the responses that waited for room in `outbound`
+    pub backlog: Arc<Backlog>,
+    /// protocol version negotiated by `HELLO`, 1 until then
+    pub version: u32,
+    /// whether a command was routed already, `HELLO` must be the first one
+    started: bool,
+    /// queues of the books used by the connection
+    handles: HashMap<BookName, BookHandle>,
+}
+
+impl Session {
+    pub fn new(addr: SocketAddr, books: Books, broker: Sender<Event>, outbound: Sender<ReturnType>, backlog: Arc<Backlog>) -> Self {
+        Self {
+            addr,
+            book_entry: BookName::from("default").unwrap(),
+            books,
+            broker,
+            outbound,
+            backlog,
+            version: 1,
+            started: false,
+            handles: HashMap::new(),
+        }
+    }
+
+    /// Queue the response of the request `id` on the connection and route its command,
+    /// returns false when the connection is closed.
+    ///
+    /// Waits for the connection to make room in its queue when it is full,
+    /// so that a client reading its responses slowly is sent them slowly.
+    pub async fn dispatch(&mut self, command: Command, id: u32) -> bool {
+        let (reply, response) = oneshot::channel();
+        let mut response = ReturnType::Pending(response);
+        if self.version >= 2 {
+            response = ReturnType::Tagged(id, content_type(&command), Box::new(response));
+        }
+        let queued = match self.outbound.try_send(response) {
+            Ok(()) => Ok(()),
+            Err(e) if e.is_full() => {
+                self.backlog.late.fetch_add(1, Ordering::Relaxed);
+                self.outbound.send(e.into_inner()).await
+            }
+            Err(e) => Err(e.into_send_error()),
+        };
+        if queued.is_err() {
+            return false;
+        }
+        self.route(command, reply).await;
+        true
+    }
+
+    /// process `command` and wait for its response
+    pub async fn process_command(&mut self, command: Command) -> ReturnType {
+        let (reply, response) = oneshot::channel();
+        self.route(command, reply).await;
+        let mut ret = ReturnType::Pending(response);
+        while let ReturnType::Pending(response) = ret {
+            ret = response.await.unwrap_or_else(|_| ReturnType::error("Command dropped"));
+        }
+        ret
+    }
+
+    async fn route(&mut self, command: Command, reply: oneshot::Sender<ReturnType>) {
+        use Command::*;
+        let first = !self.started;
+        self.started = true;
+        let ret = match command {
+            Noop => ReturnType::string(""),
+            Hello(..) if !first => ReturnType::error("HELLO must be the first command"),
+            Hello(version, capabilities) => self.hello(version, capabilities),
+            Ping => ReturnType::string("PONG"),
+            Help => ReturnType::string(ReturnType::HELP_STR),
+            Use(dbname) => {
+                if self.handle(&dbname).is_some() {
+                    self.book_entry = dbname;
+                    ReturnType::string(format!("SWITCHED TO orderbook `{}`.", &dbname))
+                } else {
+                    ReturnType::error(format!("No db named `{}`", dbname))
+                }
+            }
+            Exists(dbname) => {
+                if self.handle(&dbname).is_some() {
+                    ReturnType::ok()
+                } else {
+                    ReturnType::error(format!("No db named `{}`", dbname))
+                }
+            }
+            Insert(None, _) => ReturnType::error("Unable to parse line"),
+            ParseError(err) => {
+                error!("parse error: {}", err);
+                ReturnType::error(err.to_string())
+            }
+            BadFormat => {
+                error!("bad format error");
+                ReturnType::error("Bad format.")
+            }
+            InvalidBookName(dbname) => {
+                ReturnType::error(format!("Book name `{}` is longer than {} bytes.", dbname, BOOK_NAME_LEN))
+            }
+            command => match self.target(&command) {
+                Some(book_name) => {
+                    let from = (self.addr, self.outbound.clone());
+                    match self.handle(&book_name) {
+                        Some(handle) => return handle.command(command, from, reply).await,
+                        None => not_found(&command, &book_name),
+                    }
+                }
+                None => {
+                    // wait for the broker so that the next commands see what it did,
+                    // e.g. `USE` right after `CREATE`
+                    let (done, ret) = oneshot::channel();
+                    if self.broker.send(Event::Command { command, reply: done }).await.is_err() {
+                        error!("unable to send event to broker");
+                        return;
+                    }
+                    match ret.await {
+                        Ok(ret) => ret,
+                        Err(_) => return,
+                    }
+                }
+            },
+        };
+        let _ = reply.send(ret);
+    }
+
+    /// Negotiate the protocol version of the connection, the highest one supported by both sides,
+    /// responds with the version and the capabilities asked for that the server supports,
+    /// every capability when none is asked for
+    fn hello(&mut self, version: u32, capabilities: Vec<String>) -> ReturnType {
+        if version == 0 {
+            return ReturnType::error("Unsupported protocol version 0");
+        }
+        self.version = version.min(protocol::VERSION);
+        let capabilities: Vec<&str> = protocol::CAPABILITIES
+            .iter()
+            .copied()
+            .filter(|cap| capabilities.is_empty() || capabilities.iter().any(|asked| asked == cap))
+            .collect();
+        ReturnType::string(format!(
+            r#"{{"version":{},"capabilities":{}}}"#,
+            self.version,
+            serde_json::to_string(&capabilities).unwrap_or_default(),
+        ))
+    }
+
+    /// the book of a command on a single book, the current book if it is not named
+    fn target(&self, command: &Command) -> Option<BookName> {
+        use Command::*;
+        match command {
+            Orderbook(book_name, _) | OrderbookAt(book_name, ..) | Insert(_, book_name) =>
+                Some(book_name.unwrap_or(self.book_entry)),
+            Subscribe(book_name, _) | Load(book_name) => Some(*book_name),
+            Count(ReqCount::Count(_), _) | Clear(ReqCount::Count(_)) | Flush(ReqCount::Count(_))
+            | GetCandles(..) | Select(..) | Get(..) => Some(self.book_entry),
+            _ => None,
+        }
+    }
+
+    /// queue of a book, looked up once per connection
+    fn handle(&mut self, book_name: &BookName) -> Option<&mut BookHandle> {
+        if !self.handles.contains_key(book_name) {
+            let handle = self.books.read().unwrap().get(book_name).cloned()?;
+            self.handles.insert(*book_name, handle);
+        }
+        self.handles.get_mut(book_name)
+    }
+}
+
+/// content type of the response to `command`, errors are always text
+fn content_type(command: &Command) -> ContentType {
+    use Command::*;
+    let format = |format: &GetFormat| match format {
+        GetFormat::Dtf => ContentType::Dtf,
+        GetFormat::Json => ContentType::Json,
+        GetFormat::Csv => ContentType::Csv,
+    };
+    match command {
+        Hello(..) | Info | Perf | Retention(_) | Orderbook(..) => ContentType::Json,
+        OrderbookAt(_, _, _, GetFormat::Dtf) => ContentType::Levels,
+        OrderbookAt(..) => ContentType::Json,
+        Get(_, fmt, ..) => format(fmt),
+        GetMerged(_, _, GetFormat::Csv, ..) | GetCandles(_, _, GetFormat::Csv) | Select(_, GetFormat::Csv, _) => ContentType::Csv,
+        GetMerged(..) | GetCandles(..) | Select(..) => ContentType::Json,
+        _ => ContentType::Text,
+    }
+}
+
+/// response to a command on a book that does not exist
+fn not_found(command: &Command, book_name: &BookName) -> ReturnType {
+    use Command::*;
+    match command {
+        Orderbook(..) => ReturnType::error("Unable to get orderbook"),
+        Count(_, ReadLocation::Fs) => ReturnType::error("Unable to get count"),
+        Count(_, ReadLocation::Mem) => ReturnType::error("Unable to get count in memory"),
+        Clear(_) | Flush(_) => ReturnType::ok(),
+        Subscribe(..) | Load(_) => ReturnType::error(format!("No db named `{}`", book_name)),
+        Get(_, _, _, _, _, None) => ReturnType::error("Not enough items to return"),
+        GetCandles(..) | Select(..) | Get(..) => ReturnType::error("No db selected"),
+        _ => ReturnType::error(format!("DB {} not found.", book_name)),
+    }
+}
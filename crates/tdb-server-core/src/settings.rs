@@ -1,6 +1,9 @@
 use std::env;
 use std::error::Error;
 use std::str::FromStr;
+use tdb_core::storage::partition::Partitioning;
+use crate::plugins::retention::Retention;
+use tdb_core::postprocessing::candle::rollup::Resolution;
 
 pub fn key_or_default_parse<
     E: Into<Box<dyn Error>>,
@@ -40,6 +43,24 @@ pub struct Settings {
     pub q_capacity: usize,
     /// settings for influxdb
     pub influx: Option<InfluxSettings>,
+    /// wal: boolean. Log every insert to a per-book write-ahead log before acknowledging it.
+    pub wal: bool,
+    /// wal_sync: when to fsync the write-ahead log.
+    pub wal_sync: WalSync,
+    /// partitioning: split the dtf file of each book by hour, day or month.
+    pub partitioning: Partitioning,
+    /// retention: default max age and max size of every book.
+    pub retention: Retention,
+    /// retention_interval: u64. enforce retention policies every n seconds, never when 0.
+    pub retention_interval: u64,
+    /// rollups: candles maintained for every book as updates are added.
+    pub rollups: Vec<Resolution>,
+    /// checkpoint_interval: u32. checkpoint the orderbook of a book every n updates, never when 0.
+    pub checkpoint_interval: u32,
+    /// integrity: count the anomalies of the orderbook of every book, and clear crossed levels.
+    pub integrity: IntegrityMode,
+    /// slow_consumer: what to do with a subscriber whose outbound queue is full.
+    pub slow_consumer: SlowConsumer,
 }
 
 #[derive(Clone, Debug, Default)]
@@ -47,4 +68,77 @@ pub struct InfluxSettings {
     pub host: String,
     pub db: String,
     pub interval: u64,
-}
\ No newline at end of file
+}
+/// fsync policy of the write-ahead log
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum WalSync {
+    /// fsync after every write
+    #[default]
+    Always,
+    /// fsync at most once every n milliseconds, and no later than n milliseconds after a write
+    Interval(u64),
+    /// leave it to the OS
+    Never,
+}
+
+/// anomaly checks of the orderbooks
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum IntegrityMode {
+    /// no checks
+    #[default]
+    Off,
+    /// count negative levels, crossed books, trades at missing levels and sequence gaps
+    Count,
+    /// count, and remove the levels crossed by an update
+    Heal,
+}
+
+impl FromStr for IntegrityMode {
+    type Err = String;
+    /// parses `off`, `count` or `heal`
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(IntegrityMode::Off),
+            "count" => Ok(IntegrityMode::Count),
+            "heal" => Ok(IntegrityMode::Heal),
+            _ => Err(format!("Invalid integrity mode: `{}`", s)),
+        }
+    }
+}
+
+/// policy for the subscribers that do not read their messages fast enough
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SlowConsumer {
+    /// drop the messages that do not fit in the outbound queue
+    #[default]
+    Drop,
+    /// close the connection once the messages already queued are written
+    Disconnect,
+}
+
+impl FromStr for SlowConsumer {
+    type Err = String;
+    /// parses `drop` or `disconnect`
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(SlowConsumer::Drop),
+            "disconnect" => Ok(SlowConsumer::Disconnect),
+            _ => Err(format!("Invalid slow consumer policy: `{}`", s)),
+        }
+    }
+}
+
+impl FromStr for WalSync {
+    type Err = String;
+    /// parses `always`, `never` or an interval in milliseconds
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(WalSync::Always),
+            "never" => Ok(WalSync::Never),
+            ms => ms.trim_end_matches("ms")
+                .parse::<u64>()
+                .map(WalSync::Interval)
+                .map_err(|_| format!("Invalid wal sync policy: `{}`", s)),
+        }
+    }
+}
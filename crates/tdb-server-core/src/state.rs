@@ -16,11 +16,27 @@ use alloc_counter::{count_alloc, count_alloc_future};
 use crate::prelude::*;
 
 use circular_queue::CircularQueue;
-use tdb_core::dtf::file_format::scan_files_for_range;
-use tdb_core::postprocessing::orderbook::Orderbook;
+use tdb_core::dtf::file_format::{scan_files_for_range_for_each, CheckpointRecord, EncodeOptions, Properties};
+use tdb_core::dtf::scale::Scale;
+use tdb_core::storage::partition::{book_files, Partitioning};
+use tdb_core::storage::checkpoint::{self, Checkpoint};
+use tdb_core::postprocessing::orderbook::{Depth, Integrity, Orderbook};
+use tdb_core::postprocessing::candle::rollup::{self, Resolution, Rollup, RollupFlush};
+use tdb_core::postprocessing::aggregate::Aggregator;
+use crate::settings::{IntegrityMode, SlowConsumer};
+use crate::wal::Wal;
+use crate::plugins::retention::{self, Retention};
+use crate::handler::{self, InsertData};
+use crate::filter::{Filter, Predicate};
+use crate::stream::{stream_page, stream_updates, Mem, Resume, Scan};
+use crate::merge::{stream_merged, Source};
+use crate::actor::{self, BookHandle, Books};
+use futures::future;
 use std::time::{SystemTime, UNIX_EPOCH};
-
-static PRICE_DECIMALS: u8 = 10; // TODO: don't hardcode this
+use std::collections::{BTreeMap, VecDeque};
+use std::mem;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{PoisonError, RwLock, RwLockReadGuard};
 
 macro_rules! catch {
     ($($code:tt)*) => {
@@ -28,30 +44,6 @@ macro_rules! catch {
     }
 }
 
-pub fn into_format(result: &[Update], format: GetFormat) -> Option<ReturnType> {
-    Some(match format {
-        GetFormat::Dtf => {
-            let mut buf: Vec<u8> = Vec::with_capacity(result.len() * 10);
-            let _ = dtf::file_format::write_batches(&mut buf, result.into_iter().peekable());
-            ReturnType::Bytes(buf)
-        }
-        GetFormat::Json => {
-            ReturnType::String({
-                let mut ret = result.as_json();
-                ret.push('\n');
-                Cow::Owned(ret)
-            })
-        }
-        GetFormat::Csv => {
-            ReturnType::String({
-                let mut ret = result.to_csv();
-                ret.push('\n');
-                Cow::Owned(ret)
-            })
-        }
-    })
-}
-
 pub struct Book {
     pub vec: Vec<Update>,
     /// nominal count of updates from disk
@@ -59,75 +51,302 @@ pub struct Book {
     pub name: String,
     pub in_memory: bool,
     pub orderbook: Orderbook,
+    /// tick size and lot size of the prices and sizes in `vec`
+    pub scale: Scale,
+    /// properties stored in the header of the file
+    pub properties: Properties,
     pub settings: Arc<Settings>,
+    /// write-ahead log of the updates in `vec`
+    pub wal: Option<Wal>,
+    /// retention policy of the book, the default policy of the server when None
+    pub retention: Option<Retention>,
+    /// candles updated with every update added to the book
+    pub rollups: Vec<Rollup>,
+    /// checkpoints of the orderbook that are not flushed yet
+    pub checkpoints: Vec<Checkpoint>,
+    /// updates applied to the orderbook since the last checkpoint
+    since_checkpoint: u32,
+    /// anomalies of the updates added to the orderbook, None when integrity checks are off
+    pub integrity: Option<Integrity>,
+    /// connections subscribed to the book
+    pub subscriptions: HashMap<SocketAddr, Subscription>,
+    /// updates written by the flush in progress, still read along with `vec` until they are in the files
+    pub flushing: Option<Arc<Flush>>,
+    /// number of flushes started
+    pub(crate) flushes: u64,
+    /// files of the book with the number of the last flush that wrote all its updates,
+    /// locked for reading while they are scanned and for writing while a flush or
+    /// the retention policy changes them
+    pub(crate) files_lock: Arc<RwLock<u64>>,
+    /// flush, load or retention in progress on a blocking thread, its result is applied to the book
+    pub(crate) io: Option<oneshot::Receiver<Done>>,
+    /// flushes, loads and retentions requested during the one in progress
+    deferred: VecDeque<(Io, oneshot::Sender<ReturnType>)>,
+    /// counts of the messages the subscribers were too slow for, shared by the server
+    pub backlog: Arc<Backlog>,
+}
+
+/// updates and checkpoints written to files by a flush
+pub struct Flush {
+    pub ups: Vec<Update>,
+    pub checkpoints: Vec<Checkpoint>,
+}
+
+/// result of a flush, load or retention, applied by the actor of the book
+pub(crate) type Done = Box<dyn FnOnce(&mut Book) + Send>;
+
+/// disk I/O done on a blocking thread, one at a time per book
+enum Io {
+    Flush,
+    Load,
+    /// retention with the default policy of the server at a time in ms
+    Retention(Retention, u64),
 }
 
 impl Book {
 
-    pub fn new(name: &str, settings: Arc<Settings>, price_decimals: u8) -> Self {
+    /// `opts` are only used when there is no file yet,
+    /// otherwise the scale and properties of the file are kept
+    pub fn new(name: &str, settings: Arc<Settings>, opts: &EncodeOptions) -> Self {
         let vec = Vec::with_capacity(usize::max(settings.flush_interval as usize * 3, 1024*64));
         let nominal_count = 0;
-        let orderbook = Orderbook::with_precision(price_decimals);
+        let files = book_files(&settings.dtf_folder, name).unwrap_or_default();
+        let (scale, properties) = match files.first() {
+            Some(fname) => match dtf::file_format::read_meta(fname) {
+                Ok(meta) => (meta.scale, meta.properties),
+                Err(e) => {
+                    error!("Unable to read header of {}: {}", fname, e);
+                    (opts.scale, opts.properties.clone())
+                }
+            },
+            None => (opts.scale, opts.properties.clone()),
+        };
+        let orderbook = Orderbook::with_scale(scale);
         let name = name.to_owned();
         let in_memory = false;
+        let wal = if settings.wal {
+            utils::create_dir_if_not_exist(&settings.dtf_folder);
+            let opts = EncodeOptions { scale, properties: properties.clone(), ..Default::default() };
+            match Wal::open(&settings.dtf_folder, &name, settings.wal_sync, &opts) {
+                Ok(wal) => Some(wal),
+                Err(e) => {
+                    error!("Unable to open write-ahead log for {}: {}", name, e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let rollups = settings.rollups
+            .iter()
+            .map(|&res| Rollup::open(res, &settings.dtf_folder, &name).unwrap_or_else(|e| {
+                error!("Unable to read {} candles of {}: {}", res, name, e);
+                Rollup::new(res)
+            }))
+            .collect();
+        let retention = retention::load_policy(&settings.dtf_folder, &name).unwrap_or_else(|e| {
+            error!("Unable to read retention policy of {}: {}", name, e);
+            None
+        });
+        let integrity = match settings.integrity {
+            IntegrityMode::Off => None,
+            IntegrityMode::Count => Some(Integrity::new(false)),
+            IntegrityMode::Heal => Some(Integrity::new(true)),
+        };
         let mut ret = Self {
             vec,
             nominal_count,
             orderbook,
+            scale,
+            properties,
             name,
             in_memory,
             settings,
+            wal,
+            retention,
+            rollups,
+            checkpoints: vec![],
+            since_checkpoint: 0,
+            integrity,
+            subscriptions: HashMap::new(),
+            flushing: None,
+            flushes: 0,
+            files_lock: Default::default(),
+            io: None,
+            deferred: VecDeque::new(),
+            backlog: Default::default(),
         };
         ret.load_size_from_file();
+        ret.restore_orderbook();
         ret
     }
 
-    /// load items from dtf file
-    fn load(&mut self) {
-        let fname = format!("{}/{}.dtf", &self.settings.dtf_folder, self.name);
-        if Path::new(&fname).exists() && !self.in_memory {
-            // let file_item_count = dtf::read_meta(&fname).count;
-            // // when we have more items in memory, don't load
-            // if file_item_count < self.count() {
-            //     warn!("There are more items in memory than in file. Cannot load from file.");
-            //     return;
-            // }
-            let ups = dtf::file_format::decode(&fname, None);
-            match ups {
-                Ok(mut ups) => {
-                    // let size = ups.len() as u64;
-                    self.vec.append(&mut ups);
-                    // wtr.vec_store.insert(self.name.to_owned(), (ups, size));
-                    self.in_memory = true;
-                }
-                Err(_) => {
-                    error!("Unable to decode file during load!");
-                    return;
-                }
+    /// re-insert the updates that were logged but never flushed
+    pub fn replay_wal(&mut self) {
+        let ups = match self.wal.as_mut().map(|wal| wal.replay()) {
+            Some(Ok(ups)) => ups,
+            Some(Err(e)) => {
+                error!("Unable to replay write-ahead log for {}: {}", self.name, e);
+                return;
             }
+            None => return,
+        };
+        if !ups.is_empty() {
+            info!("Replaying {} updates from write-ahead log for {}.", ups.len(), self.name);
+        }
+        for up in ups {
+            self.vec.push(up);
+            self.nominal_count += 1;
+            self.apply(&up);
         }
     }
 
-    /// load size from file
-    pub fn load_size_from_file(&mut self) {
-        let fname = format!("{}/{}.dtf", &self.settings.dtf_folder, self.name);
-        let header_size = dtf::file_format::get_size(&fname);
-        match header_size {
-            Ok(header_size) => {
-                self.nominal_count = header_size;
-                debug!("Read header size from file {}: {}", fname, header_size);
+    /// apply an added update to the orderbook, its checkpoints and candles
+    fn apply(&mut self, up: &Update) {
+        match self.integrity.as_mut() {
+            Some(integrity) => integrity.process_update(&mut self.orderbook, up),
+            None => self.orderbook.process_update(up),
+        }
+        self.checkpoint(up);
+        for rollup in &mut self.rollups {
+            rollup.add(up);
+        }
+    }
+
+    /// checkpoint the orderbook after every `checkpoint_interval` updates
+    fn checkpoint(&mut self, up: &Update) {
+        let interval = self.settings.checkpoint_interval;
+        if interval == 0 {
+            return;
+        }
+        self.since_checkpoint += 1;
+        if self.since_checkpoint >= interval {
+            self.since_checkpoint = 0;
+            self.checkpoints.push(Checkpoint { ts: up.ts, seq: up.seq, orderbook: self.orderbook.clone() });
+        }
+    }
+
+    /// rebuild the orderbook from the last checkpoint and the updates in files after it,
+    /// the orderbook is left as is when checkpoints are disabled
+    fn restore_orderbook(&mut self) {
+        self.checkpoints.clear();
+        self.since_checkpoint = 0;
+        let interval = self.settings.checkpoint_interval;
+        if interval == 0 || !Path::new(&self.settings.dtf_folder).exists() {
+            return;
+        }
+        match checkpoint::orderbook_at(&self.settings.dtf_folder, &self.name, u64::MAX, self.scale) {
+            Ok((orderbook, _, replayed)) => {
+                self.orderbook = orderbook;
+                self.since_checkpoint = (replayed % interval as u64) as u32;
             }
+            Err(e) => error!("Unable to restore orderbook of {}: {}", self.name, e),
+        }
+    }
+
+    /// drop the updates in memory along with their log
+    fn clear(&mut self) {
+        self.vec.clear();
+        self.in_memory = false;
+        self.load_size_from_file();
+        self.restore_orderbook();
+        for rollup in &mut self.rollups {
+            rollup.reset();
+        }
+        if let Some(wal) = self.wal.as_mut() {
+            if let Err(e) = wal.truncate() {
+                error!("Unable to truncate write-ahead log for {}: {}", self.name, e);
+            }
+        }
+    }
+
+    /// sync the updates appended to the write-ahead log since its last sync
+    pub fn sync_wal(&mut self) {
+        if let Some(wal) = self.wal.as_mut() {
+            if let Err(e) = wal.sync_pending() {
+                error!("Unable to sync write-ahead log for {}: {}", self.name, e);
+            }
+        }
+    }
+
+    /// every dtf file of the book, oldest first
+    fn files(&self) -> Vec<String> {
+        match book_files(&self.settings.dtf_folder, &self.name) {
+            Ok(files) => files,
             Err(e) => {
-                error!("{}: {}", e, fname);
+                error!("Unable to list files of {}: {}", self.name, e);
+                vec![]
+            }
+        }
+    }
+
+    /// load items from dtf files on a blocking thread,
+    /// the response is sent once they are in memory
+    fn load(&mut self) -> oneshot::Receiver<ReturnType> {
+        let (reply, ret) = oneshot::channel();
+        self.run(Io::Load, reply);
+        ret
+    }
+
+    fn start_load(&mut self, reply: oneshot::Sender<ReturnType>) {
+        let loaded = ReturnType::string(format!("Loaded orderbook `{}`.", self.name));
+        let files = self.files();
+        if self.in_memory || files.is_empty() {
+            let _ = reply.send(loaded);
+            return;
+        }
+        let (tx, rx) = oneshot::channel::<Done>();
+        self.io = Some(rx);
+        actor::spawn_io(&self.name, move || {
+            let mut ups = vec![];
+            for fname in &files {
+                match dtf::file_format::decode(fname, None) {
+                    Ok(mut file_ups) => ups.append(&mut file_ups),
+                    Err(_) => {
+                        error!("Unable to decode file {} during load!", fname);
+                        let _ = tx.send(Box::new(move |_: &mut Book| { let _ = reply.send(loaded); }));
+                        return;
+                    }
+                }
+            }
+            let _ = tx.send(Box::new(move |book: &mut Book| {
+                book.vec.append(&mut ups);
+                book.in_memory = true;
+                let _ = reply.send(loaded);
+            }));
+        });
+    }
+
+    /// load size from files
+    pub fn load_size_from_file(&mut self) {
+        let files = self.files();
+        if files.is_empty() {
+            return;
+        }
+        let mut total = 0;
+        for fname in &files {
+            match dtf::file_format::get_size(fname) {
+                Ok(header_size) => {
+                    debug!("Read header size from file {}: {}", fname, header_size);
+                    total += header_size;
+                }
+                Err(e) => {
+                    error!("{}: {}", e, fname);
+                    return;
+                }
             }
         }
+        self.nominal_count = total;
     }
 
     #[cfg_attr(feature = "count_alloc", count_alloc)]
-    fn add(&mut self, up: Update) {
+    fn add(&mut self, up: Update) -> std::io::Result<()> {
+        if let Some(wal) = self.wal.as_mut() {
+            wal.append(&up)?;
+        }
         self.vec.push(up);
         self.nominal_count += 1;
-        self.orderbook.process_update(&up);
+        self.apply(&up);
         // Saves current store into disk after n items is inserted.
         let len = self.vec.len() as u32;
         if self.settings.autoflush && len != 0 && len % self.settings.flush_interval == 0 {
@@ -136,57 +355,682 @@ impl Book {
                 self.name,
                 len,
             );
-            self.flush();
+            // the response is not waited for
+            drop(self.flush());
         }
+        Ok(())
     }
 
+    /// Hand the updates in memory to a blocking thread that writes them to files,
+    /// `vec` starts over with the updates added meanwhile.
+    ///
+    /// The response is sent once the flush is over, the updates that could not be
+    /// written are then put back in memory.
     #[cfg_attr(feature = "count_alloc", count_alloc)]
-    fn flush(&mut self) -> Option<()> {
+    fn flush(&mut self) -> oneshot::Receiver<ReturnType> {
+        let (reply, ret) = oneshot::channel();
+        self.run(Io::Flush, reply);
+        ret
+    }
+
+    fn start_flush(&mut self, reply: oneshot::Sender<ReturnType>) {
         if self.vec.is_empty() {
             info!("No updates in memeory. Skipping {}.", self.name);
-            return Some(());
+            let _ = reply.send(ReturnType::ok());
+            return;
         }
 
-        let fname = format!("{}/{}.dtf", &self.settings.dtf_folder, self.name);
-        utils::create_dir_if_not_exist(&self.settings.dtf_folder);
+        let capacity = self.vec.capacity();
+        let flush = Arc::new(Flush {
+            ups: mem::replace(&mut self.vec, Vec::with_capacity(capacity)),
+            checkpoints: mem::take(&mut self.checkpoints),
+        });
+        self.flushes += 1;
+        let id = self.flushes;
+        self.flushing = Some(Arc::clone(&flush));
+        let rollups: Vec<_> = self.rollups
+            .iter()
+            .map(|rollup| rollup.prepare_flush(&self.settings.dtf_folder, &self.name))
+            .collect();
+        let files = BookFiles {
+            folder: self.settings.dtf_folder.clone(),
+            name: self.name.clone(),
+            partitioning: self.settings.partitioning,
+            opts: EncodeOptions {
+                scale: self.scale,
+                properties: self.properties.clone(),
+                ..Default::default()
+            },
+        };
+        let files_lock = Arc::clone(&self.files_lock);
+        let (tx, rx) = oneshot::channel::<Done>();
+        self.io = Some(rx);
+        actor::spawn_io(&self.name, move || {
+            let failed = {
+                // waits for the scans in progress
+                let mut written = files_lock.write().unwrap_or_else(PoisonError::into_inner);
+                let failed = files.write(&flush);
+                if failed.ups.is_empty() {
+                    *written = id;
+                }
+                failed
+            };
+            // the candles are persisted once every update is
+            let rollups = if failed.ups.is_empty() {
+                rollups.into_iter().map(|rollup| rollup.filter(|rollup| match rollup.write() {
+                    Ok(()) => true,
+                    Err(e) => {
+                        error!("Unable to flush candles of {}: {}", files.name, e);
+                        false
+                    }
+                })).collect()
+            } else {
+                vec![]
+            };
+            let _ = tx.send(Box::new(move |book: &mut Book| {
+                book.flushed(failed, rollups);
+                let _ = reply.send(ReturnType::ok());
+            }));
+        });
+    }
+
+    /// keep only the updates that could not be flushed in memory and in the log,
+    /// before the ones added during the flush
+    fn flushed(&mut self, failed: Flush, rollups: Vec<Option<RollupFlush>>) {
+        self.flushing = None;
+        for (rollup, flush) in self.rollups.iter_mut().zip(&rollups) {
+            if let Some(flush) = flush {
+                rollup.flushed(flush);
+            }
+        }
+        self.vec.splice(0..0, failed.ups);
+        self.checkpoints.splice(0..0, failed.checkpoints);
+        self.in_memory = false;
+        if let Some(wal) = self.wal.as_mut() {
+            if let Err(e) = wal.rewrite(&self.vec) {
+                error!("Unable to rewrite write-ahead log for {}: {}", self.name, e);
+            }
+        }
+    }
+
+    /// start `io` unless another one is in progress, it is started once that one is done
+    fn run(&mut self, io: Io, reply: oneshot::Sender<ReturnType>) {
+        if self.io.is_some() {
+            self.deferred.push_back((io, reply));
+            return;
+        }
+        match io {
+            Io::Flush => self.start_flush(reply),
+            Io::Load => self.start_load(reply),
+            Io::Retention(default, now) => self.start_retention(default, now, reply),
+        }
+    }
+
+    /// the thread of the io in progress died, the updates it was flushing are kept
+    /// in memory and in the log as if none of them were written
+    pub(crate) fn io_dropped(&mut self) {
+        error!("Disk I/O of {} dropped", self.name);
+        if let Some(flush) = self.flushing.take() {
+            let failed = Arc::try_unwrap(flush).unwrap_or_else(|flush| Flush {
+                ups: flush.ups.clone(),
+                checkpoints: flush.checkpoints.clone(),
+            });
+            self.flushed(failed, vec![]);
+        }
+    }
+
+    /// apply the result of the io in progress and start the next ones
+    pub(crate) fn done(&mut self, done: Done) {
+        self.io = None;
+        done(self);
+        while self.io.is_none() {
+            match self.deferred.pop_front() {
+                Some((io, reply)) => self.run(io, reply),
+                None => break,
+            }
+        }
+    }
+
+    /// updates in memory in order, the ones being flushed first
+    pub fn mem(&self) -> impl Iterator<Item = &Update> + Clone {
+        let flushing = self.flushing.as_ref().map(|flush| &flush.ups[..]).unwrap_or_default();
+        flushing.iter().chain(self.vec.iter())
+    }
+
+    /// number of updates in memory, including the ones being flushed
+    pub fn mem_len(&self) -> usize {
+        self.flushing.as_ref().map(|flush| flush.ups.len()).unwrap_or(0) + self.vec.len()
+    }
 
-        let fpath = Path::new(&fname);
-        let result = if fpath.exists() {
+    /// updates in memory mapped by `f` in order, at most `limit` of the ones being flushed and of the others
+    fn split_mem<T>(&self, mut f: impl FnMut(&Update) -> Option<T>, limit: usize) -> Mem<T> {
+        let flushing = self.flushing.as_ref().map(|flush| &flush.ups[..]).unwrap_or_default();
+        Mem {
+            flushing: flushing.iter().filter_map(&mut f).take(limit).collect(),
+            vec: self.vec.iter().filter_map(&mut f).take(limit).collect(),
+            flush: self.flushes + 1,
+        }
+    }
+
+    /// locks the files for reading with the updates in memory that are not in them
+    fn lock_files(&self) -> (RwLockReadGuard<'_, u64>, impl Iterator<Item = &Update>) {
+        let written = self.files_lock.read().unwrap_or_else(PoisonError::into_inner);
+        let flushing = match self.flushing.as_ref() {
+            Some(flush) if *written < self.flushes => &flush.ups[..],
+            _ => &[],
+        };
+        (written, flushing.iter().chain(self.vec.iter()))
+    }
+
+    /// checkpoints in memory in order, the ones being flushed first
+    fn mem_checkpoints(&self) -> impl DoubleEndedIterator<Item = &Checkpoint> {
+        let flushing = self.flushing.as_ref().map(|flush| &flush.checkpoints[..]).unwrap_or_default();
+        flushing.iter().chain(self.checkpoints.iter())
+    }
+}
+
+/// where the updates of a book are flushed
+struct BookFiles {
+    folder: String,
+    name: String,
+    partitioning: Partitioning,
+    /// options of new files
+    opts: EncodeOptions,
+}
+
+impl BookFiles {
+    /// write the updates and checkpoints of a flush to their partitions,
+    /// returns the ones of the partitions that could not be written
+    fn write(&self, flush: &Flush) -> Flush {
+        utils::create_dir_if_not_exist(&self.folder);
+        let partitioning = self.partitioning;
+        if partitioning == Partitioning::None {
+            let checkpoints: Vec<CheckpointRecord> = flush.checkpoints.iter().map(Checkpoint::to_record).collect();
+            let result = partitioning.file_path(&self.folder, &self.name, 0)
+                .and_then(|fname| self.write_file(&fname, &flush.ups, &checkpoints).map(|_| fname));
+            return match result {
+                Ok(fname) => {
+                    info!("Successfully flushed into {}.", fname);
+                    Flush { ups: vec![], checkpoints: vec![] }
+                }
+                Err(e) => {
+                    error!("Error flushing file. {}", e);
+                    Flush { ups: flush.ups.clone(), checkpoints: flush.checkpoints.clone() }
+                }
+            };
+        }
+
+        utils::create_dir_if_not_exist(&format!("{}/{}", self.folder, self.name));
+        let mut by_partition: BTreeMap<String, Vec<Update>> = BTreeMap::new();
+        // updates with a timestamp out of the range of dates have no partition
+        let mut unpartitioned = vec![];
+        for up in &flush.ups {
+            match partitioning.file_path(&self.folder, &self.name, up.ts) {
+                Ok(fname) => by_partition.entry(fname).or_default().push(*up),
+                Err(e) => {
+                    error!("Unable to flush update of {}. {}", self.name, e);
+                    unpartitioned.push(*up);
+                }
+            }
+        }
+        let mut checkpoints: BTreeMap<String, Vec<CheckpointRecord>> = BTreeMap::new();
+        for ck in &flush.checkpoints {
+            if let Ok(fname) = partitioning.file_path(&self.folder, &self.name, ck.ts) {
+                checkpoints.entry(fname).or_default().push(ck.to_record());
+            }
+        }
+        let mut failed = vec![];
+        let mut failed_files = vec![];
+        for (fname, ups) in by_partition {
+            match self.write_file(&fname, &ups, checkpoints.get(&fname).map(Vec::as_slice).unwrap_or_default()) {
+                Ok(_) => info!("Successfully flushed into {}.", fname),
+                Err(e) => {
+                    error!("Error flushing file {}. {}", fname, e);
+                    failed.extend(ups);
+                    failed_files.push(fname);
+                }
+            }
+        }
+        failed.extend(unpartitioned);
+        // the checkpoints of the partitions that failed are written with their updates
+        let checkpoints = flush.checkpoints
+            .iter()
+            .filter(|ck| partitioning.file_path(&self.folder, &self.name, ck.ts).map_or(true, |fname| failed_files.contains(&fname)))
+            .cloned()
+            .collect();
+        Flush { ups: failed, checkpoints }
+    }
+
+    /// append `ups` and the checkpoints between them to the file or create it
+    /// with the scale and properties of the book
+    fn write_file(&self, fname: &str, ups: &[Update], checkpoints: &[CheckpointRecord]) -> std::io::Result<()> {
+        if Path::new(fname).exists() {
             info!("File exists. Appending...");
-            dtf::file_format::append(&fname, &self.vec)
+            dtf::file_format::append_with_checkpoints(fname, ups, checkpoints)
+        } else {
+            dtf::file_format::encode_with_checkpoints(fname, &self.name, ups, checkpoints, &self.opts)
+        }
+    }
+}
+
+
+impl Book {
+    /// Process a command of the connection `from` on this book,
+    /// the connection is subscribed to the book by `SUBSCRIBE`
+    pub fn process_command(&mut self, command: Command, from: (SocketAddr, Sender<ReturnType>)) -> ReturnType {
+        use Command::*;
+        match command {
+            Orderbook(_, depth) => ReturnType::string(self.orderbook_as_json_str(depth)),
+            OrderbookAt(_, ts, depth, format) => self.orderbook_at(ts, depth, format),
+            Count(ReqCount::Count(_), ReadLocation::Fs) => ReturnType::string(format!("{}", self.nominal_count)),
+            Count(ReqCount::Count(_), ReadLocation::Mem) => ReturnType::string(format!("{}", self.mem_len())),
+            Clear(ReqCount::Count(_)) => {
+                self.clear();
+                ReturnType::ok()
+            }
+            Flush(ReqCount::Count(_)) => ReturnType::Pending(self.flush()),
+            Insert(Some(data), _) => {
+                let up = match data {
+                    InsertData::Units(up) => up,
+                    InsertData::Decimal(up) => match up.to_update(&self.scale) {
+                        Some(up) => up,
+                        None => return ReturnType::Error(Cow::Owned(format!(
+                            "Price {} or size {} is not a multiple of tick size {} or lot size {} of {}.",
+                            up.price, up.size, self.scale.tick_size, self.scale.lot_size, self.name
+                        ))),
+                    },
+                };
+                match self.insert(up) {
+                    Ok(()) => ReturnType::string(""),
+                    Err(e) => ReturnType::Error(Cow::Owned(format!("Unable to log update: {}", e))),
+                }
+            }
+            Subscribe(_, depth) => {
+                let (addr, outbound) = from;
+                self.subscriptions.insert(addr, Subscription { outbound, depth, last: None });
+                ReturnType::string(format!("Subscribed to {}", self.name))
+            }
+            Load(_) => ReturnType::Pending(self.load()),
+            GetCandles(res, rng, fmt) => self.get_candles(res, rng, fmt),
+            Select(query, fmt, loc) => self.select(query, fmt, loc),
+            Get(_, fmt, rng, loc, filter, Some(page)) => self.get_page(page, fmt, rng, loc, filter),
+            Get(cnt, fmt, rng, loc, filter, None) =>
+                self.get(cnt, fmt, rng, loc, filter)
+                    .unwrap_or_else(|| ReturnType::error("Not enough items to return")),
+            command => ReturnType::error(format!("Unexpected command for {}: {:?}", self.name, command)),
+        }
+    }
+
+    /// Insert a row into the book and push it to the subscribers
+    /// Returns an error if the update could not be logged
+    fn insert(&mut self, up: Update) -> std::io::Result<()> {
+        self.add(up)?;
+        self.send_subs(&up);
+        Ok(())
+    }
+
+    /// Push an update, or the best levels when they changed, to every subscriber
+    /// and drop the subscribers that disconnected.
+    ///
+    /// Pushes never wait for a subscriber: the connection may itself be waiting for a
+    /// response of this book, so a push is dropped when its queue is full.
+    fn send_subs(&mut self, up: &Update) {
+        let mut closed = vec![];
+        for (addr, sub) in self.subscriptions.iter_mut() {
+            let (msg, depth) = match sub.depth {
+                None => match tdb_core::utils::encode_insert_into(Some(&self.name), up) {
+                    Ok(bytes) => (ReturnType::Bytes(bytes), None),
+                    Err(_) => continue,
+                },
+                Some(n) => {
+                    let depth = self.orderbook.depth(n as usize);
+                    if sub.last.as_ref() == Some(&depth) {
+                        continue;
+                    }
+                    (ReturnType::string(depth.to_json()), Some(depth))
+                }
+            };
+            match sub.outbound.try_send(msg) {
+                // a dropped snapshot is sent again with the next update
+                Ok(()) => if depth.is_some() {
+                    sub.last = depth;
+                },
+                Err(e) if e.is_full() => match self.settings.slow_consumer {
+                    SlowConsumer::Drop => {
+                        warn!("Subscriber {} of {} is too slow, dropping update.", addr, self.name);
+                        self.backlog.dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                    SlowConsumer::Disconnect => {
+                        warn!("Subscriber {} of {} is too slow, disconnecting.", addr, self.name);
+                        self.backlog.disconnected.fetch_add(1, Ordering::Relaxed);
+                        // the connection is closed once its queue is written
+                        sub.outbound.close_channel();
+                        closed.push(*addr);
+                    }
+                },
+                Err(_) => closed.push(*addr),
+            }
+        }
+        for addr in closed {
+            self.subscriptions.remove(&addr);
+        }
+    }
+
+    /// entry of the book in `INFO`
+    fn info(&self) -> String {
+        format!(
+            r#"{{
+    "name": {},
+    "in_memory": {},
+    "count": {},
+    "tick_size": "{}",
+    "lot_size": "{}",
+    "properties": {},
+    "integrity": {}
+  }}"#,
+            serde_json::to_string(&self.name).unwrap_or_default(),
+            self.mem_len(),
+            self.nominal_count,
+            self.scale.tick_size,
+            self.scale.lot_size,
+            serde_json::to_string(&self.properties).unwrap_or_default(),
+            self.integrity.as_ref().map(Integrity::to_json).unwrap_or_else(|| "null".to_owned()),
+        )
+    }
+
+    /// persist the retention policy of the book, None for the default one
+    pub fn set_retention(&mut self, policy: Option<Retention>) -> std::io::Result<()> {
+        utils::create_dir_if_not_exist(&self.settings.dtf_folder);
+        retention::save_policy(&self.settings.dtf_folder, &self.name, policy)?;
+        self.retention = policy;
+        Ok(())
+    }
+
+    /// Drop the updates on disk that fall outside of the retention policy of the book,
+    /// `default` when it has none, at `now` in ms.
+    ///
+    /// Files are rewritten on a blocking thread once the flush or load in progress is done.
+    pub(crate) fn enforce_retention(&mut self, default: Retention, now: u64) -> oneshot::Receiver<ReturnType> {
+        let (reply, ret) = oneshot::channel();
+        self.run(Io::Retention(default, now), reply);
+        ret
+    }
+
+    fn start_retention(&mut self, default: Retention, now: u64, reply: oneshot::Sender<ReturnType>) {
+        let policy = self.retention.unwrap_or(default);
+        if policy.is_unlimited() {
+            let _ = reply.send(ReturnType::ok());
+            return;
+        }
+        let folder = self.settings.dtf_folder.clone();
+        let name = self.name.clone();
+        let files_lock = Arc::clone(&self.files_lock);
+        let (tx, rx) = oneshot::channel::<Done>();
+        self.io = Some(rx);
+        actor::spawn_io(&self.name, move || {
+            let result = {
+                let _files = files_lock.write().unwrap_or_else(PoisonError::into_inner);
+                retention::enforce(&folder, &name, &policy, now)
+            };
+            let _ = tx.send(Box::new(move |book: &mut Book| {
+                match result {
+                    Ok((0, _)) => (),
+                    Ok((removed, cutoff)) => {
+                        info!("Retention: dropped {} updates of {} before {}", removed, book.name, cutoff);
+                        book.nominal_count = book.nominal_count.saturating_sub(removed);
+                        if book.in_memory {
+                            book.vec.retain(|up| up.ts >= cutoff);
+                        }
+                    }
+                    Err(e) => error!("Unable to enforce retention policy of {}: {}", book.name, e),
+                }
+                let _ = reply.send(ReturnType::ok());
+            }));
+        });
+    }
+
+    fn orderbook_as_json_str(&self, depth: Option<u32>) -> String {
+        match depth {
+            Some(n) => self.orderbook.depth(n as usize).to_json(),
+            None => serde_json::to_string(&self.orderbook).unwrap_or_default(),
+        }
+    }
+
+    /// Rebuild the orderbook right after the last update at or before `ts`,
+    /// starting from the closest checkpoint in files or in memory.
+    ///
+    /// Returns the whole book as json, or the best `depth` levels of each side with cumulative
+    /// sizes like `OB ... DEPTH n`. With `GetFormat::Dtf`, at most `depth` levels of each side
+    /// are written by `Orderbook::write_levels`.
+    pub fn orderbook_at(&self, ts: u64, depth: Option<u32>, format: GetFormat) -> ReturnType {
+        let dtf_folder = &self.settings.dtf_folder;
+        let (_files, mem) = self.lock_files();
+        let (mut orderbook, mut last) = if Path::new(dtf_folder).exists() {
+            match checkpoint::orderbook_at(dtf_folder, &self.name, ts, self.scale) {
+                Ok((orderbook, last, _)) => (orderbook, last),
+                Err(e) => return ReturnType::error(format!("Unable to rebuild orderbook: {}", e)),
+            }
         } else {
-            dtf::file_format::encode(&fname, &self.name, &self.vec)
+            (Orderbook::with_scale(self.scale), None)
         };
-        match result {
-            Ok(_) => {
-                info!("Successfully flushed into {}.", fname);
-                self.vec.clear();
-                self.in_memory = false;
-                Some(())
+        // checkpoints of the unflushed updates
+        if let Some(ck) = self.mem_checkpoints().rev().find(|ck| ck.ts <= ts) {
+            if last.map(|last| (ck.ts, ck.seq) > last).unwrap_or(true) {
+                orderbook = ck.orderbook.clone();
+                last = Some((ck.ts, ck.seq));
             }
-            Err(e) => {
-                error!("Error flushing file. {}", e);
-                None
+        }
+        mem.filter(|up| up.ts <= ts && checkpoint::is_after(last, up))
+            .for_each(|up| orderbook.process_update(up));
+        match (format, depth) {
+            (GetFormat::Dtf, _) => {
+                if let Some(depth) = depth {
+                    orderbook.retain_depth(depth as usize);
+                }
+                let mut bytes = vec![];
+                match orderbook.write_levels(&mut bytes) {
+                    Ok(()) => ReturnType::Bytes(bytes),
+                    Err(e) => ReturnType::error(format!("Unable to write orderbook: {}", e)),
+                }
             }
+            (_, Some(depth)) => ReturnType::string(orderbook.depth(depth as usize).to_json()),
+            (_, None) => serde_json::to_string(&orderbook)
+                .map(ReturnType::string)
+                .unwrap_or_else(|_| ReturnType::error("Unable to get orderbook")),
         }
     }
+
+    /// get `count` items from the book
+    ///
+    /// return if request item,
+    /// get from mem
+    /// if range, filter
+    /// if count <= len, return
+    /// need more, get from fs
+    ///
+    pub fn get(&self, count: ReqCount, format: GetFormat, range: Option<(u64, u64)>, loc: ReadLocation, filter: Option<Filter>)
+        -> Option<ReturnType>
+    {
+        // return if requested 0 item
+        if let ReqCount::Count(c) = count {
+            if c == 0 {
+                return None
+            }
+        }
+
+        let predicate = filter.map(|filter| filter.compile(&self.scale));
+        let matches = |up: &Update| predicate.as_ref().map(|p| p.matches(up)).unwrap_or(true);
+
+        // if range, filter mem
+        let ranged = catch! {
+            let (min_ts, max_ts) = range?;
+            if !within_range(min_ts, max_ts, self.mem().next()?.ts, self.mem().last()?.ts) { return None; }
+            (min_ts, max_ts)
+        };
+        let in_range = |up: &Update| ranged.map(|(min_ts, max_ts)| up.ts < max_ts && up.ts > min_ts).unwrap_or(true);
+        let acc = self.split_mem(|up| Some(*up).filter(|up| in_range(up) && matches(up)), usize::MAX);
+
+        // if only requested items in memory
+        if let ReadLocation::Mem = loc {
+            return Some(stream_updates(acc, None, None, format, self.scale));
+        }
+
+        // if count <= len, return
+        let limit = match count {
+            ReqCount::Count(c) => Some(c as usize),
+            ReqCount::All => None,
+        };
+        if limit.map(|c| c <= acc.flushing.len() + acc.vec.len()).unwrap_or(false) {
+            return Some(stream_updates(acc, None, limit, format, self.scale));
+        }
+
+        // we need more items
+        // stream the dtf files in folder in requested range after the items in memory
+        let scan = range.map(|range| self.scan(range, predicate));
+        Some(stream_updates(acc, scan, limit, format, self.scale))
+    }
+
+    /// Streams the updates after `page.after` in time order, at most `page.limit` of them,
+    /// followed by the cursor of the last one.
+    ///
+    /// Only the updates of the page are held in memory, updates in files come before
+    /// the ones in memory unless the whole book is in memory.
+    pub fn get_page(&self, page: handler::Page, format: GetFormat, range: Option<(u64, u64)>, loc: ReadLocation, filter: Option<Filter>) -> ReturnType {
+        if page.limit == Some(0) {
+            return ReturnType::error("LIMIT must be at least 1");
+        }
+        let predicate = filter.map(|filter| filter.compile(&self.scale));
+        let (min_ts, max_ts) = range.unwrap_or((0, u64::MAX));
+        let limit = page.limit.map(|n| n as usize);
+
+        let dtf_folder = &self.settings.dtf_folder;
+        let scan = match loc {
+            ReadLocation::Fs if !self.in_memory && Path::new(dtf_folder).exists() => Some(self.scan((min_ts, max_ts), predicate.clone())),
+            _ => None,
+        };
+
+        // the updates in memory all come after a cursor in the files
+        let mut resume = Resume::new(page.after.filter(|c| c.offset == 0 || scan.is_none()));
+        let mem = self.split_mem(|up| {
+            if up.ts < min_ts || up.ts > max_ts || !resume.accept(up) {
+                return None;
+            }
+            Some((*up, resume.cursor(0)?)).filter(|(up, _)| predicate.as_ref().map(|p| p.matches(up)).unwrap_or(true))
+        }, limit.unwrap_or(usize::MAX));
+        stream_page(mem, scan, limit, page.after, format, self.scale)
+    }
+
+    /// updates of the book in range matching `filter` to merge with other books,
+    /// the files are only scanned with `from_files` and if the book is not in memory
+    fn source(&self, (min_ts, max_ts): (u64, u64), from_files: bool, filter: Option<Filter>) -> Source {
+        let dtf_folder = &self.settings.dtf_folder;
+        let predicate = filter.map(|filter| filter.compile(&self.scale));
+        let mem = self.split_mem(|up| {
+            Some(*up).filter(|up| up.ts >= min_ts && up.ts <= max_ts && predicate.as_ref().map(|p| p.matches(up)).unwrap_or(true))
+        }, usize::MAX);
+        let scan = if from_files && !self.in_memory && Path::new(dtf_folder).exists() {
+            Some(self.scan((min_ts, max_ts), predicate))
+        } else {
+            None
+        };
+        Source { book: self.name.clone(), scale: self.scale, mem, scan }
+    }
+
+    /// files of the book to scan in `range`
+    fn scan(&self, range: (u64, u64), predicate: Option<Predicate>) -> Scan {
+        Scan {
+            folder: self.settings.dtf_folder.clone(),
+            book: self.name.clone(),
+            range,
+            predicate,
+            files: Arc::clone(&self.files_lock),
+        }
+    }
+
+    /// candles of the book between `range` as json or csv
+    pub fn get_candles(&self, res: Resolution, range: Option<(u64, u64)>, format: GetFormat) -> ReturnType {
+        let rollup = match self.rollups.iter().find(|r| r.resolution == res) {
+            Some(rollup) => rollup,
+            None => return ReturnType::error(format!("No {} rollup for {}", res, self.name)),
+        };
+        let mut candles = match rollup.candles(&self.settings.dtf_folder, &self.name) {
+            Ok(candles) => candles,
+            Err(e) => return ReturnType::error(format!("Unable to read candles: {}", e)),
+        };
+        if let Some((min_ts, max_ts)) = range {
+            candles.retain(|c| c.start >= min_ts && c.start < max_ts);
+        }
+        let mut ret = match format {
+            GetFormat::Csv => rollup::candles_to_csv(&candles, &self.scale),
+            _ => rollup::candles_to_json(&candles, &self.scale),
+        };
+        ret.push('\n');
+        ReturnType::string(ret)
+    }
+
+    /// aggregates of the updates of the book as json or csv
+    pub fn select(&self, query: handler::Select, format: GetFormat, loc: ReadLocation) -> ReturnType {
+        let predicate = query.filter.map(|filter| filter.compile(&self.scale));
+        let (min_ts, max_ts) = query.range.unwrap_or((0, u64::MAX));
+        let mut aggregator = Aggregator::new(query.aggregates, query.group_by);
+        let mut add = |up: &Update| {
+            if up.ts >= min_ts && up.ts <= max_ts && predicate.as_ref().map(|p| p.matches(up)).unwrap_or(true) {
+                aggregator.add(up);
+            }
+        };
+
+        // a loaded book has every update in memory, otherwise memory only has the unflushed updates
+        let dtf_folder = &self.settings.dtf_folder;
+        let on_disk = Path::new(dtf_folder).exists();
+        if let (ReadLocation::Fs, false, true) = (loc, self.in_memory, on_disk) {
+            let (_files, mem) = self.lock_files();
+            if let Err(e) = scan_files_for_range_for_each(dtf_folder, &self.name, min_ts, max_ts, &mut add) {
+                return ReturnType::error(format!("Unable to scan files for range: {}", e));
+            }
+            mem.for_each(&mut add);
+        } else {
+            self.mem().for_each(&mut add);
+        }
+
+        let mut ret = match format {
+            GetFormat::Csv => aggregator.to_csv(&self.scale),
+            _ => aggregator.to_json(&self.scale),
+        };
+        ret.push('\n');
+        ReturnType::string(ret)
+    }
 }
 
 
+/// a client subscribed to a book
 #[derive(Debug)]
-pub struct Connection {
+pub struct Subscription {
     pub outbound: Sender<ReturnType>,
+    /// only push the best n levels of each side when they change instead of every update
+    pub depth: Option<u32>,
+    /// levels pushed last
+    pub last: Option<Depth>,
+}
 
-    /// the current Store client is using
-    pub book_entry: Arc<BookName>,
+/// messages that could not be queued to a connection right away, for every connection
+#[derive(Debug, Default)]
+pub struct Backlog {
+    /// subscription messages dropped for slow subscribers
+    pub dropped: AtomicU64,
+    /// responses queued only once the connection made room for them
+    pub late: AtomicU64,
+    /// slow subscribers disconnected
+    pub disconnected: AtomicU64,
+}
+
+#[derive(Debug)]
+pub struct Connection {
+    pub outbound: Sender<ReturnType>,
 }
 
 impl Connection {
     pub fn new(outbound: Sender<ReturnType>) -> Self {
-        Self {
-            outbound,
-            book_entry: Arc::new(BookName::from("default").unwrap()),
-        }
+        Self { outbound }
     }
 }
 
@@ -194,155 +1038,110 @@ impl Connection {
 ///        ...
 ///      { total => [...]}
 pub type CountHistory = HashMap<BookName, CircularQueue<(SystemTime, u64)>>;
+
+/// state of the broker, which processes the commands spanning several books
+///
+/// Commands on a single book are processed by the actor of the book (see `actor`).
 pub struct TectonicServer {
     pub connections: HashMap<SocketAddr, Connection>,
     pub settings: Arc<Settings>,
-    pub books: HashMap<BookName, Book>,
+    /// actors of the books, shared with the connections
+    pub books: Books,
     pub history: CountHistory,
-    pub subscriptions: HashMap<BookName, HashMap<SocketAddr, Sender<ReturnType>>>,
+    /// default retention policy of the books
+    pub retention: Retention,
+    /// shared by every book and connection
+    pub backlog: Arc<Backlog>,
 }
 
 impl TectonicServer {
     pub fn new(settings: Arc<Settings>) -> Self {
         let connections = HashMap::new();
-        let mut books = HashMap::new();
-        books.insert(
-            BookName::from("default").unwrap(),
-            Book::new("default", settings.clone(), PRICE_DECIMALS)
-        );
-        let subscriptions = HashMap::new();
         let history = HashMap::new();
-        Self {
+        let retention = settings.retention;
+        let mut ret = Self {
             settings,
-            books,
+            retention,
+            books: Books::default(),
             history,
-            subscriptions,
             connections,
-        }
+            backlog: Default::default(),
+        };
+        let book = Book::new("default", ret.settings.clone(), &EncodeOptions::default());
+        ret.register(BookName::from("default").unwrap(), book);
+        ret
     }
 
-    pub async fn process_command(&mut self, command: Command, addr: Option<SocketAddr>) -> ReturnType {
+    pub async fn process_command(&mut self, command: Command) -> ReturnType {
         use Command::*;
         match command {
-            Noop => ReturnType::string(""),
-            Ping => ReturnType::string("PONG"),
-            Help => ReturnType::string(ReturnType::HELP_STR),
-            Info => ReturnType::string(self.info()),
-            Perf => ReturnType::string(self.perf()),
-            Orderbook(book_name) => {
-                let book_name = book_name
-                    .map(|i| Arc::new(i))
-                    .unwrap_or_else(|| Arc::clone(&self.conn(addr).unwrap().book_entry));
-                self.orderbook_as_json_str(&book_name)
-                    .map(|c| ReturnType::string(c))
-                    .unwrap_or_else(|| ReturnType::error("Unable to get orderbook"))
-            },
-            Count(ReqCount::Count(_), ReadLocation::Fs) => {
-                self.count(addr)
-                    .map(|c| ReturnType::string(format!("{}", c)))
-                    .unwrap_or_else(|| ReturnType::error("Unable to get count"))
-            },
-            Count(ReqCount::Count(_), ReadLocation::Mem) => {
-                self.count_in_mem(addr)
-                    .map(|c| ReturnType::string(format!("{}", c)))
-                    .unwrap_or_else(|| ReturnType::error("Unable to get count in memory"))
-            },
-            Count(ReqCount::All, ReadLocation::Fs) => ReturnType::string(format!("{}", self.countall())),
-            Count(ReqCount::All, ReadLocation::Mem) => ReturnType::string(format!("{}", self.countall_in_mem())),
-            Clear(ReqCount::Count(_)) => {
-                self.clear(addr);
-                ReturnType::ok()
-            }
+            Info => ReturnType::string(self.info().await),
+            Perf => ReturnType::string(self.perf().await),
+            Count(ReqCount::All, ReadLocation::Fs) => ReturnType::string(format!("{}", self.countall().await)),
+            Count(ReqCount::All, ReadLocation::Mem) => ReturnType::string(format!("{}", self.countall_in_mem().await)),
             Clear(ReqCount::All) => {
-                self.clearall();
+                self.clearall().await;
                 ReturnType::ok()
             }
-            Flush(ReqCount::Count(_)) => {
-                self.flush(addr);
-                ReturnType::ok()
-            }
-            Flush(ReqCount::All) => {
-                self.flushall();
-                ReturnType::ok()
-            }
-            // update, dbname
-            Insert(Some(up), book_name) => {
-                let book_name = book_name
-                    .map(|i| Arc::new(i))
-                    .unwrap_or_else(|| Arc::clone(&self.conn(addr).unwrap().book_entry));
-                match self.insert(up, &book_name).await {
-                    Some(()) => ReturnType::string(""),
-                    None => ReturnType::Error(Cow::Owned(format!("DB {} not found.", &book_name))),
-                }
-            }
-            Insert(None, _) => ReturnType::error("Unable to parse line"),
-            Create(dbname) => match self.create(&dbname) {
+            Flush(ReqCount::All) => self.flushall().await,
+            Create(dbname, scale, properties) => match self.create(&dbname, scale, properties) {
                     Some(()) => ReturnType::string(format!("Created orderbook `{}`.", &dbname)),
                     None => ReturnType::error(format!("Unable to create orderbook `{}`.", &dbname)),
                 },
-            Subscribe(dbname) => {
-                self.sub(&dbname, addr);
-                ReturnType::string(format!("Subscribed to {}", dbname))
-            }
-            // Subscription => {
-            //     let message = state.rx.as_ref().unwrap().try_recv();
-            //     match message {
-            //         Ok(msg) => ReturnType::string([msg].as_json()),
-            //         _ => ReturnType::string("NONE"),
-            //     }
-            // }
-            // Unsubscribe(ReqCount::All) => {
-            //     self.unsub_all();
-            //     ReturnType::string("Unsubscribed everything!")
-            // }
-            // Unsubscribe(ReqCount::Count(_)) => {
-            //     let old_dbname = state.subscribed_db.clone().unwrap();
-            //     self.unsub();
-            //     ReturnType::string(format!("Unsubscribed from {}", old_dbname))
-            // }
-            Load(dbname) => {
-                match self.load_db(&dbname, addr) {
-                    Some(_) => ReturnType::string(format!("Loaded orderbook `{}`.", &dbname)),
-                    None => ReturnType::error(format!("No db named `{}`", dbname)),
-                }
-            }
-            Use(dbname) => {
-                match self.use_db(&dbname, addr) {
-                    Some(_) => ReturnType::string(format!("SWITCHED TO orderbook `{}`.", &dbname)),
-                    None => ReturnType::error(format!("No db named `{}`", dbname)),
-                }
-            }
-            Exists(dbname) => {
-                if self.exists(&dbname) {
-                    ReturnType::ok()
-                } else {
-                    ReturnType::error(format!("No db named `{}`", dbname))
-                }
-            }
-            Get(cnt, fmt, rng, loc) =>
-                self.get(cnt, fmt, rng, loc, addr)
-                    .unwrap_or_else(|| ReturnType::error("Not enough items to return")),
-            Unknown => {
-                error!("Unknown command");
-                ReturnType::error("Unknown command.")
-            }
-            BadFormat => {
-                error!("bad format error");
-                ReturnType::error("Bad format.")
+            Retention(dbname) => match self.retention_json(dbname.as_ref()).await {
+                Some(json) => ReturnType::string(json),
+                None => ReturnType::error(format!("No db named `{}`", dbname.unwrap())),
+            },
+            SetRetention(None, policy) => {
+                self.retention = policy.unwrap_or(self.settings.retention);
+                ReturnType::ok()
             }
+            SetRetention(Some(dbname), policy) => match self.handle(&dbname) {
+                Some(mut handle) => match handle.ask(move |book| book.set_retention(policy)).await {
+                    Some(Err(e)) => ReturnType::error(format!("Unable to save retention policy: {}", e)),
+                    _ => ReturnType::ok(),
+                },
+                None => ReturnType::error(format!("No db named `{}`", dbname)),
+            },
+            GetMerged(books, cnt, fmt, rng, loc, filter) => self.get_merged(books, cnt, fmt, rng, loc, filter).await,
+            command => ReturnType::error(format!("Unexpected command: {:?}", command)),
         }
     }
 
+    /// spawn the actor of a book
+    pub fn register(&mut self, book_name: BookName, mut book: Book) {
+        book.backlog = Arc::clone(&self.backlog);
+        self.books.write().unwrap().insert(book_name, BookHandle::spawn(book));
+    }
+
+    /// actor of a book
+    pub fn handle(&self, book_name: &str) -> Option<BookHandle> {
+        self.books.read().unwrap().get(book_name).cloned()
+    }
+
+    /// actors of every book
+    pub fn handles(&self) -> Vec<(BookName, BookHandle)> {
+        self.books.read().unwrap().iter().map(|(name, handle)| (*name, handle.clone())).collect()
+    }
+
+    /// run `f` on every book at once and collect its results
+    pub async fn ask_all<T, F>(&self, f: F) -> Vec<(BookName, T)>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Book) -> T + Clone + Send + 'static,
+    {
+        let mut handles = self.handles();
+        let results = future::join_all(handles.iter_mut().map(|(_, handle)| handle.ask(f.clone()))).await;
+        handles.into_iter()
+            .zip(results)
+            .filter_map(|((name, _), ret)| Some((name, ret?)))
+            .collect()
+    }
 
     #[cfg_attr(feature = "count_alloc", count_alloc)]
-    pub fn record_history(&mut self) {
-        let mut total = 0;
-        let mut sizes: Vec<(BookName, u64)> = Vec::with_capacity(self.books.len() + 1);
-        for (name, book) in self.books.iter() {
-            let size = book.vec.len() as u64;
-            total += size;
-            sizes.push((name.clone(), size));
-        }
+    pub async fn record_history(&mut self) {
+        let mut sizes = self.ask_all(|book| book.mem_len() as u64).await;
+        let total = sizes.iter().map(|(_, size)| size).sum();
         sizes.push((BookName::from("total").unwrap(), total));
 
         let current_t = std::time::SystemTime::now();
@@ -360,6 +1159,34 @@ impl TectonicServer {
     }
 
 
+    /// Drop the updates on disk that fall outside of the retention policy of their book,
+    /// without waiting for the books
+    pub async fn enforce_retention(&mut self) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+        let default = self.retention;
+        for (_name, mut handle) in self.handles() {
+            handle.tell(move |book| drop(book.enforce_retention(default, now))).await;
+        }
+    }
+
+    /// Retention policy of a book, or the default policy and the policy of every book as JSON
+    pub async fn retention_json(&self, book_name: Option<&BookName>) -> Option<String> {
+        let default = self.retention;
+        match book_name {
+            Some(book_name) => {
+                let policy = self.handle(book_name)?.ask(|book| book.retention).await?;
+                Some(policy.unwrap_or(default).to_string())
+            }
+            None => {
+                let books: Vec<String> = self.ask_all(|book| book.retention).await
+                    .into_iter()
+                    .map(|(name, policy)| format!("{}: {}", serde_json::to_string(name.as_str()).unwrap_or_default(), policy.unwrap_or(default)))
+                    .collect();
+                Some(format!(r#"{{"default": {}, "books": {{{}}}}}"#, default, books.join(", ")))
+            }
+        }
+    }
+
     /// Get information about the server
     ///
     /// Returns a JSON string.
@@ -376,23 +1203,9 @@ impl TectonicServer {
     ///         "count": 10 // number of rows in this store
     ///     }
     /// }
-    pub fn info(&self) -> String {
-        let info_vec: Vec<String> = self.books
-            .iter()
-            .map(|i| {
-                let (key, book) = i;
-                format!(
-                    r#"{{
-    "name": "{}",
-    "in_memory": {},
-    "count": {}
-  }}"#,
-                    key,
-                    book.vec.len(),
-                    book.nominal_count,
-                )
-            })
-            .collect();
+    pub async fn info(&self) -> String {
+        let books = self.ask_all(|book| (book.info(), book.mem_len(), book.nominal_count, book.subscriptions.len())).await;
+        let info_vec: Vec<&str> = books.iter().map(|(_, (info, ..))| info.as_str()).collect();
         let metadata = format!(
             r#"{{
     "clis": {},
@@ -402,10 +1215,13 @@ impl TectonicServer {
     "autoflush_interval": {},
     "dtf_folder": "{}",
     "total_in_memory_count": {},
-    "total_count": {}
+    "total_count": {},
+    "dropped_messages": {},
+    "late_messages": {},
+    "slow_disconnects": {}
   }}"#,
             self.connections.len(),
-            self.subscriptions.iter().map(|i| i.1.len()).sum::<usize>(),
+            books.iter().map(|(_, (.., subs))| subs).sum::<usize>(),
             SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .expect("Time went backwards")
@@ -413,14 +1229,11 @@ impl TectonicServer {
             self.settings.autoflush,
             self.settings.flush_interval,
             self.settings.dtf_folder,
-            self.books.iter().fold(
-                0,
-                |acc, (_name, tup)| acc + tup.vec.len(),
-            ),
-            self.books.iter().fold(
-                0,
-                |acc, (_name, tup)| acc + tup.nominal_count,
-            )
+            books.iter().map(|(_, (_, in_mem, ..))| in_mem).sum::<usize>(),
+            books.iter().map(|(_, (_, _, count, _))| count).sum::<u64>(),
+            self.backlog.dropped.load(Ordering::Relaxed),
+            self.backlog.late.load(Ordering::Relaxed),
+            self.backlog.disconnected.load(Ordering::Relaxed),
         );
         let mut ret = format!(
             r#"{{
@@ -434,16 +1247,12 @@ impl TectonicServer {
         ret
     }
 
-    pub fn orderbook_as_json_str(&self, book_name: &str) -> Option<String> {
-        let book = self.books.get(book_name)?;
-        let ob_json_str = serde_json::to_string(&book.orderbook).ok()?;
-        Some(ob_json_str)
-    }
-
     /// Returns a JSON object like
     /// [{"total": [1508968738: 0]}, {"default": [1508968738: 0]}]
-    pub fn perf(&self) -> String {
-        let objs: Vec<String> = (&self.history)
+    ///
+    /// followed by {"integrity": {"default": {"negative_levels": 0, ...}}} when integrity checks are on
+    pub async fn perf(&self) -> String {
+        let mut objs: Vec<String> = (&self.history)
             .iter()
             .map(|(name, vec)| {
                 let hists: Vec<String> = vec.iter()
@@ -455,219 +1264,98 @@ impl TectonicServer {
                 format!(r#"{{"{}": {{{}}}}}"#, name, hists.join(", "))
             })
             .collect();
+        if self.settings.integrity != IntegrityMode::Off {
+            let books: Vec<String> = self.ask_all(|book| book.integrity.as_ref().map(Integrity::to_json)).await
+                .into_iter()
+                .filter_map(|(name, integrity)| Some(format!(r#""{}": {}"#, name, integrity?)))
+                .collect();
+            objs.push(format!(r#"{{"integrity": {{{}}}}}"#, books.join(", ")));
+        }
 
         format!("[{}]\n", objs.join(", "))
     }
 
-    /// Insert a row into store
-    pub async fn insert(&mut self, up: Update, book_name: &str) -> Option<()> {
-        let book = self.books.get_mut(book_name)?;
-        book.add(up);
-        self.send_subs(up, book_name).await
-    }
-
-    async fn send_subs(&mut self, up: Update, book_name: &str) -> Option<()> {
-        if let Some(book_sub) = self.subscriptions.get_mut(book_name) {
-            for sub in book_sub.iter_mut() {
-                let bytes = tdb_core::utils::encode_insert_into(Some(book_name), &up).ok()?;
-                sub.1.send(ReturnType::Bytes(bytes)).await.ok()?;
-            }
-        }
-        Some(())
-    }
-
     /// Check if a table exists
-    pub fn exists(&mut self, book_name: &str) -> bool {
-        self.books.contains_key(book_name)
+    pub fn exists(&self, book_name: &str) -> bool {
+        self.books.read().unwrap().contains_key(book_name)
     }
 
     /// Create a new store
-    pub fn create(&mut self, book_name: &BookName) -> Option<()> {
-        if self.books.contains_key(book_name) {
-            None
-        } else {
-            self.books.insert(
-                book_name.to_owned(),
-                Book::new(book_name, self.settings.clone(), PRICE_DECIMALS),
-            );
-            Some(())
+    /// Fails if the book exists or its file was written with a different scale or properties
+    pub fn create(&mut self, book_name: &BookName, scale: Option<Scale>, properties: Properties) -> Option<()> {
+        if self.exists(book_name) {
+            return None;
         }
-    }
-
-    /// load a datastore file into memory
-    pub fn load_db(&mut self, book_name: &BookName, addr: Option<SocketAddr>) -> Option<()> {
-        if self.books.contains_key(book_name) {
-            self.book_mut(addr)?.load();
-            Some(())
-        } else {
-            None
+        let opts = EncodeOptions { scale: scale.unwrap_or_default(), properties, ..Default::default() };
+        let book = Book::new(book_name, self.settings.clone(), &opts);
+        if scale.is_some_and(|scale| scale != book.scale) {
+            error!("{} already has tick size {} and lot size {}.", book_name, book.scale.tick_size, book.scale.lot_size);
+            return None;
         }
-    }
-
-    /// load a datastore file into memory
-    pub fn use_db(&mut self, book_name: &BookName, addr: Option<SocketAddr>) -> Option<()> {
-        if self.books.contains_key(book_name) {
-            self.conn_mut(addr)?.book_entry = Arc::new(book_name.to_owned());
-            Some(())
-        } else {
-            None
+        if !opts.properties.is_empty() && opts.properties != book.properties {
+            error!("{} already has properties {:?}.", book_name, book.properties);
+            return None;
         }
+        self.register(book_name.to_owned(), book);
+        Some(())
     }
 
-    /// return the count of the current store
-    pub fn count(&mut self, addr: Option<SocketAddr>) -> Option<u64> {
-        let ret = self.book(addr)?.nominal_count;
-        Some(ret)
-    }
-
-    /// return current store count in mem
-    pub fn count_in_mem(&mut self, addr: Option<SocketAddr>) -> Option<u64> {
-        let ret = self.book(addr)?.vec.len() as u64;
-        Some(ret)
-    }
-
-    /// Returns the total count
-    pub fn countall_in_mem(&self) -> u64 {
-        self.books.values().fold(
-            0,
-            |acc, book| acc + book.vec.len(),
-        ) as u64
+    /// Returns the total count in memory
+    pub async fn countall_in_mem(&self) -> u64 {
+        self.ask_all(|book| book.mem_len() as u64).await.iter().map(|(_, len)| len).sum()
     }
 
     /// Returns the total count
-    pub fn countall(&self) -> u64 {
-        self.books.values().fold(
-            0,
-            |acc, book| acc + book.nominal_count,
-        )
-    }
-
-    pub fn sub(&mut self, book_name: &BookName, addr: Option<SocketAddr>) -> Option<()> {
-        let outbound = self.conn_mut(addr)?.outbound.clone();
-        let book_sub = self.subscriptions.entry(book_name.to_owned())
-            .or_insert_with(HashMap::new);
-        book_sub.insert(addr.unwrap(), outbound);
-        Some(())
+    pub async fn countall(&self) -> u64 {
+        self.ask_all(|book| book.nominal_count).await.iter().map(|(_, count)| count).sum()
     }
 
-    pub fn unsub(&mut self, addr: &SocketAddr) -> Option<()> {
-        for (_book_name, addrs) in &mut self.subscriptions {
-            addrs.remove(&addr)?;
+    /// unsubscribe a connection from every book
+    pub async fn unsub(&mut self, addr: &SocketAddr) {
+        let addr = *addr;
+        for (_name, mut handle) in self.handles() {
+            handle.tell(move |book| { book.subscriptions.remove(&addr); }).await;
         }
-        Some(())
-    }
-
-
-    /// remove everything in the current store
-    pub fn clear(&mut self, addr: Option<SocketAddr>) -> Option<()> {
-        let book = self.book_mut(addr)?;
-        book.vec.clear();
-        // vecs.1 = 0;
-        book.in_memory = false;
-        book.load_size_from_file();
-        Some(())
     }
 
     /// remove everything in every store
-    pub fn clearall(&mut self) {
-        for book in self.books.values_mut() {
-            book.vec.clear();
-            // vecs.1 = 0;
-            book.in_memory = false;
-            book.load_size_from_file();
-        }
-    }
-
-    /// write items stored in memory into file
-    /// If file exists, use append which only appends a filtered set of updates whose timestamp is larger than the old timestamp
-    /// If file doesn't exists, simply encode.
-    ///
-    pub fn flush(&mut self, addr: Option<SocketAddr>) -> Option<()> {
-        self.book_mut(addr)?.flush()
+    pub async fn clearall(&mut self) {
+        self.ask_all(|book| book.clear()).await;
     }
 
-    /// save all stores to corresponding files
-    pub fn flushall(&mut self) {
-        for book in self.books.values_mut() {
-            book.flush();
-        }
+    /// save all stores to corresponding files, the response is sent once every flush is done
+    pub async fn flushall(&mut self) -> ReturnType {
+        let flushes = self.ask_all(|book| book.flush()).await;
+        let (reply, ret) = oneshot::channel();
+        task::spawn(async move {
+            future::join_all(flushes.into_iter().map(|(_, flushed)| flushed)).await;
+            let _ = reply.send(ReturnType::ok());
+        });
+        ReturnType::Pending(ret)
     }
 
-    /// get `count` items from the current store
-    ///
-    /// return if request item,
-    /// get from mem
-    /// if range, filter
-    /// if count <= len, return
-    /// need more, get from fs
-    ///
-    pub fn get(&self, count: ReqCount, format: GetFormat, range: Option<(u64, u64)>, loc: ReadLocation, addr: Option<SocketAddr>)
-        -> Option<ReturnType>
-    {
-        // return if requested 0 item
-        if let ReqCount::Count(c) = count {
-            if c == 0 {
-                return None
-            }
-        }
-
-        let book = self.book(addr)?;
-
-        // if range, filter mem
-        let acc = catch! {
-            let (min_ts, max_ts) = range?;
-            if !within_range(min_ts, max_ts, book.vec.first()?.ts, book.vec.last()?.ts) { return None; }
-            book.vec.iter()
-                .filter(|up| up.ts < max_ts && up.ts > min_ts)
-                .map(|up| up.to_owned())
-                .collect::<Vec<_>>()
-        }.unwrap_or_else(|| book.vec.to_owned());
-
-        // if only requested items in memory
-        if let ReadLocation::Mem = loc {
-            return into_format(&acc, format);
-        }
-
-        // if count <= len, return
-        if let ReqCount::Count(c) = count {
-            if (c as usize) <= acc.len() {
-                return into_format(&acc[..c as usize], format);
-            }
-        }
-
-        // we need more items
-        // check dtf files in folder and collect updates in requested range
-        // and combine sequentially
-        let mut ups_from_fs = acc;
-        if let Some((min_ts, max_ts)) = range {
-            let folder = {
-                self.settings.dtf_folder.clone()
+    /// Streams the updates of `books` merged by (ts, seq) and tagged with their book,
+    /// the updates in files of each book come before the ones in memory.
+    pub async fn get_merged(&self, books: Vec<BookName>, count: ReqCount, format: GetFormat, range: Option<(u64, u64)>, loc: ReadLocation, filter: Option<Filter>) -> ReturnType {
+        let range = range.unwrap_or((0, u64::MAX));
+        let from_files = matches!(loc, ReadLocation::Fs);
+        let mut sources = vec![];
+        for name in books {
+            let filter = filter.clone();
+            let source = match self.handle(&name) {
+                Some(mut handle) => handle.ask(move |book| book.source(range, from_files, filter)).await,
+                None => None,
             };
-            let ups = scan_files_for_range(&folder, self.conn(addr)?.book_entry.as_str(), min_ts, max_ts);
-            match ups {
-                Ok(ups) => {
-                    ups_from_fs.extend(ups);
-                }
-                Err(_) => {
-                    error!("Unable to scan files for range.");
-                }
+            match source {
+                Some(source) => sources.push(source),
+                None => return ReturnType::error(format!("DB {} not found.", name)),
             }
         }
-
-        let result = ups_from_fs;
-
-        match count {
-            ReqCount::Count(c) => {
-                if result.len() >= c as usize {
-                    into_format(&result[..(c as usize - 1)], format)
-                } else {
-                    Some(ReturnType::Error(
-                        format!("Requested {} but only have {}.", c, result.len()).into(),
-                    ))
-                }
-            }
-            ReqCount::All => into_format(&result, format),
-        }
+        let limit = match count {
+            ReqCount::Count(c) => Some(c as usize),
+            ReqCount::All => None,
+        };
+        stream_merged(sources, limit, format)
     }
 
     pub fn new_connection(&mut self, client_sender: Sender<ReturnType>, addr: SocketAddr) -> bool {
@@ -679,32 +1367,4 @@ impl TectonicServer {
             }
         }
     }
-
-    #[cfg_attr(feature = "count_alloc", count_alloc)]
-    pub async fn command(&mut self, cmd: Command, addr: Option<SocketAddr>) {
-        let ret = self.process_command(cmd, addr).await;
-        if let Some(addr) = addr {
-            if self.connections.contains_key(&addr) {
-                self.connections.get_mut(&addr).unwrap().outbound.send(ret).await.unwrap();
-            }
-        }
-    }
-
-    pub fn conn(&self, addr: Option<SocketAddr>) -> Option<&Connection> {
-        self.connections.get(&addr?)
-    }
-
-    pub fn conn_mut(&mut self, addr: Option<SocketAddr>) -> Option<&mut Connection> {
-        self.connections.get_mut(&addr?)
-    }
-
-    pub fn book_mut(&mut self, addr: Option<SocketAddr>) -> Option<&mut Book> {
-        let book_name = Arc::clone(&self.conn(addr)?.book_entry);
-        self.books.get_mut(book_name.as_str())
-    }
-
-    pub fn book(&self, addr: Option<SocketAddr>) -> Option<&Book> {
-        let book_name = Arc::clone(&self.conn(addr)?.book_entry);
-        self.books.get(book_name.as_str())
-    }
 }
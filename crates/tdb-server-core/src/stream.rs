@@ -0,0 +1,353 @@
+//! streamed `GET` results
+//!
+//! Results are sent to the connection as a `ReturnType::Stream` of chunks so
+//! that neither the broker nor the connection holds the whole result. Each
+//! chunk is a self-contained set of dtf batches, csv lines or json lines, and
+//! the connection writer ends the stream with an empty frame.
+//!
+//! A page of `GET ... LIMIT n AFTER cursor` ends with the `Cursor` of its last
+//! update, the next page starts right after it.
+//! Updates with the same ts and seq are told apart by their ordinal among
+//! them, counted from the start of their batch in a file or of the updates
+//! in memory.
+//!
+//! The files of a book are locked for reading while they are scanned, a flush
+//! waits for the scans in progress. Updates in memory are left out of a scan
+//! once a flush has written them to the files.
+use crate::prelude::*;
+use crate::filter::Predicate;
+use tdb_core::dtf::file_format::{files_for_range, for_each_from, write_batches};
+use tdb_core::dtf::scale::Scale;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{PoisonError, RwLock, RwLockReadGuard};
+use std::thread;
+
+/// number of updates in a chunk
+pub const CHUNK_LEN: usize = 4096;
+
+/// chunks buffered between the reader and the connection
+pub const STREAM_CAPACITY: usize = 4;
+
+/// files of a book to scan after the updates in memory
+pub struct Scan {
+    pub folder: String,
+    pub book: String,
+    pub range: (u64, u64),
+    pub predicate: Option<Predicate>,
+    /// the files with the number of the last flush that wrote all its updates
+    pub files: Arc<RwLock<u64>>,
+}
+
+impl Scan {
+    /// locks the files for reading until the guard is dropped
+    pub fn lock(&self) -> RwLockReadGuard<'_, u64> {
+        self.files.read().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// updates in memory when a read starts
+pub struct Mem<T> {
+    /// updates written by the flush in progress
+    pub flushing: Vec<T>,
+    /// updates written by the next flush
+    pub vec: Vec<T>,
+    /// number of the next flush
+    pub flush: u64,
+}
+
+impl<T> Mem<T> {
+    /// the updates that are not in the files, given the last flush that wrote all its updates
+    pub fn visible(self, written: u64) -> Vec<T> {
+        match written {
+            written if written >= self.flush => vec![],
+            written if written + 1 == self.flush => self.vec,
+            _ => {
+                let mut ret = self.flushing;
+                ret.extend(self.vec);
+                ret
+            }
+        }
+    }
+}
+
+/// position of an update in the updates of a book
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cursor {
+    pub ts: u64,
+    pub seq: u32,
+    /// offset of the batch of the update in its file, 0 for an update in memory
+    pub offset: u64,
+    /// number of updates with the same ts and seq before the update
+    pub nth: u32,
+}
+
+/// 48 hex digits, clients should treat it as opaque
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}{:08x}{:016x}{:08x}", self.ts, self.seq, self.offset, self.nth)
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = ();
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s.len() != 48 || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(());
+        }
+        Ok(Cursor {
+            ts: u64::from_str_radix(&s[..16], 16).map_err(|_| ())?,
+            seq: u32::from_str_radix(&s[16..24], 16).map_err(|_| ())?,
+            offset: u64::from_str_radix(&s[24..40], 16).map_err(|_| ())?,
+            nth: u32::from_str_radix(&s[40..], 16).map_err(|_| ())?,
+        })
+    }
+}
+
+/// skips updates up to and including the update at a cursor
+///
+/// Updates are in time order, so the first update past the cursor is the one after
+/// the `nth` update with the ts and seq of the cursor, or the first one with a
+/// later ts and seq in case there are fewer of them now.
+pub struct Resume {
+    after: Option<Cursor>,
+    /// ts and seq of the last update and its ordinal among the updates with them
+    last: Option<(u64, u32, u32)>,
+}
+
+impl Resume {
+    pub fn new(cursor: Option<Cursor>) -> Self {
+        Resume { after: cursor, last: None }
+    }
+
+    /// if `up` comes after the cursor, every update is passed in order
+    pub fn accept(&mut self, up: &Update) -> bool {
+        let nth = match self.last {
+            Some((ts, seq, nth)) if (ts, seq) == (up.ts, up.seq) => nth + 1,
+            _ => 0,
+        };
+        self.last = Some((up.ts, up.seq, nth));
+        match self.after {
+            None => true,
+            Some(c) if (up.ts, up.seq, nth) <= (c.ts, c.seq, c.nth) => false,
+            Some(_) => {
+                self.after = None;
+                true
+            }
+        }
+    }
+
+    /// ordinals count from the start of each batch
+    pub fn batch(&mut self) {
+        self.last = None;
+    }
+
+    /// cursor of the last update passed to `accept`
+    pub fn cursor(&self, offset: u64) -> Option<Cursor> {
+        self.last.map(|(ts, seq, nth)| Cursor { ts, seq, offset, nth })
+    }
+}
+
+/// Streams a page of at most `limit` updates after `after`: the updates in the files
+/// of `scan` followed by `mem`, then the cursor of the last update.
+///
+/// `mem` must already be past the cursor, with the cursor of each update. An empty
+/// page ends with `after`.
+pub fn stream_page(mem: Mem<(Update, Cursor)>, scan: Option<Scan>, limit: Option<usize>, after: Option<Cursor>, format: GetFormat, scale: Scale) -> ReturnType {
+    let (tx, rx) = mpsc::channel(STREAM_CAPACITY);
+    thread::spawn(move || {
+        let files = scan.as_ref().map(Scan::lock);
+        let mem = mem.visible(files.as_deref().copied().unwrap_or(0));
+        let mut chunker = Chunker::new(tx, format, scale, limit);
+        let mut cursor = after;
+        if let Some(scan) = &scan {
+            if let Err(e) = scan_page(scan, after, &mut chunker, &mut cursor) {
+                error!("Unable to scan files for page: {}", e);
+                chunker.fail(e);
+                return;
+            }
+        }
+        for (up, up_cursor) in &mem {
+            if chunker.closed {
+                break;
+            }
+            chunker.push(up);
+            cursor = Some(*up_cursor);
+        }
+        chunker.flush();
+        if let Some(cursor) = cursor {
+            let _ = task::block_on(chunker.tx.send(ReturnType::Cursor(cursor.to_string())));
+        }
+    });
+    ReturnType::Stream(rx)
+}
+
+/// pushes the updates of the files of `scan` after `after` until the chunker is closed
+fn scan_page(scan: &Scan, after: Option<Cursor>, chunker: &mut Chunker, cursor: &mut Option<Cursor>) -> std::io::Result<()> {
+    let (min_ts, max_ts) = scan.range;
+    let min_ts = after.map(|c| c.ts.max(min_ts)).unwrap_or(min_ts);
+    // the updates in files all come before a cursor in memory
+    let mut resume = Resume::new(after.map(|c| if c.offset == 0 { Cursor { nth: u32::MAX, ..c } } else { c }));
+    // only the first file can hold the update at the cursor
+    let mut offset = after.map(|c| c.offset).filter(|&offset| offset != 0);
+    for (fname, _meta) in files_for_range(&scan.folder, &scan.book, min_ts, max_ts)? {
+        let mut batch = None;
+        for_each_from(&fname, offset.take(), min_ts, &mut |pos, up| {
+            if up.ts > max_ts {
+                return false;
+            }
+            if batch != Some(pos) {
+                batch = Some(pos);
+                resume.batch();
+            }
+            let matches = scan.predicate.as_ref().map(|p| p.matches(up)).unwrap_or(true);
+            if up.ts >= min_ts && resume.accept(up) && matches {
+                chunker.push(up);
+                *cursor = resume.cursor(pos);
+            }
+            !chunker.closed
+        })?;
+        if chunker.closed {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Streams `mem` followed by the updates found by `scan`, at most `limit` of them.
+///
+/// Files are read on a separate thread until the limit is reached or the connection
+/// is gone, a limit that is not reached or a failed scan ends the stream with an error.
+pub fn stream_updates(mem: Mem<Update>, scan: Option<Scan>, limit: Option<usize>, format: GetFormat, scale: Scale) -> ReturnType {
+    let (tx, rx) = mpsc::channel(STREAM_CAPACITY);
+    thread::spawn(move || {
+        let files = scan.as_ref().map(Scan::lock);
+        let mem = mem.visible(files.as_deref().copied().unwrap_or(0));
+        let mut chunker = Chunker::new(tx, format, scale, limit);
+        mem.iter().for_each(|up| chunker.push(up));
+        if let Some(scan) = scan.as_ref().filter(|_| !chunker.closed) {
+            if let Err(e) = scan_range(scan, &mut chunker) {
+                error!("Unable to scan files for range: {}", e);
+                chunker.fail(e);
+                return;
+            }
+        }
+        chunker.finish();
+    });
+    ReturnType::Stream(rx)
+}
+
+/// pushes the updates of the files of `scan` until the chunker is closed
+fn scan_range(scan: &Scan, chunker: &mut Chunker) -> std::io::Result<()> {
+    let (min_ts, max_ts) = scan.range;
+    for (fname, _meta) in files_for_range(&scan.folder, &scan.book, min_ts, max_ts)? {
+        for_each_from(&fname, None, min_ts, &mut |_pos, up| {
+            if up.ts > max_ts {
+                return false;
+            }
+            if up.ts >= min_ts && scan.predicate.as_ref().map(|p| p.matches(up)).unwrap_or(true) {
+                chunker.push(up);
+            }
+            !chunker.closed
+        })?;
+        if chunker.closed {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// buffers updates and sends them as chunks
+struct Chunker {
+    tx: Sender<ReturnType>,
+    format: GetFormat,
+    scale: Scale,
+    buf: Vec<Update>,
+    /// number of updates pushed so far
+    sent: usize,
+    limit: Option<usize>,
+    /// the connection is gone or the limit is reached
+    closed: bool,
+}
+
+impl Chunker {
+    /// a chunker of a stream, which starts with the scale of the updates for dtf batches
+    fn new(tx: Sender<ReturnType>, format: GetFormat, scale: Scale, limit: Option<usize>) -> Self {
+        let mut ret = Chunker { tx, format, scale, buf: Vec::with_capacity(CHUNK_LEN), sent: 0, limit, closed: false };
+        if let GetFormat::Dtf = format {
+            if task::block_on(ret.tx.send(ReturnType::Scale(scale))).is_err() {
+                ret.closed = true;
+            }
+        }
+        ret
+    }
+
+    fn push(&mut self, up: &Update) {
+        if self.closed {
+            return;
+        }
+        self.buf.push(*up);
+        self.sent += 1;
+        if self.buf.len() == CHUNK_LEN {
+            self.flush();
+        }
+        if Some(self.sent) == self.limit {
+            self.flush();
+            self.closed = true;
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.buf.is_empty() {
+            return;
+        }
+        let chunk = format_chunk(&self.buf, self.format, &self.scale);
+        self.buf.clear();
+        if task::block_on(self.tx.send(chunk)).is_err() {
+            self.closed = true;
+        }
+    }
+
+    fn finish(mut self) {
+        self.flush();
+        if let Some(limit) = self.limit {
+            if self.sent < limit {
+                let err = format!("Requested {} but only have {}.", limit, self.sent);
+                let _ = task::block_on(self.tx.send(ReturnType::error(err)));
+            }
+        }
+    }
+
+    /// ends the stream with the error of a failed scan after the updates read so far
+    fn fail(mut self, e: std::io::Error) {
+        self.flush();
+        let _ = task::block_on(self.tx.send(ReturnType::error(format!("Unable to scan files: {}", e))));
+    }
+}
+
+/// a chunk of dtf batches, csv lines or json lines
+pub fn format_chunk(ups: &[Update], format: GetFormat, scale: &Scale) -> ReturnType {
+    match format {
+        GetFormat::Dtf => {
+            let mut buf: Vec<u8> = Vec::with_capacity(ups.len() * 12);
+            let _ = write_batches(&mut buf, ups.iter().peekable());
+            ReturnType::Bytes(buf)
+        }
+        GetFormat::Json => {
+            let mut ret = String::new();
+            for up in ups {
+                ret.push_str(&up.as_json(scale));
+                ret.push('\n');
+            }
+            ReturnType::string(ret)
+        }
+        GetFormat::Csv => {
+            let mut ret = String::new();
+            for up in ups {
+                ret.push_str(&up.to_csv(scale));
+                ret.push('\n');
+            }
+            ReturnType::string(ret)
+        }
+    }
+}
@@ -2,6 +2,8 @@ use crate::prelude::*;
 use std::path::Path;
 use std::fs;
 use tdb_core::dtf;
+use tdb_core::dtf::file_format::EncodeOptions;
+use tdb_core::storage::partition::partitions;
 
 pub fn create_dir_if_not_exist(dtf_folder: &str) {
     if !Path::new(dtf_folder).exists() {
@@ -11,8 +13,11 @@ pub fn create_dir_if_not_exist(dtf_folder: &str) {
 
 /// Iterate through the dtf files in the folder and load some metadata into memory.
 /// Create corresponding Store objects in State.
+/// Then replay the write-ahead logs of every book into memory.
 pub async fn init_dbs<'a>(state: &mut TectonicServer) {
     let dtf_folder = state.settings.dtf_folder.clone();
+    let mut books: HashMap<BookName, Book> = HashMap::new();
+    create_dir_if_not_exist(&dtf_folder);
     for dtf_file in fs::read_dir(&dtf_folder).unwrap() {
         let fname_os = dtf_file.unwrap().file_name();
         let stem = fname_os.to_str().unwrap(); // sldjf-lks-djflk-sfsd--something.dtf
@@ -34,14 +39,75 @@ pub async fn init_dbs<'a>(state: &mut TectonicServer) {
                 }
             };
 
+            let book_name = match BookName::from(&symbol) {
+                Ok(book_name) => book_name,
+                Err(_) => {
+                    warn!("Symbol of DTF file {} is longer than {} bytes", full_path, BOOK_NAME_LEN);
+                    continue;
+                }
+            };
+
+            if state.exists(&book_name) {
+                continue;
+            }
             let settings = state.settings.clone();
             // if symbol is in vec_store, append to store
             // TODO: this is not accurate at all!
             // XXX: need to keep track of file names :(
-            state.books
-                .entry(BookName::from(&symbol).unwrap())
+            books
+                .entry(book_name)
                 .and_modify(|e| if e.nominal_count < header_size {e.nominal_count += header_size})
-                .or_insert_with(|| Book::new(&symbol, settings, 10_u8)); // TODO: don't hardcode price decimals
+                .or_insert_with(|| Book::new(&symbol, settings, &EncodeOptions::default()));
+        } else if partitions(&dtf_folder, stem).map(|p| !p.is_empty()).unwrap_or(false) {
+            // folder of partitions named after the book
+            let book_name = match BookName::from(stem) {
+                Ok(book_name) => book_name,
+                Err(_) => {
+                    warn!("Partitioned book {} is longer than {} bytes", stem, BOOK_NAME_LEN);
+                    continue;
+                }
+            };
+            if state.exists(&book_name) {
+                continue;
+            }
+            let settings = state.settings.clone();
+            books
+                .entry(book_name)
+                .or_insert_with(|| Book::new(stem, settings, &EncodeOptions::default()));
         }
     }
+
+    if state.settings.wal {
+        match crate::wal::Wal::list(&dtf_folder) {
+            Ok(names) => for name in names {
+                let book_name = match BookName::from(&name) {
+                    Ok(book_name) => book_name,
+                    Err(_) => {
+                        warn!("Invalid book name for write-ahead log {}", name);
+                        continue;
+                    }
+                };
+                if state.exists(&book_name) {
+                    continue;
+                }
+                let settings = state.settings.clone();
+                // a book that was never flushed has its scale and properties in its log only
+                let opts = crate::wal::Wal::options(&dtf_folder, &name).unwrap_or_else(|err| {
+                    warn!("Unable to read header of write-ahead log {}: {:?}", name, err);
+                    None
+                });
+                books
+                    .entry(book_name)
+                    .or_insert_with(|| Book::new(&name, settings, &opts.unwrap_or_default()));
+            },
+            Err(err) => warn!("Unable to list write-ahead logs in {}: {:?}", dtf_folder, err),
+        }
+    }
+
+    for (book_name, book) in books {
+        state.register(book_name, book);
+    }
+    if state.settings.wal {
+        state.ask_all(Book::replay_wal).await;
+    }
 }
@@ -0,0 +1,286 @@
+//! Write-ahead log
+//!
+//! Every update is appended to `{dtf_folder}/{book}.wal` before it is acknowledged.
+//! Records are raw updates (see `Update::serialize_raw_to_buffer`), so a torn
+//! record at the tail of the log is simply dropped during replay.
+//! The log is rewritten with the updates left in memory once a flush is done.
+//! With `WalSync::Interval`, the actor of the book syncs the records left
+//! unsynced once the interval is up (see `sync_due`) and when it stops.
+//!
+//! The log starts with a header holding the tick size, lot size and properties
+//! of the book, so that a book that was never flushed is rebuilt with them.
+//! Logs written before the header was added have none until their next truncation.
+
+use crate::prelude::*;
+use crate::settings::WalSync;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::time::{Duration, Instant};
+use tdb_core::dtf::file_format::{self, EncodeOptions};
+
+/// size of a raw update: ts(8) + seq(4) + flags(1) + price(8) + size(8)
+const RECORD_SIZE: usize = 29;
+
+/// start of the header: magic, then the u32 length of a dtf header without updates
+const MAGIC: &[u8] = b"TDBWAL1";
+
+pub struct Wal {
+    pub fname: String,
+    file: File,
+    sync: WalSync,
+    last_sync: Instant,
+    /// records appended since the last sync
+    dirty: bool,
+    buf: Vec<u8>,
+    /// header written at the start of the log
+    header: Vec<u8>,
+    /// length of the header in the file, 0 for logs written without one
+    header_len: u64,
+}
+
+impl Wal {
+    /// open the log for `book_name`, creating it with the scale and properties of `opts` if necessary
+    pub fn open(dtf_folder: &str, book_name: &str, sync: WalSync, opts: &EncodeOptions) -> io::Result<Self> {
+        let fname = Self::path(dtf_folder, book_name);
+        let mut header = MAGIC.to_vec();
+        let mut meta = Cursor::new(vec![]);
+        file_format::encode_header(&mut meta, book_name, opts)?;
+        header.write_u32::<BigEndian>(meta.get_ref().len() as u32)?;
+        header.extend_from_slice(meta.get_ref());
+        let is_new = match fs::metadata(&fname) {
+            Ok(meta) => meta.len() == 0,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => true,
+            Err(e) => return Err(e),
+        };
+        // only a new log is initialised, one with an unreadable header is left as is
+        if is_new {
+            replace(&fname, &header)?;
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&fname)?;
+        let header_len = match read_header(&mut file) {
+            Ok(Some((len, _))) => len,
+            Ok(None) => 0,
+            Err(e) => {
+                error!("Invalid header in write-ahead log {}: {}", fname, e);
+                return Err(e);
+            }
+        };
+        Ok(Self {
+            fname,
+            file,
+            sync,
+            last_sync: Instant::now(),
+            dirty: false,
+            buf: Vec::with_capacity(RECORD_SIZE),
+            header,
+            header_len,
+        })
+    }
+
+    /// scale and properties in the header of the log of `book_name`, None if it has no header
+    pub fn options(dtf_folder: &str, book_name: &str) -> io::Result<Option<EncodeOptions>> {
+        let mut file = File::open(Self::path(dtf_folder, book_name))?;
+        Ok(read_header(&mut file)?.map(|(_, opts)| opts))
+    }
+
+    pub fn path(dtf_folder: &str, book_name: &str) -> String {
+        format!("{}/{}.wal", dtf_folder, book_name)
+    }
+
+    /// append one update and fsync according to the policy
+    pub fn append(&mut self, up: &Update) -> io::Result<()> {
+        self.buf.clear();
+        up.serialize_raw_to_buffer(&mut self.buf)?;
+        self.file.write_all(&self.buf)?;
+        match self.sync {
+            WalSync::Always => self.sync(),
+            WalSync::Interval(ms) => {
+                if self.last_sync.elapsed().as_millis() as u64 >= ms {
+                    self.sync()
+                } else {
+                    self.dirty = true;
+                    Ok(())
+                }
+            }
+            WalSync::Never => Ok(()),
+        }
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.last_sync = Instant::now();
+        self.dirty = false;
+        self.file.sync_data()
+    }
+
+    /// time left before the records appended since the last sync are due to be synced
+    pub fn sync_due(&self) -> Option<Duration> {
+        match self.sync {
+            WalSync::Interval(ms) if self.dirty => Some(Duration::from_millis(ms).saturating_sub(self.last_sync.elapsed())),
+            _ => None,
+        }
+    }
+
+    /// sync the records appended since the last sync, if any
+    pub fn sync_pending(&mut self) -> io::Result<()> {
+        if self.dirty {
+            self.sync()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// read every complete record in the log
+    pub fn replay(&mut self) -> io::Result<Vec<Update>> {
+        let mut bytes = Vec::new();
+        self.file.seek(SeekFrom::Start(self.header_len))?;
+        self.file.read_to_end(&mut bytes)?;
+        let torn = bytes.len() % RECORD_SIZE;
+        if torn != 0 {
+            // cut the tail so that new records stay aligned
+            warn!("Dropping {} bytes of torn record in {}", torn, self.fname);
+            self.file.set_len(self.header_len + (bytes.len() - torn) as u64)?;
+        }
+        bytes
+            .chunks_exact(RECORD_SIZE)
+            .map(Update::from_raw)
+            .collect()
+    }
+
+    /// discard everything in the log
+    pub fn truncate(&mut self) -> io::Result<()> {
+        self.rewrite(&[])
+    }
+
+    /// replace the records in the log with `ups`, called after a flush with the updates left in memory.
+    /// The new log is written next to the old one and renamed over it, so a crash keeps either of them.
+    pub fn rewrite(&mut self, ups: &[Update]) -> io::Result<()> {
+        let mut bytes = Vec::with_capacity(self.header.len() + ups.len() * RECORD_SIZE);
+        bytes.extend_from_slice(&self.header);
+        for up in ups {
+            up.serialize_raw_to_buffer(&mut bytes)?;
+        }
+        replace(&self.fname, &bytes)?;
+        self.file = OpenOptions::new().read(true).append(true).open(&self.fname)?;
+        self.header_len = self.header.len() as u64;
+        self.last_sync = Instant::now();
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// names of the books that have a log in the folder
+    pub fn list(dtf_folder: &str) -> io::Result<Vec<String>> {
+        let mut ret = vec![];
+        for entry in fs::read_dir(dtf_folder)? {
+            let path = entry?.path();
+            if path.extension().map(|ext| ext == "wal").unwrap_or(false) {
+                if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
+                    ret.push(stem.to_owned());
+                }
+            }
+        }
+        Ok(ret)
+    }
+}
+
+/// atomically replace the content of `fname` with `bytes`
+fn replace(fname: &str, bytes: &[u8]) -> io::Result<()> {
+    let tmp = format!("{}.tmp", fname);
+    let mut file = File::create(&tmp)?;
+    file.write_all(bytes)?;
+    file.sync_data()?;
+    fs::rename(&tmp, fname)
+}
+
+/// length and options of the header of a log, None if it has none
+fn read_header(file: &mut File) -> io::Result<Option<(u64, EncodeOptions)>> {
+    let mut magic = vec![];
+    file.seek(SeekFrom::Start(0))?;
+    (&mut *file).take(MAGIC.len() as u64).read_to_end(&mut magic)?;
+    if magic != MAGIC {
+        return Ok(None);
+    }
+    let len = file.read_u32::<BigEndian>()?;
+    let mut meta = vec![0; len as usize];
+    file.read_exact(&mut meta)?;
+    let meta = file_format::read_meta_from_buf(&mut Cursor::new(meta))?;
+    let opts = EncodeOptions { scale: meta.scale, properties: meta.properties, ..Default::default() };
+    Ok(Some((MAGIC.len() as u64 + 4 + len as u64, opts)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn up(ts: u64) -> Update {
+        Update { ts, seq: 0, is_trade: false, is_bid: true, price: 193900, size: 2285000000 }
+    }
+
+    #[test]
+    fn should_replay_and_truncate() {
+        let folder = "test-wal";
+        utils::create_dir_if_not_exist(folder);
+        let mut wal = Wal::open(folder, "bnc_btc_eth", WalSync::Always, &Default::default()).unwrap();
+        wal.truncate().unwrap();
+        wal.append(&up(1)).unwrap();
+        wal.append(&up(2)).unwrap();
+
+        // a torn record at the tail is ignored
+        wal.file.write_all(&[0x1, 0x2, 0x3]).unwrap();
+
+        let mut reopened = Wal::open(folder, "bnc_btc_eth", WalSync::Never, &Default::default()).unwrap();
+        assert_eq!(reopened.replay().unwrap(), vec![up(1), up(2)]);
+        assert_eq!(Wal::list(folder).unwrap(), vec!["bnc_btc_eth".to_owned()]);
+
+        reopened.rewrite(&[up(2), up(3)]).unwrap();
+        assert_eq!(reopened.replay().unwrap(), vec![up(2), up(3)]);
+        reopened.append(&up(4)).unwrap();
+        let mut reopened = Wal::open(folder, "bnc_btc_eth", WalSync::Never, &Default::default()).unwrap();
+        assert_eq!(reopened.replay().unwrap(), vec![up(2), up(3), up(4)]);
+
+        reopened.truncate().unwrap();
+        assert!(reopened.replay().unwrap().is_empty());
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn should_keep_log_with_corrupt_header() {
+        let folder = "test-wal-corrupt";
+        utils::create_dir_if_not_exist(folder);
+        let mut wal = Wal::open(folder, "bnc_btc_eth", WalSync::Always, &Default::default()).unwrap();
+        wal.append(&up(1)).unwrap();
+        let len = fs::metadata(&wal.fname).unwrap().len();
+        wal.file.set_len(MAGIC.len() as u64 + 2).unwrap();
+        wal.file.set_len(len).unwrap();
+
+        assert!(Wal::open(folder, "bnc_btc_eth", WalSync::Always, &Default::default()).is_err());
+        assert_eq!(fs::metadata(&wal.fname).unwrap().len(), len);
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn should_sync_pending_records() {
+        let folder = "test-wal-sync";
+        utils::create_dir_if_not_exist(folder);
+        let mut wal = Wal::open(folder, "bnc_btc_eth", WalSync::Interval(60_000), &Default::default()).unwrap();
+        assert_eq!(wal.sync_due(), None);
+        wal.append(&up(1)).unwrap();
+        let due = wal.sync_due().unwrap();
+        assert!(due > Duration::from_secs(50) && due <= Duration::from_secs(60));
+        wal.sync_pending().unwrap();
+        assert_eq!(wal.sync_due(), None);
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn should_parse_sync_policy() {
+        assert_eq!("always".parse::<WalSync>().unwrap(), WalSync::Always);
+        assert_eq!("never".parse::<WalSync>().unwrap(), WalSync::Never);
+        assert_eq!("100ms".parse::<WalSync>().unwrap(), WalSync::Interval(100));
+        assert_eq!("100".parse::<WalSync>().unwrap(), WalSync::Interval(100));
+        assert!("sometimes".parse::<WalSync>().is_err());
+    }
+}
@@ -17,6 +17,7 @@ fn it_works() {
         granularity: 1000,
         q_capacity: 1000,
         influx: None,
+        ..Default::default()
     });
 
     task::block_on(async move {